@@ -3,12 +3,97 @@ extern crate cxx_build;
 
 use std::{
     env,
-    fs::File,
+    fs::{self, File},
     io::{self, Read},
     path::PathBuf,
+    process::Command,
 };
 
 fn main() -> io::Result<()> {
+    let vcpkg_static = cfg!(target_feature = "crt-static");
+    let (include_dirs, triplet) = if env::var_os("CARGO_FEATURE_SYSTEM_GQLMAPI").is_some() {
+        (find_system_gqlmapi(), String::from("system"))
+    } else {
+        let triplet = vcpkg_triplet(vcpkg_static);
+        (build_vendored_gqlmapi(&triplet, vcpkg_static)?, triplet)
+    };
+    emit_backend_version(&include_dirs, &triplet);
+
+    if vcpkg_static {
+        let cpp_libs = [
+            "gqlmapi",
+            "gqlmapiCommon",
+            "mapi_schema",
+            "mapistub",
+            "graphqlservice",
+            "graphqlpeg",
+            "graphqlresponse",
+        ];
+
+        for lib in cpp_libs {
+            println!("cargo:rustc-link-lib=static={}", lib);
+        }
+    } else {
+        let cpp_dlls = ["gqlmapi", "graphqlservice", "graphqlpeg", "graphqlresponse"];
+        for dll in cpp_dlls {
+            println!("cargo:rustc-link-lib=dylib={}", dll);
+        }
+
+        // The bindings call the MAPI profile APIs directly, which always come from the static stub.
+        println!("cargo:rustc-link-lib=static=mapistub");
+    }
+
+    cxx_build::bridge("src/bindings.rs")
+        .file("src/Bindings.cpp")
+        .includes(include_dirs)
+        .flag_if_supported("/std:c++20")
+        .flag_if_supported("/EHsc")
+        .static_crt(vcpkg_static)
+        .compile("gqlmapi-rs");
+
+    println!("cargo:rerun-if-changed=src/bindings.rs");
+    println!("cargo:rerun-if-changed=src/Bindings.cpp");
+    println!("cargo:rerun-if-changed=include/Bindings.h");
+    println!("cargo:rerun-if-changed=include/ResponseTypes.h");
+
+    Ok(())
+}
+
+/// With the `system-gqlmapi` feature, link against prebuilt `gqlmapi` and `cppgraphqlgen`
+/// libraries instead of building the sub-module. `GQLMAPI_LIB_DIR` and `GQLMAPI_INCLUDE_DIR` may
+/// each hold a list of directories separated by `;`, e.g. to add the `vcpkg` dependencies.
+fn find_system_gqlmapi() -> Vec<PathBuf> {
+    println!("cargo:rerun-if-env-changed=GQLMAPI_LIB_DIR");
+    println!("cargo:rerun-if-env-changed=GQLMAPI_INCLUDE_DIR");
+
+    let lib_dirs = env::var_os("GQLMAPI_LIB_DIR")
+        .expect("GQLMAPI_LIB_DIR must be set with the system-gqlmapi feature");
+    for lib_dir in env::split_paths(&lib_dirs) {
+        println!("cargo:rustc-link-search=native={}", lib_dir.display());
+    }
+
+    let include_dirs = env::var_os("GQLMAPI_INCLUDE_DIR")
+        .expect("GQLMAPI_INCLUDE_DIR must be set with the system-gqlmapi feature");
+    env::split_paths(&include_dirs).collect()
+}
+
+/// Pick the `vcpkg` triplet matching the target architecture and CRT linkage.
+fn vcpkg_triplet(vcpkg_static: bool) -> String {
+    let platform = if cfg!(target_pointer_width = "64") {
+        "x64-windows"
+    } else {
+        "x86-windows"
+    };
+    if vcpkg_static {
+        format!("{}-static", platform)
+    } else {
+        String::from(platform)
+    }
+}
+
+/// Build the `gqlmapi` sub-module and its `vcpkg` dependencies with `cmake`, and return the
+/// include directories for the bindings.
+fn build_vendored_gqlmapi(vcpkg_triplet: &str, vcpkg_static: bool) -> io::Result<Vec<PathBuf>> {
     println!("cargo:rerun-if-env-changed=VCPKG_ROOT");
     let vcpkg_root = env::var("VCPKG_ROOT").unwrap_or_else(|_| {
         // Try to find %LOCALAPPDATA%\vcpkg\vcpkg.path.txt if %VCPKG_ROOT% was not set.
@@ -27,31 +112,51 @@ fn main() -> io::Result<()> {
             .unwrap_or_else(|_| panic!("Failed to decode: {}", vcpkg_app_data.display()))
     });
 
-    let platform = if cfg!(target_pointer_width = "64") {
-        "x64-windows"
-    } else {
-        "x86-windows"
-    };
-    let vcpkg_static = cfg!(target_feature = "crt-static");
-    let vcpkg_triplet = if vcpkg_static {
-        format!("{}-static", platform)
-    } else {
-        String::from(platform)
-    };
+    // Contributors without Ninja, or who want another release configuration, can override the
+    // defaults.
+    println!("cargo:rerun-if-env-changed=GQLMAPI_CMAKE_GENERATOR");
+    println!("cargo:rerun-if-env-changed=GQLMAPI_CMAKE_PROFILE");
+    let generator = env::var("GQLMAPI_CMAKE_GENERATOR").unwrap_or_else(|_| String::from("Ninja"));
+    let profile =
+        env::var("GQLMAPI_CMAKE_PROFILE").unwrap_or_else(|_| String::from("RelWithDebInfo"));
+
+    // Rust always links the release CRT, and so does cxx_build for Bindings.cpp, so a Debug
+    // build of gqlmapi and its vcpkg dependencies would fail to link with a mismatched CRT and
+    // `_ITERATOR_DEBUG_LEVEL`.
+    if profile.eq_ignore_ascii_case("Debug") {
+        panic!(
+            "GQLMAPI_CMAKE_PROFILE=Debug is not supported, use RelWithDebInfo to debug the C++ code"
+        );
+    }
 
-    let gqlmapi = cmake::Config::new("gqlmapi")
+    let mut config = cmake::Config::new("gqlmapi");
+    config
         .define(
             "CMAKE_TOOLCHAIN_FILE",
             format!("{}/scripts/buildsystems/vcpkg.cmake", vcpkg_root),
         )
-        .define("VCPKG_TARGET_TRIPLET", &vcpkg_triplet)
+        .define("VCPKG_TARGET_TRIPLET", vcpkg_triplet)
         .define("BUILD_SHARED_LIBS", if vcpkg_static { "OFF" } else { "ON" })
         .define("BUILD_TESTING", "OFF")
         .define("IMPLICIT_GRAPHQLJSON_DEPENDENCY", "OFF")
         .cxxflag("/EHsc")
-        .generator("Ninja")
-        .profile("RelWithDebInfo")
-        .build();
+        .generator(generator)
+        .profile(&profile);
+
+    // Keep the cmake build tree outside of OUT_DIR if requested, so it survives `cargo clean` and
+    // only the sources which changed are rebuilt. Each triplet gets its own build tree.
+    println!("cargo:rerun-if-env-changed=GQLMAPI_BUILD_CACHE");
+    if let Some(build_cache) = env::var_os("GQLMAPI_BUILD_CACHE") {
+        let mut out_dir = PathBuf::from(build_cache);
+        out_dir.push(vcpkg_triplet);
+        config.out_dir(out_dir);
+    }
+
+    // Cargo stops scanning the whole package once there are any rerun-if-changed lines, so list
+    // the sub-module explicitly to pick up changes to the C++ sources.
+    println!("cargo:rerun-if-changed=gqlmapi");
+
+    let gqlmapi = config.build();
 
     println!("cargo:rustc-link-search=native={}/lib", gqlmapi.display());
 
@@ -69,45 +174,47 @@ fn main() -> io::Result<()> {
         vcpkg_installed.display()
     );
 
-    if vcpkg_static {
-        let cpp_libs = [
-            "gqlmapi",
-            "gqlmapiCommon",
-            "mapi_schema",
-            "mapistub",
-            "graphqlservice",
-            "graphqlpeg",
-            "graphqlresponse",
-        ];
-
-        for lib in cpp_libs {
-            println!("cargo:rustc-link-lib=static={}", lib);
-        }
-    } else {
-        let cpp_dlls = ["gqlmapi", "graphqlservice", "graphqlpeg", "graphqlresponse"];
-        for dll in cpp_dlls {
-            println!("cargo:rustc-link-lib=dylib={}", dll);
-        }
-    }
-
     let mut gqlmapi_include = gqlmapi;
     gqlmapi_include.push("include");
-    let mut vcpkg_include = vcpkg_installed.clone();
+    let mut vcpkg_include = vcpkg_installed;
     vcpkg_include.push("include");
 
-    cxx_build::bridge("src/bindings.rs")
-        .file("src/Bindings.cpp")
-        .include(gqlmapi_include)
-        .include(vcpkg_include)
-        .flag_if_supported("/std:c++20")
-        .flag_if_supported("/EHsc")
-        .static_crt(vcpkg_static)
-        .compile("gqlmapi-rs");
+    Ok(vec![gqlmapi_include, vcpkg_include])
+}
 
-    println!("cargo:rerun-if-changed=src/bindings.rs");
-    println!("cargo:rerun-if-changed=src/Bindings.cpp");
-    println!("cargo:rerun-if-changed=include/Bindings.h");
-    println!("cargo:rerun-if-changed=include/ResponseTypes.h");
+/// Capture the `gqlmapi` and `graphqlservice` versions and the `vcpkg` triplet for
+/// `MAPIGraphQL::backend_version`, so bug reports can say exactly what the crate linked against.
+fn emit_backend_version(include_dirs: &[PathBuf], triplet: &str) {
+    // Skip git if the sub-module was not checked out, or it would describe this crate instead.
+    let gqlmapi = PathBuf::from("gqlmapi/CMakeLists.txt")
+        .exists()
+        .then(|| git_describe("gqlmapi"))
+        .flatten()
+        .unwrap_or_else(|| String::from("unknown"));
 
-    Ok(())
+    let graphqlservice = include_dirs
+        .iter()
+        .map(|include_dir| include_dir.join("graphqlservice/internal/Version.h"))
+        .find_map(|version_h| fs::read_to_string(version_h).ok())
+        .and_then(|version_h| {
+            let (_, rest) = version_h.split_once("FullVersion")?;
+            let (_, rest) = rest.split_once('"')?;
+            let (version, _) = rest.split_once('"')?;
+            Some(version.to_owned())
+        })
+        .unwrap_or_else(|| String::from("unknown"));
+
+    println!(
+        "cargo:rustc-env=GQLMAPI_BACKEND_VERSION=gqlmapi {gqlmapi}, graphqlservice {graphqlservice}, vcpkg {triplet}"
+    );
+}
+
+fn git_describe(dir: &str) -> Option<String> {
+    let output = Command::new("git")
+        .args(["-C", dir, "describe", "--tags", "--always", "--dirty"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let version = String::from_utf8(output.stdout).ok()?;
+    Some(version.trim().to_owned()).filter(|version| !version.is_empty())
 }
@@ -11,6 +11,7 @@
 
 struct NextContext;
 struct CompleteContext;
+struct ProfileEntry;
 
 class Bindings
 {
@@ -18,11 +19,15 @@ public:
 	Bindings() noexcept;
 	~Bindings();
 
-	void startService(bool useDefaultProfile) const noexcept;
+	bool startService(bool useDefaultProfile) const;
 	void stopService() const;
+	void resetService() const;
 
 	std::int32_t parseQuery(rust::Str query) const;
 	void discardQuery(std::int32_t queryId) const noexcept;
+	rust::Vec<rust::String> operationNames(std::int32_t queryId) const;
+	rust::Vec<rust::String> validateQuery(std::int32_t queryId) const;
+	std::uint32_t queryComplexity(std::int32_t queryId, rust::Str operationName) const;
 
 	using NextCallback = rust::Fn<rust::Box<NextContext>(rust::Box<NextContext>, rust::String)>;
 	using CompleteCallback = rust::Fn<void(rust::Box<CompleteContext>)>;
@@ -41,4 +46,6 @@ private:
 	std::unique_ptr<impl> m_pimpl;
 };
 
-std::unique_ptr<Bindings> make_bindings() noexcept;
\ No newline at end of file
+std::unique_ptr<Bindings> make_bindings() noexcept;
+rust::Vec<ProfileEntry> list_profiles();
+void raise_com_error(std::int32_t hresult);
\ No newline at end of file
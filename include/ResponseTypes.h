@@ -25,9 +25,11 @@ struct ResponseValue
     void pushListEntry(std::unique_ptr<ResponseValue> value);
 
     void setString(rust::Str value);
+    void setStringBytes(rust::Slice<const std::uint8_t> value);
     void setBool(bool value);
     void setInt(std::int64_t value);
     void setFloat(double value);
+    void setScalar(std::unique_ptr<ResponseValue> value);
 
     std::unique_ptr<std::vector<ResponseMapEntry>> releaseMap();
     std::unique_ptr<std::vector<ResponseValue>> releaseList();
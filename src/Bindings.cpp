@@ -8,15 +8,58 @@
 
 #include "MAPIGraphQL.h"
 
+#include <comdef.h>
+#include <mapix.h>
+#include <mapiutil.h>
+
+#include <algorithm>
+#include <iomanip>
 #include <iostream>
+#include <limits>
 #include <memory>
 #include <map>
 #include <optional>
 #include <queue>
+#include <set>
+#include <sstream>
 #include <thread>
+#include <vector>
 
 using namespace graphql;
 
+namespace {
+
+// Encode the HRESULT in the exception message as "HRESULT 0x8004010F: message", which is
+// parsed back out by MapiError on the Rust side.
+[[noreturn]] void throwMapiError(HRESULT hr, std::string_view message)
+{
+	std::ostringstream encoded;
+
+	encoded << "HRESULT 0x" << std::hex << std::uppercase << std::setw(8) << std::setfill('0')
+			<< static_cast<std::uint32_t>(hr) << ": " << message;
+	throw std::runtime_error(encoded.str());
+}
+
+// Translate a _com_error thrown from a MAPI call into an exception which keeps its HRESULT
+// when it crosses the bridge, since cxx only preserves the what() message.
+template <typename Func>
+decltype(auto) translateComErrors(Func &&func)
+{
+	try
+	{
+		return func();
+	}
+	catch (const _com_error &err)
+	{
+		const _bstr_t description = err.Description();
+		const auto text = static_cast<const char *>(description);
+
+		throwMapiError(err.Error(), (text && *text) ? text : "COM error");
+	}
+}
+
+} // namespace
+
 class Subscription : public std::enable_shared_from_this<Subscription>
 {
 public:
@@ -111,7 +154,10 @@ void Subscription::Deliver(response::AwaitableValue &&payload)
 
 void Subscription::Deliver(response::Value &&document)
 {
-	_nextContext = _nextCallback(std::move(_nextContext), toJSON(std::move(document)));
+	ResponseValue payload{std::move(document)};
+	auto json = next_payload(*_nextContext, payload);
+
+	_nextContext = _nextCallback(std::move(_nextContext), std::move(json));
 }
 
 void Subscription::Complete()
@@ -131,7 +177,7 @@ public:
 									rust::Box<CompleteContext> completeContext,
 									Bindings::CompleteCallback completeCallback);
 
-	void Unsubscribe() noexcept;
+	void Unsubscribe();
 
 private:
 	std::shared_ptr<Subscription> _subscription;
@@ -177,7 +223,7 @@ RegisteredSubscription::RegisteredSubscription(const std::shared_ptr<service::Re
 	}
 }
 
-void RegisteredSubscription::Unsubscribe() noexcept
+void RegisteredSubscription::Unsubscribe()
 {
 	const auto subscription = std::move(_subscription);
 
@@ -187,17 +233,351 @@ void RegisteredSubscription::Unsubscribe() noexcept
 	}
 }
 
+// Introspection query used to find out which fields return lists, and what type each field returns.
+constexpr std::string_view c_complexityIntrospection = R"gql(query {
+	__schema {
+		queryType { name }
+		mutationType { name }
+		subscriptionType { name }
+		types {
+			name
+			fields(includeDeprecated: true) {
+				name
+				type { ...TypeRef }
+			}
+		}
+	}
+}
+
+fragment TypeRef on __Type {
+	kind
+	name
+	ofType {
+		kind
+		name
+		ofType {
+			kind
+			name
+			ofType {
+				kind
+				name
+			}
+		}
+	}
+})gql";
+
+// Each list in a field type multiplies the cost of its selection set by this much.
+constexpr std::uint64_t c_listMultiplier = 10;
+constexpr std::uint64_t c_maxComplexity = std::numeric_limits<std::uint32_t>::max();
+
+std::uint64_t saturatingAdd(std::uint64_t lhs, std::uint64_t rhs) noexcept
+{
+	return std::min(lhs + rhs, c_maxComplexity);
+}
+
+std::uint64_t saturatingMultiply(std::uint64_t lhs, std::uint64_t rhs) noexcept
+{
+	return (rhs != 0 && lhs > c_maxComplexity / rhs) ? c_maxComplexity : lhs * rhs;
+}
+
+const response::Value *findMember(const response::Value &value, std::string_view name)
+{
+	if (value.type() != response::Type::Map)
+	{
+		return nullptr;
+	}
+
+	const auto itr = value.find(name);
+
+	return (itr == value.end() ? nullptr : &itr->second);
+}
+
+std::string_view getStringMember(const response::Value &value, std::string_view name)
+{
+	const auto member = findMember(value, name);
+
+	return ((member && member->type() == response::Type::String)
+				? std::string_view{member->get<response::StringType>()}
+				: std::string_view{});
+}
+
+template <typename Rule>
+const peg::ast_node *findChild(const peg::ast_node &node)
+{
+	for (const auto &child : node.children)
+	{
+		if (child->is_type<Rule>())
+		{
+			return child.get();
+		}
+	}
+
+	return nullptr;
+}
+
+class ComplexityEstimator
+{
+public:
+	explicit ComplexityEstimator(const std::shared_ptr<service::Request> &service);
+
+	std::uint32_t estimate(const std::shared_ptr<service::Request> &service, peg::ast &ast, std::string_view operationName) const;
+
+private:
+	struct FieldCost
+	{
+		std::uint64_t multiplier = 1;
+		std::string typeName;
+	};
+
+	using FragmentMap = std::map<std::string_view, const peg::ast_node *>;
+
+	std::uint64_t visitSelectionSet(const peg::ast_node &selectionSet,
+									std::string_view typeName,
+									const FragmentMap &fragments,
+									std::set<std::string_view> &activeFragments) const;
+	const FieldCost *findField(std::string_view typeName, std::string_view fieldName) const;
+
+	static FieldCost getFieldCost(const response::Value &typeRef);
+	static std::string_view getTypeCondition(const peg::ast_node &node);
+
+	std::map<std::string, std::string, std::less<>> _rootTypes;
+	std::map<std::string, std::map<std::string, FieldCost, std::less<>>, std::less<>> _types;
+};
+
+ComplexityEstimator::ComplexityEstimator(const std::shared_ptr<service::Request> &service)
+{
+	auto introspection = peg::parseString(c_complexityIntrospection);
+	const auto document = service->resolve({introspection, {}, response::Value{response::Type::Map}}).get();
+	const auto data = findMember(document, service::strData);
+	const auto schema = (data ? findMember(*data, "__schema") : nullptr);
+
+	if (!schema)
+	{
+		throw std::runtime_error("Unable to introspect the schema");
+	}
+
+	for (const auto &[operationType, member] : {std::make_pair(service::strQuery, "queryType"),
+												std::make_pair(service::strMutation, "mutationType"),
+												std::make_pair(service::strSubscription, "subscriptionType")})
+	{
+		const auto rootType = findMember(*schema, member);
+
+		if (rootType)
+		{
+			_rootTypes.emplace(operationType, getStringMember(*rootType, "name"));
+		}
+	}
+
+	const auto types = findMember(*schema, "types");
+
+	if (!types || types->type() != response::Type::List)
+	{
+		return;
+	}
+
+	for (const auto &type : types->get<response::ListType>())
+	{
+		const auto fields = findMember(type, "fields");
+
+		if (!fields || fields->type() != response::Type::List)
+		{
+			continue;
+		}
+
+		auto &fieldCosts = _types[std::string{getStringMember(type, "name")}];
+
+		for (const auto &field : fields->get<response::ListType>())
+		{
+			const auto typeRef = findMember(field, "type");
+
+			fieldCosts.emplace(getStringMember(field, "name"), (typeRef ? getFieldCost(*typeRef) : FieldCost{}));
+		}
+	}
+}
+
+std::uint32_t ComplexityEstimator::estimate(const std::shared_ptr<service::Request> &service, peg::ast &ast, std::string_view operationName) const
+{
+	const auto [operationType, operationDefinition] = service->findOperationDefinition(ast, operationName);
+
+	if (!operationDefinition)
+	{
+		throw std::runtime_error("Missing operation");
+	}
+
+	const auto itrRootType = _rootTypes.find(operationType);
+	const auto selectionSet = findChild<peg::selection_set>(*operationDefinition);
+
+	if (itrRootType == _rootTypes.cend() || !selectionSet)
+	{
+		return 0;
+	}
+
+	FragmentMap fragments;
+
+	for (const auto &child : ast.root->children)
+	{
+		if (child->is_type<peg::fragment_definition>())
+		{
+			const auto fragmentName = findChild<peg::fragment_name>(*child);
+
+			if (fragmentName)
+			{
+				fragments.emplace(fragmentName->string_view(), child.get());
+			}
+		}
+	}
+
+	std::set<std::string_view> activeFragments;
+
+	return static_cast<std::uint32_t>(visitSelectionSet(*selectionSet, itrRootType->second, fragments, activeFragments));
+}
+
+std::uint64_t ComplexityEstimator::visitSelectionSet(const peg::ast_node &selectionSet,
+													 std::string_view typeName,
+													 const FragmentMap &fragments,
+													 std::set<std::string_view> &activeFragments) const
+{
+	std::uint64_t cost = 0;
+
+	for (const auto &selection : selectionSet.children)
+	{
+		if (selection->is_type<peg::field>())
+		{
+			const auto fieldName = findChild<peg::field_name>(*selection);
+			const auto childSelectionSet = findChild<peg::selection_set>(*selection);
+			const auto field = (fieldName ? findField(typeName, fieldName->string_view()) : nullptr);
+			const FieldCost fieldCost = (field ? *field : FieldCost{});
+
+			cost = saturatingAdd(cost, 1);
+
+			if (childSelectionSet)
+			{
+				cost = saturatingAdd(cost,
+									 saturatingMultiply(fieldCost.multiplier,
+														visitSelectionSet(*childSelectionSet, fieldCost.typeName, fragments, activeFragments)));
+			}
+		}
+		else if (selection->is_type<peg::inline_fragment>())
+		{
+			const auto childSelectionSet = findChild<peg::selection_set>(*selection);
+			const auto typeCondition = getTypeCondition(*selection);
+
+			if (childSelectionSet)
+			{
+				cost = saturatingAdd(cost,
+									 visitSelectionSet(*childSelectionSet,
+													   (typeCondition.empty() ? typeName : typeCondition),
+													   fragments,
+													   activeFragments));
+			}
+		}
+		else if (selection->is_type<peg::fragment_spread>())
+		{
+			const auto fragmentName = findChild<peg::fragment_name>(*selection);
+			const auto itrFragment = (fragmentName ? fragments.find(fragmentName->string_view()) : fragments.cend());
+
+			// Skip unknown fragments and cycles, validation reports both of those as errors.
+			if (itrFragment == fragments.cend() || !activeFragments.insert(itrFragment->first).second)
+			{
+				continue;
+			}
+
+			const auto childSelectionSet = findChild<peg::selection_set>(*itrFragment->second);
+
+			if (childSelectionSet)
+			{
+				cost = saturatingAdd(cost,
+									 visitSelectionSet(*childSelectionSet,
+													   getTypeCondition(*itrFragment->second),
+													   fragments,
+													   activeFragments));
+			}
+
+			activeFragments.erase(itrFragment->first);
+		}
+	}
+
+	return cost;
+}
+
+const ComplexityEstimator::FieldCost *ComplexityEstimator::findField(std::string_view typeName, std::string_view fieldName) const
+{
+	const auto itrType = _types.find(typeName);
+
+	if (itrType == _types.cend())
+	{
+		return nullptr;
+	}
+
+	const auto itrField = itrType->second.find(fieldName);
+
+	return (itrField == itrType->second.cend() ? nullptr : &itrField->second);
+}
+
+ComplexityEstimator::FieldCost ComplexityEstimator::getFieldCost(const response::Value &typeRef)
+{
+	FieldCost fieldCost;
+
+	for (auto current = &typeRef; current; current = findMember(*current, "ofType"))
+	{
+		const auto kind = getStringMember(*current, "kind");
+
+		if (kind == "LIST")
+		{
+			fieldCost.multiplier = saturatingMultiply(fieldCost.multiplier, c_listMultiplier);
+		}
+		else if (kind != "NON_NULL")
+		{
+			fieldCost.typeName = getStringMember(*current, "name");
+			break;
+		}
+	}
+
+	return fieldCost;
+}
+
+std::string_view ComplexityEstimator::getTypeCondition(const peg::ast_node &node)
+{
+	const auto typeCondition = findChild<peg::type_condition>(node);
+	const auto namedType = (typeCondition ? findChild<peg::named_type>(*typeCondition) : nullptr);
+
+	return (namedType ? namedType->string_view() : std::string_view{});
+}
+
+// Names of the operation definitions in a document, in document order, or an empty name for an
+// anonymous operation.
+std::vector<std::string_view> getOperationNames(const peg::ast &ast)
+{
+	std::vector<std::string_view> names;
+
+	for (const auto &child : ast.root->children)
+	{
+		if (child->is_type<peg::operation_definition>())
+		{
+			const auto operationName = findChild<peg::operation_name>(*child);
+
+			names.push_back(operationName ? operationName->string_view() : std::string_view{});
+		}
+	}
+
+	return names;
+}
+
 class Bindings::impl
 {
 public:
 	impl() = default;
 	~impl() = default;
 
-	void startService(bool useDefaultProfile) noexcept;
+	bool startService(bool useDefaultProfile);
 	void stopService();
+	void resetService();
 
 	std::int32_t parseQuery(std::string_view query);
 	void discardQuery(std::int32_t queryId) noexcept;
+	rust::Vec<rust::String> operationNames(std::int32_t queryId);
+	rust::Vec<rust::String> validateQuery(std::int32_t queryId);
+	std::uint32_t queryComplexity(std::int32_t queryId, std::string_view operationName);
 
 	std::int32_t subscribe(std::int32_t queryId,
 						   std::string_view operationName,
@@ -209,34 +589,95 @@ public:
 	void unsubscribe(std::int32_t subscriptionId);
 
 private:
+	void unsubscribeAll();
+
 	std::shared_ptr<service::Request> service;
 	std::map<std::int32_t, peg::ast> queryMap;
 	std::map<std::int32_t, std::unique_ptr<RegisteredSubscription>> subscriptionMap;
+	std::int32_t lastResetQueryId = 0;
+	std::int32_t lastSubscriptionId = 0;
+	std::unique_ptr<ComplexityEstimator> complexityEstimator;
 };
 
-void Bindings::impl::startService(bool useDefaultProfile) noexcept
+bool Bindings::impl::startService(bool useDefaultProfile)
 {
+	// Logging on again would replace the session while its queries and subscriptions still refer
+	// to it, so refuse until stopService has released it.
+	if (service)
+	{
+		return false;
+	}
+
 	service = mapi::GetService(useDefaultProfile);
+	return true;
 }
 
-void Bindings::impl::stopService()
+// Unsubscribe and remove every subscription, even if unsubscribing one of them fails, and then
+// rethrow the first failure.
+void Bindings::impl::unsubscribeAll()
 {
-	if (service)
+	std::exception_ptr firstError;
+
+	for (const auto &entry : subscriptionMap)
 	{
-		for (const auto &entry : subscriptionMap)
+		try
 		{
 			entry.second->Unsubscribe();
 		}
+		catch (...)
+		{
+			if (!firstError)
+			{
+				firstError = std::current_exception();
+			}
+		}
+	}
 
-		subscriptionMap.clear();
-		queryMap.clear();
-		service.reset();
+	subscriptionMap.clear();
+
+	if (firstError)
+	{
+		std::rethrow_exception(firstError);
 	}
 }
 
+void Bindings::impl::stopService()
+{
+	if (service)
+	{
+		// Release the session even if unsubscribing failed, so startService can log on again.
+		struct ReleaseService
+		{
+			~ReleaseService()
+			{
+				owner.queryMap.clear();
+				owner.complexityEstimator.reset();
+				owner.service.reset();
+			}
+
+			impl &owner;
+		} releaseService{*this};
+
+		unsubscribeAll();
+	}
+}
+
+void Bindings::impl::resetService()
+{
+	// Keep counting up from the ids which were still in use, so a stale queryId held on the Rust
+	// side never matches anything registered after the reset.
+	if (!queryMap.empty())
+	{
+		lastResetQueryId = queryMap.crbegin()->first;
+	}
+
+	queryMap.clear();
+	unsubscribeAll();
+}
+
 std::int32_t Bindings::impl::parseQuery(std::string_view query)
 {
-	const std::int32_t queryId = (queryMap.empty() ? 1 : queryMap.crbegin()->first + 1);
+	const std::int32_t queryId = (queryMap.empty() ? lastResetQueryId : queryMap.crbegin()->first) + 1;
 
 	queryMap[queryId] = peg::parseString(query);
 	return queryId;
@@ -247,6 +688,71 @@ void Bindings::impl::discardQuery(std::int32_t queryId) noexcept
 	queryMap.erase(queryId);
 }
 
+rust::Vec<rust::String> Bindings::impl::operationNames(std::int32_t queryId)
+{
+	const auto itrQuery = queryMap.find(queryId);
+
+	if (itrQuery == queryMap.cend())
+	{
+		throw std::runtime_error("Unknown queryId");
+	}
+
+	rust::Vec<rust::String> names;
+
+	for (const auto name : getOperationNames(itrQuery->second))
+	{
+		names.push_back(rust::String{name.data(), name.size()});
+	}
+
+	return names;
+}
+
+rust::Vec<rust::String> Bindings::impl::validateQuery(std::int32_t queryId)
+{
+	const auto itrQuery = queryMap.find(queryId);
+
+	if (itrQuery == queryMap.cend())
+	{
+		throw std::runtime_error("Unknown queryId");
+	}
+
+	if (!service)
+	{
+		throw std::runtime_error("Did not call startService");
+	}
+
+	rust::Vec<rust::String> messages;
+
+	for (const auto &error : service->validate(itrQuery->second))
+	{
+		messages.push_back(rust::String{error.message});
+	}
+
+	return messages;
+}
+
+std::uint32_t Bindings::impl::queryComplexity(std::int32_t queryId, std::string_view operationName)
+{
+	const auto itrQuery = queryMap.find(queryId);
+
+	if (itrQuery == queryMap.cend())
+	{
+		throw std::runtime_error("Unknown queryId");
+	}
+
+	if (!service)
+	{
+		throw std::runtime_error("Did not call startService");
+	}
+
+	if (!complexityEstimator)
+	{
+		complexityEstimator = std::make_unique<ComplexityEstimator>(service);
+	}
+
+	return complexityEstimator->estimate(service, itrQuery->second, operationName);
+}
+
 std::int32_t Bindings::impl::subscribe(std::int32_t queryId,
 									   std::string_view operationName,
 									   std::string_view variables,
@@ -263,6 +769,17 @@ std::int32_t Bindings::impl::subscribe(std::int32_t queryId,
 	}
 
 	auto &ast = itrQuery->second;
+
+	if (operationName.empty())
+	{
+		const auto operationCount = getOperationNames(ast).size();
+
+		if (operationCount > 1)
+		{
+			throw std::runtime_error("operation name required: document defines " + std::to_string(operationCount) + " operations");
+		}
+	}
+
 	auto parsedVariables = (variables.empty() ? response::Value(response::Type::Map) : parseJSON(variables));
 
 	if (parsedVariables.type() != response::Type::Map)
@@ -275,7 +792,9 @@ std::int32_t Bindings::impl::subscribe(std::int32_t queryId,
 		throw std::runtime_error("Did not call startService");
 	}
 
-	const std::int32_t subscriptionId = (subscriptionMap.empty() ? 1 : subscriptionMap.crbegin()->first + 1);
+	// Never reuse a subscriptionId, since the worker thread may unsubscribe one on its own when the
+	// Rust side drops its receiver, and that side may still try to unsubscribe it later.
+	const std::int32_t subscriptionId = ++lastSubscriptionId;
 
 	subscriptionMap[subscriptionId] = std::make_unique<RegisteredSubscription>(service,
 																			   ast,
@@ -295,8 +814,11 @@ void Bindings::impl::unsubscribe(std::int32_t subscriptionId)
 
 	if (itr != subscriptionMap.end())
 	{
-		itr->second->Unsubscribe();
+		// Remove it first, so a failure to unsubscribe does not leave a stale entry behind.
+		const auto subscription = std::move(itr->second);
+
 		subscriptionMap.erase(itr);
+		subscription->Unsubscribe();
 	}
 }
 
@@ -309,19 +831,32 @@ Bindings::~Bindings()
 {
 }
 
-void Bindings::startService(bool useDefaultProfile) const noexcept
+bool Bindings::startService(bool useDefaultProfile) const
 {
-	m_pimpl->startService(useDefaultProfile);
+	return translateComErrors([&]() {
+		return m_pimpl->startService(useDefaultProfile);
+	});
 }
 
 void Bindings::stopService() const
 {
-	m_pimpl->stopService();
+	translateComErrors([&]() {
+		m_pimpl->stopService();
+	});
+}
+
+void Bindings::resetService() const
+{
+	translateComErrors([&]() {
+		m_pimpl->resetService();
+	});
 }
 
 std::int32_t Bindings::parseQuery(rust::Str query) const
 {
-	return m_pimpl->parseQuery(std::string_view{query.data(), query.size()});
+	return translateComErrors([&]() {
+		return m_pimpl->parseQuery(std::string_view{query.data(), query.size()});
+	});
 }
 
 void Bindings::discardQuery(std::int32_t queryId) const noexcept
@@ -329,6 +864,27 @@ void Bindings::discardQuery(std::int32_t queryId) const noexcept
 	m_pimpl->discardQuery(queryId);
 }
 
+rust::Vec<rust::String> Bindings::operationNames(std::int32_t queryId) const
+{
+	return translateComErrors([&]() {
+		return m_pimpl->operationNames(queryId);
+	});
+}
+
+rust::Vec<rust::String> Bindings::validateQuery(std::int32_t queryId) const
+{
+	return translateComErrors([&]() {
+		return m_pimpl->validateQuery(queryId);
+	});
+}
+
+std::uint32_t Bindings::queryComplexity(std::int32_t queryId, rust::Str operationName) const
+{
+	return translateComErrors([&]() {
+		return m_pimpl->queryComplexity(queryId, std::string_view{operationName.data(), operationName.size()});
+	});
+}
+
 std::int32_t Bindings::subscribe(std::int32_t queryId,
 								 rust::Str operationName,
 								 rust::Str variables,
@@ -337,25 +893,128 @@ std::int32_t Bindings::subscribe(std::int32_t queryId,
 								 rust::Box<CompleteContext> completeContext,
 								 CompleteCallback completeCallback) const
 {
-	return m_pimpl->subscribe(queryId,
-							  std::string_view{operationName.data(), operationName.size()},
-							  std::string_view{variables.data(), variables.size()},
-							  std::move(nextContext),
-							  std::move(nextCallback),
-							  std::move(completeContext),
-							  std::move(completeCallback));
+	return translateComErrors([&]() {
+		return m_pimpl->subscribe(queryId,
+								  std::string_view{operationName.data(), operationName.size()},
+								  std::string_view{variables.data(), variables.size()},
+								  std::move(nextContext),
+								  std::move(nextCallback),
+								  std::move(completeContext),
+								  std::move(completeCallback));
+	});
 }
 
 void Bindings::unsubscribe(std::int32_t subscriptionId) const
 {
-	m_pimpl->unsubscribe(subscriptionId);
+	translateComErrors([&]() {
+		m_pimpl->unsubscribe(subscriptionId);
+	});
 }
 
+namespace {
+
+void throwIfFailed(HRESULT hr, std::string_view api)
+{
+	if (FAILED(hr))
+	{
+		throwMapiError(hr, std::string{api} + " failed");
+	}
+}
+
+struct ReleaseUnknown
+{
+	void operator()(IUnknown *unknown) const noexcept
+	{
+		unknown->Release();
+	}
+};
+
+struct FreeRows
+{
+	void operator()(LPSRowSet rows) const noexcept
+	{
+		FreeProws(rows);
+	}
+};
+
+rust::Vec<ProfileEntry> listProfiles()
+{
+	throwIfFailed(MAPIInitialize(nullptr), "MAPIInitialize");
+
+	struct MAPIUninitializeGuard
+	{
+		~MAPIUninitializeGuard()
+		{
+			MAPIUninitialize();
+		}
+	} mapiGuard;
+
+	LPPROFADMIN rawProfAdmin = nullptr;
+
+	throwIfFailed(MAPIAdminProfiles(0, &rawProfAdmin), "MAPIAdminProfiles");
+
+	std::unique_ptr<IProfAdmin, ReleaseUnknown> profAdmin { rawProfAdmin };
+	LPMAPITABLE rawTable = nullptr;
+
+	throwIfFailed(profAdmin->GetProfileTable(0, &rawTable), "IProfAdmin::GetProfileTable");
+
+	std::unique_ptr<IMAPITable, ReleaseUnknown> table { rawTable };
+	SizedSPropTagArray(2, columns) = { 2, { PR_DISPLAY_NAME_W, PR_DEFAULT_PROFILE } };
+	LPSRowSet rawRows = nullptr;
+
+	throwIfFailed(HrQueryAllRows(table.get(),
+					  reinterpret_cast<LPSPropTagArray>(&columns),
+					  nullptr,
+					  nullptr,
+					  0,
+					  &rawRows),
+		"HrQueryAllRows");
+
+	std::unique_ptr<SRowSet, FreeRows> rows { rawRows };
+	rust::Vec<ProfileEntry> profiles;
+
+	profiles.reserve(rows->cRows);
+
+	for (ULONG i = 0; i < rows->cRows; ++i)
+	{
+		const auto &row = rows->aRow[i];
+
+		if (row.cValues < 2 || row.lpProps[0].ulPropTag != PR_DISPLAY_NAME_W)
+		{
+			continue;
+		}
+
+		// Read the UTF-16 name, since the ANSI one replaces anything outside the code page.
+		profiles.push_back(ProfileEntry {
+			rust::String::lossy(reinterpret_cast<const char16_t *>(row.lpProps[0].Value.lpszW)),
+			row.lpProps[1].ulPropTag == PR_DEFAULT_PROFILE && row.lpProps[1].Value.b,
+		});
+	}
+
+	return profiles;
+}
+
+} // namespace
+
 std::unique_ptr<Bindings> make_bindings() noexcept
 {
 	return std::make_unique<Bindings>();
 }
 
+rust::Vec<ProfileEntry> list_profiles()
+{
+	return translateComErrors([]() {
+		return listProfiles();
+	});
+}
+
+void raise_com_error(std::int32_t hresult)
+{
+	translateComErrors([hresult]() {
+		_com_raise_error(static_cast<HRESULT>(hresult));
+	});
+}
+
 ResponseValue::ResponseValue(ResponseValueType type)
 	: m_impl{type}
 {
@@ -397,6 +1056,12 @@ void ResponseValue::setString(rust::Str value)
 	m_impl.set<response::StringType>(response::StringType{value.data(), value.size()});
 }
 
+void ResponseValue::setStringBytes(rust::Slice<const std::uint8_t> value)
+{
+	m_impl.set<response::StringType>(
+		response::StringType{reinterpret_cast<const char *>(value.data()), value.size()});
+}
+
 void ResponseValue::setBool(bool value)
 {
 	m_impl.set<response::BooleanType>(value);
@@ -412,6 +1077,11 @@ void ResponseValue::setFloat(double value)
 	m_impl.set<response::FloatType>(value);
 }
 
+void ResponseValue::setScalar(std::unique_ptr<ResponseValue> value)
+{
+	m_impl.set<response::ScalarType>(std::move(value->m_impl));
+}
+
 std::unique_ptr<std::vector<ResponseMapEntry>> ResponseValue::releaseMap()
 {
 	auto data = m_impl.release<response::MapType>();
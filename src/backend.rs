@@ -0,0 +1,121 @@
+use crate::{
+    bindings::{ffi, CompleteContext, NextContext},
+    map_exception, ServiceError,
+};
+
+/// Callback which delivers a `next` payload on the worker thread and hands back the context.
+pub(crate) type NextCallbackFn = fn(Box<NextContext>, String) -> Box<NextContext>;
+
+/// Callback which delivers `complete` on the worker thread.
+pub(crate) type CompleteCallbackFn = fn(Box<CompleteContext>);
+
+/// Everything the worker thread does with the [GraphQL](https://graphql.org) service. The C++
+/// `Bindings` implement it on top of gqlmapi, and with the `mock` feature, so does the
+/// in-process fake.
+pub(crate) trait Backend {
+    fn start_service(&self, use_default_profile: bool) -> Result<(), ServiceError>;
+    fn stop_service(&self) -> Result<(), String>;
+    fn reset_service(&self) -> Result<(), String>;
+
+    fn parse_query(&self, query: &str) -> Result<i32, String>;
+    fn discard_query(&self, query_id: i32);
+    fn operation_names(&self, query_id: i32) -> Result<Vec<String>, String>;
+    fn validate_query(&self, query_id: i32) -> Result<Vec<String>, String>;
+    fn query_complexity(&self, query_id: i32, operation_name: &str) -> Result<u32, String>;
+
+    #[allow(clippy::too_many_arguments)]
+    fn subscribe(
+        &self,
+        query_id: i32,
+        operation_name: &str,
+        variables: &str,
+        next_context: Box<NextContext>,
+        next_callback: NextCallbackFn,
+        complete_context: Box<CompleteContext>,
+        complete_callback: CompleteCallbackFn,
+    ) -> Result<i32, String>;
+    fn unsubscribe(&self, subscription_id: i32);
+
+    /// Deliver a `next` payload to a registered subscription, like a `MAPI` notification would.
+    /// Only the in-process fake can do that on demand.
+    #[cfg(feature = "mock")]
+    fn push_payload(&self, subscription_id: i32, payload: String) -> Result<(), String>;
+}
+
+impl Backend for cxx::UniquePtr<ffi::Bindings> {
+    fn start_service(&self, use_default_profile: bool) -> Result<(), ServiceError> {
+        match (**self).startService(use_default_profile) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ServiceError::AlreadyStarted(String::from(
+                "startService was already called",
+            ))),
+            Err(err) => Err(ServiceError::Other(map_exception(err))),
+        }
+    }
+
+    fn stop_service(&self) -> Result<(), String> {
+        (**self).stopService().map_err(map_exception)
+    }
+
+    fn reset_service(&self) -> Result<(), String> {
+        (**self).resetService().map_err(map_exception)
+    }
+
+    fn parse_query(&self, query: &str) -> Result<i32, String> {
+        (**self).parseQuery(query).map_err(map_exception)
+    }
+
+    fn discard_query(&self, query_id: i32) {
+        (**self).discardQuery(query_id)
+    }
+
+    fn operation_names(&self, query_id: i32) -> Result<Vec<String>, String> {
+        (**self).operationNames(query_id).map_err(map_exception)
+    }
+
+    fn validate_query(&self, query_id: i32) -> Result<Vec<String>, String> {
+        (**self).validateQuery(query_id).map_err(map_exception)
+    }
+
+    fn query_complexity(&self, query_id: i32, operation_name: &str) -> Result<u32, String> {
+        (**self)
+            .queryComplexity(query_id, operation_name)
+            .map_err(map_exception)
+    }
+
+    fn subscribe(
+        &self,
+        query_id: i32,
+        operation_name: &str,
+        variables: &str,
+        next_context: Box<NextContext>,
+        next_callback: NextCallbackFn,
+        complete_context: Box<CompleteContext>,
+        complete_callback: CompleteCallbackFn,
+    ) -> Result<i32, String> {
+        (**self)
+            .subscribe(
+                query_id,
+                operation_name,
+                variables,
+                next_context,
+                next_callback,
+                complete_context,
+                complete_callback,
+            )
+            .map_err(map_exception)
+    }
+
+    fn unsubscribe(&self, subscription_id: i32) {
+        // The Bindings remove the subscription before they unsubscribe it from gqlmapi, so if that
+        // fails, there is nothing left to retry, and nobody is waiting for the result.
+        let _ = (**self).unsubscribe(subscription_id);
+    }
+
+    #[cfg(feature = "mock")]
+    fn push_payload(&self, _subscription_id: i32, _payload: String) -> Result<(), String> {
+        Err(String::from(
+            "Only a mock service can push payloads, use MAPIGraphQL::new_mock",
+        ))
+    }
+}
@@ -0,0 +1,136 @@
+/// Number of raw bytes in each chunk delivered by [decode_chunks], which is a whole number of
+/// 3-byte base64 groups.
+pub(crate) const CHUNK_BYTES: usize = 48 * 1024;
+
+/// Decode the standard, padded base64 in `encoded`, e.g. a `Stream` value from gqlmapi, and hand
+/// the raw bytes to `send` in chunks of up to [CHUNK_BYTES], so the whole decoded content is
+/// never held in one buffer. It stops at the first error from `send`, or at any character which
+/// is not part of the base64 alphabet.
+pub(crate) fn decode_chunks(
+    encoded: &str,
+    mut send: impl FnMut(Vec<u8>) -> Result<(), String>,
+) -> Result<(), String> {
+    let encoded = encoded.as_bytes();
+    if encoded.len() % 4 != 0 {
+        return Err(format!(
+            "Invalid base64: the length {} is not a multiple of 4",
+            encoded.len()
+        ));
+    }
+
+    let quads = encoded.len() / 4;
+    for (index, chunk) in encoded.chunks(CHUNK_BYTES / 3 * 4).enumerate() {
+        let mut bytes = Vec::with_capacity(chunk.len() / 4 * 3);
+        for (offset, quad) in chunk.chunks(4).enumerate() {
+            let last = index * (CHUNK_BYTES / 3) + offset + 1 == quads;
+            decode_quad(quad, last, &mut bytes)?;
+        }
+        send(bytes)?;
+    }
+    Ok(())
+}
+
+/// Decode 4 base64 characters into up to 3 bytes. Only the `last` quad may end with `=` padding.
+fn decode_quad(quad: &[u8], last: bool, bytes: &mut Vec<u8>) -> Result<(), String> {
+    let padding = match quad {
+        [.., b'=', b'='] if last => 2,
+        [.., b'='] if last => 1,
+        _ => 0,
+    };
+
+    let mut group = 0_u32;
+    for &c in &quad[..4 - padding] {
+        group = (group << 6) | u32::from(decode_char(c)?);
+    }
+    group <<= 6 * padding;
+
+    let [_, first, second, third] = group.to_be_bytes();
+    bytes.extend_from_slice(&[first, second, third][..3 - padding]);
+    Ok(())
+}
+
+fn decode_char(c: u8) -> Result<u8, String> {
+    match c {
+        b'A'..=b'Z' => Ok(c - b'A'),
+        b'a'..=b'z' => Ok(c - b'a' + 26),
+        b'0'..=b'9' => Ok(c - b'0' + 52),
+        b'+' => Ok(62),
+        b'/' => Ok(63),
+        _ => Err(format!("Invalid base64 character: {:?}", char::from(c))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_chunks, CHUNK_BYTES};
+
+    fn decode(encoded: &str) -> Result<Vec<Vec<u8>>, String> {
+        let mut chunks = Vec::new();
+        decode_chunks(encoded, |chunk| {
+            chunks.push(chunk);
+            Ok(())
+        })?;
+        Ok(chunks)
+    }
+
+    #[test]
+    fn decode_padding() {
+        assert_eq!(decode(""), Ok(vec![]));
+        assert_eq!(decode("TWFu"), Ok(vec![b"Man".to_vec()]));
+        assert_eq!(decode("TWE="), Ok(vec![b"Ma".to_vec()]));
+        assert_eq!(decode("TQ=="), Ok(vec![b"M".to_vec()]));
+        assert_eq!(decode("+/+/"), Ok(vec![vec![0xfb, 0xff, 0xbf]]));
+    }
+
+    #[test]
+    fn split_into_chunks() {
+        let bytes: Vec<u8> = (0..CHUNK_BYTES * 2 + 1).map(|i| i as u8).collect();
+        let encoded = {
+            const ALPHABET: &[u8] =
+                b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+            let mut encoded = String::new();
+            for group in bytes.chunks(3) {
+                let mut padded = [0_u8; 3];
+                padded[..group.len()].copy_from_slice(group);
+                let value = u32::from_be_bytes([0, padded[0], padded[1], padded[2]]);
+                for i in 0..4 {
+                    if i <= group.len() {
+                        encoded.push(char::from(ALPHABET[(value >> (18 - 6 * i)) as usize & 63]));
+                    } else {
+                        encoded.push('=');
+                    }
+                }
+            }
+            encoded
+        };
+
+        let chunks = decode(&encoded).expect("decodes the base64");
+        assert_eq!(
+            chunks.iter().map(Vec::len).collect::<Vec<_>>(),
+            [CHUNK_BYTES, CHUNK_BYTES, 1]
+        );
+        assert_eq!(chunks.concat(), bytes);
+    }
+
+    #[test]
+    fn reject_invalid_base64() {
+        let err = decode("TWF").expect_err("should fail on a partial quad");
+        assert!(err.starts_with("Invalid base64"), "{err}");
+        let err = decode("TW=u").expect_err("should fail on misplaced padding");
+        assert!(err.starts_with("Invalid base64 character"), "{err}");
+        let err = decode("TQ==TWFu").expect_err("should fail on padding before the end");
+        assert!(err.starts_with("Invalid base64 character"), "{err}");
+    }
+
+    #[test]
+    fn stop_on_send_error() {
+        let mut sent = 0;
+        let err = decode_chunks(&"AAAA".repeat(CHUNK_BYTES), |_| {
+            sent += 1;
+            Err(String::from("receiver dropped"))
+        })
+        .expect_err("should stop sending");
+        assert_eq!(err, "receiver dropped");
+        assert_eq!(sent, 1);
+    }
+}
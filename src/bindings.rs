@@ -1,14 +1,36 @@
-use std::{pin::Pin, str::FromStr};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    io::Write,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex, PoisonError},
+};
 
+use cxx::{CxxString, CxxVector};
+use serde::{
+    ser::{Error as _, SerializeMap, SerializeSeq},
+    Serialize, Serializer,
+};
 use serde_json::Value;
 
+use crate::Metrics;
+
 #[cxx::bridge]
 pub mod ffi {
     extern "Rust" {
         type NextContext;
         type CompleteContext;
+
+        fn next_payload(
+            context: &mut NextContext,
+            value: Pin<&mut ResponseValue>,
+        ) -> Result<String>;
     }
 
+    /// Shared with `graphql::response::Type`, which is also declared as the extern C++ type
+    /// below, so cxx fails the C++ build with a `static_assert` if a variant is added, removed,
+    /// or reordered on either side without updating the other.
     enum ResponseValueType {
         Map,
         List,
@@ -54,12 +76,20 @@ pub mod ffi {
         ) -> Result<()>;
         #[cxx_name = "setString"]
         fn set_string(self: Pin<&mut ResponseValue>, value: &str) -> Result<()>;
+        /// Same as [set_string](ResponseValue::set_string), but copy the raw bytes, which may not
+        /// be valid UTF-8, like an ANSI `MAPI` string property.
+        #[cxx_name = "setStringBytes"]
+        fn set_string_bytes(self: Pin<&mut ResponseValue>, value: &[u8]) -> Result<()>;
         #[cxx_name = "setBool"]
         fn set_bool(self: Pin<&mut ResponseValue>, value: bool) -> Result<()>;
         #[cxx_name = "setInt"]
         fn set_int(self: Pin<&mut ResponseValue>, value: i64) -> Result<()>;
         #[cxx_name = "setFloat"]
         fn set_float(self: Pin<&mut ResponseValue>, value: f64) -> Result<()>;
+        /// Wrap `value` in this `Scalar`, the way gqlmapi resolves a custom scalar.
+        #[cxx_name = "setScalar"]
+        fn set_scalar(self: Pin<&mut ResponseValue>, value: UniquePtr<ResponseValue>)
+            -> Result<()>;
         #[cxx_name = "releaseMap"]
         fn release_map(
             self: Pin<&mut ResponseValue>,
@@ -85,23 +115,40 @@ pub mod ffi {
 
         fn parse_json(json: &str) -> Result<Box<JsonValue>>;
         fn to_json(&mut self) -> Result<String>;
+        fn to_json_pretty(&mut self) -> Result<String>;
 
         fn from_value(value: Pin<&mut ResponseValue>) -> Result<Box<JsonValue>>;
         fn into_value(&mut self) -> Result<UniquePtr<ResponseValue>>;
     }
 
+    struct ProfileEntry {
+        name: String,
+        is_default: bool,
+    }
+
     unsafe extern "C++" {
         include!("gqlmapi-rs/include/Bindings.h");
 
         type Bindings;
 
         fn make_bindings() -> UniquePtr<Bindings>;
+        fn list_profiles() -> Result<Vec<ProfileEntry>>;
+
+        /// Throw a `_com_error` with `hresult` through the same translation as every other entry
+        /// point, so the tests can check that the `HRESULT` survives the bridge.
+        fn raise_com_error(hresult: i32) -> Result<()>;
 
-        fn startService(&self, useDefaultProfile: bool);
-        fn stopService(&self);
+        /// Log on to the `MAPI` session. Returns `false` without logging on again if the service
+        /// was already started and has not been stopped since.
+        fn startService(&self, useDefaultProfile: bool) -> Result<bool>;
+        fn stopService(&self) -> Result<()>;
+        fn resetService(&self) -> Result<()>;
 
         fn parseQuery(&self, query: &str) -> Result<i32>;
         fn discardQuery(&self, queryId: i32);
+        fn operationNames(&self, queryId: i32) -> Result<Vec<String>>;
+        fn validateQuery(&self, queryId: i32) -> Result<Vec<String>>;
+        fn queryComplexity(&self, queryId: i32, operationName: &str) -> Result<u32>;
 
         #[allow(clippy::too_many_arguments)]
         fn subscribe(
@@ -114,21 +161,75 @@ pub mod ffi {
             completeContext: Box<CompleteContext>,
             completeCallback: fn(Box<CompleteContext>),
         ) -> Result<i32>;
-        fn unsubscribe(&self, subscriptionId: i32);
+        fn unsubscribe(&self, subscriptionId: i32) -> Result<()>;
     }
 }
 
 pub struct NextContext {
     pub callback: Box<dyn FnMut(String)>,
     pub thread_id: u32,
+    pub metrics: Arc<Metrics>,
+    pub options: ConversionOptions,
+    pub stream: Option<PayloadStream>,
+    pub decode: Option<Box<dyn FnMut(Pin<&mut ffi::ResponseValue>)>>,
 }
 
+/// Writer which the next payload is serialized into directly, instead of being delivered to the
+/// `next` callback as a [String]. Whoever takes the writer out first owns the payload, and
+/// dropping it ends the stream.
+pub type PayloadStream = Arc<Mutex<Option<Box<dyn Write + Send>>>>;
+
 pub struct CompleteContext {
     pub callback: Box<dyn FnOnce()>,
     pub thread_id: u32,
+    pub metrics: Arc<Metrics>,
 }
 
-struct JsonValue(Option<Value>);
+/// Options controlling how a `ResponseValue` is converted to JSON for a `next` payload.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConversionOptions {
+    /// Wrap custom scalar values (e.g. `DateTime`, `Guid`, or `Stream`) in an object with a
+    /// single [SCALAR_TAG] member, instead of flattening them into plain JSON values.
+    pub tag_scalars: bool,
+    /// Split a `List` which is the only member of `data` into chunks of this many items, and
+    /// deliver each chunk as a separate `next` payload.
+    pub chunk_size: Option<usize>,
+    /// Replace any `next` payload whose JSON would be longer than this many bytes with a
+    /// payload holding a single `PayloadTooLarge` error.
+    pub max_payload_bytes: Option<usize>,
+    /// How `Int` and `Float` values are written to the JSON payload.
+    pub number_format: NumberFormat,
+}
+
+impl ConversionOptions {
+    /// Whether the JSON payload holds exactly the values of the `ResponseValue`, so deserializing
+    /// the `ResponseValue` directly gives the same result as parsing the payload.
+    pub fn is_native(&self) -> bool {
+        !self.tag_scalars
+            && self.chunk_size.is_none()
+            && self.max_payload_bytes.is_none()
+            && self.number_format == NumberFormat::Native
+    }
+}
+
+/// How `Int` and `Float` values are written to a `next` payload, set with
+/// [set_number_format](crate::MAPIGraphQL::set_number_format).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NumberFormat {
+    /// Write them as native JSON numbers, e.g. `42` or `0.5`. This is the default.
+    #[default]
+    Native,
+    /// Write them as JSON strings with the same digits, e.g. `"42"` or `"0.5"`, so a consumer
+    /// such as JavaScript, which parses every number as an `f64`, cannot lose precision.
+    String,
+}
+
+/// Name of the member which wraps custom scalar values when [ConversionOptions::tag_scalars] is
+/// enabled. The `ResponseValue` does not say which custom scalar type it came from, so use the
+/// schema to tell them apart.
+pub const SCALAR_TAG: &str = "__scalar";
+
+pub(crate) struct JsonValue(pub(crate) Option<Value>);
 
 fn parse_json(json: &str) -> Result<Box<JsonValue>, String> {
     JsonValue::new(json).map_err(|err| err.to_string())
@@ -138,6 +239,190 @@ fn from_value(value: Pin<&mut ffi::ResponseValue>) -> Result<Box<JsonValue>, Str
     JsonValue::try_from(value).map(Box::new)
 }
 
+fn next_payload(
+    context: &mut NextContext,
+    mut value: Pin<&mut ffi::ResponseValue>,
+) -> Result<String, String> {
+    if let Some(decode) = context.decode.as_mut() {
+        decode(value);
+        return Ok(String::new());
+    }
+
+    let writer = context
+        .stream
+        .take()
+        .and_then(|stream| stream.lock().unwrap_or_else(PoisonError::into_inner).take());
+    if let Some(writer) = writer {
+        // If this fails, the reader sees a truncated payload and reports the parse error.
+        let _ = serde_json::to_writer(writer, &StreamedValue::new(value, &context.options));
+        return Ok(String::new());
+    }
+
+    if let Some(chunk_size) = context.options.chunk_size {
+        if value.as_mut().get_type() == ffi::ResponseValueType::Map {
+            return next_chunks(context, value, chunk_size);
+        }
+    }
+
+    let JsonValue(value) = JsonValue::from_response(value, &context.options)?;
+    to_payload(&value.unwrap_or(Value::Null), &context.options)
+}
+
+/// Serialize a `next` payload, but stop as soon as it exceeds
+/// [ConversionOptions::max_payload_bytes] instead of allocating the rest of it.
+fn to_payload(value: &Value, options: &ConversionOptions) -> Result<String, String> {
+    let Some(limit) = options.max_payload_bytes else {
+        return serde_json::to_string(value).map_err(|err| err.to_string());
+    };
+
+    let mut writer = LimitedWriter {
+        buffer: Vec::new(),
+        limit,
+    };
+    match serde_json::to_writer(&mut writer, value) {
+        Ok(()) => String::from_utf8(writer.buffer).map_err(|err| err.to_string()),
+        Err(err) if err.is_io() => Ok(too_large_payload(limit)),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Payload delivered in place of one which is too large, with a single error whose message
+/// starts with `PayloadTooLarge:`.
+fn too_large_payload(limit: usize) -> String {
+    serde_json::json!({
+        "data": null,
+        "errors": [{
+            "message": format!("PayloadTooLarge: the payload exceeded the limit of {limit} bytes"),
+        }],
+    })
+    .to_string()
+}
+
+/// [std::io::Write] which fails once more than `limit` bytes have been written.
+struct LimitedWriter {
+    buffer: Vec<u8>,
+    limit: usize,
+}
+
+impl std::io::Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.buffer.len() + buf.len() > self.limit {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::OutOfMemory,
+                "payload too large",
+            ));
+        }
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// If the payload looks like `{ "data": { "field": [...] } }`, deliver all but the last chunk of
+/// the list directly through the `next` callback, converting the items as they are delivered so
+/// the whole list is never serialized at once. The last (or only) chunk is returned to be
+/// delivered like any other payload.
+fn next_chunks(
+    context: &mut NextContext,
+    mut value: Pin<&mut ffi::ResponseValue>,
+    chunk_size: usize,
+) -> Result<String, String> {
+    let options = context.options;
+    let mut members = value
+        .as_mut()
+        .release_map()
+        .map_err(|err| format!("Failed to release Map entries: {err}"))?;
+    let mut members = members
+        .as_mut()
+        .ok_or("Map ResponseValue returned a null vector".to_owned())?;
+
+    if let [ffi::ResponseMapEntry { name, value: data }] = members.as_mut().as_mut_slice() {
+        let is_data = name.as_ref().and_then(|name| name.to_str().ok()) == Some("data");
+        if let (true, Some(mut data)) = (is_data, data.as_mut()) {
+            if data.as_mut().get_type() == ffi::ResponseValueType::Map {
+                let mut fields = data
+                    .as_mut()
+                    .release_map()
+                    .map_err(|err| format!("Failed to release Map entries: {err}"))?;
+                let mut fields = fields
+                    .as_mut()
+                    .ok_or("Map ResponseValue returned a null vector".to_owned())?;
+
+                if let [ffi::ResponseMapEntry { name, value: list }] =
+                    fields.as_mut().as_mut_slice()
+                {
+                    if let (Some(Ok(name)), Some(list)) =
+                        (name.as_ref().map(|name| name.to_str()), list.as_mut())
+                    {
+                        if list.get_type() == ffi::ResponseValueType::List {
+                            return next_list_chunks(context, name, list, chunk_size);
+                        }
+                    }
+                }
+
+                let data = map_entries(fields, &options);
+                return to_payload(&serde_json::json!({ "data": data }), &options);
+            }
+        }
+    }
+
+    to_payload(&Value::Object(map_entries(members, &options)), &options)
+}
+
+fn next_list_chunks(
+    context: &mut NextContext,
+    name: &str,
+    mut list: Pin<&mut ffi::ResponseValue>,
+    chunk_size: usize,
+) -> Result<String, String> {
+    let options = context.options;
+    let chunk_size = chunk_size.max(1);
+    let mut items = list
+        .as_mut()
+        .release_list()
+        .map_err(|err| format!("Failed to release List entries: {err}"))?;
+    let items = items
+        .as_mut()
+        .ok_or("List ResponseValue returned a null vector".to_owned())?;
+    let count = items.len();
+    let chunk_payload =
+        |chunk: Vec<Value>| to_payload(&serde_json::json!({ "data": { name: chunk } }), &options);
+    let mut chunk = Vec::with_capacity(chunk_size.min(count));
+
+    for (i, item) in items.iter_mut().enumerate() {
+        if let Ok(JsonValue(Some(item))) = JsonValue::from_response(item, &options) {
+            chunk.push(item);
+        }
+
+        if chunk.len() == chunk_size && i + 1 < count {
+            let payload = chunk_payload(std::mem::take(&mut chunk))?;
+            (context.callback)(payload);
+        }
+    }
+
+    chunk_payload(chunk)
+}
+
+fn map_entries(
+    members: Pin<&mut CxxVector<ffi::ResponseMapEntry>>,
+    options: &ConversionOptions,
+) -> serde_json::Map<String, Value> {
+    let mut map = serde_json::Map::new();
+    for ffi::ResponseMapEntry { name, value } in members.as_mut_slice() {
+        if let (Some(name), Some(value)) = (name.as_ref(), value.as_mut()) {
+            if let (Ok(name), Ok(JsonValue(Some(value)))) =
+                (name.to_str(), JsonValue::from_response(value, options))
+            {
+                map.insert(name.to_owned(), value);
+            }
+        }
+    }
+    map
+}
+
 impl JsonValue {
     fn new(json: &str) -> Result<Box<Self>, serde_json::Error> {
         let value = Value::from_str(json)?;
@@ -150,6 +435,13 @@ impl JsonValue {
         serde_json::to_string(&value)
     }
 
+    /// Same as `to_json`, but indented over multiple lines, e.g. for debugging or golden files.
+    #[allow(clippy::wrong_self_convention)]
+    fn to_json_pretty(&mut self) -> Result<String, serde_json::Error> {
+        let value = self.0.take().unwrap_or(Value::Null);
+        serde_json::to_string_pretty(&value)
+    }
+
     #[allow(clippy::wrong_self_convention)]
     fn into_value(&mut self) -> Result<cxx::UniquePtr<ffi::ResponseValue>, String> {
         let value = JsonValue(self.0.take());
@@ -262,10 +554,56 @@ impl TryInto<cxx::UniquePtr<ffi::ResponseValue>> for JsonValue {
     }
 }
 
+/// `MAPI` string properties may contain bytes which are not valid UTF-8, which JSON cannot
+/// represent, so replace each invalid sequence with `U+FFFD` instead of dropping the whole string
+/// to `null`. The rest of the string is preserved as it is.
+pub(crate) fn lossy_string(value: &CxxString) -> Cow<'_, str> {
+    value.to_string_lossy()
+}
+
+/// JSON cannot represent `NaN` or `Infinity`, so non-finite `Float` values are converted to the
+/// strings `"NaN"`, `"Infinity"`, and `"-Infinity"` instead of being silently dropped to `null`.
+/// Finite values are serialized with the shortest representation which round-trips exactly.
+fn float_to_json(value: f64) -> Value {
+    if value.is_nan() {
+        Value::String(String::from("NaN"))
+    } else if value.is_infinite() {
+        Value::String(String::from(if value.is_sign_positive() {
+            "Infinity"
+        } else {
+            "-Infinity"
+        }))
+    } else {
+        serde_json::json!(value)
+    }
+}
+
+fn int_to_json(value: i64, options: &ConversionOptions) -> Value {
+    format_number(serde_json::json!(value), options)
+}
+
+/// Apply [ConversionOptions::number_format] to a converted `Int` or `Float`. The strings which
+/// [float_to_json] uses for non-finite values are already strings, so they are left alone.
+fn format_number(value: Value, options: &ConversionOptions) -> Value {
+    match (options.number_format, value) {
+        (NumberFormat::String, Value::Number(number)) => Value::String(number.to_string()),
+        (_, value) => value,
+    }
+}
+
 impl TryFrom<Pin<&mut ffi::ResponseValue>> for JsonValue {
     type Error = String;
 
-    fn try_from(mut value: Pin<&mut ffi::ResponseValue>) -> Result<Self, String> {
+    fn try_from(value: Pin<&mut ffi::ResponseValue>) -> Result<Self, String> {
+        Self::from_response(value, &ConversionOptions::default())
+    }
+}
+
+impl JsonValue {
+    fn from_response(
+        mut value: Pin<&mut ffi::ResponseValue>,
+        options: &ConversionOptions,
+    ) -> Result<Self, String> {
         Ok(Self(Some(match value.as_mut().get_type() {
             ffi::ResponseValueType::Map => {
                 let mut members = value
@@ -275,17 +613,7 @@ impl TryFrom<Pin<&mut ffi::ResponseValue>> for JsonValue {
                 let members = members
                     .as_mut()
                     .ok_or("Map ResponseValue returned a null vector".to_owned())?;
-                let mut map = serde_json::Map::new();
-                for ffi::ResponseMapEntry { name, value } in members.as_mut_slice() {
-                    if let (Some(name), Some(value)) = (name.as_ref(), value.as_mut()) {
-                        if let (Ok(name), Ok(JsonValue(Some(value)))) =
-                            (name.to_str(), value.try_into())
-                        {
-                            map.insert(name.to_owned(), value);
-                        }
-                    }
-                }
-                Value::Object(map)
+                Value::Object(map_entries(members, options))
             }
             ffi::ResponseValueType::List => {
                 let mut members = value
@@ -297,7 +625,7 @@ impl TryFrom<Pin<&mut ffi::ResponseValue>> for JsonValue {
                     .ok_or("List ResponseValue returned a null vector".to_owned())?;
                 let mut list = Vec::new();
                 for value in members.iter_mut() {
-                    if let Ok(JsonValue(Some(value))) = value.try_into() {
+                    if let Ok(JsonValue(Some(value))) = JsonValue::from_response(value, options) {
                         list.push(value);
                     }
                 }
@@ -306,18 +634,14 @@ impl TryFrom<Pin<&mut ffi::ResponseValue>> for JsonValue {
             ffi::ResponseValueType::String
             | ffi::ResponseValueType::EnumValue
             | ffi::ResponseValueType::ID => {
-                if let Ok(value) = value
+                let mut value = value
                     .as_mut()
                     .release_string()
-                    .map_err(|err| format!("Failed to release String: {err}"))?
+                    .map_err(|err| format!("Failed to release String: {err}"))?;
+                let value = value
                     .as_mut()
-                    .ok_or("String ResponseValue returned a null value".to_owned())?
-                    .to_str()
-                {
-                    Value::String(value.to_owned())
-                } else {
-                    Value::Null
-                }
+                    .ok_or("String ResponseValue returned a null value".to_owned())?;
+                Value::String(lossy_string(&value).into_owned())
             }
             ffi::ResponseValueType::Null => Value::Null,
             ffi::ResponseValueType::Boolean => Value::Bool(
@@ -331,14 +655,14 @@ impl TryFrom<Pin<&mut ffi::ResponseValue>> for JsonValue {
                     .as_mut()
                     .get_int()
                     .map_err(|err| format!("Failed to get Int: {err}"))?;
-                serde_json::json!(value)
+                int_to_json(value, options)
             }
             ffi::ResponseValueType::Float => {
                 let value = value
                     .as_mut()
                     .get_float()
                     .map_err(|err| format!("Failed to get Float: {err}"))?;
-                serde_json::json!(value)
+                format_number(float_to_json(value), options)
             }
             ffi::ResponseValueType::Scalar => {
                 let mut value = value
@@ -348,13 +672,444 @@ impl TryFrom<Pin<&mut ffi::ResponseValue>> for JsonValue {
                 let value = value
                     .as_mut()
                     .ok_or("Scalar ResponseValue returned a null value".to_owned())?;
-                if let Ok(JsonValue(Some(value))) = value.try_into() {
-                    value
+                let value =
+                    if let Ok(JsonValue(Some(value))) = JsonValue::from_response(value, options) {
+                        value
+                    } else {
+                        Value::Null
+                    };
+                if options.tag_scalars {
+                    serde_json::json!({ SCALAR_TAG: value })
                 } else {
-                    Value::Null
+                    value
                 }
             }
             _ => unreachable!(),
         })))
     }
 }
+
+/// Serialize a `ResponseValue` like [JsonValue::from_response], but release each member as it
+/// is written, so the payload never exists as a whole [Value] or [String].
+struct StreamedValue<'a> {
+    value: RefCell<Option<Pin<&'a mut ffi::ResponseValue>>>,
+    options: &'a ConversionOptions,
+}
+
+impl<'a> StreamedValue<'a> {
+    fn new(value: Pin<&'a mut ffi::ResponseValue>, options: &'a ConversionOptions) -> Self {
+        Self {
+            value: RefCell::new(Some(value)),
+            options,
+        }
+    }
+}
+
+impl Serialize for StreamedValue<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut value = self
+            .value
+            .borrow_mut()
+            .take()
+            .ok_or_else(|| S::Error::custom("ResponseValue was already serialized"))?;
+
+        match value.as_mut().get_type() {
+            ffi::ResponseValueType::Map => {
+                let mut members = value.as_mut().release_map().map_err(|err| {
+                    S::Error::custom(format!("Failed to release Map entries: {err}"))
+                })?;
+                let members = members
+                    .as_mut()
+                    .ok_or_else(|| S::Error::custom("Map ResponseValue returned a null vector"))?;
+                let mut map = serializer.serialize_map(None)?;
+                for ffi::ResponseMapEntry { name, value } in members.as_mut_slice() {
+                    if let (Some(Ok(name)), Some(value)) =
+                        (name.as_ref().map(|name| name.to_str()), value.as_mut())
+                    {
+                        map.serialize_entry(name, &StreamedValue::new(value, self.options))?;
+                    }
+                }
+                map.end()
+            }
+            ffi::ResponseValueType::List => {
+                let mut items = value.as_mut().release_list().map_err(|err| {
+                    S::Error::custom(format!("Failed to release List entries: {err}"))
+                })?;
+                let items = items
+                    .as_mut()
+                    .ok_or_else(|| S::Error::custom("List ResponseValue returned a null vector"))?;
+                let mut list = serializer.serialize_seq(Some(items.len()))?;
+                for item in items.iter_mut() {
+                    list.serialize_element(&StreamedValue::new(item, self.options))?;
+                }
+                list.end()
+            }
+            ffi::ResponseValueType::String
+            | ffi::ResponseValueType::EnumValue
+            | ffi::ResponseValueType::ID => {
+                let mut value = value
+                    .as_mut()
+                    .release_string()
+                    .map_err(|err| S::Error::custom(format!("Failed to release String: {err}")))?;
+                let value = value.as_mut().ok_or_else(|| {
+                    S::Error::custom("String ResponseValue returned a null value")
+                })?;
+                serializer.serialize_str(&lossy_string(&value))
+            }
+            ffi::ResponseValueType::Null => serializer.serialize_unit(),
+            ffi::ResponseValueType::Boolean => serializer.serialize_bool(
+                value
+                    .as_mut()
+                    .get_bool()
+                    .map_err(|err| S::Error::custom(format!("Failed to get Boolean: {err}")))?,
+            ),
+            ffi::ResponseValueType::Int => int_to_json(
+                value
+                    .as_mut()
+                    .get_int()
+                    .map_err(|err| S::Error::custom(format!("Failed to get Int: {err}")))?,
+                self.options,
+            )
+            .serialize(serializer),
+            ffi::ResponseValueType::Float => format_number(
+                float_to_json(
+                    value
+                        .as_mut()
+                        .get_float()
+                        .map_err(|err| S::Error::custom(format!("Failed to get Float: {err}")))?,
+                ),
+                self.options,
+            )
+            .serialize(serializer),
+            ffi::ResponseValueType::Scalar => {
+                let mut value = value
+                    .as_mut()
+                    .release_scalar()
+                    .map_err(|err| S::Error::custom(format!("Failed to release Scalar: {err}")))?;
+                let value = value.as_mut().ok_or_else(|| {
+                    S::Error::custom("Scalar ResponseValue returned a null value")
+                })?;
+                let value = StreamedValue::new(value, self.options);
+                if self.options.tag_scalars {
+                    let mut map = serializer.serialize_map(Some(1))?;
+                    map.serialize_entry(SCALAR_TAG, &value)?;
+                    map.end()
+                } else {
+                    value.serialize(serializer)
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        ffi, float_to_json, lossy_string, to_payload, ConversionOptions, JsonValue, NumberFormat,
+        StreamedValue,
+    };
+    use crate::{map_exception, MapiError};
+    use serde_json::Value;
+
+    fn convert_float(value: f64) -> Value {
+        let mut response = ffi::make_response_value(ffi::ResponseValueType::Float);
+        let mut pinned = response.as_mut().expect("allocates a Float");
+        pinned.as_mut().set_float(value).expect("sets the Float");
+        let JsonValue(value) = pinned.try_into().expect("converts the Float");
+        value.expect("has a value")
+    }
+
+    #[test]
+    fn non_finite_floats() {
+        assert_eq!(float_to_json(f64::NAN), Value::from("NaN"));
+        assert_eq!(float_to_json(f64::INFINITY), Value::from("Infinity"));
+        assert_eq!(float_to_json(f64::NEG_INFINITY), Value::from("-Infinity"));
+        assert_eq!(convert_float(f64::NAN), Value::from("NaN"));
+        assert_eq!(convert_float(f64::INFINITY), Value::from("Infinity"));
+        assert_eq!(convert_float(f64::NEG_INFINITY), Value::from("-Infinity"));
+    }
+
+    #[test]
+    fn float_precision() {
+        let expected = 0.1_f64 + 0.2_f64;
+        let json = convert_float(expected).to_string();
+        assert_eq!(json, "0.30000000000000004", "uses 17 significant digits");
+        let actual: f64 = serde_json::from_str(&json).expect("parses the Float");
+        assert_eq!(actual.to_bits(), expected.to_bits(), "round-trips exactly");
+    }
+
+    /// Map with a single `name` member holding a String which is not valid UTF-8.
+    fn invalid_utf8_map() -> cxx::UniquePtr<ffi::ResponseValue> {
+        let mut name = ffi::make_response_value(ffi::ResponseValueType::String);
+        name.as_mut()
+            .expect("allocates a String")
+            .set_string_bytes(b"Caf\xe9 \xff\xfeMenu")
+            .expect("sets the String");
+        let mut map = ffi::make_response_value(ffi::ResponseValueType::Map);
+        map.as_mut()
+            .expect("allocates a Map")
+            .push_map_entry("name", name)
+            .expect("adds the member");
+        map
+    }
+
+    #[test]
+    fn replace_invalid_utf8() {
+        cxx::let_cxx_string!(valid = "Inbox");
+        assert_eq!(lossy_string(&valid), "Inbox");
+        cxx::let_cxx_string!(invalid = b"Caf\xe9 \xff\xfeMenu");
+        assert_eq!(lossy_string(&invalid), "Caf\u{fffd} \u{fffd}\u{fffd}Menu");
+
+        let expected = serde_json::json!({ "name": "Caf\u{fffd} \u{fffd}\u{fffd}Menu" });
+        let mut value = invalid_utf8_map();
+        let JsonValue(converted) = value
+            .as_mut()
+            .expect("allocates a Map")
+            .try_into()
+            .expect("converts the ResponseValue");
+        assert_eq!(converted, Some(expected.clone()));
+
+        let mut value = invalid_utf8_map();
+        let options = ConversionOptions::default();
+        let streamed = serde_json::to_string(&StreamedValue::new(
+            value.as_mut().expect("allocates a Map"),
+            &options,
+        ))
+        .expect("streams the ResponseValue");
+        assert_eq!(streamed, expected.to_string());
+    }
+
+    #[test]
+    fn format_numbers_as_strings() {
+        let json = r#"{"count":9007199254740993,"ratio":0.5,"nan":"NaN","name":"x","big":1e300}"#;
+        let options = ConversionOptions {
+            number_format: NumberFormat::String,
+            ..Default::default()
+        };
+        let expected =
+            r#"{"count":"9007199254740993","ratio":"0.5","nan":"NaN","name":"x","big":"1e300"}"#;
+
+        let mut value = JsonValue::new(json)
+            .expect("parses the JSON")
+            .into_value()
+            .expect("converts to a ResponseValue");
+        let JsonValue(converted) =
+            JsonValue::from_response(value.as_mut().expect("allocates a Map"), &options)
+                .expect("converts the ResponseValue");
+        assert_eq!(
+            serde_json::to_string(&converted).expect("formats the JSON"),
+            expected
+        );
+
+        let mut value = JsonValue::new(json)
+            .expect("parses the JSON")
+            .into_value()
+            .expect("converts to a ResponseValue");
+        let streamed = serde_json::to_string(&StreamedValue::new(
+            value.as_mut().expect("allocates a Map"),
+            &options,
+        ))
+        .expect("streams the ResponseValue");
+        assert_eq!(streamed, expected);
+    }
+
+    #[test]
+    fn pretty_json() {
+        let mut value = JsonValue::new(r#"{"data":{"b":1,"a":[true]}}"#).expect("parses the JSON");
+        let json = value.to_json_pretty().expect("formats the JSON");
+        assert_eq!(
+            json, "{\n  \"data\": {\n    \"b\": 1,\n    \"a\": [\n      true\n    ]\n  }\n}",
+            "indents and preserves the member order"
+        );
+    }
+
+    #[test]
+    fn stream_response_value() {
+        let json = r#"{"data":{"items":[{"id":"1","unread":2,"flagged":true},{"id":"2","preview":null}]}}"#;
+        let mut value = JsonValue::new(json)
+            .expect("parses the JSON")
+            .into_value()
+            .expect("converts to a ResponseValue");
+        let options = ConversionOptions::default();
+        let streamed = serde_json::to_string(&StreamedValue::new(
+            value.as_mut().expect("allocates a Map"),
+            &options,
+        ))
+        .expect("streams the ResponseValue");
+        assert_eq!(
+            streamed, json,
+            "matches the order and values of the payload"
+        );
+    }
+
+    /// Map with a custom scalar next to the other string-like variants, like a `Folder` with a
+    /// `DateTime` member.
+    fn custom_scalar_map() -> cxx::UniquePtr<ffi::ResponseValue> {
+        let mut received = ffi::make_response_value(ffi::ResponseValueType::String);
+        received
+            .as_mut()
+            .expect("allocates a String")
+            .set_string("2026-10-14T12:00:00Z")
+            .expect("sets the String");
+        let mut scalar = ffi::make_response_value(ffi::ResponseValueType::Scalar);
+        scalar
+            .as_mut()
+            .expect("allocates a Scalar")
+            .set_scalar(received)
+            .expect("sets the Scalar");
+        let mut kind = ffi::make_response_value(ffi::ResponseValueType::EnumValue);
+        kind.as_mut()
+            .expect("allocates an EnumValue")
+            .set_string("INBOX")
+            .expect("sets the EnumValue");
+        let id = ffi::make_response_value(ffi::ResponseValueType::ID);
+
+        let mut map = ffi::make_response_value(ffi::ResponseValueType::Map);
+        let mut pinned = map.as_mut().expect("allocates a Map");
+        for (name, value) in [("received", scalar), ("kind", kind), ("id", id)] {
+            pinned
+                .as_mut()
+                .push_map_entry(name, value)
+                .expect("adds the member");
+        }
+        map
+    }
+
+    #[test]
+    fn tag_custom_scalars() {
+        let tagged = ConversionOptions {
+            tag_scalars: true,
+            ..Default::default()
+        };
+        for (options, expected) in [
+            (
+                ConversionOptions::default(),
+                r#"{"received":"2026-10-14T12:00:00Z","kind":"INBOX","id":""}"#,
+            ),
+            (
+                tagged,
+                r#"{"received":{"__scalar":"2026-10-14T12:00:00Z"},"kind":"INBOX","id":""}"#,
+            ),
+        ] {
+            let mut value = custom_scalar_map();
+            let JsonValue(converted) =
+                JsonValue::from_response(value.as_mut().expect("allocates a Map"), &options)
+                    .expect("converts the ResponseValue");
+            assert_eq!(
+                serde_json::to_string(&converted).expect("formats the JSON"),
+                expected,
+                "should only tag the Scalar"
+            );
+
+            let mut value = custom_scalar_map();
+            let streamed = serde_json::to_string(&StreamedValue::new(
+                value.as_mut().expect("allocates a Map"),
+                &options,
+            ))
+            .expect("streams the ResponseValue");
+            assert_eq!(streamed, expected, "should stream the same tags");
+        }
+    }
+
+    #[test]
+    fn limit_payload_size() {
+        let options = ConversionOptions {
+            max_payload_bytes: Some(1024),
+            ..Default::default()
+        };
+        let large = serde_json::json!({ "data": { "items": vec!["x".repeat(64); 64] } });
+        let payload: Value =
+            serde_json::from_str(&to_payload(&large, &options).expect("replaces the payload"))
+                .expect("parses the replacement");
+        assert_eq!(payload["data"], Value::Null);
+        assert!(
+            payload["errors"][0]["message"]
+                .as_str()
+                .is_some_and(|message| message.starts_with("PayloadTooLarge:")),
+            "{payload}"
+        );
+
+        let small = serde_json::json!({ "data": { "__typename": "Query" } });
+        assert_eq!(to_payload(&small, &options), Ok(small.to_string()));
+    }
+
+    #[test]
+    fn translate_com_errors() {
+        let err = ffi::raise_com_error(MapiError::MAPI_E_NOT_FOUND)
+            .expect_err("should throw the _com_error");
+        let err = MapiError::from(map_exception(err));
+        assert_eq!(
+            err,
+            MapiError::Mapi {
+                hresult: MapiError::MAPI_E_NOT_FOUND,
+                message: String::from("HRESULT 0x8004010F: COM error"),
+            }
+        );
+        assert_eq!(err.name(), Some("MAPI_E_NOT_FOUND"));
+    }
+
+    #[test]
+    fn response_value_types_match() {
+        use ffi::ResponseValueType;
+
+        // Each conversion sets and reads the value with the accessor for its Rust variant, which
+        // throws in C++ if the variant maps to a different graphql::response::Type.
+        for (json, value_type) in [
+            (r#"{"a":1}"#, ResponseValueType::Map),
+            ("[1]", ResponseValueType::List),
+            (r#""s""#, ResponseValueType::String),
+            ("null", ResponseValueType::Null),
+            ("true", ResponseValueType::Boolean),
+            ("-2", ResponseValueType::Int),
+            ("1.5", ResponseValueType::Float),
+        ] {
+            let mut value = JsonValue::new(json)
+                .expect("parses the JSON")
+                .into_value()
+                .expect("converts to a ResponseValue");
+            let pinned = value.as_mut().expect("allocates a ResponseValue");
+            assert!(
+                pinned.get_type() == value_type,
+                "{json} should convert to variant {}",
+                value_type.repr
+            );
+            let JsonValue(converted) = pinned.try_into().expect("converts back to JSON");
+            assert_eq!(
+                converted.map(|value| value.to_string()).as_deref(),
+                Some(json)
+            );
+        }
+
+        let mut value = ffi::make_response_value(ResponseValueType::EnumValue);
+        let mut pinned = value.as_mut().expect("allocates an EnumValue");
+        pinned
+            .as_mut()
+            .set_string("VALUE")
+            .expect("sets the EnumValue");
+        assert!(pinned.get_type() == ResponseValueType::EnumValue);
+        let released = pinned.release_string().expect("releases the EnumValue");
+        assert_eq!(released.to_str(), Ok("VALUE"));
+
+        let mut value = ffi::make_response_value(ResponseValueType::ID);
+        let pinned = value.as_mut().expect("allocates an ID");
+        let released = pinned.release_string().expect("releases the ID");
+        assert_eq!(released.to_str(), Ok(""), "should start out empty");
+
+        let mut value = ffi::make_response_value(ResponseValueType::Scalar);
+        let pinned = value.as_mut().expect("allocates a Scalar");
+        let released = pinned.release_scalar().expect("releases the Scalar");
+        let inner = released.as_ref().expect("has a value");
+        assert!(
+            inner.get_type() == ResponseValueType::Null,
+            "should wrap Null"
+        );
+
+        let value = ffi::make_response_value(ResponseValueType::Int);
+        let value = value.as_ref().expect("allocates an Int");
+        assert!(
+            value.get_bool().is_err(),
+            "should reject the accessor for another variant"
+        );
+    }
+}
@@ -0,0 +1,375 @@
+//! Conversion between JSON and the `ResponseValue` which gqlmapi uses for variables and results,
+//! the same way the service converts them. Nothing here starts a service, spawns the worker
+//! thread, or logs on to `MAPI`, so it can be used and tested on its own, although it still links
+//! the native `gqlmapi` libraries.
+
+use std::pin::Pin;
+
+use serde::de::{
+    value::Error, DeserializeOwned, DeserializeSeed, Deserializer, Error as _, IntoDeserializer,
+    MapAccess, SeqAccess, Visitor,
+};
+use serde_json::Value;
+
+pub use crate::bindings::ffi::{ResponseValue, ResponseValueType};
+use crate::bindings::{ffi, lossy_string, JsonValue};
+
+/// Parse a JSON string into a [ResponseValue], like the service parses the `variables` for
+/// [subscribe](crate::MAPIGraphQL::subscribe).
+pub fn parse_json(json: &str) -> Result<cxx::UniquePtr<ResponseValue>, String> {
+    let value = serde_json::from_str(json).map_err(|err| format!("Error parsing JSON: {err}"))?;
+    into_value(value)
+}
+
+/// Convert a JSON [Value] into a [ResponseValue]. Every string is marked as coming from JSON, so
+/// gqlmapi may coerce it to an enum value.
+pub fn into_value(value: Value) -> Result<cxx::UniquePtr<ResponseValue>, String> {
+    JsonValue(Some(value)).try_into()
+}
+
+/// Convert a [ResponseValue] into a JSON [Value], like a `next` payload without
+/// [with_tagged_scalars](crate::MAPIGraphQL::with_tagged_scalars). This releases the
+/// members of the [ResponseValue], so it is left empty. Any invalid UTF-8 in a string is
+/// replaced with `U+FFFD`, rather than dropping the string to `null`.
+pub fn from_value(value: Pin<&mut ResponseValue>) -> Result<Value, String> {
+    let JsonValue(value) = value.try_into()?;
+    Ok(value.unwrap_or(Value::Null))
+}
+
+/// Convert a [ResponseValue] into a JSON string, like [from_value].
+pub fn to_json(value: Pin<&mut ResponseValue>) -> Result<String, String> {
+    let value = from_value(value)?;
+    serde_json::to_string(&value).map_err(|err| format!("Error formatting JSON: {err}"))
+}
+
+/// Deserialize `T` straight from a [ResponseValue] with a [ResponseValueDeserializer], without
+/// building a JSON [Value] first. This releases the members of the [ResponseValue] like
+/// [from_value].
+pub fn from_response_value<T: DeserializeOwned>(
+    value: Pin<&mut ResponseValue>,
+) -> Result<T, String> {
+    T::deserialize(ResponseValueDeserializer::new(value))
+        .map_err(|err| format!("Error deserializing ResponseValue: {err}"))
+}
+
+/// [Deserializer] which walks a [ResponseValue] tree directly, releasing each member as it is
+/// visited, so `T::deserialize` never sees an intermediate [Value] or JSON string.
+///
+/// Values are visited as their native types, the same as [from_value]: `Int` and `Float` are
+/// numbers, custom scalars are not tagged, and `EnumValue` and `ID` are strings, which can also
+/// deserialize a unit variant of a Rust `enum`. Strings are only borrowed while they are
+/// visited, so `T` cannot borrow from them, and any invalid UTF-8 is replaced with `U+FFFD`.
+pub struct ResponseValueDeserializer<'a> {
+    value: Pin<&'a mut ResponseValue>,
+}
+
+impl<'a> ResponseValueDeserializer<'a> {
+    /// Deserialize from `value`, releasing its members as they are visited, so it is left empty
+    /// once `T::deserialize` returns.
+    pub fn new(value: Pin<&'a mut ResponseValue>) -> Self {
+        Self { value }
+    }
+}
+
+impl<'de> Deserializer<'de> for ResponseValueDeserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, Error> {
+        match self.value.as_mut().get_type() {
+            ResponseValueType::Map => {
+                let mut members = self.value.as_mut().release_map().map_err(|err| {
+                    Error::custom(format!("Failed to release Map entries: {err}"))
+                })?;
+                let members = members
+                    .as_mut()
+                    .ok_or_else(|| Error::custom("Map ResponseValue returned a null vector"))?;
+                visitor.visit_map(MapEntries {
+                    entries: members.as_mut_slice().iter_mut(),
+                    value: None,
+                })
+            }
+            ResponseValueType::List => {
+                let mut items = self.value.as_mut().release_list().map_err(|err| {
+                    Error::custom(format!("Failed to release List entries: {err}"))
+                })?;
+                let items = items
+                    .as_mut()
+                    .ok_or_else(|| Error::custom("List ResponseValue returned a null vector"))?;
+                visitor.visit_seq(ListItems(items.iter_mut()))
+            }
+            ResponseValueType::String | ResponseValueType::EnumValue | ResponseValueType::ID => {
+                let mut value = self
+                    .value
+                    .as_mut()
+                    .release_string()
+                    .map_err(|err| Error::custom(format!("Failed to release String: {err}")))?;
+                let value = value
+                    .as_mut()
+                    .ok_or_else(|| Error::custom("String ResponseValue returned a null value"))?;
+                visitor.visit_str(&lossy_string(&value))
+            }
+            ResponseValueType::Null => visitor.visit_unit(),
+            ResponseValueType::Boolean => visitor.visit_bool(
+                self.value
+                    .get_bool()
+                    .map_err(|err| Error::custom(format!("Failed to get Boolean: {err}")))?,
+            ),
+            ResponseValueType::Int => visitor.visit_i64(
+                self.value
+                    .get_int()
+                    .map_err(|err| Error::custom(format!("Failed to get Int: {err}")))?,
+            ),
+            ResponseValueType::Float => visitor.visit_f64(
+                self.value
+                    .get_float()
+                    .map_err(|err| Error::custom(format!("Failed to get Float: {err}")))?,
+            ),
+            ResponseValueType::Scalar => {
+                let mut value = self
+                    .value
+                    .as_mut()
+                    .release_scalar()
+                    .map_err(|err| Error::custom(format!("Failed to release Scalar: {err}")))?;
+                let value = value
+                    .as_mut()
+                    .ok_or_else(|| Error::custom("Scalar ResponseValue returned a null value"))?;
+                ResponseValueDeserializer::new(value).deserialize_any(visitor)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.value.get_type() == ResponseValueType::Null {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        mut self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.value.get_type() {
+            ResponseValueType::String | ResponseValueType::EnumValue | ResponseValueType::ID => {
+                let mut value = self
+                    .value
+                    .as_mut()
+                    .release_string()
+                    .map_err(|err| Error::custom(format!("Failed to release String: {err}")))?;
+                let value = value
+                    .as_mut()
+                    .ok_or_else(|| Error::custom("String ResponseValue returned a null value"))?;
+                let value = lossy_string(&value);
+                visitor.visit_enum(value.as_ref().into_deserializer())
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// [MapAccess] over the released entries of a `Map`, skipping any entry without a name or value.
+struct MapEntries<'a> {
+    entries: std::slice::IterMut<'a, ffi::ResponseMapEntry>,
+    value: Option<Pin<&'a mut ResponseValue>>,
+}
+
+impl<'de> MapAccess<'de> for MapEntries<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        for ffi::ResponseMapEntry { name, value } in self.entries.by_ref() {
+            if let (Some(name), Some(value)) = (name.as_ref(), value.as_mut()) {
+                self.value = Some(value);
+                let name = lossy_string(name);
+                return seed
+                    .deserialize(name.as_ref().into_deserializer())
+                    .map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, Error> {
+        let value = self
+            .value
+            .take()
+            .ok_or_else(|| Error::custom("Map value requested before its key"))?;
+        seed.deserialize(ResponseValueDeserializer::new(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+/// [SeqAccess] over the released items of a `List`.
+struct ListItems<'a>(cxx::vector::IterMut<'a, ResponseValue>);
+
+impl<'de> SeqAccess<'de> for ListItems<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        self.0
+            .next()
+            .map(|item| seed.deserialize(ResponseValueDeserializer::new(item)))
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        ffi, from_response_value, from_value, into_value, parse_json, to_json, ResponseValueType,
+    };
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[test]
+    fn round_trip_json() {
+        let json =
+            r#"{"data":{"id":"1","count":2,"ratio":0.5,"read":true,"tags":["a","b"],"next":null}}"#;
+        let mut value = parse_json(json).expect("parses the JSON");
+        let mut pinned = value.as_mut().expect("allocates a Map");
+        assert!(pinned.get_type() == ResponseValueType::Map);
+        assert_eq!(
+            to_json(pinned.as_mut()).expect("formats the JSON"),
+            json,
+            "preserves the member order and values"
+        );
+    }
+
+    #[test]
+    fn convert_values() {
+        for value in [
+            json!(null),
+            json!(false),
+            json!(-42),
+            json!(1.25),
+            json!("INBOX"),
+            json!([1, [2, 3], { "a": [] }]),
+        ] {
+            let mut converted = into_value(value.clone()).expect("converts to a ResponseValue");
+            let converted = from_value(converted.as_mut().expect("allocates a value"))
+                .expect("converts from a ResponseValue");
+            assert_eq!(converted, value);
+        }
+    }
+
+    #[test]
+    fn reject_invalid_json() {
+        let err = parse_json("{ data: }").err().expect("should not parse");
+        assert!(err.starts_with("Error parsing JSON:"), "{err}");
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+    enum Importance {
+        Low,
+        Normal,
+        High,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[serde(rename_all = "camelCase")]
+    struct Item {
+        id: String,
+        subject: Option<String>,
+        importance: Importance,
+        size: u32,
+        score: f64,
+        read: bool,
+        categories: Vec<String>,
+    }
+
+    #[test]
+    fn deserialize_response_value() {
+        let mut value = into_value(json!([{
+            "id": "1",
+            "subject": null,
+            "importance": "HIGH",
+            "size": 42,
+            "score": 0.5,
+            "read": true,
+            "categories": ["a", "b"],
+            "ignored": { "nested": [1] },
+        }]))
+        .expect("converts to a ResponseValue");
+        let items: Vec<Item> = from_response_value(value.as_mut().expect("allocates a List"))
+            .expect("deserializes the items");
+        assert_eq!(
+            items,
+            [Item {
+                id: String::from("1"),
+                subject: None,
+                importance: Importance::High,
+                size: 42,
+                score: 0.5,
+                read: true,
+                categories: vec![String::from("a"), String::from("b")],
+            }]
+        );
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Folder {
+        name: String,
+    }
+
+    #[test]
+    fn deserialize_invalid_utf8() {
+        let mut name = ffi::make_response_value(ResponseValueType::String);
+        name.as_mut()
+            .expect("allocates a String")
+            .set_string_bytes(b"Caf\xe9 \xff\xfeMenu")
+            .expect("sets the String");
+        let mut value = ffi::make_response_value(ResponseValueType::Map);
+        value
+            .as_mut()
+            .expect("allocates a Map")
+            .push_map_entry("name", name)
+            .expect("adds the member");
+        let folder: Folder = from_response_value(value.as_mut().expect("allocates a Map"))
+            .expect("deserializes the folder");
+        assert_eq!(folder.name, "Caf\u{fffd} \u{fffd}\u{fffd}Menu");
+    }
+
+    #[test]
+    fn reject_mismatched_response_value() {
+        let mut value =
+            into_value(json!({ "size": "large" })).expect("converts to a ResponseValue");
+        let err = from_response_value::<Vec<u32>>(value.as_mut().expect("allocates a Map"))
+            .err()
+            .expect("should not deserialize a Map into a Vec");
+        assert!(
+            err.starts_with("Error deserializing ResponseValue:"),
+            "{err}"
+        );
+    }
+}
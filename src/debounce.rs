@@ -0,0 +1,291 @@
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::PayloadSink;
+
+/// Hold back the `next` payloads for up to `window` after the first one arrives, and only
+/// deliver the latest of them to `next` once the window ends, on a separate timer thread.
+/// Returns the [DebounceSink] for the worker thread, and the [Debounce] to
+/// [flush](Debounce::flush) before `complete`.
+pub(crate) fn debounce<N: PayloadSink>(
+    window: Duration,
+    next: N,
+) -> (DebounceSink<N>, Arc<Debounce<N>>) {
+    let debounce = Arc::new(Debounce {
+        window,
+        next: Mutex::new(next),
+        state: Mutex::new(State {
+            pending: None,
+            failed: None,
+            panicked: None,
+            done: false,
+        }),
+        wake: Condvar::new(),
+    });
+    let timer = debounce.clone();
+    thread::spawn(move || timer.watch());
+    (DebounceSink(debounce.clone()), debounce)
+}
+
+/// The `next` sink has a lock of its own, which is always taken before the `state`, and the
+/// `state` is never held while sending. A slow sink on the timer thread only delays the
+/// [flush](Debounce::flush), not the worker thread buffering the next payload.
+pub(crate) struct Debounce<N> {
+    window: Duration,
+    next: Mutex<N>,
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+struct State {
+    /// The latest payload and when its window ends.
+    pending: Option<(String, Instant)>,
+    /// Error from `next` on the timer thread, which the worker thread reports on the next
+    /// payload, so the subscription is cancelled like it would be without the debounce.
+    failed: Option<String>,
+    /// Panic from `next` on the timer thread, which the worker thread resumes on the next
+    /// payload, so it stops the service like it would without the debounce.
+    panicked: Option<Box<dyn Any + Send>>,
+    done: bool,
+}
+
+impl<N: PayloadSink> Debounce<N> {
+    fn push(&self, payload: String) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(payload) = state.panicked.take() {
+            drop(state);
+            panic::resume_unwind(payload);
+        }
+        if let Some(err) = state.failed.take() {
+            return Err(err);
+        }
+
+        let deadline = match state.pending.take() {
+            Some((_, deadline)) => deadline,
+            None => Instant::now() + self.window,
+        };
+        state.pending = Some((payload, deadline));
+        self.wake.notify_all();
+        Ok(())
+    }
+
+    /// Deliver the pending payload right away and stop the timer thread, e.g. before `complete`,
+    /// so the latest payload is never lost and nothing is delivered after `complete`.
+    pub(crate) fn flush(&self) {
+        let next = self.next.lock().unwrap_or_else(PoisonError::into_inner);
+        let pending = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.done = true;
+            self.wake.notify_all();
+            state.pending.take()
+        };
+        if let Some((payload, _)) = pending {
+            let _ = next.send(payload);
+        }
+    }
+
+    /// Deliver each pending payload once its window ends, until it is flushed, or until the
+    /// worker thread drops the [DebounceSink] and nothing else refers to it.
+    fn watch(self: Arc<Self>) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        while !state.done && Arc::strong_count(&self) > 1 {
+            let timeout = match &state.pending {
+                Some((_, deadline)) => deadline.saturating_duration_since(Instant::now()),
+                None => self.window,
+            };
+            if !timeout.is_zero() {
+                state = self
+                    .wake
+                    .wait_timeout(state, timeout)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+                continue;
+            }
+
+            // Take the locks in order, and check again, since a flush may have run in between.
+            drop(state);
+            let next = self.next.lock().unwrap_or_else(PoisonError::into_inner);
+            state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            if state.done {
+                break;
+            }
+            let Some((payload, _)) = state.pending.take() else {
+                continue;
+            };
+            drop(state);
+
+            let sent = panic::catch_unwind(AssertUnwindSafe(|| next.send(payload)));
+            drop(next);
+            state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            match sent {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => state.failed = Some(err),
+                Err(payload) => {
+                    state.panicked = Some(payload);
+                    state.done = true;
+                }
+            }
+        }
+    }
+}
+
+/// [PayloadSink] for the worker thread, which replaces the pending payload of its [Debounce].
+pub(crate) struct DebounceSink<N>(Arc<Debounce<N>>);
+
+impl<N: PayloadSink> PayloadSink for DebounceSink<N> {
+    fn send(&self, payload: String) -> Result<(), String> {
+        self.0.push(payload)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::debounce;
+    use crate::PayloadSink;
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::{mpsc, Mutex},
+        time::Duration,
+    };
+
+    /// [PayloadSink] which reports each payload on `entered`, then waits for `release`.
+    struct GatedSink {
+        entered: mpsc::Sender<String>,
+        release: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl PayloadSink for GatedSink {
+        fn send(&self, payload: String) -> Result<(), String> {
+            self.entered
+                .send(payload.clone())
+                .map_err(|err| err.to_string())?;
+            if payload == "panic" {
+                panic!("sink panicked");
+            }
+            self.release
+                .lock()
+                .unwrap()
+                .recv()
+                .map_err(|err| err.to_string())
+        }
+    }
+
+    fn gated_sink() -> (GatedSink, mpsc::Receiver<String>, mpsc::Sender<()>) {
+        let (tx_entered, rx_entered) = mpsc::channel();
+        let (tx_release, rx_release) = mpsc::channel();
+        let sink = GatedSink {
+            entered: tx_entered,
+            release: Mutex::new(rx_release),
+        };
+        (sink, rx_entered, tx_release)
+    }
+
+    #[test]
+    fn deliver_latest_payload() {
+        let (tx, rx) = mpsc::channel();
+        let (sink, debounce) = debounce(Duration::from_millis(100), tx);
+        for payload in ["1", "2", "3"] {
+            sink.send(String::from(payload))
+                .expect("buffers the payload");
+        }
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).as_deref(),
+            Ok("3"),
+            "should only deliver the latest payload in the window"
+        );
+
+        sink.send(String::from("4")).expect("buffers the payload");
+        debounce.flush();
+        assert_eq!(rx.try_recv().as_deref(), Ok("4"), "should flush right away");
+        drop((sink, debounce));
+        assert!(
+            rx.recv_timeout(Duration::from_millis(300)).is_err(),
+            "should not deliver anything after the flush"
+        );
+    }
+
+    #[test]
+    fn report_failed_delivery() {
+        // Without a release, the sink fails each payload like a dropped receiver.
+        let (next, rx_entered, tx_release) = gated_sink();
+        drop(tx_release);
+        let (sink, _debounce) = debounce(Duration::from_millis(10), next);
+        sink.send(String::from("1")).expect("buffers the payload");
+        assert_eq!(
+            rx_entered.recv_timeout(Duration::from_secs(5)).as_deref(),
+            Ok("1"),
+            "should deliver the payload from the timer thread"
+        );
+
+        // The timer thread records the error right after the sink returns it, so these payloads
+        // are only buffered until then.
+        let started = std::time::Instant::now();
+        while sink.send(String::from("2")).is_ok() {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "should report that the receiver is gone"
+            );
+        }
+    }
+
+    #[test]
+    fn buffer_while_timer_is_sending() {
+        let (next, rx_entered, tx_release) = gated_sink();
+        let (sink, debounce) = debounce(Duration::from_millis(10), next);
+        sink.send(String::from("1")).expect("buffers the payload");
+        assert_eq!(
+            rx_entered.recv_timeout(Duration::from_secs(5)).as_deref(),
+            Ok("1"),
+            "should deliver the first payload from the timer thread"
+        );
+
+        // The timer thread is still blocked in the sink, which must not block the worker.
+        sink.send(String::from("2"))
+            .expect("buffers the payload while the sink is busy");
+        tx_release.send(()).expect("releases the first payload");
+        tx_release.send(()).expect("releases the flushed payload");
+        debounce.flush();
+        assert_eq!(
+            rx_entered.try_iter().collect::<Vec<_>>(),
+            ["2"],
+            "should deliver the latest payload by the end of the flush"
+        );
+    }
+
+    #[test]
+    fn resume_panic_on_worker() {
+        let (next, rx_entered, _tx_release) = gated_sink();
+        let (sink, _debounce) = debounce(Duration::from_millis(10), next);
+        sink.send(String::from("panic"))
+            .expect("buffers the payload");
+        assert_eq!(
+            rx_entered.recv_timeout(Duration::from_secs(5)).as_deref(),
+            Ok("panic"),
+            "should deliver the payload from the timer thread"
+        );
+
+        // The timer thread records the panic right after the sink unwinds, and it stops
+        // delivering, so these payloads are only buffered until then.
+        let started = std::time::Instant::now();
+        let panicked = loop {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| sink.send(String::from("next"))));
+            match result {
+                Err(payload) => break payload,
+                Ok(_) => assert!(
+                    started.elapsed() < Duration::from_secs(5),
+                    "should resume the panic from the timer thread"
+                ),
+            }
+        };
+        assert!(
+            rx_entered.try_recv().is_err(),
+            "should stop delivering after a panic"
+        );
+        assert_eq!(panicked.downcast_ref::<&str>(), Some(&"sink panicked"));
+    }
+}
@@ -0,0 +1,470 @@
+use std::{error::Error, fmt, io};
+
+use crate::response::GraphQLError;
+
+/// Error returned from [parse_query](crate::MAPIGraphQL::parse_query), with the location of the
+/// syntax error in the request document if the parser reported one.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ParseError {
+    /// Description of the error, without the location prefix.
+    pub message: String,
+    /// 1-based line number of the syntax error.
+    pub line: Option<u32>,
+    /// 1-based column number of the syntax error.
+    pub column: Option<u32>,
+}
+
+impl ParseError {
+    /// Split the `source:line:column` prefix which the parser adds to its exceptions, e.g.
+    /// `GraphQL:1:8: Expected name`, from the rest of the message. Anything else, such as a
+    /// `WorkerGone` error, is kept as the whole message without a location.
+    fn from_message(message: String) -> Self {
+        let mut offset = 0;
+
+        while let Some(index) = message[offset..].find(':') {
+            let start = offset + index + 1;
+            if let Some((line, column, end)) = Self::parse_location(&message[start..]) {
+                return Self {
+                    message: message[start + end..]
+                        .trim_start_matches(':')
+                        .trim()
+                        .to_owned(),
+                    line: Some(line),
+                    column: Some(column),
+                };
+            }
+            offset = start;
+        }
+
+        Self {
+            message,
+            line: None,
+            column: None,
+        }
+    }
+
+    /// Parse `line:column`, optionally followed by a `(byte)` offset, at the start of `text`.
+    fn parse_location(text: &str) -> Option<(u32, u32, usize)> {
+        let (line, rest) = text.split_once(':')?;
+        let line = line.parse().ok()?;
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let column = rest[..digits].parse().ok()?;
+        let mut end = text.len() - rest.len() + digits;
+
+        if text[end..].starts_with('(') {
+            if let Some(close) = text[end..].find(')') {
+                end += close + 1;
+            }
+        }
+
+        Some((line, column, end))
+    }
+}
+
+impl From<String> for ParseError {
+    fn from(message: String) -> Self {
+        Self::from_message(message)
+    }
+}
+
+impl From<ParseError> for String {
+    /// Format the [ParseError] with its location, so it can be returned with `?` from methods
+    /// which report errors as a [String].
+    fn from(err: ParseError) -> Self {
+        err.to_string()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "{line}:{column}: {}", self.message),
+            _ => f.write_str(&self.message),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// Error from a `MAPI` call, with the `HRESULT` which the native bindings encode in the message
+/// as `HRESULT 0x8004010F: ...`, so callers can branch on well-known codes instead of matching
+/// the text. Convert any [String] error returned from this crate with [MapiError::from].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MapiError {
+    /// A `MAPI` or COM call failed with `hresult`. The `message` is the whole error, including
+    /// any prefix such as `WorkerGone:` which was added on the way out.
+    Mapi { hresult: i32, message: String },
+    /// Any other error, which does not carry an `HRESULT`.
+    Other(String),
+}
+
+/// Marker which the native bindings put in front of the hexadecimal `HRESULT`.
+const HRESULT_MARKER: &str = "HRESULT 0x";
+
+/// Convert the unsigned hexadecimal form of an `HRESULT` to the signed `i32` used by Windows.
+const fn hresult_from_u32(value: u32) -> i32 {
+    value as i32
+}
+
+impl MapiError {
+    pub const MAPI_E_CALL_FAILED: i32 = hresult_from_u32(0x8000_4005);
+    pub const MAPI_E_NOT_ENOUGH_MEMORY: i32 = hresult_from_u32(0x8007_000E);
+    pub const MAPI_E_INVALID_PARAMETER: i32 = hresult_from_u32(0x8007_0057);
+    pub const MAPI_E_INTERFACE_NOT_SUPPORTED: i32 = hresult_from_u32(0x8000_4002);
+    pub const MAPI_E_NO_ACCESS: i32 = hresult_from_u32(0x8007_0005);
+    pub const MAPI_E_NO_SUPPORT: i32 = hresult_from_u32(0x8004_0102);
+    pub const MAPI_E_INVALID_ENTRYID: i32 = hresult_from_u32(0x8004_0107);
+    pub const MAPI_E_INVALID_OBJECT: i32 = hresult_from_u32(0x8004_0108);
+    pub const MAPI_E_OBJECT_CHANGED: i32 = hresult_from_u32(0x8004_0109);
+    pub const MAPI_E_OBJECT_DELETED: i32 = hresult_from_u32(0x8004_010A);
+    pub const MAPI_E_BUSY: i32 = hresult_from_u32(0x8004_010B);
+    pub const MAPI_E_NOT_ENOUGH_DISK: i32 = hresult_from_u32(0x8004_010D);
+    pub const MAPI_E_NOT_ENOUGH_RESOURCES: i32 = hresult_from_u32(0x8004_010E);
+    pub const MAPI_E_NOT_FOUND: i32 = hresult_from_u32(0x8004_010F);
+    pub const MAPI_E_VERSION: i32 = hresult_from_u32(0x8004_0110);
+    pub const MAPI_E_LOGON_FAILED: i32 = hresult_from_u32(0x8004_0111);
+    pub const MAPI_E_SESSION_LIMIT: i32 = hresult_from_u32(0x8004_0112);
+    pub const MAPI_E_USER_CANCEL: i32 = hresult_from_u32(0x8004_0113);
+    pub const MAPI_E_NETWORK_ERROR: i32 = hresult_from_u32(0x8004_0115);
+    pub const MAPI_E_DISK_ERROR: i32 = hresult_from_u32(0x8004_0116);
+    pub const MAPI_E_TOO_COMPLEX: i32 = hresult_from_u32(0x8004_0117);
+    pub const MAPI_E_BAD_COLUMN: i32 = hresult_from_u32(0x8004_0118);
+    pub const MAPI_E_EXTENDED_ERROR: i32 = hresult_from_u32(0x8004_0119);
+    pub const MAPI_E_CORRUPT_DATA: i32 = hresult_from_u32(0x8004_011B);
+    pub const MAPI_E_UNCONFIGURED: i32 = hresult_from_u32(0x8004_011C);
+    pub const MAPI_E_END_OF_SESSION: i32 = hresult_from_u32(0x8004_0200);
+    pub const MAPI_E_UNKNOWN_ENTRYID: i32 = hresult_from_u32(0x8004_0201);
+    pub const MAPI_E_TIMEOUT: i32 = hresult_from_u32(0x8004_0401);
+    pub const MAPI_E_TABLE_EMPTY: i32 = hresult_from_u32(0x8004_0402);
+    pub const MAPI_E_TABLE_TOO_BIG: i32 = hresult_from_u32(0x8004_0403);
+    pub const MAPI_E_NOT_INITIALIZED: i32 = hresult_from_u32(0x8004_0605);
+
+    /// Names of the common `MAPI_E_*` codes, for [name](MapiError::name).
+    const NAMES: &'static [(i32, &'static str)] = &[
+        (Self::MAPI_E_CALL_FAILED, "MAPI_E_CALL_FAILED"),
+        (Self::MAPI_E_NOT_ENOUGH_MEMORY, "MAPI_E_NOT_ENOUGH_MEMORY"),
+        (Self::MAPI_E_INVALID_PARAMETER, "MAPI_E_INVALID_PARAMETER"),
+        (
+            Self::MAPI_E_INTERFACE_NOT_SUPPORTED,
+            "MAPI_E_INTERFACE_NOT_SUPPORTED",
+        ),
+        (Self::MAPI_E_NO_ACCESS, "MAPI_E_NO_ACCESS"),
+        (Self::MAPI_E_NO_SUPPORT, "MAPI_E_NO_SUPPORT"),
+        (Self::MAPI_E_INVALID_ENTRYID, "MAPI_E_INVALID_ENTRYID"),
+        (Self::MAPI_E_INVALID_OBJECT, "MAPI_E_INVALID_OBJECT"),
+        (Self::MAPI_E_OBJECT_CHANGED, "MAPI_E_OBJECT_CHANGED"),
+        (Self::MAPI_E_OBJECT_DELETED, "MAPI_E_OBJECT_DELETED"),
+        (Self::MAPI_E_BUSY, "MAPI_E_BUSY"),
+        (Self::MAPI_E_NOT_ENOUGH_DISK, "MAPI_E_NOT_ENOUGH_DISK"),
+        (
+            Self::MAPI_E_NOT_ENOUGH_RESOURCES,
+            "MAPI_E_NOT_ENOUGH_RESOURCES",
+        ),
+        (Self::MAPI_E_NOT_FOUND, "MAPI_E_NOT_FOUND"),
+        (Self::MAPI_E_VERSION, "MAPI_E_VERSION"),
+        (Self::MAPI_E_LOGON_FAILED, "MAPI_E_LOGON_FAILED"),
+        (Self::MAPI_E_SESSION_LIMIT, "MAPI_E_SESSION_LIMIT"),
+        (Self::MAPI_E_USER_CANCEL, "MAPI_E_USER_CANCEL"),
+        (Self::MAPI_E_NETWORK_ERROR, "MAPI_E_NETWORK_ERROR"),
+        (Self::MAPI_E_DISK_ERROR, "MAPI_E_DISK_ERROR"),
+        (Self::MAPI_E_TOO_COMPLEX, "MAPI_E_TOO_COMPLEX"),
+        (Self::MAPI_E_BAD_COLUMN, "MAPI_E_BAD_COLUMN"),
+        (Self::MAPI_E_EXTENDED_ERROR, "MAPI_E_EXTENDED_ERROR"),
+        (Self::MAPI_E_CORRUPT_DATA, "MAPI_E_CORRUPT_DATA"),
+        (Self::MAPI_E_UNCONFIGURED, "MAPI_E_UNCONFIGURED"),
+        (Self::MAPI_E_END_OF_SESSION, "MAPI_E_END_OF_SESSION"),
+        (Self::MAPI_E_UNKNOWN_ENTRYID, "MAPI_E_UNKNOWN_ENTRYID"),
+        (Self::MAPI_E_TIMEOUT, "MAPI_E_TIMEOUT"),
+        (Self::MAPI_E_TABLE_EMPTY, "MAPI_E_TABLE_EMPTY"),
+        (Self::MAPI_E_TABLE_TOO_BIG, "MAPI_E_TABLE_TOO_BIG"),
+        (Self::MAPI_E_NOT_INITIALIZED, "MAPI_E_NOT_INITIALIZED"),
+    ];
+
+    /// The `HRESULT`, if this is a [MapiError::Mapi] error.
+    pub fn hresult(&self) -> Option<i32> {
+        match self {
+            Self::Mapi { hresult, .. } => Some(*hresult),
+            Self::Other(_) => None,
+        }
+    }
+
+    /// The name of a well-known `HRESULT`, e.g. `MAPI_E_NOT_FOUND`, if this is a
+    /// [MapiError::Mapi] error with one of the common codes.
+    pub fn name(&self) -> Option<&'static str> {
+        let hresult = self.hresult()?;
+        Self::NAMES
+            .iter()
+            .find(|(code, _)| *code == hresult)
+            .map(|(_, name)| *name)
+    }
+
+    /// Find the `HRESULT 0x...` marker anywhere in `message` and parse the 8 hex digits after it.
+    fn parse_hresult(message: &str) -> Option<i32> {
+        let start = message.find(HRESULT_MARKER)? + HRESULT_MARKER.len();
+        let digits = message.get(start..start + 8)?;
+        u32::from_str_radix(digits, 16).ok().map(hresult_from_u32)
+    }
+}
+
+impl From<String> for MapiError {
+    fn from(message: String) -> Self {
+        match Self::parse_hresult(&message) {
+            Some(hresult) => Self::Mapi { hresult, message },
+            None => Self::Other(message),
+        }
+    }
+}
+
+impl From<MapiError> for String {
+    fn from(err: MapiError) -> Self {
+        match err {
+            MapiError::Mapi { message, .. } | MapiError::Other(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for MapiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mapi { message, .. } | Self::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl Error for MapiError {}
+
+/// Error returned from the methods which send a request to the worker thread, which tells the
+/// failures a caller may want to handle, e.g. by retrying after a [Timeout](ServiceError::Timeout)
+/// or starting a new service after [WorkerGone](ServiceError::WorkerGone), apart from the rest.
+///
+/// It formats with the same prefix as the name of the variant, e.g. `WorkerGone: ...`, except
+/// for [Other](ServiceError::Other), which is just the message.
+#[derive(Debug)]
+pub enum ServiceError {
+    /// The worker thread is no longer handling commands, so nothing else will be handled. The
+    /// message says why, including the error which the worker thread exited with, if any.
+    WorkerGone(String),
+    /// The worker thread did not reply, or did not log on, within the timeout. It may still
+    /// handle the request later, but the caller stopped waiting for it.
+    Timeout(String),
+    /// A callback panicked on the worker thread while handling the request, which also stops
+    /// the service.
+    Panicked(String),
+    /// `startService` was called again before `stopService`, so it did not log on again.
+    AlreadyStarted(String),
+    /// A file could not be read, e.g. by [run_from_files](crate::MAPIGraphQL::run_from_files).
+    Io(io::Error),
+    /// Any other error reported by the service, e.g. from gqlmapi or a failed `MAPI` call. Use
+    /// [MapiError::from] to find the `HRESULT` of a failed `MAPI` call.
+    Other(String),
+}
+
+impl From<String> for ServiceError {
+    fn from(message: String) -> Self {
+        Self::Other(message)
+    }
+}
+
+impl From<io::Error> for ServiceError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<ParseError> for ServiceError {
+    fn from(err: ParseError) -> Self {
+        Self::Other(err.to_string())
+    }
+}
+
+impl From<ServiceError> for String {
+    /// Format the [ServiceError] with its prefix, so it can be returned with `?` from methods
+    /// which report errors as a [String].
+    fn from(err: ServiceError) -> Self {
+        err.to_string()
+    }
+}
+
+impl From<ServiceError> for MapiError {
+    fn from(err: ServiceError) -> Self {
+        Self::from(err.to_string())
+    }
+}
+
+impl From<ServiceError> for ParseError {
+    fn from(err: ServiceError) -> Self {
+        Self::from(err.to_string())
+    }
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WorkerGone(message) => write!(f, "WorkerGone: {message}"),
+            Self::Timeout(message) => write!(f, "Timeout: {message}"),
+            Self::Panicked(message) => write!(f, "Panicked: {message}"),
+            Self::AlreadyStarted(message) => write!(f, "AlreadyStarted: {message}"),
+            Self::Io(err) => write!(f, "Io: {err}"),
+            Self::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl Error for ServiceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Error returned from [query](crate::MAPIGraphQL::query), which tells a request that could not
+/// run apart from one that ran and reported GraphQL `errors`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryError {
+    /// The request could not be evaluated, e.g. because the document did not parse, the variables
+    /// could not be serialized, or the worker thread is gone. Use [MapiError::from] to find the
+    /// `HRESULT` of a failed `MAPI` call.
+    Transport(String),
+    /// The response had a non-empty `errors` member, so any `data` may be partial.
+    GraphQL(Vec<GraphQLError>),
+    /// The `data` member of the response could not be deserialized into the requested type.
+    Deserialize(String),
+}
+
+impl From<ServiceError> for QueryError {
+    fn from(err: ServiceError) -> Self {
+        Self::Transport(err.to_string())
+    }
+}
+
+impl From<QueryError> for String {
+    /// Format the [QueryError], so it can be returned with `?` from methods which report errors
+    /// as a [String].
+    fn from(err: QueryError) -> Self {
+        err.to_string()
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transport(message) | Self::Deserialize(message) => f.write_str(message),
+            Self::GraphQL(errors) => {
+                f.write_str("GraphQL errors: ")?;
+                for (index, err) in errors.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str("; ")?;
+                    }
+                    f.write_str(&err.message)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for QueryError {}
+
+#[cfg(test)]
+mod test {
+    use super::{MapiError, ParseError, QueryError, ServiceError};
+    use crate::response::GraphQLError;
+    use std::{error::Error, io};
+
+    #[test]
+    fn split_location() {
+        let err = ParseError::from(String::from("GraphQL:2:14(20): Expected name"));
+        assert_eq!(
+            err,
+            ParseError {
+                message: String::from("Expected name"),
+                line: Some(2),
+                column: Some(14),
+            }
+        );
+        assert_eq!(err.to_string(), "2:14: Expected name");
+    }
+
+    #[test]
+    fn keep_message_without_location() {
+        let err = ParseError::from(String::from("WorkerGone: the worker thread was stopped"));
+        assert_eq!(err.message, "WorkerGone: the worker thread was stopped");
+        assert_eq!((err.line, err.column), (None, None));
+    }
+
+    #[test]
+    fn parse_hresult() {
+        let err = MapiError::from(String::from(
+            "WorkerGone: the worker thread failed: HRESULT 0x80040115: MAPILogonEx failed",
+        ));
+        assert_eq!(err.hresult(), Some(MapiError::MAPI_E_NETWORK_ERROR));
+        assert_eq!(err.name(), Some("MAPI_E_NETWORK_ERROR"));
+        assert_eq!(
+            err.to_string(),
+            "WorkerGone: the worker thread failed: HRESULT 0x80040115: MAPILogonEx failed"
+        );
+
+        let err = MapiError::from(String::from("HRESULT 0x8004D00A: unknown provider error"));
+        assert_eq!(err.hresult(), Some(0x8004_D00A_u32 as i32));
+        assert_eq!(err.name(), None);
+    }
+
+    #[test]
+    fn keep_message_without_hresult() {
+        let err = MapiError::from(String::from("Unknown queryId"));
+        assert_eq!(err, MapiError::Other(String::from("Unknown queryId")));
+        assert_eq!((err.hresult(), err.name()), (None, None));
+        assert_eq!(String::from(err), "Unknown queryId");
+    }
+
+    #[test]
+    fn format_query_errors() {
+        let err = QueryError::GraphQL(vec![
+            GraphQLError {
+                message: String::from("Unknown field"),
+                ..Default::default()
+            },
+            GraphQLError {
+                message: String::from("Missing variable"),
+                ..Default::default()
+            },
+        ]);
+        assert_eq!(
+            String::from(err),
+            "GraphQL errors: Unknown field; Missing variable"
+        );
+
+        let err = QueryError::Transport(String::from("WorkerGone: the worker thread was stopped"));
+        assert_eq!(err.to_string(), "WorkerGone: the worker thread was stopped");
+    }
+
+    #[test]
+    fn format_service_errors() {
+        let err = ServiceError::WorkerGone(String::from("the worker thread was stopped"));
+        assert_eq!(err.to_string(), "WorkerGone: the worker thread was stopped");
+        let err = ServiceError::from(String::from("Unknown queryId"));
+        assert!(matches!(&err, ServiceError::Other(message) if message == "Unknown queryId"));
+        assert_eq!(String::from(err), "Unknown queryId");
+
+        let err = ServiceError::Other(String::from("HRESULT 0x8004010F: not found"));
+        assert_eq!(
+            MapiError::from(err).hresult(),
+            Some(MapiError::MAPI_E_NOT_FOUND)
+        );
+    }
+
+    #[test]
+    fn keep_io_error() {
+        let err = ServiceError::from(io::Error::new(io::ErrorKind::NotFound, "query.graphql"));
+        assert_eq!(err.to_string(), "Io: query.graphql");
+        match &err {
+            ServiceError::Io(err) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+            err => panic!("should keep the io::Error: {err:?}"),
+        }
+        assert!(err.source().is_some(), "should expose the io::Error");
+    }
+}
@@ -1,527 +1,4392 @@
 use std::{
-    sync::{mpsc, Arc, Mutex, PoisonError},
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, VecDeque},
+    fmt, fs,
+    hash::{Hash, Hasher},
+    io::{self, BufWriter, Write},
+    mem,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex, OnceLock, PoisonError, Weak,
+    },
     thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
 };
 
+use serde::{de::DeserializeOwned, Serialize};
+
+mod backend;
+use backend::Backend;
+
+mod base64;
+
 mod bindings;
-use bindings::{ffi, CompleteContext, NextContext};
+use bindings::{ffi, CompleteContext, ConversionOptions, NextContext, PayloadStream};
+pub use bindings::{NumberFormat, SCALAR_TAG};
+
+pub mod conversion;
+
+mod debounce;
+
+mod error;
+pub use error::{MapiError, ParseError, QueryError, ServiceError};
+
+#[cfg(feature = "mock")]
+mod mock;
 
+mod oneshot;
+
+mod response;
+use response::TypedResponse;
+pub use response::{ErrorLocation, GraphQLError, Response};
+
+mod schema;
+pub use schema::SchemaDiff;
+use schema::{IntrospectionData, INTROSPECTION_QUERY};
+
+mod pipe;
+
+mod sink;
+pub use sink::PayloadSink;
+
+mod typed_stream;
+pub use typed_stream::TypedStream;
+
+#[cfg(feature = "schema-types")]
+pub mod types;
+
+use windows::core::PCWSTR;
 use windows::Win32::{
-    Foundation::*, System::Threading::GetCurrentThreadId, UI::WindowsAndMessaging::*,
+    Foundation::*,
+    System::{
+        Com::{CoInitializeEx, CoUninitialize, COINIT_APARTMENTTHREADED, COINIT_MULTITHREADED},
+        Threading::{CreateEventW, GetCurrentThreadId, SetEvent, INFINITE},
+    },
+    UI::WindowsAndMessaging::*,
 };
 
+/// Callback invoked on the worker thread with each `next` payload, which returns an
+/// [Err(String)](Err) once the receiving end is gone.
+type NextCallback = Box<dyn FnMut(String) -> Result<(), String> + Send>;
+
+/// Callback invoked on the worker thread with the `ResponseValue` of each `next` payload, which
+/// deserializes it without converting it to JSON first.
+type DecodeCallback = Box<dyn FnMut(Pin<&mut ffi::ResponseValue>) + Send>;
+
+/// Callback invoked on the worker thread when the subscription calls `complete`.
+type CompleteCallback = Box<dyn FnOnce() + Send>;
+
+/// Hook installed with [set_payload_logger](MAPIGraphQL::set_payload_logger).
+type PayloadLogger = Arc<dyn Fn(&str) + Send + Sync>;
+
+fn next_sender<N: PayloadSink>(next: N) -> NextCallback {
+    Box::new(move |payload| next.send(payload))
+}
+
+/// Deserialize each `next` payload into `T`, and hand the result to `send`. The Bindings
+/// deserialize it straight from the `ResponseValue` with a
+/// [ResponseValueDeserializer](conversion::ResponseValueDeserializer), but a payload which was
+/// converted to JSON, e.g. by the mock or with
+/// [with_tagged_scalars](MAPIGraphQL::with_tagged_scalars), is read with `parse` instead.
+struct TypedSender<T> {
+    parse: fn(&str) -> Result<T, String>,
+    send: Arc<dyn Fn(Result<T, String>) -> Result<(), String> + Send + Sync>,
+}
+
+impl<T: DeserializeOwned + 'static> TypedSender<T> {
+    fn new(send: impl Fn(Result<T, String>) -> Result<(), String> + Send + Sync + 'static) -> Self {
+        Self {
+            parse: |payload| {
+                serde_json::from_str(payload)
+                    .map_err(|err| format!("Error deserializing payload: {err}"))
+            },
+            send: Arc::new(send),
+        }
+    }
+
+    /// Split the [TypedSender] into the callbacks for a subscription. The Bindings call `next`
+    /// with an empty payload right after `decode`, and `next` reports whether `decode` could
+    /// send its result.
+    fn into_callbacks(self) -> (NextCallback, DecodeCallback) {
+        let Self { parse, send } = self;
+        let decoded = Arc::new(Mutex::new(None));
+        let next_decoded = decoded.clone();
+        let decode_send = send.clone();
+        let decode: DecodeCallback = Box::new(move |value| {
+            let result = T::deserialize(conversion::ResponseValueDeserializer::new(value))
+                .map_err(|err| format!("Error deserializing payload: {err}"));
+            *decoded.lock().unwrap_or_else(PoisonError::into_inner) = Some(decode_send(result));
+        });
+        let next: NextCallback = Box::new(move |payload| {
+            let sent = next_decoded
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take();
+            sent.unwrap_or_else(|| send(parse(&payload)))
+        });
+        (next, decode)
+    }
+}
+
+fn complete_sender(complete: mpsc::Sender<()>) -> CompleteCallback {
+    Box::new(move || {
+        let _ = complete.send(());
+    })
+}
+
 enum ServiceCommand {
     Stop,
+    Shutdown {
+        tx_result: mpsc::Sender<Result<(), String>>,
+    },
+    Reset {
+        tx_result: mpsc::Sender<Result<(), String>>,
+    },
     ParsedQuery {
         query: String,
         tx_result: mpsc::Sender<Result<i32, String>>,
     },
+    ParsedQueryAsync {
+        query: String,
+        tx_result: oneshot::Sender<Result<i32, String>>,
+    },
+    ParsedQueries {
+        queries: Vec<String>,
+        tx_result: mpsc::Sender<Result<Vec<i32>, String>>,
+    },
     DiscardQuery {
         query_id: i32,
     },
+    OperationNames {
+        query_id: i32,
+        tx_result: mpsc::Sender<Result<Vec<String>, String>>,
+    },
+    ValidateQuery {
+        query_id: i32,
+        tx_result: mpsc::Sender<Result<Vec<String>, String>>,
+    },
+    QueryComplexity {
+        query_id: i32,
+        operation_name: String,
+        tx_result: mpsc::Sender<Result<u32, String>>,
+    },
     Subscribe {
         query_id: i32,
         operation_name: String,
         variables: String,
-        tx_next: mpsc::Sender<String>,
-        tx_complete: mpsc::Sender<()>,
-        tx_result: mpsc::Sender<Result<i32, String>>,
+        options: ConversionOptions,
+        next: NextCallback,
+        stream: Option<PayloadStream>,
+        decode: Option<DecodeCallback>,
+        complete: CompleteCallback,
+        tx_result: mpsc::Sender<Result<i32, ServiceError>>,
     },
     Unsubscribe {
         subscription_id: i32,
     },
+    #[cfg(feature = "mock")]
+    PushPayload {
+        subscription_id: i32,
+        payload: String,
+        tx_result: mpsc::Sender<Result<(), String>>,
+    },
+    #[cfg(test)]
+    Fail(String),
+    #[cfg(test)]
+    Block(mpsc::Receiver<()>),
 }
 
-/// Hold the `Bindings` object and automatically clean up when [Service] drops.
-struct Service {
-    worker: Option<JoinHandle<Result<(), String>>>,
-    sender: Mutex<mpsc::Sender<ServiceCommand>>,
-    thread_id: u32,
+/// Counters updated by the worker thread, the `next`/`complete` callbacks, and the threads
+/// sending commands to the worker thread. The command counters back
+/// [pending_commands](MAPIGraphQL::pending_commands), the rest are only counted with the
+/// `metrics` feature.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    payloads_delivered: Counter,
+    subscriptions_started: Counter,
+    subscriptions_completed: Counter,
+    pump_kicks: Counter,
+    commands_sent: AtomicU64,
+    commands_received: AtomicU64,
 }
 
-impl Service {
-    fn new(use_default_profile: bool) -> Arc<Self> {
-        let (tx_thread_id, rx_thread_id) = mpsc::channel();
-        let (tx_command, rx_command) = mpsc::channel();
-        let worker = Some(thread::spawn(move || {
-            Self::ensure_message_queue();
+#[cfg(feature = "metrics")]
+impl Metrics {
+    fn snapshot(&self) -> MetricsSnapshot {
+        let started = self.subscriptions_started.get();
+        let completed = self.subscriptions_completed.get();
+        MetricsSnapshot {
+            payloads_delivered: self.payloads_delivered.get(),
+            subscriptions_started: started,
+            active_subscriptions: started.saturating_sub(completed),
+            pump_kicks: self.pump_kicks.get(),
+        }
+    }
+}
 
-            let thread_id = unsafe { GetCurrentThreadId() };
-            tx_thread_id
-                .send(thread_id)
-                .expect("Error sending thread ID");
+/// Relaxed [AtomicU64] counter, which is empty and never touches memory without the `metrics`
+/// feature.
+#[derive(Default)]
+struct Counter(#[cfg(feature = "metrics")] AtomicU64);
 
-            let bindings = ffi::make_bindings();
-            bindings.startService(use_default_profile);
+impl Counter {
+    fn increment(&self) {
+        #[cfg(feature = "metrics")]
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
 
-            loop {
-                match Self::wait_with_pump(&rx_command)? {
-                    ServiceCommand::Stop => {
-                        bindings.stopService();
-                        break;
-                    }
-                    ServiceCommand::ParsedQuery { query, tx_result } => tx_result
-                        .send(bindings.parseQuery(&query).map_err(map_exception))
-                        .map_err(map_send_error)?,
-                    ServiceCommand::DiscardQuery { query_id } => bindings.discardQuery(query_id),
-                    ServiceCommand::Subscribe {
-                        query_id,
-                        operation_name,
-                        variables,
-                        tx_next,
-                        tx_complete,
-                        tx_result,
-                    } => {
-                        let next_context = Box::new(NextContext {
-                            callback: Box::new(move |payload| {
-                                tx_next.send(payload).expect("Error sending next payload")
-                            }),
-                            thread_id,
-                        });
-                        let complete_context = Box::new(CompleteContext {
-                            callback: Box::new(move || {
-                                let _ = tx_complete.send(());
-                            }),
-                            thread_id,
-                        });
-                        let subscription_id = bindings
-                            .subscribe(
-                                query_id,
-                                &operation_name,
-                                &variables,
-                                next_context,
-                                |mut context, payload| {
-                                    (context.callback)(payload);
-                                    Self::kick_pump(context.thread_id);
-                                    context
-                                },
-                                complete_context,
-                                |context| {
-                                    (context.callback)();
-                                    Self::kick_pump(context.thread_id);
-                                },
-                            )
-                            .map_err(map_exception);
-                        tx_result.send(subscription_id).map_err(map_send_error)?
-                    }
-                    ServiceCommand::Unsubscribe { subscription_id } => {
-                        bindings.unsubscribe(subscription_id)
-                    }
-                }
-            }
+    #[cfg(feature = "metrics")]
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
 
-            Ok(())
-        }));
-        let thread_id = rx_thread_id.recv().expect("Error receiving thread ID");
+/// Point-in-time copy of the counters returned by [metrics](MAPIGraphQL::metrics).
+#[cfg(feature = "metrics")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Number of `next` payloads delivered to all subscriptions.
+    pub payloads_delivered: u64,
+    /// Number of subscriptions which were successfully started.
+    pub subscriptions_started: u64,
+    /// Number of subscriptions which were started and have not called `complete` yet.
+    pub active_subscriptions: u64,
+    /// Number of times the worker thread was kicked, by signaling its wake event or posting a
+    /// `WM_APP` message with `PostThreadMessageW`. Commands sent while a kick is still pending
+    /// share it, so this can be lower than the command count.
+    pub pump_kicks: u64,
+}
 
-        Arc::new(Service {
-            worker,
-            sender: Mutex::new(tx_command),
-            thread_id,
-        })
-    }
+/// Description of a live subscription returned by
+/// [active_subscriptions](MAPIGraphQL::active_subscriptions).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubscriptionInfo {
+    /// Identifier assigned by the worker thread, which is never reused.
+    pub id: i32,
+    /// Operation name passed to [subscribe](MAPIGraphQL::subscribe), which may be empty.
+    pub operation_name: String,
+    /// When the subscription was started with [listen](Subscription::listen).
+    pub created: SystemTime,
+}
 
-    fn kick_pump(thread_id: u32) {
-        unsafe {
-            PostThreadMessageW(thread_id, WM_APP, WPARAM::default(), LPARAM::default())
-                .expect("PostThreadMessageA failed");
+/// Per-subscription counters returned by
+/// [subscription_stats](MAPIGraphQL::subscription_stats).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SubscriptionStats {
+    /// Number of `next` payloads delivered to the subscription.
+    pub payloads_delivered: u64,
+    /// Total length of those payloads in bytes of JSON.
+    pub bytes_delivered: u64,
+    /// Time since the subscription was started.
+    pub elapsed: Duration,
+}
+
+/// Counters which the `next` callback of a registered subscription updates without locking the
+/// [SubscriptionRegistry].
+struct SubscriptionCounters {
+    payloads: AtomicU64,
+    bytes: AtomicU64,
+    started: Instant,
+}
+
+impl SubscriptionCounters {
+    fn new() -> Self {
+        Self {
+            payloads: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            started: Instant::now(),
         }
     }
 
-    fn ensure_message_queue() {
-        let mut msg = MSG::default();
-        let hwnd = HWND::default();
-        unsafe { PeekMessageW(&mut msg, hwnd, WM_USER, WM_USER, PM_NOREMOVE) };
+    fn record(&self, payload: &str) {
+        self.payloads.fetch_add(1, Ordering::Relaxed);
+        self.bytes
+            .fetch_add(payload.len() as u64, Ordering::Relaxed);
     }
 
-    fn wait_with_pump<T>(rx: &mpsc::Receiver<T>) -> Result<T, String> {
-        let mut msg = MSG::default();
-        let hwnd = HWND::default();
-
-        loop {
-            if let Ok(result) = rx.try_recv() {
-                return Ok(result);
-            }
-
-            unsafe {
-                match GetMessageW(&mut msg, hwnd, 0, 0).0 {
-                    -1 => {
-                        return Err(format!(
-                            "GetMessageA error: {}",
-                            windows::core::Error::from_win32().code().0
-                        ));
-                    }
-                    0 => return Err(String::from("Cancelled")),
-                    _ => {
-                        TranslateMessage(&msg);
-                        DispatchMessageW(&msg);
-                    }
-                }
-            }
+    fn snapshot(&self) -> SubscriptionStats {
+        SubscriptionStats {
+            payloads_delivered: self.payloads.load(Ordering::Relaxed),
+            bytes_delivered: self.bytes.load(Ordering::Relaxed),
+            elapsed: self.started.elapsed(),
         }
     }
+}
 
-    fn stop(&mut self) -> Result<(), String> {
-        self.sender
-            .lock()
-            .map_err(map_lock_error)?
-            .send(ServiceCommand::Stop)
-            .map_err(map_send_error)?;
-        Self::kick_pump(self.thread_id);
+/// Entry in the [SubscriptionRegistry].
+struct TrackedSubscription {
+    info: SubscriptionInfo,
+    counters: Arc<SubscriptionCounters>,
+}
 
-        if let Some(worker) = self.worker.take() {
-            let result = worker
-                .join()
-                .map_err(|_| String::from("Error joining the worker"))?;
-            result?;
-        }
+/// Event delivered by [listen_events](Subscription::listen_events), in the same order as the
+/// `next` and `complete` callbacks on the worker thread. The stream always ends with exactly one
+/// [Complete](SubscriptionEvent::Complete) or [Error](SubscriptionEvent::Error), so if the
+/// channel disconnects before either of them, the worker thread went away.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SubscriptionEvent {
+    /// A `next` payload. GraphQL errors reported while executing the operation are part of the
+    /// payload, e.g. in [Response::errors].
+    Data(String),
+    /// The subscription called `complete`, so there are no more events.
+    Complete,
+    /// The subscription could not be started, so there are no more events. This is the same
+    /// error which [listen_events](Subscription::listen_events) returns.
+    Error(String),
+    /// No [Data](SubscriptionEvent::Data) arrived within the `max_idle` window passed to
+    /// [listen_events_keepalive](Subscription::listen_events_keepalive). The subscription is
+    /// still active, and this is sent again after every further window without a payload.
+    Idle(Duration),
+}
 
-        Ok(())
+/// [PayloadSink] which wraps each payload in [SubscriptionEvent::Data].
+struct EventSink(mpsc::Sender<SubscriptionEvent>);
+
+impl PayloadSink for EventSink {
+    fn send(&self, payload: String) -> Result<(), String> {
+        self.0
+            .send(SubscriptionEvent::Data(payload))
+            .map_err(|err| err.to_string())
     }
 }
 
-impl Drop for Service {
-    /// Shutdown the [GraphQL](https://graphql.org) service and log off from the `MAPI` session.
-    fn drop(&mut self) {
-        self.stop().expect("Unable to stop the service");
-    }
+/// Watchdog for [listen_events_keepalive](Subscription::listen_events_keepalive), shared by the
+/// [KeepaliveSink], the `complete` callback, and the thread which sends the
+/// [Idle](SubscriptionEvent::Idle) events.
+struct Keepalive {
+    events: mpsc::Sender<SubscriptionEvent>,
+    state: Mutex<KeepaliveState>,
+    wake: Condvar,
 }
 
-/// Rust-friendly bindings to [gqlmapi](https://github.com/microsoft/gqlmapi).
-pub struct MAPIGraphQL(Arc<Service>);
+struct KeepaliveState {
+    last_event: Instant,
+    done: bool,
+}
 
-impl MAPIGraphQL {
-    /// Start the [GraphQL](https://graphql.org) service and log on to the `MAPI` session.
-    pub fn new(use_default_profile: bool) -> Self {
-        Self(Service::new(use_default_profile))
+impl Keepalive {
+    fn new(events: mpsc::Sender<SubscriptionEvent>) -> Self {
+        Self {
+            events,
+            state: Mutex::new(KeepaliveState {
+                last_event: Instant::now(),
+                done: false,
+            }),
+            wake: Condvar::new(),
+        }
     }
 
-    /// Parse a [GraphQL](https://graphql.org) request document and return a [ParsedQuery] that can
-    /// be used to represent the request in 1 or more calls to [subscribe](MAPIGraphQL::subscribe).
-    ///
-    /// If the request document cannot be parsed, it will return an [Err(String)](Err).
-    pub fn parse_query(&self, query: &str) -> Result<Arc<ParsedQuery>, String> {
-        let (tx, rx) = mpsc::channel();
-        self.0
-            .sender
-            .lock()
-            .map_err(map_lock_error)?
-            .send(ServiceCommand::ParsedQuery {
-                query: String::from(query),
-                tx_result: tx,
-            })
-            .map_err(map_send_error)?;
-        Service::kick_pump(self.0.thread_id);
-        let result = rx.recv().map_err(map_recv_error)?;
-        Ok(Arc::new(ParsedQuery(self.0.clone(), result?)))
+    /// Send a [SubscriptionEvent] and restart the idle window. The lock is held while sending, so
+    /// an `Idle` event is never sent after the final `Complete` or `Error`.
+    fn send(&self, event: SubscriptionEvent) -> Result<(), mpsc::SendError<SubscriptionEvent>> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.last_event = Instant::now();
+        state.done |= !matches!(event, SubscriptionEvent::Data(_));
+        let result = self.events.send(event);
+        self.wake.notify_all();
+        result
     }
 
-    /// Subscribe to a [GraphQL](https://graphql.org) [ParsedQuery] that was previously parsed with
-    /// [parse_query](MAPIGraphQL::parse_query).
-    pub fn subscribe(
-        &self,
-        query: Arc<ParsedQuery>,
-        operation_name: &str,
-        variables: &str,
-    ) -> Mutex<Subscription> {
-        Mutex::new(Subscription {
-            subscription_id: 0,
-            query,
-            operation_name: operation_name.into(),
-            variables: variables.into(),
-        })
+    /// Send an [Idle](SubscriptionEvent::Idle) event each time `max_idle` passes without another
+    /// event, until the stream ends, the receiver drops, or nothing else can send an event.
+    fn watch(self: Arc<Self>, max_idle: Duration) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        while !state.done && Arc::strong_count(&self) > 1 {
+            let idle = state.last_event.elapsed();
+            if idle < max_idle {
+                state = self
+                    .wake
+                    .wait_timeout(state, max_idle - idle)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+                continue;
+            }
+
+            if self.events.send(SubscriptionEvent::Idle(max_idle)).is_err() {
+                break;
+            }
+            state.last_event = Instant::now();
+        }
     }
 }
 
-/// Hold on to a query parsed with [parse_query](MAPIGraphQL::parse_query) and automatically clean
-/// up when [ParsedQuery] drops.
-pub struct ParsedQuery(Arc<Service>, i32);
+/// [PayloadSink] which wraps each payload in [SubscriptionEvent::Data] and restarts the idle
+/// window of its [Keepalive].
+struct KeepaliveSink(Arc<Keepalive>);
 
-impl ParsedQuery {
-    fn discard_query(&mut self) -> Result<(), String> {
-        if self.1 != 0 {
-            self.0
-                .sender
-                .lock()
-                .map_err(map_lock_error)?
-                .send(ServiceCommand::DiscardQuery { query_id: self.1 })
-                .map_err(map_send_error)?;
-            Service::kick_pump(self.0.thread_id);
-            self.1 = 0;
-        }
-        Ok(())
+impl PayloadSink for KeepaliveSink {
+    fn send(&self, payload: String) -> Result<(), String> {
+        self.0
+            .send(SubscriptionEvent::Data(payload))
+            .map_err(|err| err.to_string())
     }
 }
 
-impl Drop for ParsedQuery {
-    /// Cleanup a [GraphQL](https://graphql.org) request document that was previously parsed with
-    /// [parse_query](MAPIGraphQL::parse_query).
-    fn drop(&mut self) {
-        self.discard_query().expect("Unable to discard query");
-    }
+/// `MAPI` profile returned by [list_profiles](MAPIGraphQL::list_profiles).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Profile {
+    /// Display name of the profile, which is also the name used to log on with it.
+    pub name: String,
+    /// Whether this is the default profile, which `use_default_profile` logs on with.
+    pub is_default: bool,
 }
 
-/// Hold on to an operation subscription created with [subscribe](MAPIGraphQL::subscribe) and
-/// automatically clean up when [Subscription] drops..
-pub struct Subscription {
-    subscription_id: i32,
-    query: Arc<ParsedQuery>,
-    operation_name: String,
-    variables: String,
+impl From<ffi::ProfileEntry> for Profile {
+    fn from(entry: ffi::ProfileEntry) -> Self {
+        Self {
+            name: entry.name,
+            is_default: entry.is_default,
+        }
+    }
 }
 
-impl Subscription {
-    /// Start listening to the [Subscription] that was previously created with
-    /// [subscribe](MAPIGraphQL::subscribe). This will return an [Err(String)](Err) if the
-    /// request failed.
-    ///
-    /// If the specified operation is a `Query` or `Mutation`, it will be evaluated immediately and
-    /// result in a pair of calls to `next` and then `complete`.
-    ///
-    /// If it is a `Subscription` operation, each time the event stream is updated, the payload
-    /// will be delivered through another call to `next`. `Subscription` operations will also
-    /// invoke `complete` once they are removed by dropping the [Subscription].
-    pub fn listen(
-        &mut self,
-        next: mpsc::Sender<String>,
-        complete: mpsc::Sender<()>,
-    ) -> Result<(), String> {
-        self.unsubscribe()?;
+/// Subscriptions which have been started and have not called `complete` yet, keyed by id.
+type SubscriptionRegistry = Arc<Mutex<BTreeMap<i32, TrackedSubscription>>>;
 
-        let (tx, rx) = mpsc::channel();
-        self.query
-            .0
-            .sender
-            .lock()
-            .map_err(map_lock_error)?
-            .send(ServiceCommand::Subscribe {
-                query_id: self.query.1,
-                operation_name: self.operation_name.clone(),
-                variables: self.variables.clone(),
-                tx_next: next,
-                tx_complete: complete,
-                tx_result: tx,
-            })
-            .map_err(map_send_error)?;
-        Service::kick_pump(self.query.0.thread_id);
-        let result = rx.recv().map_err(map_recv_error)?;
+/// State shared between the worker loop and the `next` and `complete` callbacks, which run while
+/// the `Bindings` are delivering a payload, so they cannot call back into the `Bindings` directly.
+#[derive(Default)]
+struct WorkerState {
+    /// Subscriptions whose `next` receiver was dropped, which the worker thread unsubscribes once
+    /// it is no longer delivering a payload to them.
+    cancelled: Mutex<Vec<i32>>,
+    /// Message from the first panic caught on the worker thread, which stops the service.
+    panicked: Mutex<Option<String>>,
+}
 
-        self.subscription_id = result?;
-        Ok(())
+impl WorkerState {
+    /// Whether the worker loop has something to do besides handling commands.
+    fn is_pending(&self) -> bool {
+        !self
+            .cancelled
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_empty()
+            || self.panic_error().is_some()
     }
 
-    fn unsubscribe(&mut self) -> Result<(), String> {
-        if self.subscription_id != 0 {
-            self.query
-                .0
-                .sender
-                .lock()
-                .map_err(map_lock_error)?
-                .send(ServiceCommand::Unsubscribe {
-                    subscription_id: self.subscription_id,
-                })
-                .map_err(map_send_error)?;
-            Service::kick_pump(self.query.0.thread_id);
-            self.subscription_id = 0;
+    /// Run `f`, and record its panic instead of unwinding. A panic which unwinds out of a callback
+    /// into the `Bindings` would abort the process.
+    fn catch_panic<T>(&self, f: impl FnOnce() -> T) -> Option<T> {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(value) => Some(value),
+            Err(payload) => {
+                let message = if let Some(message) = payload.downcast_ref::<&str>() {
+                    (*message).to_owned()
+                } else if let Some(message) = payload.downcast_ref::<String>() {
+                    message.clone()
+                } else {
+                    String::from("unknown panic")
+                };
+                self.panicked
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .get_or_insert(message);
+                None
+            }
         }
-        Ok(())
     }
-}
 
-impl Drop for Subscription {
-    /// Cleanup a `Subscription` that was previously created with [subscribe](MAPIGraphQL::subscribe).
-    ///
-    /// This is a no-op for `Query` or `Mutation` requests since they deliver 1 immediate result.
-    fn drop(&mut self) {
-        self.unsubscribe().expect("Unable to unsubscribe");
+    /// Describe the first panic caught on the worker thread, if there was one.
+    fn panic_error(&self) -> Option<ServiceError> {
+        self.panicked
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+            .map(ServiceError::Panicked)
     }
 }
 
-fn map_lock_error<T>(err: PoisonError<T>) -> String {
-    format!("Error locking mutex: {}", err)
+/// Identify subscriptions which can share one underlying subscription on the worker thread, by
+/// `query_id`, `operation_name`, and `variables`.
+type SharedKey = (i32, String, String);
+
+/// One of the [listen](Subscription::listen) channels fed by a shared subscription.
+struct SharedConsumer {
+    id: u64,
+    next: Box<dyn PayloadSink>,
+    complete: CompleteCallback,
 }
 
-fn map_send_error<T>(err: mpsc::SendError<T>) -> String {
-    format!("Error sending message: {}", err)
+/// Consumers of a shared subscription, which the `next` and `complete` callbacks fan out to.
+#[derive(Default)]
+struct SharedConsumers {
+    consumers: Vec<SharedConsumer>,
+    completed: bool,
 }
 
-fn map_recv_error(err: mpsc::RecvError) -> String {
-    format!("Error receiving message: {}", err)
+struct SharedSubscription {
+    subscription_id: i32,
+    consumers: Arc<Mutex<SharedConsumers>>,
 }
 
-fn map_exception(err: cxx::Exception) -> String {
-    String::from(err.what())
+/// Opt-in state for [with_shared_subscriptions](MAPIGraphQL::with_shared_subscriptions).
+#[derive(Default)]
+struct SubscriptionSharing {
+    next_consumer_id: u64,
+    subscriptions: HashMap<SharedKey, SharedSubscription>,
 }
 
-#[cfg(test)]
-mod test {
-    extern crate serde;
-    use serde::{Deserialize, Serialize};
+/// COM apartment which the worker thread joins before it starts the `MAPI` session, see
+/// [ServiceOptions].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ComApartment {
+    /// Single-threaded apartment (STA). The worker thread always runs a message loop, which an
+    /// STA requires to receive calls and `MAPI` notifications.
+    #[default]
+    SingleThreaded,
+    /// Multi-threaded apartment (MTA), e.g. to share the process-wide MTA with other COM code.
+    MultiThreaded,
+}
+
+/// Options for [new_with_options](MAPIGraphQL::new_with_options).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServiceOptions {
+    /// Log on to the default `MAPI` profile instead of prompting for one.
+    pub use_default_profile: bool,
+    /// Number of times to retry the `MAPI` logon if it fails.
+    pub retries: u32,
+    /// Delay before the first retry, which doubles after each failure.
+    pub backoff: Duration,
+    /// COM apartment for the worker thread.
+    pub apartment: ComApartment,
+}
 
-    use crate::MAPIGraphQL;
-    use std::sync::mpsc;
+/// Configure and start a [MAPIGraphQL] with chained setters, see
+/// [builder](MAPIGraphQL::builder). Every setting is optional, and the defaults match
+/// [new(false)](MAPIGraphQL::new).
+#[derive(Clone, Default)]
+pub struct MAPIGraphQLBuilder {
+    options: ServiceOptions,
+    profile: Option<String>,
+    payload_logger: Option<PayloadLogger>,
+    max_payload_bytes: usize,
+}
 
-    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
-    struct OperationType {
-        name: String,
+impl MAPIGraphQLBuilder {
+    /// Log on to the default `MAPI` profile instead of prompting for one.
+    pub fn default_profile(mut self, use_default_profile: bool) -> Self {
+        self.options.use_default_profile = use_default_profile;
+        self.profile = None;
+        self
     }
 
-    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
-    struct TypeKind {
-        kind: String,
-        name: String,
+    /// Log on to the `MAPI` profile with this name, as returned by
+    /// [list_profiles](MAPIGraphQL::list_profiles).
+    ///
+    /// gqlmapi can only log on to the default profile or prompt for one, so
+    /// [build](MAPIGraphQLBuilder::build) returns an [Err(ServiceError)](Err) unless this is the
+    /// default profile.
+    pub fn profile(mut self, name: &str) -> Self {
+        self.profile = Some(name.to_owned());
+        self
     }
 
-    #[allow(non_snake_case)]
-    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
-    struct Schema {
-        queryType: OperationType,
-        mutationType: OperationType,
-        subscriptionType: OperationType,
-        types: Vec<TypeKind>,
+    /// Retry the `MAPI` logon like [new_with_retry](MAPIGraphQL::new_with_retry).
+    pub fn retry(mut self, retries: u32, backoff: Duration) -> Self {
+        self.options.retries = retries;
+        self.options.backoff = backoff;
+        self
     }
 
-    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
-    struct Data {
-        __schema: Schema,
+    /// Join this COM apartment on the worker thread, see
+    /// [new_with_options](MAPIGraphQL::new_with_options).
+    pub fn apartment(mut self, apartment: ComApartment) -> Self {
+        self.options.apartment = apartment;
+        self
     }
 
-    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
-    struct IntrospectionResults {
-        data: Data,
+    /// Install a hook for every `next` payload, like
+    /// [set_payload_logger](MAPIGraphQL::set_payload_logger).
+    pub fn payload_logger(mut self, logger: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.payload_logger = Some(Arc::new(logger));
+        self
     }
 
-    #[test]
-    fn fetch_introspection() {
-        let gqlmapi = MAPIGraphQL::new(true);
-        let query = gqlmapi
-            .parse_query(
-                r#"query {
-                    __schema {
-                        queryType {
-                            name
+    /// Cap the size of each `next` payload, like
+    /// [set_max_payload_bytes](MAPIGraphQL::set_max_payload_bytes).
+    pub fn max_payload_bytes(mut self, max_payload_bytes: usize) -> Self {
+        self.max_payload_bytes = max_payload_bytes;
+        self
+    }
+
+    /// Start the [GraphQL](https://graphql.org) service with these settings.
+    ///
+    /// This only returns an [Err(ServiceError)](Err) if the settings cannot be used, e.g. for an
+    /// unknown [profile](MAPIGraphQLBuilder::profile). Like [new](MAPIGraphQL::new), it does not
+    /// wait for the `MAPI` logon, so if that fails, the error is returned from the next method
+    /// which needs the worker thread.
+    pub fn build(mut self) -> Result<MAPIGraphQL, ServiceError> {
+        if let Some(name) = self.profile.take() {
+            let profile = MAPIGraphQL::list_profiles()?
+                .into_iter()
+                .find(|profile| profile.name == name)
+                .ok_or_else(|| format!("Unknown profile: {name}"))?;
+            if !profile.is_default {
+                return Err(format!(
+                    "Unable to log on to {name}: gqlmapi can only log on to the default profile"
+                )
+                .into());
+            }
+            self.options.use_default_profile = true;
+        }
+        Ok(self.start())
+    }
+
+    fn start(self) -> MAPIGraphQL {
+        let gqlmapi = MAPIGraphQL::new_with_options(self.options);
+        *gqlmapi
+            .0
+            .payload_logger
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = self.payload_logger;
+        gqlmapi.set_max_payload_bytes(self.max_payload_bytes);
+        gqlmapi
+    }
+}
+
+/// Balance a successful `CoInitializeEx` on the worker thread with `CoUninitialize` once every
+/// `MAPI` object has been released.
+struct ComApartmentGuard;
+
+impl ComApartmentGuard {
+    fn initialize(apartment: ComApartment) -> Result<Self, String> {
+        let coinit = match apartment {
+            ComApartment::SingleThreaded => COINIT_APARTMENTTHREADED,
+            ComApartment::MultiThreaded => COINIT_MULTITHREADED,
+        };
+        unsafe { CoInitializeEx(None, coinit) }
+            .map_err(|err| format!("Error initializing COM: {err}"))?;
+        Ok(Self)
+    }
+}
+
+impl Drop for ComApartmentGuard {
+    fn drop(&mut self) {
+        unsafe { CoUninitialize() };
+    }
+}
+
+/// Most messages which [wait_with_pump](Service::wait_with_pump) dispatches before it checks for
+/// commands again, even if more are queued.
+const MAX_MESSAGES_PER_WAKE: usize = 64;
+
+/// Coalesced wake-up for the thread which handles commands, sent by [kick](Service::kick).
+struct Kick {
+    /// Set while a kick is pending, until that thread drains the command channel.
+    pending: AtomicBool,
+    /// Event which the worker thread waits on together with its message queue. This is [None]
+    /// with [attach](MAPIGraphQL::attach), which posts a `WM_APP` message instead, since only the
+    /// message loop of the application runs on that thread. It is also [None] if the event could
+    /// not be created, in which case the worker thread exits with that error right away.
+    event: Option<WakeEvent>,
+}
+
+/// Auto-reset Win32 event, which is closed when it drops.
+struct WakeEvent(HANDLE);
+
+impl WakeEvent {
+    fn new() -> windows::core::Result<Self> {
+        unsafe { CreateEventW(None, FALSE, FALSE, PCWSTR::null()) }.map(Self)
+    }
+
+    fn set(&self) -> windows::core::Result<()> {
+        unsafe { SetEvent(self.0) }
+    }
+}
+
+impl Drop for WakeEvent {
+    fn drop(&mut self) {
+        let _ = unsafe { CloseHandle(self.0) };
+    }
+}
+
+/// Result of logging on to the `MAPI` session, which the worker thread records once
+/// `startService` completes, for [wait_ready](MAPIGraphQL::wait_ready).
+#[derive(Default)]
+struct LogonStatus {
+    result: Mutex<Option<Result<(), String>>>,
+    ready: Condvar,
+}
+
+impl LogonStatus {
+    /// Record the `result` and wake every waiting thread, unless a result was already recorded.
+    fn record(&self, result: &Result<(), String>) {
+        self.result
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_or_insert_with(|| result.clone());
+        self.ready.notify_all();
+    }
+
+    fn wait(&self, timeout: Duration) -> Result<(), ServiceError> {
+        let (result, _) = self
+            .ready
+            .wait_timeout_while(
+                self.result.lock().unwrap_or_else(PoisonError::into_inner),
+                timeout,
+                |result| result.is_none(),
+            )
+            .unwrap_or_else(PoisonError::into_inner);
+        match result.as_ref() {
+            Some(result) => result.clone().map_err(ServiceError::Other),
+            None => Err(ServiceError::Timeout(format!(
+                "the worker thread did not log on within {timeout:?}"
+            ))),
+        }
+    }
+}
+
+/// Hold the `Bindings` object and automatically clean up when [Service] drops.
+struct Service {
+    worker: Option<JoinHandle<Result<(), String>>>,
+    sender: Mutex<mpsc::Sender<ServiceCommand>>,
+    thread_id: u32,
+    metrics: Arc<Metrics>,
+    kick: Arc<Kick>,
+    subscriptions: SubscriptionRegistry,
+    sharing: Mutex<Option<SubscriptionSharing>>,
+    payload_logger: Mutex<Option<PayloadLogger>>,
+    exit_status: Arc<Mutex<Option<Result<(), String>>>>,
+    logon: Arc<LogonStatus>,
+    conversion: Mutex<ConversionOptions>,
+    /// Whether the [Stop](ServiceCommand::Stop) command reached the worker thread, once it has
+    /// been sent by [request_stop](Service::request_stop).
+    stop_sent: OnceLock<bool>,
+}
+
+impl Service {
+    fn new(options: ServiceOptions) -> Arc<Self> {
+        Self::spawn(options, || Box::new(ffi::make_bindings()))
+    }
+
+    /// Spawn the worker thread, which creates its [Backend] with `make_backend` once COM is
+    /// initialized and then logs on to the service.
+    fn spawn(
+        options: ServiceOptions,
+        make_backend: impl FnOnce() -> Box<dyn Backend> + Send + 'static,
+    ) -> Arc<Self> {
+        let (tx_thread_id, rx_thread_id) = mpsc::channel();
+        let (tx_command, rx_command) = mpsc::channel();
+        let metrics = Arc::new(Metrics::default());
+        let worker_metrics = metrics.clone();
+        let (event, wake_result) = match WakeEvent::new() {
+            Ok(event) => (Some(event), Ok(())),
+            Err(err) => (None, Err(format!("Error creating the wake event: {err}"))),
+        };
+        let kick = Arc::new(Kick {
+            pending: AtomicBool::new(false),
+            event,
+        });
+        let worker_kick = kick.clone();
+        let subscriptions = SubscriptionRegistry::default();
+        let worker_subscriptions = subscriptions.clone();
+        let exit_status = Arc::new(Mutex::new(None));
+        let worker_exit_status = exit_status.clone();
+        let logon = Arc::new(LogonStatus::default());
+        let worker_logon = logon.clone();
+        let worker = Some(thread::spawn(move || {
+            Self::ensure_message_queue();
+
+            let thread_id = unsafe { GetCurrentThreadId() };
+            tx_thread_id
+                .send(thread_id)
+                .expect("Error sending thread ID");
+
+            let com = wake_result.and_then(|()| ComApartmentGuard::initialize(options.apartment));
+
+            // The bindings are dropped at the end of the closure, before COM is uninitialized.
+            let result = com.and_then(|_com| {
+                let bindings = make_backend();
+                let result = Self::start_service(
+                    bindings.as_ref(),
+                    options.use_default_profile,
+                    options.retries,
+                    options.backoff,
+                )
+                .map_err(String::from);
+                worker_logon.record(&result);
+                result.and_then(|()| {
+                    Self::run_worker(
+                        bindings.as_ref(),
+                        &rx_command,
+                        thread_id,
+                        &worker_metrics,
+                        &worker_kick,
+                        &worker_subscriptions,
+                        &Arc::default(),
+                    )
+                    .map_err(String::from)
+                })
+            });
+
+            // If the wake event or COM failed, the worker never tried to log on.
+            worker_logon.record(&result);
+
+            // Record the exit status before dropping rx_command, so any caller which sees the
+            // channel disconnect can report why the worker exited.
+            *worker_exit_status
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Some(result.clone());
+            result
+        }));
+        let thread_id = rx_thread_id.recv().expect("Error receiving thread ID");
+
+        Arc::new(Service {
+            worker,
+            sender: Mutex::new(tx_command),
+            thread_id,
+            metrics,
+            kick,
+            subscriptions,
+            sharing: Mutex::new(None),
+            payload_logger: Mutex::new(None),
+            exit_status,
+            logon,
+            conversion: Mutex::new(ConversionOptions::default()),
+            stop_sent: OnceLock::new(),
+        })
+    }
+
+    /// Create the `Bindings` on the calling thread instead of spawning a worker thread, and
+    /// return the [ServicePump] which must be used to handle commands on that thread.
+    fn attach(use_default_profile: bool) -> Result<(Arc<Self>, ServicePump), ServiceError> {
+        let (tx_command, rx_command) = mpsc::channel();
+        let metrics = Arc::new(Metrics::default());
+        let kick = Arc::new(Kick {
+            pending: AtomicBool::new(false),
+            event: None,
+        });
+        let subscriptions = SubscriptionRegistry::default();
+        let exit_status = Arc::new(Mutex::new(None));
+
+        Self::ensure_message_queue();
+
+        let thread_id = unsafe { GetCurrentThreadId() };
+        let bindings = ffi::make_bindings();
+        Self::start_service(&bindings, use_default_profile, 0, Duration::ZERO)?;
+        let logon = LogonStatus::default();
+        logon.record(&Ok(()));
+
+        let pump = ServicePump {
+            bindings,
+            rx_command,
+            thread_id,
+            metrics: metrics.clone(),
+            kick: kick.clone(),
+            subscriptions: subscriptions.clone(),
+            state: Arc::default(),
+            exit_status: exit_status.clone(),
+            running: true,
+        };
+
+        Ok((
+            Arc::new(Service {
+                worker: None,
+                sender: Mutex::new(tx_command),
+                thread_id,
+                metrics,
+                kick,
+                subscriptions,
+                sharing: Mutex::new(None),
+                payload_logger: Mutex::new(None),
+                exit_status,
+                logon: Arc::new(logon),
+                conversion: Mutex::new(ConversionOptions::default()),
+                stop_sent: OnceLock::new(),
+            }),
+            pump,
+        ))
+    }
+
+    /// Log on to the `MAPI` session, retrying up to `retries` more times with an exponential
+    /// `backoff` if it fails, e.g. because `MAPI` is still initializing.
+    ///
+    /// The `Bindings` refuse to log on again until the service is stopped, so an
+    /// [AlreadyStarted](ServiceError::AlreadyStarted) error is returned right away instead of
+    /// being retried. If it retried before giving up, the error lists the failure from each
+    /// attempt.
+    fn start_service(
+        bindings: &dyn Backend,
+        use_default_profile: bool,
+        retries: u32,
+        backoff: Duration,
+    ) -> Result<(), ServiceError> {
+        let mut failures = Vec::new();
+        let mut delay = backoff;
+
+        loop {
+            match bindings.start_service(use_default_profile) {
+                Ok(()) => return Ok(()),
+                Err(err @ ServiceError::AlreadyStarted(_)) => return Err(err),
+                Err(err) if failures.len() < retries as usize => {
+                    failures.push(err.to_string());
+                    thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                }
+                Err(err) if failures.is_empty() => {
+                    return Err(ServiceError::Other(format!(
+                        "Error starting the service: {err}"
+                    )))
+                }
+                Err(err) => {
+                    failures.push(err.to_string());
+                    return Err(ServiceError::Other(format!(
+                        "Error starting the service after {} attempts: {}",
+                        failures.len(),
+                        failures.join("; ")
+                    )));
+                }
+            }
+        }
+    }
+
+    fn run_worker(
+        bindings: &dyn Backend,
+        rx_command: &mpsc::Receiver<ServiceCommand>,
+        thread_id: u32,
+        metrics: &Arc<Metrics>,
+        kick: &Kick,
+        subscriptions: &SubscriptionRegistry,
+        state: &Arc<WorkerState>,
+    ) -> Result<(), ServiceError> {
+        loop {
+            let commands = Self::wait_with_pump(rx_command, kick, state)?;
+            Self::unsubscribe_cancelled(bindings, state);
+            for command in commands {
+                let running = Self::handle_command_or_panic(
+                    bindings,
+                    command,
+                    thread_id,
+                    metrics,
+                    subscriptions,
+                    state,
+                )?;
+                if !running {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Unsubscribe everything in [WorkerState::cancelled]. This must not be called while
+    /// delivering a payload, since that would remove the subscription which is delivering it.
+    fn unsubscribe_cancelled(bindings: &dyn Backend, state: &WorkerState) {
+        let subscription_ids = mem::take(
+            &mut *state
+                .cancelled
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        for subscription_id in subscription_ids {
+            bindings.unsubscribe(subscription_id);
+        }
+    }
+
+    /// Same as [handle_command](Service::handle_command), but if it or one of the callbacks
+    /// panicked, stop the service and fail with a [Panicked](ServiceError::Panicked) error instead
+    /// of unwinding, so the worker thread records why it exited and every pending caller gets a
+    /// [WorkerGone](ServiceError::WorkerGone) error.
+    fn handle_command_or_panic(
+        bindings: &dyn Backend,
+        command: ServiceCommand,
+        thread_id: u32,
+        metrics: &Arc<Metrics>,
+        subscriptions: &SubscriptionRegistry,
+        state: &Arc<WorkerState>,
+    ) -> Result<bool, ServiceError> {
+        metrics.commands_received.fetch_add(1, Ordering::Relaxed);
+        let result = state.catch_panic(|| {
+            Self::handle_command(bindings, command, thread_id, metrics, subscriptions, state)
+        });
+        match state.panic_error() {
+            Some(err) => {
+                // The panic is what the caller needs to see, even if stopping fails as well.
+                let _ = state.catch_panic(|| bindings.stop_service());
+                Err(err)
+            }
+            None => result.unwrap_or(Ok(true)).map_err(ServiceError::Other),
+        }
+    }
+
+    /// Handle a single [ServiceCommand] on the thread which owns the `Bindings`, returning
+    /// `false` once the service has been stopped.
+    fn handle_command(
+        bindings: &dyn Backend,
+        command: ServiceCommand,
+        thread_id: u32,
+        metrics: &Arc<Metrics>,
+        subscriptions: &SubscriptionRegistry,
+        state: &Arc<WorkerState>,
+    ) -> Result<bool, String> {
+        match command {
+            ServiceCommand::Stop => {
+                bindings.stop_service()?;
+                return Ok(false);
+            }
+            ServiceCommand::Shutdown { tx_result } => {
+                // Deliver any notifications which are already queued, then stopping the service
+                // unsubscribes everything and calls each complete callback.
+                Self::dispatch_pending_messages();
+                let _ = tx_result.send(bindings.stop_service());
+                return Ok(false);
+            }
+            ServiceCommand::Reset { tx_result } => {
+                // Unsubscribing calls each complete callback, and the service keeps running.
+                let _ = tx_result.send(bindings.reset_service());
+            }
+            ServiceCommand::ParsedQuery { query, tx_result } => {
+                // If the caller timed out before the reply, nothing will ever discard the query.
+                if let Err(mpsc::SendError(Ok(query_id))) =
+                    tx_result.send(bindings.parse_query(&query))
+                {
+                    bindings.discard_query(query_id);
+                }
+            }
+            ServiceCommand::ParsedQueryAsync { query, tx_result } => {
+                // If the future was dropped before the reply, nothing will ever discard the query.
+                if let Err(Ok(query_id)) = tx_result.send(bindings.parse_query(&query)) {
+                    bindings.discard_query(query_id);
+                }
+            }
+            ServiceCommand::ParsedQueries { queries, tx_result } => {
+                let mut query_ids = Vec::with_capacity(queries.len());
+                let mut result = Ok(());
+                for (index, query) in queries.iter().enumerate() {
+                    match bindings.parse_query(query) {
+                        Ok(query_id) => query_ids.push(query_id),
+                        Err(err) => {
+                            result = Err(format!("Error parsing query {index}: {err}"));
+                            break;
                         }
-                        mutationType {
-                            name
+                    }
+                }
+                let result = match result {
+                    Ok(()) => Ok(query_ids),
+                    Err(err) => {
+                        for query_id in query_ids {
+                            bindings.discard_query(query_id);
                         }
-                        subscriptionType {
-                            name
+                        Err(err)
+                    }
+                };
+                tx_result.send(result).map_err(map_send_error)?
+            }
+            ServiceCommand::DiscardQuery { query_id } => bindings.discard_query(query_id),
+            ServiceCommand::OperationNames {
+                query_id,
+                tx_result,
+            } => tx_result
+                .send(bindings.operation_names(query_id))
+                .map_err(map_send_error)?,
+            ServiceCommand::ValidateQuery {
+                query_id,
+                tx_result,
+            } => tx_result
+                .send(bindings.validate_query(query_id))
+                .map_err(map_send_error)?,
+            ServiceCommand::QueryComplexity {
+                query_id,
+                operation_name,
+                tx_result,
+            } => tx_result
+                .send(bindings.query_complexity(query_id, &operation_name))
+                .map_err(map_send_error)?,
+            ServiceCommand::Subscribe {
+                query_id,
+                operation_name,
+                variables,
+                options,
+                mut next,
+                stream,
+                decode,
+                complete,
+                tx_result,
+            } => {
+                // Without an operation name, a document with several operations fails to
+                // subscribe, so say which names it could have used before setting anything up.
+                if operation_name.is_empty() {
+                    if let Ok(operation_names) = bindings.operation_names(query_id) {
+                        if operation_names.len() > 1 {
+                            let _ = tx_result.send(Err(ServiceError::Other(
+                                operation_name_required(&operation_names),
+                            )));
+                            return Ok(true);
                         }
-                        types {
-                            kind
-                            name
+                    }
+                }
+
+                // Holds the registered id, or -1 once complete has been called.
+                let registered = Arc::new(AtomicI32::new(0));
+                // Set once the next receiver is gone, so the subscription can be torn down.
+                let dropped = Arc::new(AtomicBool::new(false));
+                let counters = Arc::new(SubscriptionCounters::new());
+                let next_counters = counters.clone();
+                let next_registered = registered.clone();
+                let next_dropped = dropped.clone();
+                let next_state = state.clone();
+                let next_metrics = metrics.clone();
+                let next_context = Box::new(NextContext {
+                    callback: Box::new(move |payload| {
+                        next_metrics.payloads_delivered.increment();
+                        next_counters.record(&payload);
+                        let sent = next_state.catch_panic(|| next(payload));
+                        if !matches!(sent, Some(Ok(())))
+                            && !next_dropped.swap(true, Ordering::AcqRel)
+                        {
+                            // Unsubscribing here would remove the subscription while it is still
+                            // delivering this payload, so leave that to the worker loop.
+                            let subscription_id = next_registered.load(Ordering::Acquire);
+                            if subscription_id > 0 {
+                                next_state
+                                    .cancelled
+                                    .lock()
+                                    .unwrap_or_else(PoisonError::into_inner)
+                                    .push(subscription_id);
+                            }
+                        }
+                    }),
+                    thread_id,
+                    metrics: metrics.clone(),
+                    options,
+                    stream: stream.clone(),
+                    decode: decode.map(|mut decode| {
+                        let decode_state = state.clone();
+                        Box::new(move |value: Pin<&mut ffi::ResponseValue>| {
+                            decode_state.catch_panic(|| decode(value));
+                        }) as Box<dyn FnMut(Pin<&mut ffi::ResponseValue>)>
+                    }),
+                });
+                let complete_registered = registered.clone();
+                let complete_subscriptions = subscriptions.clone();
+                let complete_metrics = metrics.clone();
+                let complete_state = state.clone();
+                let complete_context = Box::new(CompleteContext {
+                    callback: Box::new(move || {
+                        complete_metrics.subscriptions_completed.increment();
+                        let subscription_id = complete_registered.swap(-1, Ordering::AcqRel);
+                        if subscription_id > 0 {
+                            complete_subscriptions
+                                .lock()
+                                .unwrap_or_else(PoisonError::into_inner)
+                                .remove(&subscription_id);
+                        }
+                        complete_state.catch_panic(complete);
+                    }),
+                    thread_id,
+                    metrics: metrics.clone(),
+                });
+                let subscription_id = bindings.subscribe(
+                    query_id,
+                    &operation_name,
+                    &variables,
+                    next_context,
+                    // The kicks only make the message loop notice a cancelled subscription sooner,
+                    // and the queue may be full while it is flooded with notifications, so failing
+                    // to post one must not unwind into the Bindings.
+                    |mut context, payload| {
+                        (context.callback)(payload);
+                        let _ = Self::kick_pump(context.thread_id, &context.metrics);
+                        context
+                    },
+                    complete_context,
+                    |context| {
+                        (context.callback)();
+                        let _ = Self::kick_pump(context.thread_id, &context.metrics);
+                    },
+                );
+                if let Ok(subscription_id) = subscription_id {
+                    metrics.subscriptions_started.increment();
+
+                    // Queries call complete before subscribe returns, so they are never registered.
+                    if registered
+                        .compare_exchange(0, subscription_id, Ordering::AcqRel, Ordering::Acquire)
+                        .is_ok()
+                    {
+                        subscriptions
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .insert(
+                                subscription_id,
+                                TrackedSubscription {
+                                    info: SubscriptionInfo {
+                                        id: subscription_id,
+                                        operation_name,
+                                        created: SystemTime::now(),
+                                    },
+                                    counters,
+                                },
+                            );
+
+                        // The receiver was dropped before it was registered, and subscribe has
+                        // already returned, so it is safe to unsubscribe right away.
+                        if dropped.load(Ordering::Acquire) {
+                            bindings.unsubscribe(subscription_id);
                         }
                     }
-                }"#,
-            )
-            .expect("parses the introspection query");
-        assert_ne!(0, query.1, "query ID is not 0");
+                }
+                // Anything which did not deliver its payload before subscribe returned never will,
+                // so end the stream instead of leaving the reader waiting.
+                if let Some(stream) = stream {
+                    stream.lock().unwrap_or_else(PoisonError::into_inner).take();
+                }
+                // The worker loop stops the service after a callback panicked, so report that to
+                // the caller instead of a subscription which is about to be removed.
+                let subscription_id = match state.panic_error() {
+                    Some(err) => Err(err),
+                    None => subscription_id.map_err(ServiceError::Other),
+                };
+                // If listen timed out before the reply, nothing will ever unsubscribe it.
+                if let Err(mpsc::SendError(Ok(subscription_id))) = tx_result.send(subscription_id) {
+                    bindings.unsubscribe(subscription_id);
+                }
+            }
+            ServiceCommand::Unsubscribe { subscription_id } => {
+                bindings.unsubscribe(subscription_id)
+            }
+            #[cfg(feature = "mock")]
+            ServiceCommand::PushPayload {
+                subscription_id,
+                payload,
+                tx_result,
+            } => tx_result
+                .send(bindings.push_payload(subscription_id, payload))
+                .map_err(map_send_error)?,
+            #[cfg(test)]
+            ServiceCommand::Fail(message) => return Err(message),
+            #[cfg(test)]
+            ServiceCommand::Block(rx_release) => {
+                let _ = rx_release.recv();
+            }
+        }
+
+        Ok(true)
+    }
 
-        let subscription = gqlmapi.subscribe(query, "", "");
-        let mut locked_subscription = subscription
+    /// Send a [ServiceCommand] to the worker thread and kick the message pump.
+    ///
+    /// The lock only guards the [mpsc::Sender], which is still valid if another thread panicked
+    /// while holding it, so this recovers from a poisoned lock instead of failing.
+    fn send(&self, command: ServiceCommand) -> Result<(), ServiceError> {
+        // Count the command before sending it, so the worker never receives it first.
+        self.metrics.commands_sent.fetch_add(1, Ordering::Relaxed);
+        self.sender
             .lock()
-            .expect("should lock the mut subscription");
-        let (tx_next, rx_next) = mpsc::channel();
-        let (tx_complete, rx_complete) = mpsc::channel();
-        locked_subscription
-            .listen(tx_next, tx_complete)
-            .expect("subscribes to the query");
-        assert_ne!(
-            locked_subscription.subscription_id, 0,
-            "subscription ID is not 0"
-        );
-        let results = rx_next.recv().expect("should always receive a payload");
-        let results = serde_json::from_str::<IntrospectionResults>(&results)
-            .expect("payload should fit query");
-        rx_complete.recv().expect("should always call complete");
+            .unwrap_or_else(PoisonError::into_inner)
+            .send(command)
+            .map_err(|_| {
+                self.metrics.commands_sent.fetch_sub(1, Ordering::Relaxed);
+                self.worker_gone()
+            })?;
+        self.kick()
+    }
 
-        let expected = IntrospectionResults {
-            data: Data {
-                __schema: Schema {
-                    queryType: OperationType {
-                        name: "Query".into(),
-                    },
-                    mutationType: OperationType {
-                        name: "Mutation".into(),
-                    },
-                    subscriptionType: OperationType {
-                        name: "Subscription".into(),
-                    },
-                    types: vec![
-                        TypeKind {
-                            kind: "SCALAR".into(),
-                            name: "Boolean".into(),
-                        },
-                        TypeKind {
-                            kind: "SCALAR".into(),
-                            name: "Float".into(),
-                        },
-                        TypeKind {
-                            kind: "SCALAR".into(),
-                            name: "ID".into(),
-                        },
-                        TypeKind {
-                            kind: "SCALAR".into(),
-                            name: "Int".into(),
-                        },
-                        TypeKind {
-                            kind: "SCALAR".into(),
-                            name: "String".into(),
-                        },
-                        TypeKind {
-                            kind: "ENUM".into(),
-                            name: "__TypeKind".into(),
-                        },
-                        TypeKind {
-                            kind: "ENUM".into(),
-                            name: "__DirectiveLocation".into(),
-                        },
-                        TypeKind {
-                            kind: "OBJECT".into(),
-                            name: "__Schema".into(),
-                        },
-                        TypeKind {
-                            kind: "OBJECT".into(),
-                            name: "__Type".into(),
-                        },
-                        TypeKind {
-                            kind: "OBJECT".into(),
-                            name: "__Field".into(),
-                        },
-                        TypeKind {
-                            kind: "OBJECT".into(),
-                            name: "__InputValue".into(),
-                        },
-                        TypeKind {
-                            kind: "OBJECT".into(),
-                            name: "__EnumValue".into(),
-                        },
-                        TypeKind {
-                            kind: "OBJECT".into(),
-                            name: "__Directive".into(),
-                        },
-                        TypeKind {
-                            kind: "SCALAR".into(),
-                            name: "DateTime".into(),
-                        },
-                        TypeKind {
-                            kind: "SCALAR".into(),
-                            name: "Guid".into(),
-                        },
-                        TypeKind {
-                            kind: "SCALAR".into(),
-                            name: "Stream".into(),
-                        },
-                        TypeKind {
-                            kind: "ENUM".into(),
-                            name: "SpecialFolder".into(),
-                        },
-                        TypeKind {
-                            kind: "ENUM".into(),
-                            name: "PropType".into(),
-                        },
-                        TypeKind {
-                            kind: "INPUT_OBJECT".into(),
+    /// Send a [ServiceCommand] which releases something on the worker thread. Once the worker
+    /// thread has exited, stopping the service already released everything, so it is not an
+    /// error if the command cannot be sent.
+    fn send_cleanup(&self, command: ServiceCommand) -> Result<(), ServiceError> {
+        match self.send(command) {
+            Err(_)
+                if self
+                    .exit_status
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .is_some() =>
+            {
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Remove a consumer from a shared subscription. If it was the last consumer, remove the
+    /// shared subscription and return the id which should be unsubscribed on the worker thread.
+    ///
+    /// An earlier consumer which leaves is sent its own `complete`, since the underlying
+    /// subscription only calls `complete` once it is removed.
+    fn release_shared(&self, key: &SharedKey, consumer_id: u64) -> Option<i32> {
+        let mut sharing = self.sharing.lock().unwrap_or_else(PoisonError::into_inner);
+        let subscriptions = &mut sharing.as_mut()?.subscriptions;
+        let shared = subscriptions.get(key)?;
+        let subscription_id = shared.subscription_id;
+        {
+            let mut consumers = shared
+                .consumers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let index = consumers
+                .consumers
+                .iter()
+                .position(|consumer| consumer.id == consumer_id)?;
+
+            if consumers.consumers.len() > 1 {
+                let consumer = consumers.consumers.remove(index);
+                (consumer.complete)();
+                return None;
+            }
+        }
+
+        subscriptions.remove(key);
+        Some(subscription_id)
+    }
+
+    /// Wait for the worker thread to reply to a [ServiceCommand].
+    fn recv<T>(&self, rx: mpsc::Receiver<T>) -> Result<T, ServiceError> {
+        rx.recv().map_err(|_| self.worker_gone())
+    }
+
+    /// Wait for the worker thread to reply to a [ServiceCommand], but only up to `timeout`. The
+    /// worker thread must handle the reply [mpsc::Receiver] dropping after a timeout.
+    fn recv_timeout<T>(&self, rx: mpsc::Receiver<T>, timeout: Duration) -> Result<T, ServiceError> {
+        rx.recv_timeout(timeout).map_err(|err| match err {
+            mpsc::RecvTimeoutError::Timeout => ServiceError::Timeout(format!(
+                "the worker thread did not reply within {timeout:?}"
+            )),
+            mpsc::RecvTimeoutError::Disconnected => self.worker_gone(),
+        })
+    }
+
+    /// Describe why the worker thread is no longer handling commands, using the exit status it
+    /// recorded before it stopped listening to the command channel.
+    fn worker_gone(&self) -> ServiceError {
+        ServiceError::WorkerGone(self.worker_gone_reason())
+    }
+
+    fn worker_gone_reason(&self) -> String {
+        match self
+            .exit_status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+        {
+            Some(Ok(())) => String::from("the worker thread was stopped"),
+            Some(Err(err)) => format!("the worker thread failed: {err}"),
+            None => String::from("the worker thread exited unexpectedly"),
+        }
+    }
+
+    /// Wake the worker thread after sending a [ServiceCommand], by signaling its [WakeEvent], or
+    /// with [attach](MAPIGraphQL::attach), by posting a `WM_APP` message to the attached thread.
+    ///
+    /// Kicks are coalesced: if a kick is already pending, the worker thread has not drained the
+    /// command channel since it was sent, so it will pick up this command in the same batch and
+    /// there is no need to send another one. A burst of 16 `parse_query` calls while the worker
+    /// thread is busy sends at most 1 kick instead of 16, and none at all if the kick which woke
+    /// it for the command it is busy with is still pending.
+    ///
+    /// If the attached thread already exited, posting the message fails with
+    /// `ERROR_INVALID_THREAD_ID` and nothing will ever handle the command, so report that as a
+    /// [WorkerGone](ServiceError::WorkerGone) error instead of letting the caller wait for a
+    /// reply.
+    fn kick(&self) -> Result<(), ServiceError> {
+        if self.kick.pending.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        match &self.kick.event {
+            Some(event) => {
+                self.metrics.pump_kicks.increment();
+                event.set().map_err(|err| {
+                    ServiceError::WorkerGone(format!(
+                        "{} (SetEvent: {err})",
+                        self.worker_gone_reason()
+                    ))
+                })
+            }
+            None => Self::kick_pump(self.thread_id, &self.metrics).map_err(|err| {
+                ServiceError::WorkerGone(format!(
+                    "{} (PostThreadMessageW: {err})",
+                    self.worker_gone_reason()
+                ))
+            }),
+        }
+    }
+
+    fn kick_pump(thread_id: u32, metrics: &Metrics) -> windows::core::Result<()> {
+        metrics.pump_kicks.increment();
+        unsafe { PostThreadMessageW(thread_id, WM_APP, WPARAM::default(), LPARAM::default()) }
+    }
+
+    fn ensure_message_queue() {
+        let mut msg = MSG::default();
+        let hwnd = HWND::default();
+        unsafe { PeekMessageW(&mut msg, hwnd, WM_USER, WM_USER, PM_NOREMOVE) };
+    }
+
+    fn dispatch_pending_messages() {
+        let mut msg = MSG::default();
+        let hwnd = HWND::default();
+
+        unsafe {
+            while PeekMessageW(&mut msg, hwnd, 0, 0, PM_REMOVE).as_bool() {
+                TranslateMessage(&msg);
+                DispatchMessageW(&msg);
+            }
+        }
+    }
+
+    /// Pump messages until there are commands waiting, then return all of them as one batch.
+    ///
+    /// This waits on the [WakeEvent] signaled by [kick](Service::kick) and on the message queue
+    /// at the same time, so a flood of `MAPI` notifications cannot delay a command, and commands
+    /// do not depend on a posted message reaching the queue.
+    fn wait_with_pump<T>(
+        rx: &mpsc::Receiver<T>,
+        kick: &Kick,
+        state: &WorkerState,
+    ) -> Result<Vec<T>, String> {
+        let mut msg = MSG::default();
+        let hwnd = HWND::default();
+        let events = kick.event.as_ref().map(|event| [event.0]);
+
+        loop {
+            // Clear the pending kick before draining, so any command sent after this sends a new
+            // kick, and any command sent before it is already in the batch.
+            kick.pending.swap(false, Ordering::AcqRel);
+            let batch: Vec<T> = rx.try_iter().collect();
+            if !batch.is_empty() || state.is_pending() {
+                return Ok(batch);
+            }
+
+            let wait = unsafe {
+                MsgWaitForMultipleObjectsEx(
+                    events.as_ref().map(|events| &events[..]),
+                    INFINITE,
+                    QS_ALLINPUT,
+                    MWMO_INPUTAVAILABLE,
+                )
+            };
+            if wait == WAIT_FAILED {
+                return Err(format!(
+                    "MsgWaitForMultipleObjectsEx error: {}",
+                    windows::core::Error::from_win32().code().0
+                ));
+            }
+
+            // Dispatch the queue in bounded batches, and stop as soon as a command is kicked, so a
+            // sustained flood of messages cannot keep the commands waiting. Anything left in the
+            // queue makes the next wait return right away, because of MWMO_INPUTAVAILABLE.
+            for _ in 0..MAX_MESSAGES_PER_WAKE {
+                if kick.pending.load(Ordering::Acquire)
+                    || !unsafe { PeekMessageW(&mut msg, hwnd, 0, 0, PM_REMOVE) }.as_bool()
+                {
+                    break;
+                }
+                if msg.message == WM_QUIT {
+                    return Err(String::from("Cancelled"));
+                }
+                unsafe {
+                    TranslateMessage(&msg);
+                    DispatchMessageW(&msg);
+                }
+            }
+        }
+    }
+
+    /// Send the [Stop](ServiceCommand::Stop) command at most once, and return whether it reached
+    /// the worker thread before it exited.
+    fn request_stop(&self) -> bool {
+        *self
+            .stop_sent
+            .get_or_init(|| self.send(ServiceCommand::Stop).is_ok())
+    }
+
+    fn stop(&mut self) -> Result<(), String> {
+        // If the worker thread already exited, callers were told why with a WorkerGone error.
+        let running = self.request_stop();
+
+        // A callback on the worker thread may drop the last reference, and the worker thread
+        // cannot join itself. Leave it detached, and it exits once it handles the Stop command.
+        if self.thread_id == unsafe { GetCurrentThreadId() } {
+            return Ok(());
+        }
+
+        if let Some(worker) = self.worker.take() {
+            let result = worker.join();
+
+            if running {
+                result.map_err(|_| String::from("Error joining the worker"))??;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Service {
+    /// Shutdown the [GraphQL](https://graphql.org) service and log off from the `MAPI` session.
+    fn drop(&mut self) {
+        self.stop().expect("Unable to stop the service");
+    }
+}
+
+/// Handle commands for a [MAPIGraphQL] created with [attach](MAPIGraphQL::attach) on the thread
+/// which owns the `MAPI` session, from a message loop the caller already runs.
+///
+/// Every command sent to the service posts a `WM_APP` thread message (with no `HWND`) to the
+/// attached thread, and so do the `next` and `complete` callbacks. Call
+/// [pump_once](ServicePump::pump_once) whenever the message loop retrieves one of those messages,
+/// or after each iteration of the loop. Nothing is handled between calls, so any thread waiting on
+/// a reply from the service will block until the attached thread pumps again.
+///
+/// [ServicePump] is neither `Send` nor `Sync`: the `MAPI` objects may only be used on the thread
+/// which created them.
+pub struct ServicePump {
+    bindings: cxx::UniquePtr<ffi::Bindings>,
+    rx_command: mpsc::Receiver<ServiceCommand>,
+    thread_id: u32,
+    metrics: Arc<Metrics>,
+    kick: Arc<Kick>,
+    subscriptions: SubscriptionRegistry,
+    state: Arc<WorkerState>,
+    exit_status: Arc<Mutex<Option<Result<(), String>>>>,
+    running: bool,
+}
+
+impl ServicePump {
+    /// Handle every command which is already waiting, without blocking.
+    ///
+    /// Returns `Ok(true)` while the service is still running, or `Ok(false)` once it has been
+    /// stopped because the last reference to the [MAPIGraphQL] was dropped. If a command fails,
+    /// the service stops and the error is returned here as well as from the next method which
+    /// sends a command to it.
+    pub fn pump_once(&mut self) -> Result<bool, ServiceError> {
+        if !self.running {
+            return Ok(false);
+        }
+
+        // Every command sent after this posts a new kick, so the message loop calls us again.
+        self.kick.pending.swap(false, Ordering::AcqRel);
+        Service::unsubscribe_cancelled(&self.bindings, &self.state);
+        let result = loop {
+            match self.rx_command.try_recv() {
+                Ok(command) => match Service::handle_command_or_panic(
+                    &self.bindings,
+                    command,
+                    self.thread_id,
+                    &self.metrics,
+                    &self.subscriptions,
+                    &self.state,
+                ) {
+                    Ok(true) => (),
+                    Ok(false) => break Ok(()),
+                    Err(err) => break Err(err),
+                },
+                Err(mpsc::TryRecvError::Empty) => return Ok(true),
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    break self.bindings.stop_service().map_err(ServiceError::Other);
+                }
+            }
+        };
+
+        self.running = false;
+        *self
+            .exit_status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(match &result {
+            Ok(()) => Ok(()),
+            Err(err) => Err(err.to_string()),
+        });
+        result.map(|()| false)
+    }
+}
+
+impl Drop for ServicePump {
+    /// Log off from the `MAPI` session if the service is still running.
+    fn drop(&mut self) {
+        if self.running {
+            let result = self.bindings.stop_service();
+            *self
+                .exit_status
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Some(result);
+        }
+    }
+}
+
+/// Reply to [parse_query_async](MAPIGraphQL::parse_query_async) which discards the `query_id` if
+/// the future is dropped after the worker thread replied, but before it was polled again. If it
+/// is dropped before the reply, the worker thread discards the `query_id` itself.
+struct PendingParse {
+    service: Arc<Service>,
+    rx: oneshot::Receiver<Result<i32, String>>,
+}
+
+impl Drop for PendingParse {
+    fn drop(&mut self) {
+        if let Some(Ok(query_id)) = self.rx.close() {
+            let _ = self
+                .service
+                .send_cleanup(ServiceCommand::DiscardQuery { query_id });
+        }
+    }
+}
+
+/// Least-recently-used cache of [ParsedQuery] results, keyed by a hash of the request document.
+///
+/// The cache is owned by [MAPIGraphQL] and shared by its clones rather than owned by [Service],
+/// since each [ParsedQuery] holds a reference to the [Service] and would otherwise keep it alive
+/// forever.
+#[derive(Default)]
+struct QueryCache {
+    capacity: usize,
+    entries: VecDeque<CachedQuery>,
+}
+
+/// The request document is not copied, since the [ParsedQuery] already holds its
+/// [source](ParsedQuery::source).
+struct CachedQuery {
+    hash: u64,
+    parsed: Arc<ParsedQuery>,
+}
+
+impl CachedQuery {
+    fn matches(&self, hash: u64, query: &str) -> bool {
+        self.hash == hash && QueryCache::normalize(self.parsed.source()) == query
+    }
+}
+
+impl QueryCache {
+    fn normalize(query: &str) -> &str {
+        query.trim()
+    }
+
+    fn hash(query: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        query.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn get(&mut self, query: &str) -> Option<Arc<ParsedQuery>> {
+        let query = Self::normalize(query);
+        let hash = Self::hash(query);
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.matches(hash, query))?;
+        let entry = self.entries.remove(index)?;
+        let parsed = entry.parsed.clone();
+        self.entries.push_back(entry);
+        Some(parsed)
+    }
+
+    fn insert(&mut self, parsed: Arc<ParsedQuery>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let query = Self::normalize(parsed.source());
+        let hash = Self::hash(query);
+        self.entries.retain(|entry| !entry.matches(hash, query));
+        self.entries.push_back(CachedQuery { hash, parsed });
+        self.evict();
+    }
+
+    /// Dropping the evicted [Arc<ParsedQuery>](ParsedQuery) only discards the query once every
+    /// other reference to it has also been dropped.
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+    }
+}
+
+/// [ParsedQuery] results registered with [register_query](MAPIGraphQL::register_query), keyed by
+/// the id supplied by the client. Like the [QueryCache], this is owned by [MAPIGraphQL] rather
+/// than [Service], so it does not keep the [Service] alive.
+type PersistedQueries = Arc<Mutex<HashMap<String, Arc<ParsedQuery>>>>;
+
+/// How long [health_check](MAPIGraphQL::health_check) waits for each reply from the worker thread.
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Rust-friendly bindings to [gqlmapi](https://github.com/microsoft/gqlmapi).
+///
+/// # Threading
+///
+/// All of the `MAPI` and [GraphQL](https://graphql.org) work happens on a dedicated worker
+/// thread, and every public method just sends a command to that thread and waits for the reply.
+/// Use [attach](MAPIGraphQL::attach) instead of [new](MAPIGraphQL::new) to run that work on a
+/// thread the application already owns.
+/// [MAPIGraphQL], [ParsedQuery], and [Subscription] are all `Send + Sync`, so they may be used
+/// from any thread:
+///
+/// - Share a [MAPIGraphQL] between threads by reference, or [clone](MAPIGraphQL::clone) it.
+/// - [ParsedQuery] is always returned in an [Arc], which can be cloned and passed to
+///   [subscribe](MAPIGraphQL::subscribe) from any thread.
+/// - [listen](Subscription::listen) takes `&mut self`, so a [Subscription] needs to be wrapped in
+///   a [Mutex] if more than one thread should be able to call it.
+///
+/// The `next` and `complete` callbacks only ever run on the worker thread, and they forward the
+/// payloads through the [PayloadSink] and [mpsc::Sender] passed to [listen](Subscription::listen).
+///
+/// # Drop order
+///
+/// Every [ParsedQuery], and so every [Subscription] and [PreparedOperation], holds a reference to
+/// the service, so the [MAPIGraphQL] may be dropped in any order relative to them. The worker
+/// thread keeps running until the last of them drops, so a [Subscription] which outlives the
+/// [MAPIGraphQL] keeps delivering payloads, and dropping it still unsubscribes it on the worker
+/// thread before the service stops. Use [shutdown](MAPIGraphQL::shutdown) to stop the service
+/// without waiting for them, which unsubscribes everything on the worker thread itself and
+/// turns their cleanup into a no-op.
+///
+/// # Cloning
+///
+/// Cloning a [MAPIGraphQL] is cheap, since it only clones a reference to the service. All of the
+/// clones share the same `MAPI` session, worker thread, and query cache, and the session is only
+/// torn down once the last clone, and the last [ParsedQuery] which outlives them, drops. Calling
+/// [shutdown](MAPIGraphQL::shutdown) on any clone stops the service for all of them, after
+/// which the rest fail with a [ServiceError::WorkerGone].
+#[derive(Clone)]
+pub struct MAPIGraphQL(Arc<Service>, Arc<Mutex<QueryCache>>, PersistedQueries);
+
+impl fmt::Debug for MAPIGraphQL {
+    /// Print the worker thread id and, with the `metrics` feature, the current `MetricsSnapshot`,
+    /// which only loads atomic counters, so formatting never waits on a lock.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("MAPIGraphQL");
+        debug.field("thread_id", &self.0.thread_id);
+        #[cfg(feature = "metrics")]
+        debug.field("metrics", &self.0.metrics.snapshot());
+        debug.finish_non_exhaustive()
+    }
+}
+
+impl MAPIGraphQL {
+    /// Start the [GraphQL](https://graphql.org) service and log on to the `MAPI` session.
+    pub fn new(use_default_profile: bool) -> Self {
+        Self::builder().default_profile(use_default_profile).start()
+    }
+
+    /// Configure the service with a [MAPIGraphQLBuilder], which combines every option of the
+    /// other constructors, and then start it with [build](MAPIGraphQLBuilder::build).
+    pub fn builder() -> MAPIGraphQLBuilder {
+        MAPIGraphQLBuilder::default()
+    }
+
+    /// Start the [GraphQL](https://graphql.org) service like [new](MAPIGraphQL::new), but if the
+    /// `MAPI` logon fails, retry up to `retries` more times, waiting `backoff` before the first
+    /// retry and doubling it each time.
+    ///
+    /// If every attempt fails, the error is returned from the next method which needs the worker
+    /// thread, and it lists the error from each attempt in order.
+    pub fn new_with_retry(use_default_profile: bool, retries: u32, backoff: Duration) -> Self {
+        Self::builder()
+            .default_profile(use_default_profile)
+            .retry(retries, backoff)
+            .start()
+    }
+
+    /// Start the [GraphQL](https://graphql.org) service with every [ServiceOptions] setting,
+    /// including the [ComApartment] for the worker thread.
+    ///
+    /// # COM apartment
+    ///
+    /// `MAPI` is a COM API, so the worker thread calls `CoInitializeEx` before logging on to the
+    /// `MAPI` session, and `CoUninitialize` after logging off when the service stops. The default
+    /// [SingleThreaded](ComApartment::SingleThreaded) apartment matches the message loop on the
+    /// worker thread. If COM cannot be initialized in the requested apartment, the error is
+    /// returned from the next method which needs the worker thread.
+    pub fn new_with_options(options: ServiceOptions) -> Self {
+        Self(Service::new(options), Arc::default(), Arc::default())
+    }
+
+    /// Start the [GraphQL](https://graphql.org) service and log on to the `MAPI` session on the
+    /// calling thread, instead of spawning a dedicated worker thread.
+    ///
+    /// Use this when the application already has a thread with a message loop which should own
+    /// the `MAPI` session, e.g. a UI thread. The returned [ServicePump] must stay on this thread,
+    /// and the message loop must call [pump_once](ServicePump::pump_once) to handle requests from
+    /// the [MAPIGraphQL], which may still be used from any thread.
+    ///
+    /// # COM apartment
+    ///
+    /// Unlike [new_with_options](MAPIGraphQL::new_with_options), this does not initialize COM,
+    /// since the calling thread owns its apartment. `MAPI` notifications are delivered through
+    /// window messages on the thread which logged on, so if the calling thread has initialized
+    /// COM, it must be a single-threaded apartment (STA) and keep pumping messages for as long as
+    /// the service is running. Calling a blocking method like
+    /// [parse_query](MAPIGraphQL::parse_query) from the attached thread itself will deadlock,
+    /// since nothing can handle the command until it returns to the message loop.
+    pub fn attach(use_default_profile: bool) -> Result<(Self, ServicePump), ServiceError> {
+        let (service, pump) = Service::attach(use_default_profile)?;
+        Ok((Self(service, Arc::default(), Arc::default()), pump))
+    }
+
+    /// Start a fake service which does not log on to `MAPI` at all, so code built on this crate
+    /// can be tested without Outlook or a configured profile. Enable the `mock` feature to use it.
+    ///
+    /// The fake runs on a worker thread like the real service, but it does not parse anything
+    /// against the `MAPI` schema. `{ __typename }` returns `{"data":{"__typename":"Query"}}`, and
+    /// every other `Query` or `Mutation` returns a payload with a single error. Use
+    /// [new_mock_with_responses](MAPIGraphQL::new_mock_with_responses) for anything else.
+    #[cfg(feature = "mock")]
+    pub fn new_mock() -> Self {
+        Self::new_mock_with_responses(HashMap::new())
+    }
+
+    /// Start a fake service like [new_mock](MAPIGraphQL::new_mock), which returns the canned
+    /// JSON payload from `responses` for each request document, matched after trimming any
+    /// leading and trailing whitespace.
+    ///
+    /// A `Subscription` operation only delivers the payloads passed to
+    /// [push_mock_payload](MAPIGraphQL::push_mock_payload), and calls `complete` once it is
+    /// unsubscribed. The payloads are delivered as they are, so
+    /// [with_tagged_scalars](MAPIGraphQL::with_tagged_scalars) and
+    /// [listen_chunked](Subscription::listen_chunked) do not change them.
+    #[cfg(feature = "mock")]
+    pub fn new_mock_with_responses(responses: HashMap<String, String>) -> Self {
+        let service = Service::spawn(ServiceOptions::default(), move || {
+            Box::new(mock::MockBackend::new(responses))
+        });
+        Self(service, Arc::default(), Arc::default())
+    }
+
+    /// Deliver `payload` to the `next` callback of an active subscription on a
+    /// [new_mock](MAPIGraphQL::new_mock) service, like a `MAPI` notification would, e.g. to test
+    /// how a consumer handles each payload, or what happens when it stops listening. The
+    /// `subscription_id` is the [id](SubscriptionInfo::id) from
+    /// [active_subscriptions](MAPIGraphQL::active_subscriptions).
+    ///
+    /// It returns once the worker thread has delivered the payload, or an [Err(ServiceError)](Err)
+    /// if the subscription is not active, or if this is not a mock service.
+    #[cfg(feature = "mock")]
+    pub fn push_mock_payload(
+        &self,
+        subscription_id: i32,
+        payload: &str,
+    ) -> Result<(), ServiceError> {
+        let (tx, rx) = mpsc::channel();
+        self.0.send(ServiceCommand::PushPayload {
+            subscription_id,
+            payload: payload.to_owned(),
+            tx_result: tx,
+        })?;
+        Ok(self.0.recv(rx)??)
+    }
+
+    /// List the `MAPI` profiles on this machine without logging on to any of them, e.g. to let
+    /// the user pick one instead of relying on the interactive profile prompt.
+    ///
+    /// This initializes `MAPI` on a short-lived thread of its own, so it may be called before
+    /// starting the service, and from any thread regardless of its COM apartment.
+    pub fn list_profiles() -> Result<Vec<Profile>, ServiceError> {
+        let profiles = thread::spawn(|| ffi::list_profiles().map_err(map_exception))
+            .join()
+            .map_err(|_| String::from("Error joining the profile thread"))??;
+        Ok(profiles.into_iter().map(Profile::from).collect())
+    }
+
+    /// Describe the native libraries this crate was built against, e.g. for bug reports:
+    ///
+    /// `gqlmapi <git describe>, graphqlservice <version>, vcpkg <triplet>`
+    ///
+    /// Each version is `unknown` if the build could not determine it, and the triplet is
+    /// `system` with the `system-gqlmapi` feature.
+    pub fn backend_version() -> &'static str {
+        env!("GQLMAPI_BACKEND_VERSION")
+    }
+
+    /// Stop the [GraphQL](https://graphql.org) service and log off from the `MAPI` session
+    /// without waiting for every [ParsedQuery] and [Subscription] to drop.
+    ///
+    /// Commands which were already sent are handled first, and any notifications which are
+    /// already queued are delivered. Then every active subscription is unsubscribed, which calls
+    /// its `complete` callback, so consumers waiting on the `complete` channel see a clean
+    /// shutdown instead of a disconnected channel. After this returns, any remaining
+    /// [ParsedQuery] or [Subscription] fails with a [ServiceError::WorkerGone], and dropping them
+    /// is a no-op.
+    pub fn shutdown(self) -> Result<(), ServiceError> {
+        let (tx, rx) = mpsc::channel();
+        self.0.send(ServiceCommand::Shutdown { tx_result: tx })?;
+        Ok(self.0.recv(rx)??)
+    }
+
+    /// Ask the worker thread to stop the [GraphQL](https://graphql.org) service and log off from
+    /// the `MAPI` session, without waiting for it, e.g. from a Ctrl+C handler or another thread.
+    ///
+    /// Unlike [shutdown](MAPIGraphQL::shutdown), this only takes `&self` and never blocks on the
+    /// worker thread, and it does not deliver notifications which are still queued. Commands
+    /// which were already sent are handled first, and everything sent afterwards fails with a
+    /// [ServiceError::WorkerGone]. The worker thread is still joined when the last reference drops.
+    /// Calling this more than once, or dropping the [MAPIGraphQL] afterwards, is safe.
+    pub fn request_stop(&self) {
+        self.0.request_stop();
+    }
+
+    /// Unsubscribe every active subscription and discard every parsed request document, but keep
+    /// the worker thread and the `MAPI` session running, e.g. to recover from an error without
+    /// logging on again.
+    ///
+    /// Each active subscription calls its `complete` callback, and the query cache and the queries
+    /// registered with [register_query](MAPIGraphQL::register_query) are cleared.
+    /// Any [ParsedQuery] or [Subscription] which is still held afterwards refers to a discarded
+    /// request document, so using it returns an error, but dropping it is still safe, since the
+    /// worker thread never reuses their ids. Parse the request documents again to keep going.
+    pub fn reset(&self) -> Result<(), ServiceError> {
+        let cached = std::mem::take(
+            &mut self
+                .1
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entries,
+        );
+        drop(cached);
+        let persisted = std::mem::take(&mut *self.2.lock().unwrap_or_else(PoisonError::into_inner));
+        drop(persisted);
+
+        let (tx, rx) = mpsc::channel();
+        self.0.send(ServiceCommand::Reset { tx_result: tx })?;
+        Ok(self.0.recv(rx)??)
+    }
+
+    /// Opt in to sharing one underlying subscription between every [Subscription] which calls
+    /// [listen](Subscription::listen) with the same [ParsedQuery], operation name, and JSON
+    /// `variables` string, e.g. for folder change subscriptions watched by many UI components.
+    ///
+    /// Each `next` payload is fanned out to all of the consumer channels. A consumer which joins
+    /// an existing subscription only receives the payloads delivered after it joined. The
+    /// underlying subscription is removed when the last consumer is unsubscribed or dropped, and
+    /// each consumer still gets its own call to `complete`.
+    pub fn with_shared_subscriptions(self) -> Self {
+        self.0
+            .sharing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_or_insert_with(SubscriptionSharing::default);
+        self
+    }
+
+    /// Opt in to caching up to `capacity` results from [parse_query](MAPIGraphQL::parse_query).
+    /// Parsing the same request document again returns the shared [ParsedQuery] from the cache
+    /// instead of making another round-trip to the worker thread.
+    ///
+    /// A `capacity` of 0 disables the cache, which is the default.
+    pub fn with_query_cache(self, capacity: usize) -> Self {
+        {
+            let mut cache = self.1.lock().unwrap_or_else(PoisonError::into_inner);
+            cache.capacity = capacity;
+            cache.evict();
+        }
+        self
+    }
+
+    /// Opt in to wrapping custom scalar values (e.g. `DateTime`, `Guid`, or `Stream`) in an object
+    /// with a single [SCALAR_TAG] member in the payloads for subsequent calls to
+    /// [listen](Subscription::listen), e.g. `{ "__scalar": "..." }`, so they can be decoded
+    /// differently than plain JSON values. This is disabled by default.
+    ///
+    /// The tag does not name the scalar type. gqlmapi resolves every custom scalar to a
+    /// `graphql::response::Value` of type `Scalar` wrapping the plain value, and the type name
+    /// only exists in the schema, so neither the `Bindings` nor the conversion ever see it. Use
+    /// the type of the selected field, e.g. from [schema_sdl](MAPIGraphQL::schema_sdl) or the
+    /// `schema-types` structs, to decide whether a tagged value is a `Guid` or a `DateTime`.
+    pub fn with_tagged_scalars(self) -> Self {
+        self.0
+            .conversion
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .tag_scalars = true;
+        self
+    }
+
+    /// Parse a [GraphQL](https://graphql.org) request document and return a [ParsedQuery] that can
+    /// be used to represent the request in 1 or more calls to [subscribe](MAPIGraphQL::subscribe).
+    ///
+    /// Any number of those subscriptions may be listening at the same time. Each gets its own
+    /// subscription id on the worker thread and its own `next` and `complete` channels, and the
+    /// request document stays parsed until the last of them drops, as described in
+    /// [Sharing](ParsedQuery#sharing).
+    ///
+    /// If the request document cannot be parsed, it will return an [Err(ParseError)](Err) with
+    /// the line and column of the syntax error, if the parser reported one.
+    pub fn parse_query(&self, query: &str) -> Result<Arc<ParsedQuery>, ParseError> {
+        if let Some(parsed) = self
+            .1
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(query)
+        {
+            return Ok(parsed);
+        }
+
+        let parsed = self.parse_uncached(query)?;
+        self.1
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(parsed.clone());
+        Ok(parsed)
+    }
+
+    /// Parse a [GraphQL](https://graphql.org) request document like
+    /// [parse_query](MAPIGraphQL::parse_query), but return a [Future](std::future::Future) which
+    /// completes when the worker thread replies, instead of blocking the calling thread. This
+    /// works with any async runtime, since the worker thread wakes the task directly.
+    pub async fn parse_query_async(&self, query: &str) -> Result<Arc<ParsedQuery>, ParseError> {
+        if let Some(parsed) = self
+            .1
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(query)
+        {
+            return Ok(parsed);
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.0.send(ServiceCommand::ParsedQueryAsync {
+            query: String::from(query),
+            tx_result: tx,
+        })?;
+        let mut pending = PendingParse {
+            service: self.0.clone(),
+            rx,
+        };
+        let result = (&mut pending.rx).await.map_err(|_| self.0.worker_gone())?;
+        let parsed = Arc::new(ParsedQuery(self.0.clone(), result?, String::from(query)));
+        self.1
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(parsed.clone());
+        Ok(parsed)
+    }
+
+    fn parse_uncached(&self, query: &str) -> Result<Arc<ParsedQuery>, ParseError> {
+        let (tx, rx) = mpsc::channel();
+        self.0.send(ServiceCommand::ParsedQuery {
+            query: String::from(query),
+            tx_result: tx,
+        })?;
+        let result = self.0.recv(rx)?;
+        Ok(Arc::new(ParsedQuery(
+            self.0.clone(),
+            result?,
+            String::from(query),
+        )))
+    }
+
+    /// Parse several [GraphQL](https://graphql.org) request documents in a single round-trip to the
+    /// worker thread, returning a [ParsedQuery] for each of them in the same order.
+    ///
+    /// If any of the request documents cannot be parsed, none of them are kept and it will return
+    /// the first [Err(ServiceError)](Err), which starts with the index of that document, e.g.
+    /// `Error parsing query 2: ...`.
+    pub fn parse_queries(&self, queries: &[&str]) -> Result<Vec<Arc<ParsedQuery>>, ServiceError> {
+        let (tx, rx) = mpsc::channel();
+        self.0.send(ServiceCommand::ParsedQueries {
+            queries: queries.iter().map(|query| String::from(*query)).collect(),
+            tx_result: tx,
+        })?;
+        let result = self.0.recv(rx)?;
+        Ok(result?
+            .into_iter()
+            .zip(queries)
+            .map(|(query_id, query)| {
+                Arc::new(ParsedQuery(self.0.clone(), query_id, String::from(*query)))
+            })
+            .collect())
+    }
+
+    /// Choose how `Int` and `Float` values are written to the payloads for subsequent calls to
+    /// [listen](Subscription::listen). The default is [NumberFormat::Native], which writes them
+    /// as JSON numbers. Values inside custom scalars are formatted the same way, while strings,
+    /// such as `DateTime` values, are left as they are.
+    pub fn set_number_format(&self, number_format: NumberFormat) {
+        self.0
+            .conversion
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .number_format = number_format;
+    }
+
+    /// Cap the size of the JSON for a single `next` payload in subsequent calls to
+    /// [listen](Subscription::listen), e.g. to keep an unexpectedly large mailbox dump from
+    /// exhausting memory. A limit of 0 removes the cap, which is the default.
+    ///
+    /// The payload is serialized into a buffer which stops growing at the limit. If it does not
+    /// fit, `next` receives a payload with `null` `data` and a single error whose message starts
+    /// with `PayloadTooLarge:` instead, and the subscription keeps running. With
+    /// [listen_chunked](Subscription::listen_chunked), the limit applies to each chunk. gqlmapi
+    /// still resolves the whole result before it is serialized, so select less to use less.
+    pub fn set_max_payload_bytes(&self, max_payload_bytes: usize) {
+        self.0
+            .conversion
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .max_payload_bytes = (max_payload_bytes > 0).then_some(max_payload_bytes);
+    }
+
+    /// Install a hook which is called on the worker thread with the raw JSON of every `next`
+    /// payload, before it is sent to the consumer, e.g. to capture unexpected data for debugging.
+    /// The `logger` should return quickly, since it delays the delivery of the payload, and it
+    /// should not panic, since that stops the service (see [listen](Subscription::listen)).
+    ///
+    /// Like [with_tagged_scalars](MAPIGraphQL::with_tagged_scalars), this applies to
+    /// subsequent calls to [listen](Subscription::listen). There is no logger by default, and
+    /// without one the `next` callbacks are not wrapped at all.
+    pub fn set_payload_logger(&self, logger: impl Fn(&str) + Send + Sync + 'static) {
+        *self
+            .0
+            .payload_logger
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(logger));
+    }
+
+    /// Remove the hook installed with [set_payload_logger](MAPIGraphQL::set_payload_logger) for
+    /// subsequent calls to [listen](Subscription::listen).
+    pub fn clear_payload_logger(&self) {
+        *self
+            .0
+            .payload_logger
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    /// Check that the worker thread and the `MAPI` session are still responsive by running
+    /// `{ __typename }`, e.g. from a supervisor which recreates the [MAPIGraphQL] if it fails.
+    ///
+    /// Each round-trip to the worker thread gives up after [HEALTH_CHECK_TIMEOUT] with a
+    /// [ServiceError::Timeout]. It only queues ordinary commands, so active subscriptions keep
+    /// running, but a long-running request queued ahead of it can make it time out.
+    pub fn health_check(&self) -> Result<(), ServiceError> {
+        let (tx, rx) = mpsc::channel();
+        self.0.send(ServiceCommand::ParsedQuery {
+            query: String::from("{ __typename }"),
+            tx_result: tx,
+        })?;
+        let query_id = self.0.recv_timeout(rx, HEALTH_CHECK_TIMEOUT)??;
+        let query = Arc::new(ParsedQuery(
+            self.0.clone(),
+            query_id,
+            String::from("{ __typename }"),
+        ));
+
+        let mut subscription = self.subscribe(query, "", "");
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, _rx_complete) = mpsc::channel();
+        subscription.listen_timeout(HEALTH_CHECK_TIMEOUT, tx_next, tx_complete)?;
+        let payload = rx_next.recv_timeout(HEALTH_CHECK_TIMEOUT).map_err(|_| {
+            ServiceError::Timeout(String::from("the health check did not return a payload"))
+        })?;
+
+        let response = Response::from_payload(&payload)?;
+        if response.errors.is_empty() && response.data["__typename"] == "Query" {
+            Ok(())
+        } else {
+            Err(ServiceError::Other(format!(
+                "Unexpected health check response: {payload}"
+            )))
+        }
+    }
+
+    /// Get a [MetricsSnapshot] of the counters tracking subscription throughput and message pump
+    /// activity on the worker thread.
+    ///
+    /// This needs the `metrics` feature. Without it, the `next` callbacks and the kicks do not
+    /// update any shared counters, so there is no cost when nothing reads them.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.0.metrics.snapshot()
+    }
+
+    /// Estimate how many commands, e.g. queries to parse or operations to subscribe, are queued
+    /// for the worker thread and have not been picked up yet, so a caller can avoid piling more
+    /// work on a saturated session.
+    ///
+    /// This is only an estimate, since other threads may be sending commands and the worker
+    /// thread may be handling them while it is counted. Once the worker thread has exited,
+    /// nothing else will be handled, so it returns 0.
+    pub fn pending_commands(&self) -> usize {
+        if self
+            .0
+            .exit_status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some()
+        {
+            return 0;
+        }
+
+        let metrics = &self.0.metrics;
+        let sent = metrics.commands_sent.load(Ordering::Relaxed);
+        let received = metrics.commands_received.load(Ordering::Relaxed);
+        usize::try_from(sent.saturating_sub(received)).unwrap_or(usize::MAX)
+    }
+
+    /// Get the Win32 thread id of the thread which owns the `MAPI` session and pumps its
+    /// messages, e.g. to find it in an ETW trace or a debugger. With
+    /// [attach](MAPIGraphQL::attach), this is the thread which called it.
+    pub fn worker_thread_id(&self) -> u32 {
+        self.0.thread_id
+    }
+
+    /// Block until the worker thread has logged on to the `MAPI` session, but only up to
+    /// `timeout`. [new](MAPIGraphQL::new) returns before logging on, so this can be used to
+    /// show progress while it connects, or to fail fast if the logon stalls.
+    ///
+    /// Returns the error from `startService` if logging on failed, including any retries, or a
+    /// [ServiceError::Timeout] if it has not finished yet, in which case it is safe to call this
+    /// again. With [attach](MAPIGraphQL::attach), the session is already logged on.
+    pub fn wait_ready(&self, timeout: Duration) -> Result<(), ServiceError> {
+        self.0.logon.wait(timeout)
+    }
+
+    /// List the subscriptions which have been started with [listen](Subscription::listen) and
+    /// have not called `complete` yet, ordered by id. Queries complete as soon as they start, so
+    /// this only includes `Subscription` operations, e.g. to find one that was never dropped.
+    pub fn active_subscriptions(&self) -> Vec<SubscriptionInfo> {
+        self.0
+            .subscriptions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .map(|tracked| tracked.info.clone())
+            .collect()
+    }
+
+    /// Get the [SubscriptionStats] for one of the
+    /// [active_subscriptions](MAPIGraphQL::active_subscriptions) by id, e.g. to find a chatty
+    /// folder watcher. This returns [None] once the subscription has called `complete`.
+    ///
+    /// The counters are updated by the `next` callback on the worker thread, and they count the
+    /// payloads of a subscription shared with
+    /// [with_shared_subscriptions](MAPIGraphQL::with_shared_subscriptions) once, however many
+    /// consumers it fans out to.
+    pub fn subscription_stats(&self, subscription_id: i32) -> Option<SubscriptionStats> {
+        self.0
+            .subscriptions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&subscription_id)
+            .map(|tracked| tracked.counters.snapshot())
+    }
+
+    /// Subscribe to a [GraphQL](https://graphql.org) [ParsedQuery] that was previously parsed with
+    /// [parse_query](MAPIGraphQL::parse_query).
+    ///
+    /// The worker thread does not hold a reference to the [Subscription], so it is returned
+    /// without any synchronization. Wrap it in a [Mutex] if it needs to be shared between threads.
+    ///
+    /// # Enum variables
+    ///
+    /// JSON has no enum literals, so pass a value of an enum type like `SpecialFolder` or
+    /// `PropType` as a JSON string with the name of the enum value, e.g.
+    /// `{ "ids": ["INBOX"] }` for `$ids: [SpecialFolder!]!`. Every string in the `variables` is
+    /// marked as coming from JSON, which lets gqlmapi coerce it to the enum value of a variable
+    /// whose type is an enum, the same way it would treat the literal `INBOX` in the request
+    /// document. A unit-only enum with `#[serde(rename_all = "SCREAMING_SNAKE_CASE")]` serializes
+    /// that way with [subscribe_with](MAPIGraphQL::subscribe_with).
+    pub fn subscribe(
+        &self,
+        query: Arc<ParsedQuery>,
+        operation_name: &str,
+        variables: &str,
+    ) -> Subscription {
+        Subscription {
+            active: None,
+            completed: Arc::default(),
+            listened: false,
+            query,
+            operation_name: operation_name.into(),
+            variables: variables.into(),
+        }
+    }
+
+    /// Subscribe to a [ParsedQuery] like [subscribe](MAPIGraphQL::subscribe), and start
+    /// [listen](Subscription::listen) with a new pair of channels. Return the [Subscription],
+    /// which unsubscribes when it drops, along with the receivers for `next` and `complete`.
+    ///
+    /// Both channels are unbounded, so the worker thread never blocks on a slow consumer.
+    pub fn subscribe_channels(
+        &self,
+        query: Arc<ParsedQuery>,
+        operation_name: &str,
+        variables: &str,
+    ) -> Result<(Subscription, mpsc::Receiver<String>, mpsc::Receiver<()>), ServiceError> {
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        let mut subscription = self.subscribe(query, operation_name, variables);
+        subscription.listen(tx_next, tx_complete)?;
+        Ok((subscription, rx_next, rx_complete))
+    }
+
+    /// Start listening to a [ParsedQuery] like [listen](Subscription::listen), but without a
+    /// [Subscription] to clean up when it drops, e.g. to bridge the subscription to a non-Rust
+    /// caller which cannot rely on `Drop`. Return the `subscription_id` assigned by the worker
+    /// thread.
+    ///
+    /// The caller is responsible for passing the id to
+    /// [unsubscribe_raw](MAPIGraphQL::unsubscribe_raw) exactly once, even for `Query` and
+    /// `Mutation` operations which have already called `complete`, or the subscription stays
+    /// registered until the service stops. Like [listen_chunked](Subscription::listen_chunked),
+    /// this always starts a subscription of its own, even with
+    /// [with_shared_subscriptions](MAPIGraphQL::with_shared_subscriptions).
+    pub fn subscribe_raw<N: PayloadSink>(
+        &self,
+        query: Arc<ParsedQuery>,
+        operation_name: &str,
+        variables: &str,
+        next: N,
+        complete: mpsc::Sender<()>,
+    ) -> Result<i32, ServiceError> {
+        let mut subscription = self.subscribe(query, operation_name, variables);
+        let options = subscription.conversion_options();
+        let complete = subscription.track_complete(complete_sender(complete));
+        subscription.start(options, None, next_sender(next), complete)?;
+        Ok(subscription.detach())
+    }
+
+    /// Remove a subscription started with [subscribe_raw](MAPIGraphQL::subscribe_raw), which
+    /// invokes `complete` for a `Subscription` operation. Once the worker thread has stopped,
+    /// this does nothing, since stopping the service already removed everything.
+    ///
+    /// Only pass ids returned from [subscribe_raw](MAPIGraphQL::subscribe_raw). The worker thread
+    /// never reuses an id, so unsubscribing the same id twice does nothing the second time.
+    pub fn unsubscribe_raw(&self, subscription_id: i32) -> Result<(), ServiceError> {
+        self.0
+            .send_cleanup(ServiceCommand::Unsubscribe { subscription_id })
+    }
+
+    /// Subscribe to a [GraphQL](https://graphql.org) [ParsedQuery] like
+    /// [subscribe](MAPIGraphQL::subscribe), but serialize the `variables` to JSON with
+    /// [serde_json](https://docs.rs/serde_json) instead of taking a JSON string. This accepts a
+    /// `&serde_json::Value` built with [serde_json::json] as well as any `Serialize` struct.
+    ///
+    /// If the `variables` cannot be serialized, or if they are not a JSON object, it will return
+    /// an [Err(ServiceError)](Err).
+    pub fn subscribe_with<V: Serialize>(
+        &self,
+        query: Arc<ParsedQuery>,
+        operation_name: &str,
+        variables: &V,
+    ) -> Result<Subscription, ServiceError> {
+        let variables = serialize_variables(variables)?;
+        Ok(self.subscribe(query, operation_name, &variables))
+    }
+
+    /// Parse a [GraphQL](https://graphql.org) request document and evaluate a `Query` or
+    /// `Mutation` operation in one call, returning the single JSON payload.
+    ///
+    /// If the request fails, or if the operation is a long-lived `Subscription`, it will return
+    /// an [Err(ServiceError)](Err) instead of waiting for the payload.
+    pub fn run(
+        &self,
+        query: &str,
+        operation_name: &str,
+        variables: &str,
+    ) -> Result<String, ServiceError> {
+        let query = self.parse_query(query)?;
+        self.subscribe(query, operation_name, variables).run_once()
+    }
+
+    /// Parse a [GraphQL](https://graphql.org) request document once, and evaluate every
+    /// operation it defines with the same `variables`, one after another in document order, e.g.
+    /// a mutation followed by a query which reads back the result. Returns the name and payload
+    /// of each operation.
+    ///
+    /// It stops at the first operation which fails or whose payload has any `errors`, and returns
+    /// an [Err(ServiceError)](Err) naming that operation. The operations before it have already
+    /// run, so any mutations among them are not rolled back.
+    pub fn run_all(
+        &self,
+        query: &str,
+        variables: &str,
+    ) -> Result<Vec<(String, String)>, ServiceError> {
+        let query = self.parse_query(query)?;
+        let mut results = Vec::new();
+        for operation_name in query.operation_names()? {
+            let name = match operation_name.as_str() {
+                "" => "<anonymous>",
+                name => name,
+            };
+            let payload = self
+                .subscribe(query.clone(), &operation_name, variables)
+                .run_once()
+                .map_err(|err| format!("Error running operation {name}: {err}"))?;
+            let response = Response::from_payload(&payload)
+                .map_err(|err| format!("Error running operation {name}: {err}"))?;
+            if let Some(err) = response.errors.first() {
+                return Err(format!("Error running operation {name}: {}", err.message).into());
+            }
+            results.push((operation_name, payload));
+        }
+        Ok(results)
+    }
+
+    /// Parse a [GraphQL](https://graphql.org) request document once, and register it under an
+    /// `id` chosen by the client, e.g. for a thin RPC layer where each request only sends the id.
+    /// Use [run_persisted](MAPIGraphQL::run_persisted) to evaluate it by `id`.
+    ///
+    /// Registering another document under the same `id` replaces the previous one. The
+    /// registered queries are shared by every clone of the [MAPIGraphQL], and the request
+    /// documents stay parsed until the last clone drops.
+    pub fn register_query(&self, id: &str, query: &str) -> Result<(), ParseError> {
+        let parsed = self.parse_query(query)?;
+        self.2
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(String::from(id), parsed);
+        Ok(())
+    }
+
+    /// Evaluate a `Query` or `Mutation` operation like [run](MAPIGraphQL::run), using the request
+    /// document registered under `id` with [register_query](MAPIGraphQL::register_query), so it
+    /// is not parsed again. If nothing is registered under `id`, it will return an
+    /// [Err(ServiceError)](Err).
+    pub fn run_persisted(
+        &self,
+        id: &str,
+        operation_name: &str,
+        variables: &str,
+    ) -> Result<String, ServiceError> {
+        let query = self
+            .2
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(id)
+            .cloned()
+            .ok_or_else(|| format!("Unknown persisted query: {id}"))?;
+        self.subscribe(query, operation_name, variables).run_once()
+    }
+
+    /// Evaluate a `Query` or `Mutation` operation like [run](MAPIGraphQL::run), reading the
+    /// request document from `query_path` and the JSON variables from `variables_path`, e.g. for
+    /// ad-hoc queries from the command line. Without a `variables_path`, or if the file is
+    /// empty, there are no variables.
+    ///
+    /// If either file cannot be read, it will return a [ServiceError::Io] with the [io::Error],
+    /// to tell it apart from a GraphQL error in the request.
+    pub fn run_from_files(
+        &self,
+        query_path: &Path,
+        variables_path: Option<&Path>,
+        operation_name: &str,
+    ) -> Result<String, ServiceError> {
+        let query = read_file(query_path)?;
+        let variables = match variables_path {
+            Some(path) => read_file(path)?,
+            None => String::new(),
+        };
+        self.run(&query, operation_name, variables.trim())
+    }
+
+    /// Same as [run](MAPIGraphQL::run), but reformat the payload as indented JSON, e.g. for
+    /// debugging or writing golden files. Members keep the order of the selection set.
+    pub fn run_pretty(
+        &self,
+        query: &str,
+        operation_name: &str,
+        variables: &str,
+    ) -> Result<String, ServiceError> {
+        let payload = self.run(query, operation_name, variables)?;
+        let value: serde_json::Value = serde_json::from_str(&payload)
+            .map_err(|err| format!("Error parsing payload: {err}"))?;
+        serde_json::to_string_pretty(&value)
+            .map_err(|err| ServiceError::Other(format!("Error formatting payload: {err}")))
+    }
+
+    /// Evaluate a `Query` or `Mutation` operation like [run](MAPIGraphQL::run), but parse the
+    /// whole payload into a [Response], including any `errors` and `extensions`. Use
+    /// [run_response](Subscription::run_response) to do the same with a [ParsedQuery].
+    pub fn execute_full(
+        &self,
+        query: &str,
+        operation_name: &str,
+        variables: &str,
+    ) -> Result<Response, ServiceError> {
+        let payload = self.run(query, operation_name, variables)?;
+        Ok(Response::from_payload(&payload)?)
+    }
+
+    /// Evaluate a `Query` or `Mutation` operation like [run](MAPIGraphQL::run), but deserialize
+    /// the payload into `T` while the worker thread is still writing it, so neither the JSON
+    /// [String] nor a whole [serde_json::Value] is ever held in memory, e.g. for a huge `items`
+    /// list.
+    ///
+    /// This does not avoid the result itself: gqlmapi has already resolved the whole
+    /// `ResponseValue` tree on the worker thread before the payload is written, and it stays in
+    /// memory until the whole payload has been written. The peak memory is therefore about the
+    /// size of that tree plus `T`, rather than the tree, the JSON, and `T` together.
+    ///
+    /// The worker thread serializes the result through a bounded pipe, which holds a few 8 KiB
+    /// chunks, and `T` is constructed from it on the calling thread. Once the pipe is full, the
+    /// worker thread waits for the calling thread to read more, so while `T::deserialize` runs it
+    /// cannot handle any other command, deliver payloads to other subscriptions, or pump `MAPI`
+    /// notifications. Keep `T` cheap to deserialize, and never call back into the service from
+    /// its [Deserialize](serde::Deserialize) impl, which would wait on the worker forever.
+    /// [with_tagged_scalars](MAPIGraphQL::with_tagged_scalars) still applies, but
+    /// [set_max_payload_bytes](MAPIGraphQL::set_max_payload_bytes) and the
+    /// [set_payload_logger](MAPIGraphQL::set_payload_logger) hook do not, since the payload is
+    /// never buffered. Deserialize a [Response] or your own struct with an `errors` member to
+    /// see any errors in the payload.
+    pub fn execute_into_streaming<T: DeserializeOwned>(
+        &self,
+        query: &str,
+        operation_name: &str,
+        variables: &str,
+    ) -> Result<T, ServiceError> {
+        let query = self.parse_query(query)?;
+        self.subscribe(query, operation_name, variables)
+            .run_streaming()
+    }
+
+    /// Parse a [GraphQL](https://graphql.org) request document, evaluate a `Query` or `Mutation`
+    /// operation, and deserialize the `data` member into `T`, in one call. The `variables` are
+    /// serialized like [subscribe_with](MAPIGraphQL::subscribe_with).
+    ///
+    /// The worker thread deserializes the `data` straight from the `ResponseValue`, like
+    /// [listen_typed](Subscription::listen_typed), so the response is never formatted as JSON.
+    ///
+    /// If the request cannot be evaluated, it will return [QueryError::Transport]. If the response
+    /// has any `errors`, it will return every one of them in [QueryError::GraphQL] instead of the
+    /// `data`, and if the `data` does not match `T`, [QueryError::Deserialize].
+    pub fn query<T: DeserializeOwned + Send + 'static, V: Serialize>(
+        &self,
+        query: &str,
+        operation_name: &str,
+        variables: &V,
+    ) -> Result<T, QueryError> {
+        let variables = serialize_variables(variables).map_err(QueryError::Transport)?;
+        let query = self.parse_query(query)?;
+        self.subscribe(query, operation_name, &variables)
+            .run_typed()?
+            .into_data()
+    }
+
+    /// Evaluate a `Mutation` operation and deserialize the `data` member into `T`, e.g. to read
+    /// back the ids of the created or modified objects. This is the same as
+    /// [query](MAPIGraphQL::query), which reads better at the call site of a mutation.
+    ///
+    /// If the response has any `errors`, the mutation may have been partially applied, so it
+    /// will return every one of them in [QueryError::GraphQL] instead of the `data`.
+    pub fn mutate<T: DeserializeOwned + Send + 'static, V: Serialize>(
+        &self,
+        query: &str,
+        operation_name: &str,
+        variables: &V,
+    ) -> Result<T, QueryError> {
+        self.query(query, operation_name, variables)
+    }
+
+    /// Introspect the live `MAPI` schema and render it as SDL, leaving out the introspection
+    /// types, the built-in scalars, descriptions, and directives.
+    pub fn schema_sdl(&self) -> Result<String, ServiceError> {
+        let response = self.execute_full(INTROSPECTION_QUERY, "", "")?;
+        if let Some(err) = response.errors.first() {
+            return Err(format!("Error introspecting the schema: {}", err.message).into());
+        }
+        let data: IntrospectionData = serde_json::from_value(response.data)
+            .map_err(|err| format!("Error parsing introspection results: {err}"))?;
+        Ok(data.render_sdl())
+    }
+
+    /// Introspect the live `MAPI` schema like [schema_sdl](MAPIGraphQL::schema_sdl) and compare
+    /// it to `expected_sdl`, e.g. a snapshot embedded with `include_str!`, as a self-test
+    /// against a gqlmapi upgrade changing the schema.
+    ///
+    /// The comparison is by type name. Descriptions, comments, directives, and the order of
+    /// fields, enum values, union members, and implemented interfaces are ignored, as are
+    /// `directive` and `extend` definitions in `expected_sdl`. Use
+    /// [is_empty](SchemaDiff::is_empty) to check whether they match.
+    pub fn verify_schema(&self, expected_sdl: &str) -> Result<SchemaDiff, ServiceError> {
+        let live_sdl = self.schema_sdl()?;
+        Ok(SchemaDiff::compare(&live_sdl, expected_sdl))
+    }
+}
+
+/// Hold on to a query parsed with [parse_query](MAPIGraphQL::parse_query) and automatically clean
+/// up when [ParsedQuery] drops.
+///
+/// # Sharing
+///
+/// [parse_query](MAPIGraphQL::parse_query) returns an [`Arc<ParsedQuery>`](Arc), which may be
+/// cloned and passed to any number of [Subscription] or [PreparedOperation] values. They each
+/// hold a clone, as does the query cache if it is enabled with
+/// [with_query_cache](MAPIGraphQL::with_query_cache). The request document is only discarded on
+/// the worker thread once the last clone drops, so dropping all but one of them never
+/// invalidates the query for the rest. Use [try_discard_now](ParsedQuery::try_discard_now) to
+/// find out whether a clone was the last one.
+pub struct ParsedQuery(Arc<Service>, i32, String);
+
+impl fmt::Debug for ParsedQuery {
+    /// Print the query id, but not the request document, which may be very long.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParsedQuery")
+            .field("query_id", &self.1)
+            .finish()
+    }
+}
+
+impl ParsedQuery {
+    /// The request document exactly as it was passed to [parse_query](MAPIGraphQL::parse_query),
+    /// e.g. for logging or for building a map of persisted queries. If the query came from the
+    /// [query cache](MAPIGraphQL::with_query_cache), this is the document which was parsed first,
+    /// which may differ from a later one in its leading and trailing whitespace.
+    pub fn source(&self) -> &str {
+        &self.2
+    }
+
+    /// Validate the request document against the `MAPI` schema without executing it, e.g. to
+    /// catch references to fields which do not exist before calling
+    /// [subscribe](MAPIGraphQL::subscribe).
+    ///
+    /// If validation fails, it will return an [Err(Vec<String>)](Err) with every validation error
+    /// message. If the worker thread cannot validate the query at all, the [Vec] holds that single
+    /// error instead.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let (tx, rx) = mpsc::channel();
+        self.0
+            .send(ServiceCommand::ValidateQuery {
+                query_id: self.1,
+                tx_result: tx,
+            })
+            .map_err(|err| vec![err.to_string()])?;
+        let errors = self
+            .0
+            .recv(rx)
+            .map_err(|err| vec![err.to_string()])?
+            .map_err(|err| vec![err])?;
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Estimate the cost of executing `operation_name` in the request document, e.g. to reject
+    /// expensive queries before calling [subscribe](MAPIGraphQL::subscribe) on a shared `MAPI`
+    /// session. The `operation_name` may be empty if the document only has 1 operation.
+    ///
+    /// Every field in the selection set, including fragments, costs 1. The cost of the nested
+    /// selection set under a field which returns a list is multiplied by 10 for each level of
+    /// list in the field type, so the estimate grows with the potential size of the results
+    /// rather than the length of the document. The estimate saturates at [u32::MAX].
+    pub fn complexity(&self, operation_name: &str) -> Result<u32, ServiceError> {
+        let (tx, rx) = mpsc::channel();
+        self.0.send(ServiceCommand::QueryComplexity {
+            query_id: self.1,
+            operation_name: String::from(operation_name),
+            tx_result: tx,
+        })?;
+        Ok(self.0.recv(rx)??)
+    }
+
+    /// Bind one of the operations in the request document to a [PreparedOperation], which can
+    /// be given `variables` and then [run](PreparedOperation::run) or
+    /// [listen](PreparedOperation::listen) to.
+    ///
+    /// The `operation_name` may be empty if the document only has 1 operation. If the document
+    /// does not define `operation_name`, it will return an [Err(ServiceError)](Err).
+    pub fn operation(
+        self: &Arc<Self>,
+        operation_name: &str,
+    ) -> Result<PreparedOperation, ServiceError> {
+        let operation_names = self.operation_names()?;
+
+        if operation_name.is_empty() {
+            if operation_names.len() != 1 {
+                return Err(operation_name_required(&operation_names).into());
+            }
+        } else if !operation_names.iter().any(|name| name == operation_name) {
+            return Err(format!("Unknown operation: {operation_name}").into());
+        }
+
+        Ok(PreparedOperation {
+            query: self.clone(),
+            operation_name: operation_name.into(),
+            variables: String::new(),
+        })
+    }
+
+    /// Names of the operations defined in the request document, in document order, to discover
+    /// which names can be passed to [subscribe](MAPIGraphQL::subscribe). Anonymous operations
+    /// have an empty name.
+    ///
+    /// The operation name may only be empty if the document defines exactly 1 operation, which
+    /// is selected automatically. Otherwise [listen](Subscription::listen) and
+    /// [run](MAPIGraphQL::run) return an [Err(ServiceError)](Err) listing the operations it
+    /// defines, before the request reaches gqlmapi.
+    pub fn operation_names(&self) -> Result<Vec<String>, ServiceError> {
+        let (tx, rx) = mpsc::channel();
+        self.0.send(ServiceCommand::OperationNames {
+            query_id: self.1,
+            tx_result: tx,
+        })?;
+        Ok(self.0.recv(rx)??)
+    }
+
+    /// Drop this reference to the [ParsedQuery], and if it was the last one, discard the request
+    /// document right away. Unlike dropping it, this returns an error instead of panicking if the
+    /// worker thread cannot discard the query.
+    ///
+    /// Returns `Ok(true)` if the query was discarded, or `Ok(false)` if other clones still hold
+    /// it, in which case it will be discarded when the last of them drops.
+    pub fn try_discard_now(self: Arc<Self>) -> Result<bool, ServiceError> {
+        match Arc::try_unwrap(self) {
+            Ok(mut query) => query.discard_query().map(|_| true),
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn discard_query(&mut self) -> Result<(), ServiceError> {
+        if self.1 != 0 {
+            self.0
+                .send_cleanup(ServiceCommand::DiscardQuery { query_id: self.1 })?;
+            self.1 = 0;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ParsedQuery {
+    /// Cleanup a [GraphQL](https://graphql.org) request document that was previously parsed with
+    /// [parse_query](MAPIGraphQL::parse_query).
+    fn drop(&mut self) {
+        self.discard_query().expect("Unable to discard query");
+    }
+}
+
+/// A [ParsedQuery] bound to one of its operations and a set of variables, created with
+/// [operation](ParsedQuery::operation).
+pub struct PreparedOperation {
+    query: Arc<ParsedQuery>,
+    operation_name: String,
+    variables: String,
+}
+
+impl fmt::Debug for PreparedOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PreparedOperation")
+            .field("query_id", &self.query.1)
+            .field("operation_name", &self.operation_name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PreparedOperation {
+    /// Serialize the `variables` for the operation to JSON with
+    /// [serde_json](https://docs.rs/serde_json).
+    ///
+    /// If the `variables` cannot be serialized, or if they are not a JSON object, it will return
+    /// an [Err(ServiceError)](Err).
+    pub fn variables<V: Serialize>(mut self, variables: &V) -> Result<Self, ServiceError> {
+        self.variables = serialize_variables(variables)?;
+        Ok(self)
+    }
+
+    /// Use a JSON string for the `variables`, like [subscribe](MAPIGraphQL::subscribe).
+    pub fn variables_json(mut self, variables: &str) -> Self {
+        self.variables = variables.into();
+        self
+    }
+
+    /// Evaluate a `Query` or `Mutation` operation, like [run](MAPIGraphQL::run).
+    pub fn run(&self) -> Result<String, ServiceError> {
+        self.subscribe().run_once()
+    }
+
+    /// Create a [Subscription] for the operation, which can be used like one returned from
+    /// [subscribe](MAPIGraphQL::subscribe).
+    pub fn subscribe(&self) -> Subscription {
+        Subscription {
+            active: None,
+            completed: Arc::default(),
+            listened: false,
+            query: self.query.clone(),
+            operation_name: self.operation_name.clone(),
+            variables: self.variables.clone(),
+        }
+    }
+
+    /// Start listening to the operation like [listen](Subscription::listen), and return the
+    /// [Subscription], which unsubscribes when it drops.
+    pub fn listen<N: PayloadSink>(
+        &self,
+        next: N,
+        complete: mpsc::Sender<()>,
+    ) -> Result<Subscription, ServiceError> {
+        let mut subscription = self.subscribe();
+        subscription.listen(next, complete)?;
+        Ok(subscription)
+    }
+}
+
+/// Shared state for a single call to [listen](Subscription::listen), which may be removed by the
+/// [Subscription] or by any of its [SubscriptionHandle] clones, whichever happens first.
+struct ActiveSubscription {
+    service: Arc<Service>,
+    subscription_id: AtomicI32,
+    shared: Option<(SharedKey, u64)>,
+}
+
+impl ActiveSubscription {
+    fn unsubscribe(&self) -> Result<(), ServiceError> {
+        let mut subscription_id = self.subscription_id.swap(0, Ordering::AcqRel);
+        if let Some((key, consumer_id)) = &self.shared {
+            if subscription_id != 0 {
+                subscription_id = self
+                    .service
+                    .release_shared(key, *consumer_id)
+                    .unwrap_or_default();
+            }
+        }
+        if subscription_id != 0 {
+            self.service
+                .send_cleanup(ServiceCommand::Unsubscribe { subscription_id })?;
+        }
+        Ok(())
+    }
+}
+
+/// Lightweight handle returned from [listen](Subscription::listen), which can be cloned and sent
+/// to another thread to [cancel](SubscriptionHandle::cancel) the [Subscription].
+#[derive(Clone)]
+pub struct SubscriptionHandle(Arc<ActiveSubscription>);
+
+impl fmt::Debug for SubscriptionHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SubscriptionHandle")
+            .field(
+                "subscription_id",
+                &self.0.subscription_id.load(Ordering::Acquire),
+            )
+            .finish()
+    }
+}
+
+impl SubscriptionHandle {
+    /// Remove the subscription, which invokes `complete` for a `Subscription` operation.
+    ///
+    /// This is idempotent, and it is also safe to call after the [Subscription] has been dropped
+    /// or started listening again, in which case it does nothing.
+    pub fn cancel(&self) -> Result<(), ServiceError> {
+        self.0.unsubscribe()
+    }
+}
+
+/// Cooperative cancellation for [listen_with_token](Subscription::listen_with_token). Clones
+/// share the same state, so the token can be handed to another thread or task, and cancelling
+/// any of them removes every subscription which is listening with it.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<Mutex<CancelState>>);
+
+/// The subscriptions are only held weakly, so a long-lived token does not keep the service alive
+/// after every [Subscription] and [SubscriptionHandle] listening with it has been dropped.
+#[derive(Default)]
+struct CancelState {
+    cancelled: bool,
+    handles: Vec<Weak<ActiveSubscription>>,
+}
+
+impl CancelToken {
+    /// Create a token which has not been cancelled yet, the same as [CancelToken::default].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trip the token, and remove every subscription listening with it, like calling
+    /// [cancel](SubscriptionHandle::cancel) on each of them. This is idempotent, and if any of
+    /// them fails, the rest are still cancelled before the first error is returned.
+    pub fn cancel(&self) -> Result<(), ServiceError> {
+        let handles = {
+            let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+            state.cancelled = true;
+            mem::take(&mut state.handles)
+        };
+        handles
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|active| active.unsubscribe())
+            .fold(Ok(()), Result::and)
+    }
+
+    /// Whether [cancel](CancelToken::cancel) has been called on any clone of the token.
+    pub fn is_cancelled(&self) -> bool {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .cancelled
+    }
+
+    /// Cancel the `handle` along with the token, or right away if it was already cancelled.
+    fn register(&self, handle: SubscriptionHandle) -> Result<(), ServiceError> {
+        let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.cancelled {
+            drop(state);
+            return handle.cancel();
+        }
+
+        // Forget the subscriptions which were dropped, since dropping them already unsubscribed.
+        state.handles.retain(|active| active.strong_count() > 0);
+        state.handles.push(Arc::downgrade(&handle.0));
+        Ok(())
+    }
+}
+
+/// Hold on to an operation subscription created with [subscribe](MAPIGraphQL::subscribe) and
+/// automatically clean up when [Subscription] drops..
+pub struct Subscription {
+    active: Option<Arc<ActiveSubscription>>,
+    completed: Arc<AtomicBool>,
+    listened: bool,
+    query: Arc<ParsedQuery>,
+    operation_name: String,
+    variables: String,
+}
+
+impl fmt::Debug for Subscription {
+    /// Print the ids and operation name, but not the request document or `variables`. The
+    /// `subscription_id` is 0 if it is not listening.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscription")
+            .field("query_id", &self.query.1)
+            .field("subscription_id", &self.subscription_id())
+            .field("operation_name", &self.operation_name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Subscription {
+    /// Start listening to the [Subscription] that was previously created with
+    /// [subscribe](MAPIGraphQL::subscribe). This will return an [Err(ServiceError)](Err) if the
+    /// request failed.
+    ///
+    /// If the specified operation is a `Query` or `Mutation`, it will be evaluated immediately and
+    /// result in a pair of calls to `next` and then `complete`.
+    ///
+    /// If it is a `Subscription` operation, each time the event stream is updated, the payload
+    /// will be delivered through another call to `next`. `Subscription` operations will also
+    /// invoke `complete` once they are removed by dropping the [Subscription], or by calling
+    /// [cancel](SubscriptionHandle::cancel) on the returned [SubscriptionHandle].
+    ///
+    /// `next` can be any [PayloadSink], e.g. an [mpsc::Sender<String>], or with the
+    /// `crossbeam-channel` and `flume` features, the `Sender` from either of those crates.
+    ///
+    /// # Complete
+    ///
+    /// If this returns [Ok], `complete` is sent exactly once, after the last `next` payload. If
+    /// it returns [Err], `complete` is never sent. Either way, the worker thread drops both
+    /// senders once it is done with them, even if the request failed or the worker exited before
+    /// handling it, so a consumer blocked on `complete` always unblocks with an error instead of
+    /// waiting forever.
+    ///
+    /// Since the worker thread owns `next` and drops it after `complete`, a receiver whose only
+    /// sender was passed here is closed once it has been drained. To listen again with the same
+    /// receiver, e.g. after the [Subscription] is re-listened with new variables, keep the
+    /// original sender and pass a clone of it to each call instead, so the receiver stays open
+    /// until the original is dropped as well.
+    ///
+    /// # Ordering
+    ///
+    /// Both channels are fed from the worker thread, and every `next` payload is sent before
+    /// `complete`, so once `complete` has been received, all of the payloads are already waiting
+    /// in `next` and can be drained with [try_iter](mpsc::Receiver::try_iter). A consumer which
+    /// polls both channels may still see `complete` first, since nothing orders the receivers.
+    /// Use [listen_events](Subscription::listen_events) to receive both over one ordered channel.
+    ///
+    /// # Dropping the receiver
+    ///
+    /// If sending a payload to `next` fails because its receiver was dropped, or because a
+    /// bounded channel is full (see [Blocking](PayloadSink#blocking)), the worker thread
+    /// unsubscribes on its own, which sends `complete`. Dropping the [Subscription] afterwards is
+    /// still safe, since the worker thread never reuses a subscription id.
+    ///
+    /// # Panicking callbacks
+    ///
+    /// A panic in a [PayloadSink] or in the
+    /// [set_payload_logger](MAPIGraphQL::set_payload_logger) hook is caught on the worker thread,
+    /// since unwinding into the `Bindings` would abort the process. The worker thread then stops
+    /// the service, this returns a [ServiceError::Panicked] if it is still waiting for the reply,
+    /// and every later call fails with a [ServiceError::WorkerGone].
+    ///
+    /// A callback may drop a [Subscription], a [ParsedQuery], or a [MAPIGraphQL] clone, which
+    /// queues the cleanup for the worker thread to handle once the callback returns. Dropping the
+    /// last reference to the service there stops the worker thread without waiting for it.
+    pub fn listen<N: PayloadSink>(
+        &mut self,
+        next: N,
+        complete: mpsc::Sender<()>,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        self.listen_within(None, next, complete_sender(complete))
+    }
+
+    /// Start listening to the [Subscription] like [listen](Subscription::listen), and remove it
+    /// once the `token` is [cancelled](CancelToken::cancel), which invokes `complete` for a
+    /// `Subscription` operation. If the `token` was already cancelled, the subscription is
+    /// removed as soon as it starts.
+    pub fn listen_with_token<N: PayloadSink>(
+        &mut self,
+        next: N,
+        complete: mpsc::Sender<()>,
+        token: &CancelToken,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        let handle = self.listen(next, complete)?;
+        token.register(handle.clone())?;
+        Ok(handle)
+    }
+
+    /// Start listening to the [Subscription] like [listen](Subscription::listen), but deliver
+    /// each payload and then `complete` as a [SubscriptionEvent] over a single channel, so they
+    /// are always received in the order they were sent, and the stream has an unambiguous end.
+    ///
+    /// If the subscription cannot be started, the error is sent as a
+    /// [SubscriptionEvent::Error] as well as returned, so a consumer which only holds the
+    /// receiving end still sees why the stream ended.
+    pub fn listen_events(
+        &mut self,
+        events: mpsc::Sender<SubscriptionEvent>,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        let complete = events.clone();
+        let failed = events.clone();
+        self.listen_within(
+            None,
+            EventSink(events),
+            Box::new(move || {
+                let _ = complete.send(SubscriptionEvent::Complete);
+            }),
+        )
+        .map_err(|err| {
+            let _ = failed.send(SubscriptionEvent::Error(err.to_string()));
+            err
+        })
+    }
+
+    /// Start listening to the [Subscription] like [listen_events](Subscription::listen_events),
+    /// and send a [SubscriptionEvent::Idle] whenever `max_idle` passes without a payload, e.g. so
+    /// a UI can warn that a long-lived folder subscription may be showing stale data.
+    ///
+    /// This is only advisory: the subscription is not torn down, and `MAPI` does not send any
+    /// notifications while nothing changes, so an idle subscription is not necessarily broken.
+    /// The `Idle` events come from a separate thread, which exits once the stream ends or the
+    /// receiver drops.
+    pub fn listen_events_keepalive(
+        &mut self,
+        events: mpsc::Sender<SubscriptionEvent>,
+        max_idle: Duration,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        let keepalive = Arc::new(Keepalive::new(events));
+        let complete = keepalive.clone();
+        let watchdog = keepalive.clone();
+        let result = self.listen_within(
+            None,
+            KeepaliveSink(keepalive.clone()),
+            Box::new(move || {
+                let _ = complete.send(SubscriptionEvent::Complete);
+            }),
+        );
+
+        match result {
+            Ok(handle) => {
+                thread::spawn(move || watchdog.watch(max_idle));
+                Ok(handle)
+            }
+            Err(err) => {
+                let _ = keepalive.send(SubscriptionEvent::Error(err.to_string()));
+                Err(err)
+            }
+        }
+    }
+
+    /// Start listening to the [Subscription] like [listen](Subscription::listen), but give up on
+    /// the initial handshake with the worker thread if it does not reply within `timeout`, e.g.
+    /// because it is busy with a long-running query.
+    ///
+    /// A timeout returns a [ServiceError::Timeout], which is distinct from the errors returned
+    /// when the worker thread fails to subscribe. If the worker thread subscribes after
+    /// the timeout, it unsubscribes right away, so nothing is left running on its side and
+    /// `complete` is still sent. The timeout only covers the handshake, not the payloads which
+    /// follow it.
+    pub fn listen_timeout<N: PayloadSink>(
+        &mut self,
+        timeout: Duration,
+        next: N,
+        complete: mpsc::Sender<()>,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        self.listen_within(Some(timeout), next, complete_sender(complete))
+    }
+
+    fn listen_within<N: PayloadSink>(
+        &mut self,
+        timeout: Option<Duration>,
+        next: N,
+        complete: CompleteCallback,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        let options = self.conversion_options();
+
+        // Leaving a shared subscription locks the sharing state, so do that before locking it.
+        self.unsubscribe()?;
+        let complete = self.track_complete(complete);
+
+        let shared = self
+            .query
+            .0
+            .sharing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some();
+        if shared {
+            return self.listen_shared(options, timeout, next, complete);
+        }
+
+        self.start(options, timeout, next_sender(next), complete)
+    }
+
+    /// Join an existing shared subscription with the same [SharedKey] if it is still active, or
+    /// start a new one which fans out to all of its consumers.
+    ///
+    /// The sharing state is not locked while waiting for the worker thread to subscribe, since
+    /// a callback on the worker thread may drop another shared [Subscription], which locks it to
+    /// leave. If another consumer started the same subscription in the meantime, this one keeps
+    /// running on its own instead of being shared.
+    fn listen_shared<N: PayloadSink>(
+        &mut self,
+        options: ConversionOptions,
+        timeout: Option<Duration>,
+        next: N,
+        complete: CompleteCallback,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        let key = (
+            self.query.1,
+            self.operation_name.clone(),
+            self.variables.clone(),
+        );
+        let service = self.query.0.clone();
+        let consumer = {
+            let mut sharing = service
+                .sharing
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let sharing = sharing.get_or_insert_with(SubscriptionSharing::default);
+            sharing.next_consumer_id += 1;
+            let consumer = SharedConsumer {
+                id: sharing.next_consumer_id,
+                next: Box::new(next),
+                complete,
+            };
+
+            if let Some(shared) = sharing.subscriptions.get(&key) {
+                let mut consumers = shared
+                    .consumers
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                if !consumers.completed {
+                    let shared_id = (key.clone(), consumer.id);
+                    consumers.consumers.push(consumer);
+                    return Ok(self.activate(shared.subscription_id, Some(shared_id)));
+                }
+            }
+            consumer
+        };
+
+        let shared_id = (key.clone(), consumer.id);
+        let consumers = Arc::new(Mutex::new(SharedConsumers {
+            consumers: vec![consumer],
+            completed: false,
+        }));
+        let next_consumers = consumers.clone();
+        let complete_consumers = consumers.clone();
+        let subscription_id = self.send_subscribe(
+            options,
+            timeout,
+            Box::new(move |payload| {
+                for consumer in next_consumers
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .consumers
+                    .iter()
+                {
+                    let _ = consumer.next.send(payload.clone());
+                }
+                Ok(())
+            }),
+            None,
+            Box::new(move || {
+                let mut consumers = complete_consumers
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                consumers.completed = true;
+                for consumer in consumers.consumers.drain(..) {
+                    (consumer.complete)();
+                }
+            }),
+        )?;
+
+        // `Query` and `Mutation` operations have already completed, so there is nothing to join.
+        let completed = consumers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .completed;
+        if completed {
+            return Ok(self.activate(subscription_id, Some(shared_id)));
+        }
+
+        let mut sharing = service
+            .sharing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let sharing = sharing.get_or_insert_with(SubscriptionSharing::default);
+        let raced = sharing.subscriptions.get(&key).is_some_and(|shared| {
+            !shared
+                .consumers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .completed
+        });
+        if raced {
+            return Ok(self.activate(subscription_id, None));
+        }
+
+        sharing.subscriptions.insert(
+            key,
+            SharedSubscription {
+                subscription_id,
+                consumers,
+            },
+        );
+        Ok(self.activate(subscription_id, Some(shared_id)))
+    }
+
+    /// Start listening to the [Subscription] like [listen](Subscription::listen), but coalesce
+    /// bursts of payloads, e.g. the several notifications `MAPI` may fire for a single change to
+    /// a folder. The first payload starts a `window`, and only the latest payload received by
+    /// the end of it is delivered to `next`, from a separate timer thread.
+    ///
+    /// This trades the intermediate states within each window for fewer updates, so use it for
+    /// payloads which each describe the whole current state, e.g. to refresh a UI, rather than
+    /// ones which must all be seen. Every payload is delayed by up to `window`. The pending
+    /// payload is delivered before `complete`, so the latest state is never lost.
+    pub fn listen_debounced<N: PayloadSink>(
+        &mut self,
+        window: Duration,
+        next: N,
+        complete: mpsc::Sender<()>,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        let (sink, pending) = debounce::debounce(window, next);
+        let complete = complete_sender(complete);
+        self.listen_within(
+            None,
+            sink,
+            Box::new(move || {
+                pending.flush();
+                complete();
+            }),
+        )
+    }
+
+    /// Start listening to the [Subscription] like [listen](Subscription::listen), but if the
+    /// payload's `data` has a single member which is a `List`, deliver the items in chunks of up
+    /// to `chunk_size` through separate calls to `next`. Each chunk has the same shape as the
+    /// whole payload, e.g. `{ "data": { "field": [...] } }`, and they are delivered in order.
+    ///
+    /// This avoids serializing a huge result into a single JSON string, but gqlmapi still
+    /// produces the whole result before the first chunk is delivered.
+    pub fn listen_chunked<N: PayloadSink>(
+        &mut self,
+        chunk_size: usize,
+        next: N,
+        complete: mpsc::Sender<()>,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        let options = ConversionOptions {
+            chunk_size: Some(chunk_size),
+            ..self.conversion_options()
+        };
+        let complete = self.track_complete(complete_sender(complete));
+        self.start(options, None, next_sender(next), complete)
+    }
+
+    /// Start listening to the [Subscription] like [listen](Subscription::listen), but parse each
+    /// `next` payload into a [serde_json::Value] before sending it. If a payload is not valid
+    /// JSON, the parse error is sent to `errors` instead of `next`, and the subscription keeps
+    /// running.
+    ///
+    /// Like [listen_chunked](Subscription::listen_chunked), this always starts a subscription of
+    /// its own, even with [with_shared_subscriptions](MAPIGraphQL::with_shared_subscriptions).
+    pub fn listen_json(
+        &mut self,
+        next: mpsc::Sender<serde_json::Value>,
+        complete: mpsc::Sender<()>,
+        errors: mpsc::Sender<String>,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        let options = self.conversion_options();
+        let complete = self.track_complete(complete_sender(complete));
+        self.start(
+            options,
+            None,
+            Box::new(move |payload| match serde_json::from_str(&payload) {
+                Ok(value) => next.send(value).map_err(|err| err.to_string()),
+                Err(err) => errors
+                    .send(format!("Error parsing payload: {err}"))
+                    .map_err(|err| err.to_string()),
+            }),
+            complete,
+        )
+    }
+
+    /// Start listening to the [Subscription] like [listen](Subscription::listen), but
+    /// deserialize each `next` payload into `T` before sending it. A payload which does not match
+    /// `T` is sent as an [Err(String)](Err), and the subscription keeps running.
+    ///
+    /// The worker thread deserializes `T` straight from the `ResponseValue`, like
+    /// [from_response_value](conversion::from_response_value), without formatting a JSON payload.
+    /// With [with_tagged_scalars](MAPIGraphQL::with_tagged_scalars),
+    /// [set_number_format](MAPIGraphQL::set_number_format),
+    /// [set_max_payload_bytes](MAPIGraphQL::set_max_payload_bytes), or a
+    /// [set_payload_logger](MAPIGraphQL::set_payload_logger) hook, it still parses the JSON
+    /// payload with [serde_json](https://docs.rs/serde_json), so they apply as usual.
+    ///
+    /// Like [listen_chunked](Subscription::listen_chunked), this always starts a subscription of
+    /// its own, even with [with_shared_subscriptions](MAPIGraphQL::with_shared_subscriptions).
+    pub fn listen_typed<T: DeserializeOwned + Send + 'static>(
+        &mut self,
+        next: mpsc::Sender<Result<T, String>>,
+        complete: mpsc::Sender<()>,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        let complete = self.track_complete(complete_sender(complete));
+        self.start_typed(
+            TypedSender::new(move |result| next.send(result).map_err(|err| err.to_string())),
+            complete,
+        )
+    }
+
+    /// Start listening to the [Subscription] like [listen_typed](Subscription::listen_typed), but
+    /// return an async [TypedStream] of the deserialized payloads instead of sending them to a
+    /// channel, e.g. for an async UI which watches a folder for changes. A payload which does not
+    /// match `T` is an [Err(String)](Err) item, and the stream keeps going until `complete`. The
+    /// payloads are deserialized on the worker thread the same way.
+    ///
+    /// If the subscription cannot be started, the stream yields a single [Err(String)](Err) and
+    /// ends. Dropping the stream cancels the subscription on the next payload, and dropping the
+    /// [Subscription] ends the stream.
+    pub fn stream_typed<T: DeserializeOwned + Send + 'static>(
+        &mut self,
+    ) -> TypedStream<Result<T, String>> {
+        let (tx, stream) = typed_stream::channel();
+        let next = Arc::new(tx);
+        let complete = next.clone();
+        let failed = next.clone();
+        let complete = self.track_complete(Box::new(move || complete.close()));
+        let result = self.start_typed(
+            TypedSender::new(move |result| {
+                next.send(result)
+                    .map_err(|_| String::from("sending on a closed channel"))
+            }),
+            complete,
+        );
+
+        if let Err(err) = result {
+            let _ = failed.send(Err(err.to_string()));
+            failed.close();
+        }
+        stream
+    }
+
+    /// Check whether the last call to [listen](Subscription::listen) (or any of its variants)
+    /// has sent `complete`, without draining the `complete` channel. This is `false` if it has
+    /// never started listening, if the last attempt to listen failed, or if it is still running.
+    ///
+    /// `Query` and `Mutation` operations are complete as soon as `listen` returns, while a
+    /// `Subscription` operation only completes once it is unsubscribed, e.g. by
+    /// [cancel](SubscriptionHandle::cancel) or by [shutdown](MAPIGraphQL::shutdown).
+    pub fn is_complete(&self) -> bool {
+        self.completed.load(Ordering::Acquire)
+    }
+
+    /// Wrap `complete` in a callback which also records it for
+    /// [is_complete](Subscription::is_complete), resetting it for the next attempt to listen.
+    fn track_complete(&mut self, complete: CompleteCallback) -> CompleteCallback {
+        let completed = Arc::new(AtomicBool::new(false));
+        self.completed = completed.clone();
+        self.listened = true;
+        Box::new(move || {
+            completed.store(true, Ordering::Release);
+            complete()
+        })
+    }
+
+    fn conversion_options(&self) -> ConversionOptions {
+        *self
+            .query
+            .0
+            .conversion
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn start(
+        &mut self,
+        options: ConversionOptions,
+        timeout: Option<Duration>,
+        next: NextCallback,
+        complete: CompleteCallback,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        self.unsubscribe()?;
+        let subscription_id = self.send_subscribe(options, timeout, next, None, complete)?;
+        Ok(self.activate(subscription_id, None))
+    }
+
+    /// Start listening like [start](Subscription::start), but deserialize each payload into `T`
+    /// with a [TypedSender], straight from the `ResponseValue` when nothing needs the JSON.
+    fn start_typed<T: DeserializeOwned + Send + 'static>(
+        &mut self,
+        sender: TypedSender<T>,
+        complete: CompleteCallback,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        self.unsubscribe()?;
+        let (next, decode) = sender.into_callbacks();
+        let subscription_id = self.send_subscribe(
+            self.conversion_options(),
+            None,
+            next,
+            Some(decode),
+            complete,
+        )?;
+        Ok(self.activate(subscription_id, None))
+    }
+
+    fn send_subscribe(
+        &self,
+        options: ConversionOptions,
+        timeout: Option<Duration>,
+        next: NextCallback,
+        decode: Option<DecodeCallback>,
+        complete: CompleteCallback,
+    ) -> Result<i32, ServiceError> {
+        let logger = self
+            .query
+            .0
+            .payload_logger
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        // The logger and the conversion options need the JSON payload.
+        let decode = decode.filter(|_| logger.is_none() && options.is_native());
+        let next: NextCallback = match logger {
+            Some(logger) => {
+                let mut next = next;
+                Box::new(move |payload| {
+                    logger(&payload);
+                    next(payload)
+                })
+            }
+            None => next,
+        };
+
+        let (tx, rx) = mpsc::channel();
+        self.query.0.send(ServiceCommand::Subscribe {
+            query_id: self.query.1,
+            operation_name: self.operation_name.clone(),
+            variables: self.variables.clone(),
+            options,
+            next,
+            stream: None,
+            decode,
+            complete,
+            tx_result: tx,
+        })?;
+        match timeout {
+            Some(timeout) => self.query.0.recv_timeout(rx, timeout)?,
+            None => self.query.0.recv(rx)?,
+        }
+    }
+
+    /// Hand the subscription started by the last call to [start](Subscription::start) over to
+    /// the caller, so dropping the [Subscription] no longer unsubscribes it.
+    fn detach(mut self) -> i32 {
+        self.active
+            .take()
+            .map(|active| active.subscription_id.swap(0, Ordering::AcqRel))
+            .unwrap_or_default()
+    }
+
+    fn activate(
+        &mut self,
+        subscription_id: i32,
+        shared: Option<(SharedKey, u64)>,
+    ) -> SubscriptionHandle {
+        let active = Arc::new(ActiveSubscription {
+            service: self.query.0.clone(),
+            subscription_id: AtomicI32::new(subscription_id),
+            shared,
+        });
+        self.active = Some(active.clone());
+        SubscriptionHandle(active)
+    }
+
+    /// Replace the `variables` for the [Subscription] and start listening again, like
+    /// [listen](Subscription::listen), without parsing the [ParsedQuery] again.
+    ///
+    /// The worker thread handles commands in order, so the previous run is always removed and
+    /// its `complete` is called before the new run starts delivering payloads to `next`.
+    pub fn listen_with<N: PayloadSink>(
+        &mut self,
+        variables: &str,
+        next: N,
+        complete: mpsc::Sender<()>,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        self.unsubscribe()?;
+        self.variables = variables.into();
+        self.listen(next, complete)
+    }
+
+    /// Same as [listen_with](Subscription::listen_with), but serialize the `variables` to JSON
+    /// with [serde_json](https://docs.rs/serde_json), e.g. from a `&serde_json::Value`.
+    ///
+    /// If the `variables` cannot be serialized, or if they are not a JSON object, it will return
+    /// an [Err(ServiceError)](Err) without removing the previous run.
+    pub fn listen_with_variables<V: Serialize, N: PayloadSink>(
+        &mut self,
+        variables: &V,
+        next: N,
+        complete: mpsc::Sender<()>,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        let variables = serialize_variables(variables)?;
+        self.listen_with(&variables, next, complete)
+    }
+
+    /// Replace the `variables` for a [Subscription] which is already listening, without a gap
+    /// where events could be missed.
+    ///
+    /// gqlmapi fixes the variables of a subscription when it is registered, so they cannot be
+    /// changed in place. Instead, unlike [listen_with](Subscription::listen_with), this starts
+    /// listening with the new `variables` first, and only then removes the previous run, which
+    /// calls its own `complete`. Events delivered while both are registered may reach both sets
+    /// of channels. If the new run fails to start, the previous run keeps listening.
+    pub fn update_variables<N: PayloadSink>(
+        &mut self,
+        variables: &str,
+        next: N,
+        complete: mpsc::Sender<()>,
+    ) -> Result<SubscriptionHandle, ServiceError> {
+        let previous = self.active.take();
+        let previous_completed = self.completed.clone();
+        let previous_variables = std::mem::replace(&mut self.variables, variables.into());
+
+        match self.listen(next, complete) {
+            Ok(handle) => {
+                if let Some(previous) = previous {
+                    previous.unsubscribe()?;
+                }
+                Ok(handle)
+            }
+            Err(err) => {
+                self.active = previous;
+                self.completed = previous_completed;
+                self.variables = previous_variables;
+                Err(err)
+            }
+        }
+    }
+
+    /// Start listening to the [Subscription] and collect every `next` payload until `complete`
+    /// is called, then unsubscribe and return the payloads.
+    ///
+    /// A `Subscription` operation only calls `complete` once it is removed, so use
+    /// [collect_timeout](Subscription::collect_timeout) for those instead.
+    pub fn collect(&mut self) -> Result<Vec<String>, ServiceError> {
+        self.collect_until(None)
+    }
+
+    /// Like [collect](Subscription::collect), but if `complete` is not called within `timeout`,
+    /// stop early, unsubscribe, and return the payloads that were delivered so far.
+    pub fn collect_timeout(&mut self, timeout: Duration) -> Result<Vec<String>, ServiceError> {
+        self.collect_until(Some(timeout))
+    }
+
+    /// Evaluate a `Query` or `Mutation` operation like [run](MAPIGraphQL::run), and return the
+    /// value at the [JSON Pointer](https://www.rfc-editor.org/rfc/rfc6901) `pointer` in its
+    /// payload, e.g. `/data/__schema/queryType/name`, without deserializing anything else.
+    ///
+    /// If `pointer` does not resolve, e.g. because the request returned `errors` instead of
+    /// `data`, this returns an [Err(ServiceError)](Err) with the whole payload.
+    pub fn run_field(&mut self, pointer: &str) -> Result<serde_json::Value, ServiceError> {
+        let payload = self.run_once()?;
+        let mut value: serde_json::Value = serde_json::from_str(&payload)
+            .map_err(|err| format!("Error parsing payload: {err}"))?;
+        value
+            .pointer_mut(pointer)
+            .map(serde_json::Value::take)
+            .ok_or_else(|| {
+                ServiceError::Other(format!("No value at {pointer} in the payload: {payload}"))
+            })
+    }
+
+    /// Evaluate a `Query` or `Mutation` operation like [run_field](Subscription::run_field), but
+    /// take the `Stream` value at the [JSON Pointer](https://www.rfc-editor.org/rfc/rfc6901)
+    /// `pointer` out of the payload, e.g. `/data/item/attachments/0/stream` for the content of a
+    /// `FileAttachment`, and send its raw bytes to `chunks` instead of embedding them in the JSON.
+    /// Returns the rest of the payload, with `null` in place of the `Stream` value.
+    ///
+    /// gqlmapi encodes a `Stream` as base64, which the worker thread decodes into chunks of up to
+    /// 48 KiB, so neither the JSON escaping nor a second copy of the whole content is ever built.
+    /// gqlmapi still reads the whole value into its result before the first chunk is sent, so
+    /// read `chunks` on another thread to keep only that and a few decoded chunks in memory.
+    /// `chunks` is dropped once the payload has been evaluated, which ends the stream.
+    ///
+    /// If `pointer` does not resolve to a string, or it is not valid base64, this returns an
+    /// [Err(ServiceError)](Err), and any chunks which were already sent are incomplete.
+    pub fn run_stream(
+        &mut self,
+        pointer: &str,
+        chunks: mpsc::Sender<Vec<u8>>,
+    ) -> Result<serde_json::Value, ServiceError> {
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        let complete = self.track_complete(complete_sender(tx_complete));
+        let pointer = pointer.to_owned();
+        self.start_typed(
+            TypedSender::new(move |payload: Result<serde_json::Value, String>| {
+                let result = payload.and_then(|mut payload| {
+                    send_stream(&mut payload, &pointer, &chunks)?;
+                    Ok(payload)
+                });
+                tx_next.send(result).map_err(|err| err.to_string())
+            }),
+            complete,
+        )?;
+
+        // `Query` and `Mutation` operations deliver their payload and call `complete` before the
+        // worker replies to `listen`, so anything else must be a `Subscription`.
+        if rx_complete.try_recv().is_err() {
+            return Err(ServiceError::Other(String::from(
+                "Cannot run a Subscription operation, use subscribe instead",
+            )));
+        }
+
+        Ok(rx_next.try_recv().map_err(map_try_recv_error)??)
+    }
+
+    /// Evaluate a `Query` or `Mutation` operation like [run](MAPIGraphQL::run), and parse the
+    /// whole payload into a [Response] like [execute_full](MAPIGraphQL::execute_full), for a
+    /// [ParsedQuery] which has already been parsed.
+    pub fn run_response(&mut self) -> Result<Response, ServiceError> {
+        let payload = self.run_once()?;
+        Ok(Response::from_payload(&payload)?)
+    }
+
+    fn collect_until(&mut self, timeout: Option<Duration>) -> Result<Vec<String>, ServiceError> {
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        self.listen(tx_next, tx_complete)?;
+
+        match timeout {
+            Some(timeout) => match rx_complete.recv_timeout(timeout) {
+                Ok(()) => self.unsubscribe()?,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    // Unsubscribing a `Subscription` operation calls `complete` once it is removed.
+                    self.unsubscribe()?;
+                    let _ = rx_complete.recv();
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(ServiceError::Other(String::from(
+                        "Error receiving message: complete disconnected",
+                    )));
+                }
+            },
+            None => {
+                rx_complete.recv().map_err(map_recv_error)?;
+                self.unsubscribe()?;
+            }
+        }
+
+        // Every `next` payload is sent before `complete`, so they are already in the channel.
+        Ok(rx_next.try_iter().collect())
+    }
+
+    /// Evaluate a `Query` or `Mutation` operation and return its single payload.
+    fn run_once(&mut self) -> Result<String, ServiceError> {
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        self.listen(tx_next, tx_complete)?;
+
+        // `Query` and `Mutation` operations deliver their payload and call `complete` before the
+        // worker replies to `listen`, so anything else must be a `Subscription`.
+        if rx_complete.try_recv().is_err() {
+            return Err(ServiceError::Other(String::from(
+                "Cannot run a Subscription operation, use subscribe instead",
+            )));
+        }
+
+        Ok(rx_next.try_recv().map_err(map_try_recv_error)?)
+    }
+
+    /// Evaluate a `Query` or `Mutation` operation and deserialize its single payload into a
+    /// [TypedResponse] on the worker thread, like [listen_typed](Subscription::listen_typed).
+    fn run_typed<T: DeserializeOwned + Send + 'static>(
+        &mut self,
+    ) -> Result<TypedResponse<T>, ServiceError> {
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        let complete = self.track_complete(complete_sender(tx_complete));
+        let sender = TypedSender {
+            parse: TypedResponse::from_payload,
+            ..TypedSender::new(move |result| tx_next.send(result).map_err(|err| err.to_string()))
+        };
+        self.start_typed(sender, complete)?;
+
+        // `Query` and `Mutation` operations deliver their payload and call `complete` before the
+        // worker replies to `listen`, so anything else must be a `Subscription`.
+        if rx_complete.try_recv().is_err() {
+            return Err(ServiceError::Other(String::from(
+                "Cannot run a Subscription operation, use subscribe instead",
+            )));
+        }
+
+        Ok(rx_next.try_recv().map_err(map_try_recv_error)??)
+    }
+
+    /// Evaluate a `Query` or `Mutation` operation and deserialize its single payload into `T` as
+    /// it is streamed from the worker thread.
+    fn run_streaming<T: DeserializeOwned>(&mut self) -> Result<T, ServiceError> {
+        self.unsubscribe()?;
+        let (writer, reader) = pipe::pipe();
+        let writer: Box<dyn Write + Send> = Box::new(BufWriter::new(writer));
+        let stream: PayloadStream = Arc::new(Mutex::new(Some(writer)));
+        let next_stream = stream.clone();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        let complete = self.track_complete(complete_sender(tx_complete));
+        let (tx, rx) = mpsc::channel();
+        self.query.0.send(ServiceCommand::Subscribe {
+            query_id: self.query.1,
+            operation_name: self.operation_name.clone(),
+            variables: self.variables.clone(),
+            options: self.conversion_options(),
+            // The Bindings stream the payload before calling next, but a backend which only
+            // produces JSON strings, like the mock, delivers it here instead.
+            next: Box::new(move |payload| {
+                let writer = next_stream
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .take();
+                match writer {
+                    Some(mut writer) => writer
+                        .write_all(payload.as_bytes())
+                        .map_err(|err| err.to_string()),
+                    None => Ok(()),
+                }
+            }),
+            stream: Some(stream),
+            decode: None,
+            complete,
+            tx_result: tx,
+        })?;
+
+        // The worker thread writes the payload before it replies, so read it first.
+        let result = serde_json::from_reader(reader)
+            .map_err(|err| format!("Error deserializing payload: {err}"));
+        let subscription_id = self.query.0.recv(rx)??;
+        self.activate(subscription_id, None);
+
+        if rx_complete.try_recv().is_err() {
+            self.unsubscribe()?;
+            return Err(ServiceError::Other(String::from(
+                "Cannot run a Subscription operation, use subscribe instead",
+            )));
+        }
+
+        Ok(result?)
+    }
+
+    fn subscription_id(&self) -> i32 {
+        self.active
+            .as_ref()
+            .map_or(0, |active| active.subscription_id.load(Ordering::Acquire))
+    }
+
+    fn unsubscribe(&mut self) -> Result<(), ServiceError> {
+        if let Some(active) = self.active.take() {
+            active.unsubscribe()?;
+        }
+        Ok(())
+    }
+}
+
+impl IntoIterator for Subscription {
+    type Item = Result<String, ServiceError>;
+    type IntoIter = SubscriptionIter;
+
+    /// Start listening to the [Subscription] and return a blocking [Iterator] over the `next`
+    /// payloads, which ends once `complete` is called. Dropping the [SubscriptionIter] drops the
+    /// [Subscription], which unsubscribes.
+    ///
+    /// If the request fails, the [Iterator] yields a single [Err(ServiceError)](Err).
+    fn into_iter(mut self) -> SubscriptionIter {
+        let (tx, rx) = mpsc::channel();
+        let tx_complete = tx.clone();
+        let options = self.conversion_options();
+        let complete = self.track_complete(Box::new(move || {
+            let _ = tx_complete.send(None);
+        }));
+        let error = self
+            .start(
+                options,
+                None,
+                Box::new(move |payload| tx.send(Some(payload)).map_err(|err| err.to_string())),
+                complete,
+            )
+            .err();
+
+        SubscriptionIter {
+            subscription: self,
+            rx,
+            error,
+            done: false,
+        }
+    }
+}
+
+/// Blocking [Iterator] over the `next` payloads of a [Subscription], returned from
+/// [into_iter](Subscription::into_iter).
+pub struct SubscriptionIter {
+    subscription: Subscription,
+    rx: mpsc::Receiver<Option<String>>,
+    error: Option<ServiceError>,
+    done: bool,
+}
+
+impl Iterator for SubscriptionIter {
+    type Item = Result<String, ServiceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            self.done = true;
+            return Some(Err(err));
+        }
+
+        if self.done {
+            return None;
+        }
+
+        match self.rx.recv() {
+            Ok(Some(payload)) => Some(Ok(payload)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(_) => {
+                self.done = true;
+                Some(Err(self.subscription.query.0.worker_gone()))
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    /// Cleanup a `Subscription` that was previously created with [subscribe](MAPIGraphQL::subscribe).
+    ///
+    /// This is a no-op for `Query` or `Mutation` requests since they deliver 1 immediate result.
+    ///
+    /// In debug builds, this also asserts that [listen](Subscription::listen) was called, since
+    /// dropping it without listening is almost always a mistake: the operation never ran.
+    fn drop(&mut self) {
+        debug_assert!(
+            self.listened || thread::panicking(),
+            "dropping a Subscription which never started listening: {self:?}"
+        );
+
+        self.unsubscribe().expect("Unable to unsubscribe");
+    }
+}
+
+fn map_send_error<T>(err: mpsc::SendError<T>) -> String {
+    format!("Error sending message: {}", err)
+}
+
+fn map_recv_error(err: mpsc::RecvError) -> String {
+    format!("Error receiving message: {}", err)
+}
+
+fn map_try_recv_error(err: mpsc::TryRecvError) -> String {
+    format!("Error receiving message: {}", err)
+}
+
+/// Describe why an empty operation name cannot select one of the `operation_names` in a request
+/// document, listing the names which could be used instead.
+fn operation_name_required(operation_names: &[String]) -> String {
+    let mut message = format!(
+        "operation name required: document defines {} operations",
+        operation_names.len()
+    );
+    if !operation_names.is_empty() {
+        let names: Vec<&str> = operation_names
+            .iter()
+            .map(|name| match name.as_str() {
+                "" => "<anonymous>",
+                name => name,
+            })
+            .collect();
+        message.push_str(&format!(" ({})", names.join(", ")));
+    }
+    message
+}
+
+/// Serialize `variables` to a JSON string, which must be an object since
+/// [GraphQL](https://graphql.org) variables are a map from names to values.
+fn serialize_variables<V: Serialize>(variables: &V) -> Result<String, String> {
+    match serde_json::to_value(variables) {
+        Ok(value @ serde_json::Value::Object(_)) => Ok(value.to_string()),
+        Ok(value) => Err(format!(
+            "Error serializing variables: expected a JSON object, found {value}"
+        )),
+        Err(err) => Err(format!("Error serializing variables: {err}")),
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, ServiceError> {
+    fs::read_to_string(path).map_err(|err| {
+        ServiceError::Io(io::Error::new(
+            err.kind(),
+            format!("failed to read {}: {err}", path.display()),
+        ))
+    })
+}
+
+/// Take the base64 `Stream` value at `pointer` out of `payload`, leaving `null` in its place, and
+/// send the decoded bytes to `chunks` for [run_stream](Subscription::run_stream).
+fn send_stream(
+    payload: &mut serde_json::Value,
+    pointer: &str,
+    chunks: &mpsc::Sender<Vec<u8>>,
+) -> Result<(), String> {
+    let value = payload
+        .pointer_mut(pointer)
+        .ok_or_else(|| format!("No value at {pointer} in the payload"))?;
+    // With tagged scalars, the string is wrapped in an object with a single SCALAR_TAG member.
+    let value = match value.get_mut(SCALAR_TAG) {
+        Some(tagged) => mem::take(tagged),
+        None => mem::take(value),
+    };
+    let serde_json::Value::String(encoded) = value else {
+        return Err(format!("The value at {pointer} is not a Stream"));
+    };
+    base64::decode_chunks(&encoded, |chunk| {
+        chunks
+            .send(chunk)
+            .map_err(|_| String::from("Error sending Stream chunk: receiver dropped"))
+    })
+}
+
+fn map_exception(err: cxx::Exception) -> String {
+    String::from(err.what())
+}
+
+#[cfg(test)]
+mod test {
+    extern crate serde;
+    use serde::{Deserialize, Serialize};
+
+    use crate::{
+        backend::Backend, ffi, CancelToken, ComApartment, ComApartmentGuard, MAPIGraphQL,
+        ParsedQuery, PayloadSink, QueryError, Response, SchemaDiff, Service, ServiceCommand,
+        ServiceError, ServiceOptions, Subscription, SubscriptionCounters, SubscriptionEvent,
+        SubscriptionHandle, SubscriptionInfo, SubscriptionStats, TypedStream,
+    };
+    use std::{
+        env, fs,
+        future::Future,
+        pin::pin,
+        process,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc, Arc, Mutex,
+        },
+        task::{Context, Poll, Wake, Waker},
+        thread,
+        time::{Duration, Instant},
+    };
+    use windows::Win32::{
+        Foundation::{LPARAM, WPARAM},
+        System::Threading::GetCurrentThreadId,
+        UI::WindowsAndMessaging::{PostThreadMessageW, WM_USER},
+    };
+
+    const _: () = {
+        const fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MAPIGraphQL>();
+        assert_send_sync::<ParsedQuery>();
+        assert_send_sync::<Subscription>();
+        assert_send_sync::<SubscriptionHandle>();
+        assert_send_sync::<CancelToken>();
+        assert_send_sync::<TypedStream<Result<String, String>>>();
+        #[cfg(feature = "metrics")]
+        assert_send_sync::<crate::MetricsSnapshot>();
+        assert_send_sync::<Response>();
+        assert_send_sync::<SubscriptionInfo>();
+        assert_send_sync::<SubscriptionStats>();
+    };
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    struct OperationType {
+        name: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    struct TypeKind {
+        kind: String,
+        name: String,
+    }
+
+    #[allow(non_snake_case)]
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    struct Schema {
+        queryType: OperationType,
+        mutationType: OperationType,
+        subscriptionType: OperationType,
+        types: Vec<TypeKind>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    struct Data {
+        __schema: Schema,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    struct IntrospectionResults {
+        data: Data,
+    }
+
+    fn operation_names_by_id(
+        gqlmapi: &MAPIGraphQL,
+        query_id: i32,
+    ) -> Result<Vec<String>, ServiceError> {
+        let (tx, rx) = mpsc::channel();
+        gqlmapi.0.send(ServiceCommand::OperationNames {
+            query_id,
+            tx_result: tx,
+        })?;
+        Ok(gqlmapi.0.recv(rx)??)
+    }
+
+    #[test]
+    fn parse_batch_error() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let queries = gqlmapi
+            .parse_queries(&["{ __typename }", "{ stores { id } }"])
+            .expect("parses both documents");
+        assert_eq!(queries.len(), 2, "returns a ParsedQuery per document");
+        assert_ne!(queries[0].1, queries[1].1, "query IDs are distinct");
+
+        let err = gqlmapi
+            .parse_queries(&["{ __typename }", "{ stores { id } }", "{ __typename"])
+            .expect_err("should fail to parse the last document")
+            .to_string();
+        assert!(err.starts_with("Error parsing query 2: "), "{err}");
+
+        // The failed batch was parsed right after the first one, so it used the next 2 ids.
+        let last_id = queries[1].1;
+        for query_id in [last_id + 1, last_id + 2] {
+            assert!(
+                operation_names_by_id(&gqlmapi, query_id).is_err(),
+                "should discard query {query_id} from the failed batch"
+            );
+        }
+        assert!(
+            queries[1].operation_names().is_ok(),
+            "should keep the queries from the first batch"
+        );
+    }
+
+    #[test]
+    fn reuse_cached_queries() {
+        let gqlmapi = MAPIGraphQL::new(true).with_query_cache(2);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let sent = gqlmapi.0.metrics.commands_sent.load(Ordering::Relaxed);
+        let cached = gqlmapi
+            .parse_query("  { __typename }\n")
+            .expect("parses the query");
+        assert!(
+            std::sync::Arc::ptr_eq(&query, &cached),
+            "should return the cached query"
+        );
+        assert_eq!(
+            gqlmapi.0.metrics.commands_sent.load(Ordering::Relaxed),
+            sent,
+            "should not parse the query again on the worker thread"
+        );
+    }
+
+    #[test]
+    fn evict_cached_queries() {
+        let gqlmapi = MAPIGraphQL::new(true).with_query_cache(1);
+        let query_id = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query")
+            .1;
+        assert!(
+            operation_names_by_id(&gqlmapi, query_id).is_ok(),
+            "should keep the cached query"
+        );
+
+        let other = gqlmapi
+            .parse_query("query Other { __typename }")
+            .expect("parses the other query");
+        assert_eq!(gqlmapi.1.lock().expect("locks the cache").entries.len(), 1);
+        let err = operation_names_by_id(&gqlmapi, query_id)
+            .expect_err("should discard the evicted query on the worker thread")
+            .to_string();
+        assert!(err.contains("Unknown queryId"), "{err}");
+        assert_eq!(other.operation_names(), Ok(vec![String::from("Other")]));
+
+        let reparsed = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the evicted query again");
+        assert_ne!(reparsed.1, query_id, "should parse the evicted query again");
+    }
+
+    #[test]
+    fn complete_previous_run_before_listen_with() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let (query, variables) = root_folders_query(&gqlmapi);
+        let mut subscription = gqlmapi.subscribe(query, "", &variables);
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_first_complete, rx_first_complete) = mpsc::channel();
+        subscription
+            .listen(tx_next, tx_first_complete)
+            .expect("subscribes to the subscription");
+        let first_id = subscription.subscription_id();
+
+        // The new run cannot deliver anything to `next` before the worker thread replies to its
+        // Subscribe command, so `complete` for the previous run must already be waiting.
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_second_complete, rx_second_complete) = mpsc::channel();
+        let handle = subscription
+            .listen_with(&variables, tx_next, tx_second_complete)
+            .expect("listens with the new variables");
+        assert_ne!(subscription.subscription_id(), first_id);
+        assert_eq!(
+            rx_first_complete.try_recv(),
+            Ok(()),
+            "should complete the previous run before the new one starts"
+        );
+        assert_eq!(gqlmapi.active_subscriptions().len(), 1);
+
+        handle.cancel().expect("cancels the new run");
+        rx_second_complete
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("should complete the new run");
+    }
+
+    #[test]
+    fn listen_in_chunks() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let results: serde_json::Value = serde_json::from_str(
+            &gqlmapi
+                .run("{ stores { id name } }", "", "")
+                .expect("runs the stores query"),
+        )
+        .expect("parses the results");
+        let stores = results["data"]["stores"]
+            .as_array()
+            .expect("should return a list of stores")
+            .clone();
+        assert!(!stores.is_empty(), "should have at least 1 store");
+
+        let query = gqlmapi
+            .parse_query("{ stores { id name } }")
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        subscription
+            .listen_chunked(1, tx_next, tx_complete)
+            .expect("runs the query");
+        rx_complete
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("should call complete");
+
+        let chunks: Vec<serde_json::Value> = rx_next
+            .try_iter()
+            .map(|payload| serde_json::from_str(&payload).expect("parses the chunk"))
+            .collect();
+        assert_eq!(
+            chunks.len(),
+            stores.len(),
+            "should deliver 1 chunk per store"
+        );
+        let mut items = Vec::new();
+        for chunk in chunks {
+            let object = chunk.as_object().expect("should be an object");
+            assert_eq!(object.len(), 1, "should only have data: {chunk}");
+            let data = chunk["data"].as_object().expect("should have data");
+            assert_eq!(data.len(), 1, "should only have stores: {chunk}");
+            let list = data["stores"].as_array().expect("should have a list");
+            assert_eq!(list.len(), 1, "should hold 1 item: {chunk}");
+            items.extend(list.iter().cloned());
+        }
+        assert_eq!(items, stores, "should deliver the chunks in order");
+    }
+
+    #[test]
+    fn iterate_subscription_payloads() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let mut payloads = Vec::new();
+        for payload in gqlmapi.subscribe(query.clone(), "", "") {
+            payloads.push(payload.expect("runs the query"));
+        }
+        assert_eq!(payloads, [r#"{"data":{"__typename":"Query"}}"#]);
+
+        let mut results = gqlmapi.subscribe(query, "", "[]").into_iter();
+        assert!(
+            results.next().is_some_and(|result| result.is_err()),
+            "should yield the error for invalid variables"
+        );
+        assert!(results.next().is_none(), "should end after the error");
+    }
+
+    #[test]
+    fn track_iterator_completion() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let mut payloads = gqlmapi.subscribe(query, "", "").into_iter();
+        assert_eq!(
+            payloads.by_ref().collect::<Result<Vec<_>, _>>(),
+            Ok(vec![String::from(r#"{"data":{"__typename":"Query"}}"#)])
+        );
+        assert!(
+            payloads.subscription.listened,
+            "should not assert after iterating"
+        );
+        assert!(
+            payloads.subscription.is_complete(),
+            "should complete after the last payload"
+        );
+    }
+
+    #[test]
+    fn fetch_introspection() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query(
+                r#"query {
+                    __schema {
+                        queryType {
+                            name
+                        }
+                        mutationType {
+                            name
+                        }
+                        subscriptionType {
+                            name
+                        }
+                        types {
+                            kind
+                            name
+                        }
+                    }
+                }"#,
+            )
+            .expect("parses the introspection query");
+        assert_ne!(0, query.1, "query ID is not 0");
+
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        subscription
+            .listen(tx_next, tx_complete)
+            .expect("subscribes to the query");
+        assert_ne!(
+            subscription.subscription_id(),
+            0,
+            "subscription ID is not 0"
+        );
+        let results = rx_next.recv().expect("should always receive a payload");
+        let results = serde_json::from_str::<IntrospectionResults>(&results)
+            .expect("payload should fit query");
+        rx_complete.recv().expect("should always call complete");
+
+        let expected = IntrospectionResults {
+            data: Data {
+                __schema: Schema {
+                    queryType: OperationType {
+                        name: "Query".into(),
+                    },
+                    mutationType: OperationType {
+                        name: "Mutation".into(),
+                    },
+                    subscriptionType: OperationType {
+                        name: "Subscription".into(),
+                    },
+                    types: vec![
+                        TypeKind {
+                            kind: "SCALAR".into(),
+                            name: "Boolean".into(),
+                        },
+                        TypeKind {
+                            kind: "SCALAR".into(),
+                            name: "Float".into(),
+                        },
+                        TypeKind {
+                            kind: "SCALAR".into(),
+                            name: "ID".into(),
+                        },
+                        TypeKind {
+                            kind: "SCALAR".into(),
+                            name: "Int".into(),
+                        },
+                        TypeKind {
+                            kind: "SCALAR".into(),
+                            name: "String".into(),
+                        },
+                        TypeKind {
+                            kind: "ENUM".into(),
+                            name: "__TypeKind".into(),
+                        },
+                        TypeKind {
+                            kind: "ENUM".into(),
+                            name: "__DirectiveLocation".into(),
+                        },
+                        TypeKind {
+                            kind: "OBJECT".into(),
+                            name: "__Schema".into(),
+                        },
+                        TypeKind {
+                            kind: "OBJECT".into(),
+                            name: "__Type".into(),
+                        },
+                        TypeKind {
+                            kind: "OBJECT".into(),
+                            name: "__Field".into(),
+                        },
+                        TypeKind {
+                            kind: "OBJECT".into(),
+                            name: "__InputValue".into(),
+                        },
+                        TypeKind {
+                            kind: "OBJECT".into(),
+                            name: "__EnumValue".into(),
+                        },
+                        TypeKind {
+                            kind: "OBJECT".into(),
+                            name: "__Directive".into(),
+                        },
+                        TypeKind {
+                            kind: "SCALAR".into(),
+                            name: "DateTime".into(),
+                        },
+                        TypeKind {
+                            kind: "SCALAR".into(),
+                            name: "Guid".into(),
+                        },
+                        TypeKind {
+                            kind: "SCALAR".into(),
+                            name: "Stream".into(),
+                        },
+                        TypeKind {
+                            kind: "ENUM".into(),
+                            name: "SpecialFolder".into(),
+                        },
+                        TypeKind {
+                            kind: "ENUM".into(),
+                            name: "PropType".into(),
+                        },
+                        TypeKind {
+                            kind: "INPUT_OBJECT".into(),
                             name: "ObjectId".into(),
                         },
                         TypeKind {
@@ -704,6 +4569,2651 @@ mod test {
                 },
             },
         };
-        assert_eq!(results, expected, "results should match expected snapshot");
+        assert_eq!(results, expected, "results should match expected snapshot");
+    }
+
+    #[test]
+    fn verify_schema_snapshot() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let sdl = gqlmapi.schema_sdl().expect("renders the schema");
+        assert!(sdl.contains("type Store {"), "{sdl}");
+        assert!(sdl.contains("union PropValue = "), "{sdl}");
+
+        let diff = gqlmapi.verify_schema(&sdl).expect("introspects the schema");
+        assert!(diff.is_empty(), "{diff:?}");
+
+        let expected = format!(
+            "{}\ntype Removed {{ id: ID! }}\n",
+            sdl.replace("type Store {", "type Store {\n  changed: Int")
+                .replace("scalar Stream", "")
+        );
+        let diff = gqlmapi
+            .verify_schema(&expected)
+            .expect("introspects the schema");
+        assert_eq!(
+            diff,
+            SchemaDiff {
+                added: vec![String::from("Stream")],
+                removed: vec![String::from("Removed")],
+                changed: vec![String::from("Store")],
+            }
+        );
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+    enum SpecialFolder {
+        Inbox,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    struct SpecialFolderVariables {
+        ids: Vec<SpecialFolder>,
+    }
+
+    #[test]
+    fn subscribe_with_typed_variables() {
+        let variables = SpecialFolderVariables {
+            ids: vec![SpecialFolder::Inbox],
+        };
+        let json = serde_json::to_string(&variables).expect("serializes the variables");
+        assert_eq!(json, r#"{"ids":["INBOX"]}"#, "enum serializes as a string");
+        assert_eq!(
+            serde_json::from_str::<SpecialFolderVariables>(&json).expect("round-trips"),
+            variables,
+            "variables should round-trip"
+        );
+
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query(
+                r#"query ($ids: [SpecialFolder!]!) {
+                    stores {
+                        specialFolders(ids: $ids) {
+                            name
+                        }
+                    }
+                }"#,
+            )
+            .expect("parses the special folders query");
+        let mut subscription = gqlmapi
+            .subscribe_with(query, "", &variables)
+            .expect("serializes the variables");
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        subscription
+            .listen(tx_next, tx_complete)
+            .expect("subscribes to the query");
+        let results = rx_next.recv().expect("should always receive a payload");
+        let results =
+            serde_json::from_str::<serde_json::Value>(&results).expect("payload should be JSON");
+        rx_complete.recv().expect("should always call complete");
+        assert!(results.get("errors").is_none(), "should not return errors");
+        assert!(
+            results["data"]["stores"].is_array(),
+            "should return a list of stores"
+        );
+    }
+
+    #[test]
+    fn match_enum_variables_and_literals() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let literal = gqlmapi
+            .run(
+                r#"{
+                    stores {
+                        specialFolders(ids: [INBOX, OUTBOX]) {
+                            specialFolder
+                        }
+                    }
+                }"#,
+                "",
+                "",
+            )
+            .expect("runs the query with enum literals");
+        let variables = gqlmapi
+            .run(
+                r#"query ($ids: [SpecialFolder!]!) {
+                    stores {
+                        specialFolders(ids: $ids) {
+                            specialFolder
+                        }
+                    }
+                }"#,
+                "",
+                r#"{ "ids": ["INBOX", "OUTBOX"] }"#,
+            )
+            .expect("runs the query with enum variables");
+        assert!(!literal.contains(r#""errors""#), "{literal}");
+        assert_eq!(
+            variables, literal,
+            "should coerce the strings to enum values"
+        );
+
+        let rejected = match gqlmapi.run(
+            r#"query ($ids: [SpecialFolder!]!) {
+                    stores {
+                        specialFolders(ids: $ids) {
+                            specialFolder
+                        }
+                    }
+                }"#,
+            "",
+            r#"{ "ids": ["NOT_A_FOLDER"] }"#,
+        ) {
+            Ok(payload) => payload.contains(r#""errors""#),
+            Err(_) => true,
+        };
+        assert!(rejected, "should reject unknown enum values");
+    }
+
+    #[test]
+    fn run_field_by_pointer() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __schema { queryType { name } } }")
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        assert_eq!(
+            subscription
+                .run_field("/data/__schema/queryType/name")
+                .expect("resolves the pointer"),
+            serde_json::json!("Query")
+        );
+        let err = subscription
+            .run_field("/data/__schema/mutationType/fields")
+            .err()
+            .expect("should not resolve a missing field")
+            .to_string();
+        assert!(
+            err.starts_with("No value at /data/__schema/mutationType/fields"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn subscribe_with_channels() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let (_subscription, rx_next, rx_complete) = gqlmapi
+            .subscribe_channels(query, "", "")
+            .expect("listens to the query");
+        rx_complete.recv().expect("completes the query");
+        assert_eq!(
+            rx_next.try_iter().collect::<Vec<_>>(),
+            vec![r#"{"data":{"__typename":"Query"}}"#]
+        );
+    }
+
+    #[test]
+    fn preserve_selection_order() {
+        let results = MAPIGraphQL::new(true)
+            .run(
+                r#"{ __type(name: "Query") { name kind } __schema { queryType { name } } }"#,
+                "",
+                "",
+            )
+            .expect("runs the introspection query");
+        assert_eq!(
+            results,
+            r#"{"data":{"__type":{"name":"Query","kind":"OBJECT"},"__schema":{"queryType":{"name":"Query"}}}}"#,
+            "members should follow the selection set instead of sorting"
+        );
+    }
+
+    #[test]
+    fn run_full_response() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        let response = subscription.run_response().expect("runs the query");
+        assert_eq!(response.data, serde_json::json!({ "__typename": "Query" }));
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+
+        let query = gqlmapi
+            .parse_query("query ($name: String!) { __type(name: $name) { name } }")
+            .expect("parses the query");
+        let response = gqlmapi
+            .subscribe(query, "", "")
+            .run_response()
+            .expect("runs the query");
+        assert!(
+            !response.errors.is_empty(),
+            "should report the missing variable"
+        );
+    }
+
+    #[test]
+    fn fail_promptly_after_stop() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        gqlmapi
+            .0
+            .send(ServiceCommand::Stop)
+            .expect("sends the stop command");
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(gqlmapi.parse_query("{ __typename }").err());
+        });
+        let err = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should fail promptly instead of blocking")
+            .expect("should fail to parse after the service stops")
+            .to_string();
+        assert!(err.starts_with("WorkerGone"), "{err}");
+    }
+
+    #[test]
+    fn initialize_multithreaded_apartment() {
+        let gqlmapi = MAPIGraphQL::new_with_options(ServiceOptions {
+            use_default_profile: true,
+            apartment: ComApartment::MultiThreaded,
+            ..Default::default()
+        });
+        let results = gqlmapi
+            .run("{ __typename }", "", "")
+            .expect("runs the query in the MTA");
+        assert_eq!(results, r#"{"data":{"__typename":"Query"}}"#);
+    }
+
+    #[test]
+    fn report_parse_error_location() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let err = gqlmapi
+            .parse_query("{\n  stores {\n    id\n  }")
+            .err()
+            .expect("should fail to parse the malformed query");
+        assert!(err.line.is_some() && err.column.is_some(), "{err}");
+        assert!(!err.message.is_empty(), "{err}");
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct ThreadWaker(thread::Thread);
+
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut context = Context::from_waker(&waker);
+        let mut future = pin!(future);
+
+        loop {
+            match future.as_mut().poll(&mut context) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_query_async() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = block_on(gqlmapi.parse_query_async("{ __typename }"))
+            .expect("parses the query without blocking");
+        let results = gqlmapi
+            .subscribe(query, "", "")
+            .collect()
+            .expect("runs the query");
+        assert_eq!(results, vec![r#"{"data":{"__typename":"Query"}}"#]);
+
+        let err = block_on(gqlmapi.parse_query_async("{ __typename"))
+            .err()
+            .expect("should fail to parse the malformed query");
+        assert!(err.line.is_some(), "{err}");
+    }
+
+    #[test]
+    fn discard_cancelled_async_parse() {
+        struct NoopWaker;
+
+        impl Wake for NoopWaker {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        let gqlmapi = MAPIGraphQL::new(true);
+        let before = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let (tx_release, rx_release) = mpsc::channel();
+        gqlmapi
+            .0
+            .send(ServiceCommand::Block(rx_release))
+            .expect("blocks the worker");
+        let mut parse = Box::pin(gqlmapi.parse_query_async("query Cancelled { __typename }"));
+        let waker = Waker::from(Arc::new(NoopWaker));
+        assert!(parse
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
+        tx_release.send(()).expect("releases the worker");
+
+        // Once this round-trip returns, the worker has replied to the parse as well.
+        before.operation_names().expect("round-trips to the worker");
+        drop(parse);
+
+        // Query ids count up from the highest one still parsed, so a leaked id would be skipped.
+        let after = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        assert_eq!(
+            after.1,
+            before.1 + 1,
+            "should discard the cancelled query_id"
+        );
+
+        // Dropping the future before the worker replies leaves the query_id to the worker.
+        let (tx_release, rx_release) = mpsc::channel();
+        gqlmapi
+            .0
+            .send(ServiceCommand::Block(rx_release))
+            .expect("blocks the worker");
+        let mut parse = Box::pin(gqlmapi.parse_query_async("query Dropped { __typename }"));
+        assert!(parse
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
+        drop(parse);
+        tx_release.send(()).expect("releases the worker");
+        before.operation_names().expect("round-trips to the worker");
+
+        let last = gqlmapi
+            .parse_query("query Last { __typename }")
+            .expect("parses the query");
+        assert_eq!(
+            last.1,
+            after.1 + 1,
+            "should discard the query_id parsed after the future was dropped"
+        );
+    }
+
+    #[test]
+    fn run_prepared_operation() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query(
+                r#"query Typename { __typename }
+                query Stores($ids: [ID!]) { stores(ids: $ids) { id } }"#,
+            )
+            .expect("parses the query");
+        assert!(
+            query.operation("").is_err(),
+            "should require a name for multiple operations"
+        );
+        assert!(
+            query.operation("Missing").is_err(),
+            "should reject an unknown operation"
+        );
+
+        let results = query
+            .operation("Typename")
+            .expect("finds the operation")
+            .run()
+            .expect("runs the operation");
+        assert_eq!(results, r#"{"data":{"__typename":"Query"}}"#);
+
+        let results = query
+            .operation("Stores")
+            .expect("finds the operation")
+            .variables(&serde_json::json!({ "ids": null }))
+            .expect("serializes the variables")
+            .run()
+            .expect("runs the operation");
+        let results =
+            serde_json::from_str::<serde_json::Value>(&results).expect("payload should be JSON");
+        assert!(
+            results["data"]["stores"].is_array(),
+            "should return a list of stores"
+        );
+    }
+
+    #[test]
+    fn stream_typed_payloads() {
+        #[derive(Deserialize)]
+        struct Payload {
+            data: Typename,
+        }
+
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query.clone(), "", "");
+        let mut stream = subscription.stream_typed::<Payload>();
+        let payload = block_on(stream.recv())
+            .expect("should yield the payload")
+            .expect("deserializes the payload");
+        assert_eq!(payload.data.__typename, "Query");
+        assert!(
+            block_on(stream.recv()).is_none(),
+            "should end the stream on complete"
+        );
+
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        let mut stream = subscription.stream_typed::<Vec<u32>>();
+        let err = block_on(stream.recv())
+            .expect("should yield an item")
+            .err()
+            .expect("should fail to deserialize");
+        assert!(err.starts_with("Error deserializing payload"), "{err}");
+        assert!(block_on(stream.recv()).is_none());
+    }
+
+    #[test]
+    fn close_next_after_complete() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        subscription
+            .listen(tx_next, tx_complete)
+            .expect("runs the query");
+        rx_complete.recv().expect("should call complete");
+        rx_next.recv().expect("should deliver the payload");
+        assert_eq!(
+            rx_next.recv(),
+            Err(mpsc::RecvError),
+            "should close the only sender after complete"
+        );
+    }
+
+    #[test]
+    fn keep_next_open_with_a_clone() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        let (tx_next, rx_next) = mpsc::channel();
+        for _ in 0..2 {
+            let (tx_complete, rx_complete) = mpsc::channel();
+            subscription
+                .listen(tx_next.clone(), tx_complete)
+                .expect("runs the query");
+            rx_complete.recv().expect("should call complete");
+            assert_eq!(
+                rx_next.try_recv().as_deref(),
+                Ok(r#"{"data":{"__typename":"Query"}}"#)
+            );
+            assert_eq!(
+                rx_next.try_recv(),
+                Err(mpsc::TryRecvError::Empty),
+                "should keep the receiver open while the original sender is held"
+            );
+        }
+
+        drop(tx_next);
+        assert_eq!(rx_next.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn run_all_operations() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let results = gqlmapi
+            .run_all(
+                "query First { __typename } query Second { stores { id } }",
+                "",
+            )
+            .expect("runs every operation");
+        let names: Vec<_> = results.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["First", "Second"]);
+        assert_eq!(results[0].1, r#"{"data":{"__typename":"Query"}}"#);
+
+        let err = gqlmapi
+            .run_all(
+                "query First { __typename } query Broken { missingField } query Last { __typename }",
+                "",
+            )
+            .expect_err("should stop at the failed operation")
+            .to_string();
+        assert!(err.starts_with("Error running operation Broken:"), "{err}");
+    }
+
+    #[test]
+    fn keep_query_source() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("  { __typename }\n")
+            .expect("parses the query");
+        assert_eq!(query.source(), "  { __typename }\n");
+
+        let queries = gqlmapi
+            .parse_queries(&["{ __typename }", "{ stores { id } }"])
+            .expect("parses the queries");
+        let sources: Vec<_> = queries.iter().map(|query| query.source()).collect();
+        assert_eq!(sources, ["{ __typename }", "{ stores { id } }"]);
+    }
+
+    #[test]
+    fn run_persisted_query() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        gqlmapi
+            .register_query("typename", "{ __typename }")
+            .expect("registers the query");
+        let query_id = gqlmapi.2.lock().expect("locks the queries")["typename"].1;
+
+        for _ in 0..2 {
+            let results = gqlmapi
+                .run_persisted("typename", "", "")
+                .expect("runs the registered query");
+            assert_eq!(results, r#"{"data":{"__typename":"Query"}}"#);
+        }
+        // The worker thread assigns query ids in order, so if either run had parsed the request
+        // document again, the next query would not get the very next id.
+        let next = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        assert_eq!(
+            next.1,
+            query_id + 1,
+            "should not parse the query again on the worker thread"
+        );
+
+        let err = gqlmapi
+            .run_persisted("missing", "", "")
+            .expect_err("should not find an unregistered id");
+        assert_eq!(err.to_string(), "Unknown persisted query: missing");
+
+        gqlmapi
+            .register_query("typename", "query Replaced { __typename }")
+            .expect("replaces the query");
+        let results = gqlmapi
+            .run_persisted("typename", "Replaced", "")
+            .expect("runs the replacement");
+        assert_eq!(results, r#"{"data":{"__typename":"Query"}}"#);
+
+        gqlmapi.reset().expect("resets the service");
+        let err = gqlmapi
+            .run_persisted("typename", "Replaced", "")
+            .expect_err("should discard the registered queries");
+        assert_eq!(err.to_string(), "Unknown persisted query: typename");
+    }
+
+    #[test]
+    fn require_operation_name() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("query First { __typename } query Second { __typename }")
+            .expect("parses the query");
+        assert_eq!(
+            query.operation_names().expect("lists the operations"),
+            vec!["First", "Second"]
+        );
+
+        let err = gqlmapi
+            .subscribe(query.clone(), "", "")
+            .collect()
+            .expect_err("should require an operation name");
+        assert_eq!(
+            err.to_string(),
+            "operation name required: document defines 2 operations (First, Second)"
+        );
+        let err = query
+            .operation("")
+            .err()
+            .expect("should require an operation name");
+        assert_eq!(
+            err.to_string(),
+            "operation name required: document defines 2 operations (First, Second)"
+        );
+
+        let results = gqlmapi
+            .run("query Only { __typename }", "", "")
+            .expect("selects the only operation");
+        assert_eq!(results, r#"{"data":{"__typename":"Query"}}"#);
+    }
+
+    #[test]
+    fn complete_once_after_success() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        subscription
+            .listen(tx_next, tx_complete)
+            .expect("runs the query");
+        drop(subscription);
+
+        rx_complete.recv().expect("should call complete");
+        assert_eq!(
+            rx_complete.recv_timeout(Duration::from_secs(5)),
+            Err(mpsc::RecvTimeoutError::Disconnected),
+            "should only call complete once"
+        );
+    }
+
+    #[test]
+    fn close_complete_after_failure() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query, "", "not a JSON object");
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        subscription
+            .listen(tx_next, tx_complete)
+            .expect_err("should fail to parse the variables");
+
+        assert_eq!(
+            rx_complete.recv_timeout(Duration::from_secs(5)),
+            Err(mpsc::RecvTimeoutError::Disconnected),
+            "should close complete without calling it"
+        );
+        assert_eq!(
+            rx_next.recv_timeout(Duration::from_secs(5)),
+            Err(mpsc::RecvTimeoutError::Disconnected),
+            "should close next without calling it"
+        );
+    }
+
+    #[test]
+    fn run_pretty_query() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let results = gqlmapi
+            .run_pretty("{ __typename }", "", "")
+            .expect("runs the query");
+        assert_eq!(
+            results,
+            "{\n  \"data\": {\n    \"__typename\": \"Query\"\n  }\n}"
+        );
+    }
+
+    #[test]
+    fn tag_union_values_with_typename() {
+        #[derive(Deserialize)]
+        #[serde(tag = "__typename")]
+        enum PropValue {
+            StringValue {
+                string: String,
+            },
+            IntValue {
+                integer: i32,
+            },
+            #[serde(other)]
+            Other,
+        }
+
+        #[derive(Deserialize)]
+        struct Property {
+            value: PropValue,
+        }
+
+        #[derive(Deserialize)]
+        struct Store {
+            columns: Vec<Property>,
+        }
+
+        #[derive(Deserialize)]
+        struct Data {
+            stores: Vec<Store>,
+        }
+
+        #[derive(Deserialize)]
+        struct Payload {
+            data: Data,
+        }
+
+        let gqlmapi = MAPIGraphQL::new(true);
+        let results = gqlmapi
+            .run(
+                r#"{
+                    stores {
+                        columns {
+                            value {
+                                __typename
+                                ... on StringValue { string }
+                                ... on IntValue { integer }
+                            }
+                        }
+                    }
+                }"#,
+                "",
+                "",
+            )
+            .expect("runs the query");
+        let Payload { data } =
+            serde_json::from_str(&results).expect("deserializes the tagged union values");
+        let values: Vec<_> = data
+            .stores
+            .into_iter()
+            .flat_map(|store| store.columns)
+            .map(|property| property.value)
+            .collect();
+        assert!(
+            values.iter().any(
+                |value| matches!(value, PropValue::StringValue { string } if !string.is_empty())
+            ),
+            "should have a string property, e.g. the display name"
+        );
+        let integers: Vec<i32> = values
+            .iter()
+            .filter_map(|value| match value {
+                PropValue::IntValue { integer } => Some(*integer),
+                _ => None,
+            })
+            .collect();
+        assert!(
+            !integers.is_empty(),
+            "should have an integer property, e.g. the store flags"
+        );
+    }
+
+    #[test]
+    fn discard_after_last_clone() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let first = query.clone();
+        let second = query.clone();
+        drop(query);
+
+        assert_eq!(first.try_discard_now(), Ok(false), "should still be shared");
+        let results = gqlmapi
+            .subscribe(second.clone(), "", "")
+            .run_once()
+            .expect("runs the query");
+        assert_eq!(results, r#"{"data":{"__typename":"Query"}}"#);
+        assert_eq!(
+            second.try_discard_now(),
+            Ok(true),
+            "should discard the query"
+        );
+    }
+
+    #[test]
+    fn listen_typed_payloads() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Data {
+            __typename: String,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Payload {
+            data: Data,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct Mismatch {
+            #[allow(dead_code)]
+            missing: String,
+        }
+
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query.clone(), "", "");
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, _rx_complete) = mpsc::channel();
+        subscription
+            .listen_typed::<Payload>(tx_next, tx_complete)
+            .expect("runs the query");
+        assert_eq!(
+            rx_next.recv().expect("should get a payload"),
+            Ok(Payload {
+                data: Data {
+                    __typename: String::from("Query")
+                }
+            })
+        );
+
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, _rx_complete) = mpsc::channel();
+        subscription
+            .listen_typed::<Mismatch>(tx_next, tx_complete)
+            .expect("runs the query");
+        let err = rx_next
+            .recv()
+            .expect("should get a payload")
+            .expect_err("should not match the type");
+        assert!(err.starts_with("Error deserializing payload"), "{err}");
+    }
+
+    #[test]
+    fn listen_json_values() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        let (tx_errors, rx_errors) = mpsc::channel();
+        subscription
+            .listen_json(tx_next, tx_complete, tx_errors)
+            .expect("runs the query");
+
+        let value = rx_next.recv().expect("should get a value");
+        rx_complete.recv().expect("should call complete");
+        assert_eq!(
+            value,
+            serde_json::json!({ "data": { "__typename": "Query" } })
+        );
+        assert!(
+            rx_errors.try_recv().is_err(),
+            "should not report any parse errors"
+        );
+    }
+
+    #[test]
+    fn listen_with_payload_sink() {
+        struct CollectSink(Arc<Mutex<Vec<String>>>);
+
+        impl PayloadSink for CollectSink {
+            fn send(&self, payload: String) -> Result<(), String> {
+                self.0.lock().map_err(|err| err.to_string())?.push(payload);
+                Ok(())
+            }
+        }
+
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        let mut subscription = gqlmapi.subscribe(query.clone(), "", "");
+        let (tx_complete, rx_complete) = mpsc::channel();
+        subscription
+            .listen(CollectSink(collected.clone()), tx_complete)
+            .expect("runs the query");
+        rx_complete.recv().expect("should call complete");
+        assert_eq!(
+            *collected.lock().expect("locks the payloads"),
+            vec![String::from(r#"{"data":{"__typename":"Query"}}"#)]
+        );
+
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        let (tx_next, rx_next) = mpsc::sync_channel(1);
+        let (tx_complete, rx_complete) = mpsc::channel();
+        subscription
+            .listen(tx_next, tx_complete)
+            .expect("runs the query");
+        rx_complete.recv().expect("should call complete");
+        assert_eq!(
+            rx_next.recv().expect("should get a payload"),
+            r#"{"data":{"__typename":"Query"}}"#
+        );
+    }
+
+    #[test]
+    fn reject_oversized_payloads() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let fields: Vec<_> = (0..256).map(|i| format!("field{i}: __typename")).collect();
+        let query = format!("{{ {} }}", fields.join(" "));
+        let full = gqlmapi.run(&query, "", "").expect("runs the query");
+        assert!(full.len() > 4096, "should generate a large payload");
+
+        gqlmapi.set_max_payload_bytes(4096);
+        let response = gqlmapi
+            .execute_full(&query, "", "")
+            .expect("runs the query");
+        assert!(response.data.is_null(), "should not deliver any data");
+        assert_eq!(response.errors.len(), 1);
+        assert!(
+            response.errors[0].message.starts_with("PayloadTooLarge:"),
+            "{:?}",
+            response.errors
+        );
+        assert_eq!(
+            gqlmapi
+                .run("{ __typename }", "", "")
+                .expect("runs the query"),
+            r#"{"data":{"__typename":"Query"}}"#,
+            "should deliver payloads under the limit"
+        );
+
+        gqlmapi.set_max_payload_bytes(0);
+        assert_eq!(gqlmapi.run(&query, "", "").expect("runs the query"), full);
+    }
+
+    #[test]
+    fn listen_ordered_events() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        let (tx_events, rx_events) = mpsc::channel();
+        subscription
+            .listen_events(tx_events)
+            .expect("runs the query");
+
+        assert_eq!(
+            rx_events.recv().expect("should get the payload"),
+            SubscriptionEvent::Data(String::from(r#"{"data":{"__typename":"Query"}}"#))
+        );
+        assert_eq!(
+            rx_events.recv().expect("should call complete"),
+            SubscriptionEvent::Complete
+        );
+        assert!(rx_events.try_recv().is_err(), "should end with complete");
+        assert!(subscription.is_complete());
+    }
+
+    #[test]
+    fn end_events_with_error() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("query A { __typename } query B { __typename }")
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        let (tx_events, rx_events) = mpsc::channel();
+        let err = subscription
+            .listen_events(tx_events)
+            .expect_err("requires an operation name");
+        assert_eq!(
+            rx_events.recv().expect("should report the error"),
+            SubscriptionEvent::Error(err.to_string())
+        );
+        assert!(
+            rx_events.recv().is_err(),
+            "should not send anything after the error"
+        );
+    }
+
+    #[test]
+    fn build_with_options() {
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let logger = logged.clone();
+        let gqlmapi = MAPIGraphQL::builder()
+            .default_profile(true)
+            .retry(1, Duration::from_millis(100))
+            .payload_logger(move |payload| {
+                logger
+                    .lock()
+                    .expect("locks the log")
+                    .push(payload.to_owned());
+            })
+            .max_payload_bytes(8)
+            .build()
+            .expect("builds the service");
+
+        let results = gqlmapi
+            .run("{ __typename }", "", "")
+            .expect("runs the query");
+        assert!(results.contains("PayloadTooLarge"), "{results}");
+        assert_eq!(*logged.lock().expect("locks the log"), vec![results]);
+
+        let err = MAPIGraphQL::builder()
+            .profile("No such profile")
+            .build()
+            .err()
+            .expect("should not build with an unknown profile");
+        assert_eq!(err.to_string(), "Unknown profile: No such profile");
+    }
+
+    #[test]
+    fn log_raw_payloads() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let logger = logged.clone();
+        gqlmapi.set_payload_logger(move |payload| {
+            logger
+                .lock()
+                .expect("locks the log")
+                .push(payload.to_owned());
+        });
+
+        let results = gqlmapi
+            .run("{ __typename }", "", "")
+            .expect("runs the query");
+        assert_eq!(*logged.lock().expect("locks the log"), vec![results]);
+
+        gqlmapi.clear_payload_logger();
+        gqlmapi
+            .run("{ __typename }", "", "")
+            .expect("runs the query");
+        assert_eq!(logged.lock().expect("locks the log").len(), 1);
+    }
+
+    #[test]
+    fn format_ids_for_debugging() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("query Typename { __typename }")
+            .expect("parses the query");
+        assert_eq!(
+            format!("{query:?}"),
+            format!("ParsedQuery {{ query_id: {} }}", query.1)
+        );
+
+        let subscription = gqlmapi.subscribe(query.clone(), "Typename", "");
+        assert_eq!(
+            format!("{subscription:?}"),
+            format!(
+                r#"Subscription {{ query_id: {}, subscription_id: 0, operation_name: "Typename", .. }}"#,
+                query.1
+            )
+        );
+        assert!(format!("{gqlmapi:?}").starts_with("MAPIGraphQL { thread_id: "));
+    }
+
+    #[test]
+    fn flag_unlistened_subscriptions() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        assert!(!subscription.listened, "should start out unlistened");
+
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_complete, _rx_complete) = mpsc::channel();
+        subscription
+            .listen(tx_next, tx_complete)
+            .expect("runs the query");
+        assert!(subscription.listened, "should not assert after listen");
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "dropping a Subscription which never started listening")]
+    fn assert_unlistened_subscription_drop() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        drop(gqlmapi.subscribe(query, "", ""));
+    }
+
+    #[test]
+    fn guard_double_start_and_stop() {
+        let bindings = ffi::make_bindings();
+        Service::start_service(&bindings, true, 0, Duration::ZERO).expect("starts the service");
+
+        let started = Instant::now();
+        let err = Service::start_service(&bindings, true, 2, Duration::from_secs(5))
+            .expect_err("should not start the service twice");
+        assert!(matches!(err, ServiceError::AlreadyStarted(_)), "{err}");
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "should not retry the second start"
+        );
+
+        bindings.stop_service().expect("stops the service");
+        bindings
+            .stop_service()
+            .expect("stopping it again does nothing");
+        Service::start_service(&bindings, true, 0, Duration::ZERO)
+            .expect("starts the service again after stopping it");
+        bindings.stop_service().expect("stops the service");
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn retry_start_after_failure() {
+        let backend = crate::mock::MockBackend::new(Default::default()).fail_start(1);
+        Service::start_service(&backend, true, 1, Duration::ZERO)
+            .expect("a failed start should not count as started");
+
+        let started = Instant::now();
+        let err = Service::start_service(&backend, true, 2, Duration::from_secs(5))
+            .expect_err("should not start the service twice");
+        assert!(matches!(err, ServiceError::AlreadyStarted(_)), "{err}");
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "should not retry the second start"
+        );
+
+        backend.stop_service().expect("stops the service");
+        Service::start_service(&backend, true, 0, Duration::ZERO)
+            .expect("starts the service again after stopping it");
+    }
+
+    #[test]
+    fn report_worker_failure() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        gqlmapi
+            .0
+            .send(ServiceCommand::Fail(String::from("forced failure")))
+            .expect("sends the command");
+        let err = gqlmapi
+            .parse_query("{ __typename }")
+            .err()
+            .expect("should fail to parse after the worker exits");
+        assert!(
+            err.message.starts_with("WorkerGone") && err.message.contains("forced failure"),
+            "error should describe the worker failure: {err}"
+        );
+    }
+
+    #[test]
+    fn stop_the_worker_after_a_callback_panics() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        gqlmapi.set_payload_logger(|_| panic!("forced panic"));
+        let err = gqlmapi
+            .run("{ __typename }", "", "")
+            .err()
+            .expect("should fail instead of delivering the payload");
+        assert!(
+            matches!(&err, ServiceError::Panicked(message) if message == "forced panic"),
+            "{err}"
+        );
+        let err = gqlmapi
+            .run("{ __typename }", "", "")
+            .err()
+            .expect("should fail after the worker exits");
+        assert!(
+            matches!(err, ServiceError::WorkerGone(_)),
+            "error should report that the worker is gone: {err}"
+        );
+    }
+
+    /// Poll `condition` until it holds, and fail the test if it does not within 5 seconds.
+    fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting until {what}");
+            thread::yield_now();
+        }
+    }
+
+    fn first_store_id(gqlmapi: &MAPIGraphQL) -> String {
+        let results = gqlmapi
+            .run("{ stores { id } }", "", "")
+            .expect("runs the stores query");
+        let results =
+            serde_json::from_str::<serde_json::Value>(&results).expect("payload should be JSON");
+        results["data"]["stores"][0]["id"]
+            .as_str()
+            .expect("should have at least 1 store")
+            .to_owned()
+    }
+
+    const ROOT_FOLDERS_SUBSCRIPTION: &str = r#"subscription ($storeId: ID!) {
+        rootFolders(storeId: $storeId) {
+            __typename
+        }
+    }"#;
+
+    /// Parse [ROOT_FOLDERS_SUBSCRIPTION], and the variables which bind it to the first store.
+    fn root_folders_query(gqlmapi: &MAPIGraphQL) -> (Arc<ParsedQuery>, String) {
+        let variables = serde_json::json!({ "storeId": first_store_id(gqlmapi) }).to_string();
+        let query = gqlmapi
+            .parse_query(ROOT_FOLDERS_SUBSCRIPTION)
+            .expect("parses the subscription");
+        (query, variables)
+    }
+
+    /// Listen to [ROOT_FOLDERS_SUBSCRIPTION] on the first store with a new pair of channels. Keep
+    /// the `next` receiver alive, or the subscription is cancelled by the first payload.
+    fn listen_root_folders(
+        gqlmapi: &MAPIGraphQL,
+    ) -> (
+        Subscription,
+        SubscriptionHandle,
+        mpsc::Receiver<String>,
+        mpsc::Receiver<()>,
+    ) {
+        let (query, variables) = root_folders_query(gqlmapi);
+        let mut subscription = gqlmapi.subscribe(query, "", &variables);
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        let handle = subscription
+            .listen(tx_next, tx_complete)
+            .expect("subscribes to the subscription");
+        (subscription, handle, rx_next, rx_complete)
+    }
+
+    #[test]
+    fn cancel_subscription_from_another_thread() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let (_subscription, handle, _rx_next, rx_complete) = listen_root_folders(&gqlmapi);
+        assert!(
+            rx_complete.try_recv().is_err(),
+            "should not call complete before cancel"
+        );
+
+        thread::spawn(move || handle.cancel())
+            .join()
+            .expect("joins the thread")
+            .expect("cancels the subscription");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should call complete after cancel");
+    }
+
+    #[test]
+    fn balance_com_apartment_guard() {
+        thread::spawn(|| {
+            let sta = ComApartmentGuard::initialize(ComApartment::SingleThreaded)
+                .expect("initializes COM");
+            let err = ComApartmentGuard::initialize(ComApartment::MultiThreaded)
+                .err()
+                .expect("should not switch the apartment of an initialized thread");
+            assert!(err.starts_with("Error initializing COM"), "{err}");
+
+            // A nested guard on the same apartment succeeds, and must be balanced separately.
+            let nested = ComApartmentGuard::initialize(ComApartment::SingleThreaded)
+                .expect("initializes COM again");
+            drop(nested);
+            assert!(
+                ComApartmentGuard::initialize(ComApartment::MultiThreaded).is_err(),
+                "should stay initialized until the outer guard drops"
+            );
+
+            drop(sta);
+            ComApartmentGuard::initialize(ComApartment::MultiThreaded)
+                .expect("should uninitialize COM when the guard drops");
+        })
+        .join()
+        .expect("joins the thread");
+    }
+
+    #[test]
+    fn run_query_from_files() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let dir = env::temp_dir().join(format!("gqlmapi-run-from-files-{}", process::id()));
+        fs::create_dir_all(&dir).expect("creates the directory");
+        let query_path = dir.join("query.graphql");
+        let variables_path = dir.join("variables.json");
+        fs::write(
+            &query_path,
+            "query ($name: String!) { __type(name: $name) { name } }",
+        )
+        .expect("writes the query");
+        fs::write(&variables_path, r#"{ "name": "Query" }"#).expect("writes the variables");
+
+        let results = gqlmapi
+            .run_from_files(&query_path, Some(&variables_path), "")
+            .expect("runs the query");
+        let results =
+            serde_json::from_str::<serde_json::Value>(&results).expect("payload should be JSON");
+        assert_eq!(results["data"]["__type"]["name"], "Query", "{results}");
+
+        let results = gqlmapi
+            .run_from_files(&query_path, None, "")
+            .expect("runs the query");
+        assert!(results.contains("errors"), "should require the variables");
+
+        let err = gqlmapi
+            .run_from_files(&dir.join("missing.graphql"), None, "")
+            .expect_err("should fail to read the query");
+        match err {
+            ServiceError::Io(err) => assert_eq!(err.kind(), std::io::ErrorKind::NotFound, "{err}"),
+            err => panic!("should fail with an io::Error: {err}"),
+        }
+
+        fs::remove_dir_all(&dir).expect("removes the directory");
+    }
+
+    #[derive(Deserialize)]
+    struct Typename {
+        __typename: String,
+    }
+
+    #[test]
+    fn query_and_deserialize() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let data: Typename = gqlmapi
+            .query("{ __typename }", "", &serde_json::json!({}))
+            .expect("runs the query");
+        assert_eq!(data.__typename, "Query");
+
+        match gqlmapi.query::<Typename, _>("{ missingField }", "", &serde_json::json!({})) {
+            Err(QueryError::GraphQL(errors)) => assert!(!errors.is_empty()),
+            result => panic!("should fail with GraphQL errors: {:?}", result.err()),
+        }
+
+        match gqlmapi.query::<Typename, _>("{ __typename", "", &serde_json::json!({})) {
+            Err(QueryError::Transport(_)) => (),
+            result => panic!("should fail to parse: {:?}", result.err()),
+        }
+
+        match gqlmapi.query::<Vec<String>, _>("{ __typename }", "", &serde_json::json!({})) {
+            Err(QueryError::Deserialize(err)) => {
+                assert!(err.starts_with("Error parsing results"), "{err}")
+            }
+            result => panic!("should fail to deserialize: {:?}", result.err()),
+        }
+    }
+
+    #[test]
+    fn query_through_json_payload() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let logger = logged.clone();
+        gqlmapi.set_payload_logger(move |payload| {
+            logger
+                .lock()
+                .expect("locks the log")
+                .push(payload.to_owned());
+        });
+
+        // The logger needs the JSON payload, so the data is parsed from it instead.
+        let data: Typename = gqlmapi
+            .query("{ __typename }", "", &serde_json::json!({}))
+            .expect("runs the query");
+        assert_eq!(data.__typename, "Query");
+        let logged = logged.lock().expect("locks the log").clone();
+        assert_eq!(logged.len(), 1);
+        assert!(logged[0].contains(r#""__typename":"Query""#), "{logged:?}");
+
+        match gqlmapi.query::<Vec<String>, _>("{ missingField }", "", &serde_json::json!({})) {
+            Err(QueryError::GraphQL(errors)) => assert!(!errors.is_empty()),
+            result => panic!("should fail with GraphQL errors: {:?}", result.err()),
+        }
+    }
+
+    #[test]
+    fn reject_mutation_errors() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        match gqlmapi.mutate::<Typename, _>(
+            "mutation ($storeId: ID!) { missingMutation(storeId: $storeId) }",
+            "",
+            &serde_json::json!({ "storeId": "missing" }),
+        ) {
+            Err(QueryError::GraphQL(errors)) => assert!(!errors.is_empty()),
+            result => panic!("should fail with GraphQL errors: {:?}", result.err()),
+        }
+
+        match gqlmapi.mutate::<Typename, _>("mutation { __typename }", "", &[1, 2]) {
+            Err(QueryError::Transport(err)) => {
+                assert!(err.starts_with("Error serializing variables"), "{err}")
+            }
+            result => panic!("should fail to serialize the variables: {:?}", result.err()),
+        }
+    }
+
+    #[test]
+    fn deserialize_mutation_data() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let data: Typename = gqlmapi
+            .mutate("mutation { __typename }", "", &serde_json::json!({}))
+            .expect("runs the mutation");
+        assert_eq!(data.__typename, "Mutation");
+    }
+
+    #[test]
+    fn send_idle_events() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let (query, variables) = root_folders_query(&gqlmapi);
+        let mut subscription = gqlmapi.subscribe(query, "", &variables);
+        let (tx, rx) = mpsc::channel();
+        let max_idle = Duration::from_millis(100);
+        subscription
+            .listen_events_keepalive(tx, max_idle)
+            .expect("subscribes to the subscription");
+
+        for _ in 0..2 {
+            assert_eq!(
+                rx.recv_timeout(Duration::from_secs(5)),
+                Ok(SubscriptionEvent::Idle(max_idle)),
+                "should keep sending Idle while nothing changes"
+            );
+        }
+
+        drop(subscription);
+        let last = rx
+            .iter()
+            .find(|event| !matches!(event, SubscriptionEvent::Idle(_)));
+        assert_eq!(last, Some(SubscriptionEvent::Complete));
+        assert!(
+            rx.recv_timeout(max_idle * 3).is_err(),
+            "should stop sending Idle after Complete"
+        );
+    }
+
+    #[test]
+    fn cancel_subscription_with_token() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let (query, variables) = root_folders_query(&gqlmapi);
+        let token = CancelToken::new();
+        let mut subscription = gqlmapi.subscribe(query.clone(), "", &variables);
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        subscription
+            .listen_with_token(tx_next, tx_complete, &token)
+            .expect("subscribes to the subscription");
+        assert!(
+            rx_complete.try_recv().is_err(),
+            "should not call complete before the token is cancelled"
+        );
+
+        let cancel = token.clone();
+        thread::spawn(move || cancel.cancel())
+            .join()
+            .expect("joins the thread")
+            .expect("cancels the token");
+        assert!(token.is_cancelled());
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should call complete after the token is cancelled");
+
+        let mut late = gqlmapi.subscribe(query.clone(), "", &variables);
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        late.listen_with_token(tx_next, tx_complete, &token)
+            .expect("subscribes to the subscription");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should cancel right away with a cancelled token");
+
+        let idle = CancelToken::new();
+        let mut watched = gqlmapi.subscribe(query, "", &variables);
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_complete, _rx_complete) = mpsc::channel();
+        watched
+            .listen_with_token(tx_next, tx_complete, &idle)
+            .expect("subscribes to the subscription");
+        let service = Arc::downgrade(&gqlmapi.0);
+        drop((watched, late, subscription, gqlmapi));
+        assert!(
+            service.upgrade().is_none(),
+            "a token which was never cancelled should not keep the service alive"
+        );
+        idle.cancel()
+            .expect("cancelling after the service is gone should do nothing");
+    }
+
+    #[test]
+    fn track_active_subscriptions() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let (subscription, handle, _rx_next, rx_complete) = listen_root_folders(&gqlmapi);
+
+        // Looking up the store runs a query first, which should not stay registered.
+        let active = gqlmapi.active_subscriptions();
+        assert_eq!(active.len(), 1, "should only register the subscription");
+        assert_eq!(active[0].id, subscription.subscription_id());
+        assert_eq!(active[0].operation_name, "");
+
+        handle.cancel().expect("cancels the subscription");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should call complete after cancel");
+        assert!(
+            gqlmapi.active_subscriptions().is_empty(),
+            "should unregister the subscription"
+        );
+    }
+
+    #[test]
+    fn time_out_subscribe_handshake() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let (query, variables) = root_folders_query(&gqlmapi);
+        let (tx_release, rx_release) = mpsc::channel();
+        gqlmapi
+            .0
+            .send(ServiceCommand::Block(rx_release))
+            .expect("blocks the worker thread");
+
+        let mut subscription = gqlmapi.subscribe(query, "", &variables);
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        let err = subscription
+            .listen_timeout(Duration::from_millis(100), tx_next, tx_complete)
+            .expect_err("should time out");
+        assert!(
+            matches!(err, ServiceError::Timeout(_)),
+            "unexpected error: {err}"
+        );
+
+        tx_release.send(()).expect("releases the worker thread");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should unsubscribe after the timeout");
+        assert!(
+            gqlmapi.active_subscriptions().is_empty(),
+            "should not leak the subscription"
+        );
+    }
+
+    #[test]
+    fn drop_service_before_subscriptions() {
+        for shutdown in [false, true] {
+            let gqlmapi = MAPIGraphQL::new(true);
+            let (query, variables) = root_folders_query(&gqlmapi);
+            let mut subscriptions = Vec::new();
+            let mut completions = Vec::new();
+            for _ in 0..8 {
+                let mut subscription = gqlmapi.subscribe(query.clone(), "", &variables);
+                let (tx_next, _rx_next) = mpsc::channel();
+                let (tx_complete, rx_complete) = mpsc::channel();
+                subscription
+                    .listen(tx_next, tx_complete)
+                    .expect("subscribes to the subscription");
+                subscriptions.push(subscription);
+                completions.push(rx_complete);
+            }
+            drop(query);
+
+            if shutdown {
+                gqlmapi.shutdown().expect("shuts down the service");
+            } else {
+                drop(gqlmapi);
+            }
+
+            // Drop the subscriptions concurrently after the MAPIGraphQL is gone.
+            let threads: Vec<_> = subscriptions
+                .into_iter()
+                .map(|subscription| thread::spawn(move || drop(subscription)))
+                .collect();
+            for thread in threads {
+                thread
+                    .join()
+                    .expect("should not panic in Subscription::drop");
+            }
+            for rx_complete in completions {
+                rx_complete
+                    .recv_timeout(Duration::from_secs(5))
+                    .expect("should call complete for every subscription");
+            }
+        }
+    }
+
+    #[test]
+    fn clone_across_threads() {
+        let gqlmapi = MAPIGraphQL::new(true).with_query_cache(4);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let gqlmapi = gqlmapi.clone();
+                thread::spawn(move || {
+                    gqlmapi
+                        .run("{ __typename }", "", "")
+                        .expect("runs the query")
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(
+                thread.join().expect("joins the thread"),
+                r#"{"data":{"__typename":"Query"}}"#
+            );
+        }
+
+        // The clones share the query cache and the worker thread.
+        let clone = gqlmapi.clone();
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let cached = clone
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        assert!(Arc::ptr_eq(&query, &cached), "should share the query cache");
+        assert!(
+            Arc::ptr_eq(&gqlmapi.0, &clone.0),
+            "should share the worker thread"
+        );
+
+        // Dropping the original leaves the session running for the clone.
+        drop(gqlmapi);
+        assert_eq!(
+            clone
+                .run("{ __typename }", "", "")
+                .expect("runs the query after the original drops"),
+            r#"{"data":{"__typename":"Query"}}"#
+        );
+    }
+
+    #[test]
+    fn subscribe_and_unsubscribe_raw() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let (query, variables) = root_folders_query(&gqlmapi);
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        let subscription_id = gqlmapi
+            .subscribe_raw(query, "", &variables, tx_next, tx_complete)
+            .expect("subscribes to the subscription");
+        assert_ne!(subscription_id, 0, "subscription ID is not 0");
+
+        // Nothing is left to unsubscribe it when it goes out of scope.
+        assert!(
+            rx_complete
+                .recv_timeout(Duration::from_millis(100))
+                .is_err(),
+            "should keep listening"
+        );
+        let active: Vec<_> = gqlmapi
+            .active_subscriptions()
+            .into_iter()
+            .map(|info| info.id)
+            .collect();
+        assert_eq!(active, vec![subscription_id]);
+
+        gqlmapi
+            .unsubscribe_raw(subscription_id)
+            .expect("unsubscribes the subscription");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should call complete after unsubscribe_raw");
+        assert!(gqlmapi.active_subscriptions().is_empty());
+    }
+
+    #[test]
+    fn drop_next_receiver() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        let (tx_next, rx_next) = mpsc::channel::<String>();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        drop(rx_next);
+        subscription
+            .listen(tx_next, tx_complete)
+            .expect("delivers to a dropped receiver without panicking");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should still call complete");
+        drop(subscription);
+        assert!(gqlmapi.active_subscriptions().is_empty());
+
+        // The worker thread survived, and it never hands out the same subscription id twice.
+        let (query, variables) = root_folders_query(&gqlmapi);
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_complete, _rx_complete) = mpsc::channel();
+        let first = gqlmapi
+            .subscribe_raw(query.clone(), "", &variables, tx_next, tx_complete)
+            .expect("subscribes to the subscription");
+        gqlmapi
+            .unsubscribe_raw(first)
+            .expect("unsubscribes the subscription");
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_complete, _rx_complete) = mpsc::channel();
+        let second = gqlmapi
+            .subscribe_raw(query, "", &variables, tx_next, tx_complete)
+            .expect("subscribes to the subscription again");
+        assert!(second > first, "should not reuse the subscription id");
+        gqlmapi
+            .unsubscribe_raw(second)
+            .expect("unsubscribes the subscription");
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn drop_next_receiver_while_subscribed() {
+        let gqlmapi = MAPIGraphQL::new_mock();
+        let query = gqlmapi
+            .parse_query("subscription { items }")
+            .expect("parses the subscription");
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        subscription
+            .listen(tx_next, tx_complete)
+            .expect("subscribes to the subscription");
+        let id = gqlmapi.active_subscriptions()[0].id;
+        gqlmapi
+            .push_mock_payload(id, r#"{"data":{"items":[]}}"#)
+            .expect("pushes the first payload");
+        assert_eq!(
+            rx_next.try_recv().as_deref(),
+            Ok(r#"{"data":{"items":[]}}"#)
+        );
+
+        // The next callback fails to deliver this one, and the worker loop unsubscribes it.
+        drop(rx_next);
+        gqlmapi
+            .push_mock_payload(id, r#"{"data":{"items":[1]}}"#)
+            .expect("pushes a payload to the dropped receiver");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should unsubscribe and call complete");
+        assert!(gqlmapi.active_subscriptions().is_empty());
+        assert_eq!(
+            gqlmapi
+                .push_mock_payload(id, "{}")
+                .map_err(|err| err.to_string()),
+            Err(String::from("Unknown subscriptionId")),
+            "should have unsubscribed in the backend"
+        );
+    }
+
+    #[test]
+    fn track_subscription_stats() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let before = Instant::now();
+        let (_subscription, handle, _rx_next, rx_complete) = listen_root_folders(&gqlmapi);
+        let id = gqlmapi.active_subscriptions()[0].id;
+
+        let stats = gqlmapi
+            .subscription_stats(id)
+            .expect("tracks the active subscription");
+        assert_eq!(stats.payloads_delivered, 0);
+        assert_eq!(stats.bytes_delivered, 0);
+        assert!(stats.elapsed <= before.elapsed(), "{stats:?}");
+        let later = gqlmapi
+            .subscription_stats(id)
+            .expect("still tracks the active subscription");
+        assert!(later.elapsed >= stats.elapsed, "{later:?}");
+
+        handle.cancel().expect("cancels the subscription");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should call complete after cancel");
+        assert_eq!(gqlmapi.subscription_stats(id), None);
+    }
+
+    #[test]
+    fn count_delivered_payloads() {
+        let counters = SubscriptionCounters::new();
+        counters.record(r#"{"data":{}}"#);
+        counters.record(r#"{"data":{"a":1}}"#);
+        let stats = counters.snapshot();
+        assert_eq!(stats.payloads_delivered, 2);
+        assert_eq!(stats.bytes_delivered, 11 + 16);
+        assert!(stats.elapsed <= counters.started.elapsed(), "{stats:?}");
+    }
+
+    #[test]
+    fn reset_subscriptions_and_queries() {
+        let gqlmapi = MAPIGraphQL::new(true).with_query_cache(4);
+        let (query, variables) = root_folders_query(&gqlmapi);
+        let mut subscription = gqlmapi.subscribe(query.clone(), "", &variables);
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        subscription
+            .listen(tx_next, tx_complete)
+            .expect("subscribes to the subscription");
+        assert_eq!(gqlmapi.active_subscriptions().len(), 1);
+
+        gqlmapi.reset().expect("resets the service");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should call complete after reset");
+        assert!(gqlmapi.active_subscriptions().is_empty());
+        assert!(
+            query.operation_names().is_err(),
+            "should discard the parsed query"
+        );
+
+        // The new query must not be discarded when the stale ParsedQuery and Subscription drop.
+        let fresh = gqlmapi
+            .parse_query(ROOT_FOLDERS_SUBSCRIPTION)
+            .expect("parses the subscription again");
+        assert!(!Arc::ptr_eq(&query, &fresh), "should clear the query cache");
+        drop(subscription);
+        drop(query);
+        assert!(fresh.operation_names().is_ok(), "should keep the new query");
+        assert_eq!(
+            gqlmapi
+                .run("{ __typename }", "", "")
+                .expect("runs the query after reset"),
+            r#"{"data":{"__typename":"Query"}}"#
+        );
+    }
+
+    #[test]
+    fn accept_variables_value() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("query ($name: String) { __typename }")
+            .expect("parses the query");
+        let err = gqlmapi
+            .subscribe_with(query.clone(), "", &serde_json::json!(["not", "a", "map"]))
+            .expect_err("should reject a list")
+            .to_string();
+        assert!(err.contains("expected a JSON object"), "{err}");
+
+        let mut subscription = gqlmapi
+            .subscribe_with(query, "", &serde_json::json!({ "name": "first" }))
+            .expect("accepts an object");
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, _rx_complete) = mpsc::channel();
+        subscription
+            .listen_with_variables(
+                &serde_json::json!({ "name": "second" }),
+                tx_next,
+                tx_complete,
+            )
+            .expect("runs the query");
+        assert_eq!(
+            rx_next.recv().expect("should get a payload"),
+            r#"{"data":{"__typename":"Query"}}"#
+        );
+    }
+
+    #[test]
+    fn check_health() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let (_subscription, _handle, _rx_next, rx_complete) = listen_root_folders(&gqlmapi);
+
+        gqlmapi.health_check().expect("should be healthy");
+        assert!(
+            rx_complete.try_recv().is_err(),
+            "should not interfere with the subscription"
+        );
+
+        gqlmapi
+            .0
+            .send(ServiceCommand::Stop)
+            .expect("sends the stop command");
+        let err = gqlmapi
+            .health_check()
+            .expect_err("should fail after the service stops");
+        assert!(matches!(err, ServiceError::WorkerGone(_)), "{err}");
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn count_metrics() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let before = gqlmapi.metrics();
+        gqlmapi
+            .run("{ __typename }", "", "")
+            .expect("runs the query");
+        // The worker thread counts the query as started after it completes, so wait for the reply
+        // to the next command before looking at the counters.
+        gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let after = gqlmapi.metrics();
+        assert_eq!(after.payloads_delivered, before.payloads_delivered + 1);
+        assert_eq!(
+            after.subscriptions_started,
+            before.subscriptions_started + 1
+        );
+        assert_eq!(after.active_subscriptions, before.active_subscriptions);
+        assert!(after.pump_kicks > before.pump_kicks, "{after:?}");
+
+        let (_subscription, handle, _rx_next, rx_complete) = listen_root_folders(&gqlmapi);
+        let listening = gqlmapi.metrics();
+        assert_eq!(
+            listening.active_subscriptions,
+            before.active_subscriptions + 1
+        );
+
+        handle.cancel().expect("cancels the subscription");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should call complete after cancel");
+        let cancelled = gqlmapi.metrics();
+        assert_eq!(cancelled.active_subscriptions, before.active_subscriptions);
+        assert_eq!(
+            cancelled.subscriptions_started,
+            listening.subscriptions_started
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn coalesce_pump_kicks() {
+        let gqlmapi = Arc::new(MAPIGraphQL::new(true));
+        let (tx_release, rx_release) = mpsc::channel();
+        gqlmapi
+            .0
+            .send(ServiceCommand::Block(rx_release))
+            .expect("blocks the worker thread");
+        wait_until("the worker picks up the block", || {
+            gqlmapi.pending_commands() == 0
+        });
+        let before = gqlmapi.metrics().pump_kicks;
+
+        let threads: Vec<_> = (0..16)
+            .map(|index| {
+                let gqlmapi = gqlmapi.clone();
+                thread::spawn(move || {
+                    gqlmapi
+                        .parse_uncached(&format!("query Burst{index} {{ __typename }}"))
+                        .expect("parses the query")
+                })
+            })
+            .collect();
+        wait_until("every thread sends its command", || {
+            gqlmapi.pending_commands() == 16
+        });
+        tx_release.send(()).expect("releases the worker thread");
+        for thread in threads {
+            thread.join().expect("joins the client thread");
+        }
+        let kicks = gqlmapi.metrics().pump_kicks - before;
+
+        // The kick for the block is still pending, unless the worker woke up and drained the
+        // block before it was sent, in which case the first command of the burst sends 1 more.
+        assert!(
+            kicks <= 1,
+            "should send at most 1 kick for the burst of 16: {kicks}"
+        );
+    }
+
+    #[test]
+    fn handle_commands_during_message_flood() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let thread_id = gqlmapi.worker_thread_id();
+        let flooding = Arc::new(AtomicBool::new(true));
+        let flood = {
+            let flooding = flooding.clone();
+            thread::spawn(move || {
+                while flooding.load(Ordering::Acquire) {
+                    let _ = unsafe {
+                        PostThreadMessageW(thread_id, WM_USER, WPARAM::default(), LPARAM::default())
+                    };
+                }
+            })
+        };
+
+        for _ in 0..16 {
+            let results = gqlmapi
+                .run("{ __typename }", "", "")
+                .expect("runs the query during the flood");
+            assert_eq!(results, r#"{"data":{"__typename":"Query"}}"#);
+        }
+        flooding.store(false, Ordering::Release);
+        flood.join().expect("joins the flooding thread");
+    }
+
+    #[test]
+    fn track_completion() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let query = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        assert!(!subscription.is_complete(), "should not start complete");
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_complete, _rx_complete) = mpsc::channel();
+        subscription
+            .listen(tx_next, tx_complete)
+            .expect("runs the query");
+        assert!(subscription.is_complete(), "queries complete immediately");
+
+        let (subscription, handle, _rx_next, rx_complete) = listen_root_folders(&gqlmapi);
+        assert!(!subscription.is_complete(), "should still be running");
+
+        handle.cancel().expect("cancels the subscription");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should call complete after cancel");
+        assert!(subscription.is_complete(), "should complete after cancel");
+    }
+
+    #[test]
+    fn subscribe_many_times_from_one_parse() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let (query, variables) = root_folders_query(&gqlmapi);
+        let mut first = gqlmapi.subscribe(query.clone(), "", &variables);
+        let mut second = gqlmapi.subscribe(query.clone(), "", &variables);
+        let (tx_first_next, _rx_first_next) = mpsc::channel();
+        let (tx_first_complete, rx_first_complete) = mpsc::channel();
+        let first_handle = first
+            .listen(tx_first_next, tx_first_complete)
+            .expect("subscribes the first subscription");
+        let (tx_second_next, _rx_second_next) = mpsc::channel();
+        let (tx_second_complete, rx_second_complete) = mpsc::channel();
+        second
+            .listen(tx_second_next, tx_second_complete)
+            .expect("subscribes the second subscription");
+
+        assert_ne!(first.subscription_id(), 0);
+        assert_ne!(second.subscription_id(), 0);
+        assert_ne!(
+            first.subscription_id(),
+            second.subscription_id(),
+            "should get distinct subscription ids for the same query"
+        );
+        assert_eq!(gqlmapi.active_subscriptions().len(), 2);
+
+        // Dropping the last outside reference defers the discard until the subscriptions drop.
+        assert_eq!(query.try_discard_now(), Ok(false), "should still be shared");
+
+        first_handle
+            .cancel()
+            .expect("cancels the first subscription");
+        rx_first_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should complete the first subscription");
+        assert!(
+            rx_second_complete.try_recv().is_err(),
+            "should not complete the second subscription"
+        );
+        let active = gqlmapi.active_subscriptions();
+        assert_eq!(active.len(), 1, "should keep the second subscription");
+        assert_eq!(active[0].id, second.subscription_id());
+
+        drop(first);
+        drop(second);
+        rx_second_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should complete the second subscription when it drops");
+    }
+
+    #[test]
+    fn update_variables_without_gap() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let (query, variables) = root_folders_query(&gqlmapi);
+        let mut subscription = gqlmapi.subscribe(query, "", &variables);
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_first_complete, rx_first_complete) = mpsc::channel();
+        subscription
+            .listen(tx_next, tx_first_complete)
+            .expect("subscribes to the subscription");
+        let first_id = subscription.subscription_id();
+
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_second_complete, rx_second_complete) = mpsc::channel();
+        subscription
+            .update_variables(&variables, tx_next, tx_second_complete)
+            .expect("updates the variables");
+        assert_ne!(
+            subscription.subscription_id(),
+            first_id,
+            "should subscribe again before the previous run is removed"
+        );
+        rx_first_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should complete the previous run");
+        assert!(
+            rx_second_complete.try_recv().is_err(),
+            "should keep the new run listening"
+        );
+        assert_eq!(gqlmapi.active_subscriptions().len(), 1);
+
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_third_complete, _rx_third_complete) = mpsc::channel();
+        let second_id = subscription.subscription_id();
+        subscription
+            .update_variables("not a JSON object", tx_next, tx_third_complete)
+            .expect_err("should fail to parse the variables");
+        assert_eq!(
+            subscription.subscription_id(),
+            second_id,
+            "should keep the previous run after a failure"
+        );
+        assert!(rx_second_complete.try_recv().is_err());
+    }
+
+    #[test]
+    fn share_identical_subscriptions() {
+        let gqlmapi = MAPIGraphQL::new(true).with_shared_subscriptions();
+        let (query, variables) = root_folders_query(&gqlmapi);
+        let mut first = gqlmapi.subscribe(query.clone(), "", &variables);
+        let mut second = gqlmapi.subscribe(query, "", &variables);
+        let (tx_next, _rx_next) = mpsc::channel();
+        let (tx_first, rx_first) = mpsc::channel();
+        let (tx_second, rx_second) = mpsc::channel();
+        first
+            .listen(tx_next.clone(), tx_first)
+            .expect("subscribes the first consumer");
+        second
+            .listen(tx_next, tx_second)
+            .expect("subscribes the second consumer");
+        assert_eq!(
+            gqlmapi.active_subscriptions().len(),
+            1,
+            "should share the underlying subscription"
+        );
+
+        drop(first);
+        rx_first
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should call complete for the first consumer");
+        assert_eq!(
+            gqlmapi.active_subscriptions().len(),
+            1,
+            "should keep the subscription for the second consumer"
+        );
+
+        drop(second);
+        rx_second
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should call complete for the last consumer");
+        assert!(gqlmapi.active_subscriptions().is_empty());
+    }
+
+    /// Leave the shared subscription it holds the first time it receives a payload, on the
+    /// worker thread.
+    struct LeaveOnNext {
+        held: Mutex<Option<Subscription>>,
+        tx_left: mpsc::Sender<()>,
+    }
+
+    impl PayloadSink for LeaveOnNext {
+        fn send(&self, _payload: String) -> Result<(), String> {
+            if let Some(held) = self.held.lock().expect("locks the held values").take() {
+                drop(held);
+                self.tx_left.send(()).map_err(|err| err.to_string())?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn leave_shared_subscription_inside_callback() {
+        let gqlmapi = MAPIGraphQL::new(true).with_shared_subscriptions();
+        let (held, _handle, _rx_next, rx_complete) = listen_root_folders(&gqlmapi);
+
+        // The shared listen below waits for the worker thread to subscribe, while the callback
+        // on the worker thread leaves the held shared subscription.
+        let (tx_left, rx_left) = mpsc::channel();
+        let sink = LeaveOnNext {
+            held: Mutex::new(Some(held)),
+            tx_left,
+        };
+        let mut subscription = gqlmapi.subscribe(
+            gqlmapi
+                .parse_query("{ __typename }")
+                .expect("parses the query"),
+            "",
+            "",
+        );
+        let (tx_complete_query, _rx_complete_query) = mpsc::channel();
+        subscription
+            .listen(sink, tx_complete_query)
+            .expect("runs the shared query");
+        rx_left
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should leave the shared subscription in the callback");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should unsubscribe the subscription it left");
+        assert!(gqlmapi.active_subscriptions().is_empty());
+    }
+
+    #[test]
+    fn complete_subscriptions_on_shutdown() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let (mut subscription, _handle, _rx_next, rx_complete) = listen_root_folders(&gqlmapi);
+
+        gqlmapi.shutdown().expect("shuts down the service");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should call complete on shutdown");
+
+        let err = subscription
+            .collect()
+            .expect_err("should fail to listen after shutdown");
+        assert!(matches!(err, ServiceError::WorkerGone(_)), "{err}");
+    }
+
+    #[test]
+    fn recover_from_poisoned_sender() {
+        let gqlmapi = Arc::new(MAPIGraphQL::new(true));
+        let poison = gqlmapi.clone();
+        thread::spawn(move || {
+            let _sender = poison.0.sender.lock().expect("locks the sender");
+            panic!("poison the sender lock");
+        })
+        .join()
+        .expect_err("thread should panic");
+        assert!(gqlmapi.0.sender.is_poisoned(), "sender lock is poisoned");
+
+        gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the query with a poisoned sender lock");
+    }
+
+    #[test]
+    fn validate_query() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let valid = gqlmapi
+            .parse_query("{ stores { id } }")
+            .expect("parses the valid query");
+        valid
+            .validate()
+            .expect("valid query should pass validation");
+
+        let invalid = gqlmapi
+            .parse_query("{ stores { notAField } }")
+            .expect("parses the invalid query");
+        let errors = invalid
+            .validate()
+            .expect_err("invalid query should fail validation");
+        assert!(
+            errors.iter().any(|message| message.contains("notAField")),
+            "should report the undefined field: {errors:?}"
+        );
+    }
+
+    #[test]
+    fn estimate_complexity() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let scalar = gqlmapi
+            .parse_query("{ __typename }")
+            .expect("parses the scalar query");
+        assert_eq!(scalar.complexity("").expect("estimates the cost"), 1);
+
+        let nested = gqlmapi
+            .parse_query("{ stores { id rootFolders { id } } }")
+            .expect("parses the nested query");
+        // stores + 10 * (id + rootFolders + 10 * id)
+        assert_eq!(
+            nested.complexity("").expect("estimates the cost"),
+            1 + 10 * (2 + 10)
+        );
+    }
+
+    #[test]
+    fn describe_backend_version() {
+        let version = MAPIGraphQL::backend_version();
+        assert!(version.starts_with("gqlmapi "), "{version}");
+        assert!(version.contains(", graphqlservice "), "{version}");
+        assert!(version.contains(", vcpkg "), "{version}");
+    }
+
+    #[test]
+    fn execute_into_streaming_target() {
+        #[derive(Deserialize)]
+        struct Store {
+            id: String,
+            name: String,
+        }
+
+        #[derive(Deserialize)]
+        struct Data {
+            stores: Vec<Store>,
+        }
+
+        #[derive(Deserialize)]
+        struct Payload {
+            data: Data,
+        }
+
+        let gqlmapi = MAPIGraphQL::new(true);
+        let Payload { data } = gqlmapi
+            .execute_into_streaming("{ stores { id name } }", "", "")
+            .expect("streams the result");
+        let response = gqlmapi
+            .execute_full("{ stores { id name } }", "", "")
+            .expect("runs the query");
+        let ids: Vec<_> = data.stores.iter().map(|store| store.id.as_str()).collect();
+        let expected: Vec<_> = response.data["stores"]
+            .as_array()
+            .expect("has a list of stores")
+            .iter()
+            .map(|store| store["id"].as_str().expect("has an id"))
+            .collect();
+        assert_eq!(ids, expected);
+        assert!(data.stores.iter().all(|store| !store.name.is_empty()));
+
+        let variables = serde_json::json!({ "storeId": first_store_id(&gqlmapi) }).to_string();
+        let err = gqlmapi
+            .execute_into_streaming::<Payload>(ROOT_FOLDERS_SUBSCRIPTION, "", &variables)
+            .err()
+            .expect("should not stream a Subscription operation");
+        assert_eq!(
+            err.to_string(),
+            "Cannot run a Subscription operation, use subscribe instead"
+        );
+        assert!(gqlmapi.active_subscriptions().is_empty());
+    }
+
+    #[cfg(feature = "schema-types")]
+    #[test]
+    fn deserialize_schema_types() {
+        #[derive(Deserialize)]
+        struct Data {
+            stores: Vec<crate::types::Store>,
+        }
+
+        let gqlmapi = MAPIGraphQL::new(true);
+        let Response { data, errors, .. } = gqlmapi
+            .execute_full(
+                r#"{
+                    stores {
+                        id
+                        name
+                        columns {
+                            id {
+                                __typename
+                                ... on IntId { id }
+                            }
+                            value {
+                                __typename
+                                ... on StringValue { string }
+                                ... on IntValue { integer }
+                                ... on BoolValue { boolean }
+                            }
+                        }
+                        rootFolders {
+                            id
+                            name
+                            count
+                            unread
+                            specialFolder
+                        }
+                    }
+                }"#,
+                "",
+                "",
+            )
+            .expect("runs the query");
+        assert!(errors.is_empty(), "{errors:?}");
+        let Data { stores } =
+            serde_json::from_value(data).expect("deserializes into the schema types");
+        assert!(!stores.is_empty(), "should have at least one store");
+        for store in stores {
+            assert!(store.name.is_some(), "should select the store name");
+            assert!(
+                store
+                    .columns
+                    .unwrap_or_default()
+                    .iter()
+                    .all(|property| property.id.is_some() && property.value.is_some()),
+                "should deserialize every property"
+            );
+        }
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn retry_failed_start() {
+        let backend = crate::mock::MockBackend::new(Default::default()).fail_start(2);
+        crate::Service::start_service(&backend, true, 2, Duration::from_millis(10))
+            .expect("starts on the last attempt");
+
+        let backend = crate::mock::MockBackend::new(Default::default()).fail_start(3);
+        let err = crate::Service::start_service(&backend, true, 2, Duration::from_millis(10))
+            .expect_err("should give up after the retries")
+            .to_string();
+        assert!(
+            err.starts_with("Error starting the service after 3 attempts: Mock start error"),
+            "{err}"
+        );
+        assert_eq!(err.matches("Mock start error").count(), 3, "{err}");
+
+        let backend = crate::mock::MockBackend::new(Default::default()).fail_start(1);
+        let err = crate::Service::start_service(&backend, true, 0, Duration::ZERO)
+            .expect_err("should not retry without retries");
+        assert_eq!(
+            err.to_string(),
+            "Error starting the service: Mock start error: MAPI is still initializing"
+        );
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn stream_attachment_bytes() {
+        const ATTACHMENT_QUERY: &str = "{ attachment { name stream } }";
+        let gqlmapi = MAPIGraphQL::new_mock_with_responses(
+            [(
+                String::from(ATTACHMENT_QUERY),
+                String::from(r#"{"data":{"attachment":{"name":"a.bin","stream":"AAEC/w=="}}}"#),
+            )]
+            .into_iter()
+            .collect(),
+        );
+        let query = gqlmapi
+            .parse_query(ATTACHMENT_QUERY)
+            .expect("parses the query");
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+
+        let (tx_chunks, rx_chunks) = mpsc::channel();
+        let payload = subscription
+            .run_stream("/data/attachment/stream", tx_chunks)
+            .expect("streams the attachment");
+        assert_eq!(
+            payload,
+            serde_json::json!({ "data": { "attachment": { "name": "a.bin", "stream": null } } })
+        );
+        assert_eq!(rx_chunks.iter().collect::<Vec<_>>(), [vec![0, 1, 2, 255]]);
+
+        let (tx_chunks, rx_chunks) = mpsc::channel();
+        let err = subscription
+            .run_stream("/data/attachment/name", tx_chunks)
+            .expect_err("should not decode a name")
+            .to_string();
+        assert!(err.starts_with("Invalid base64"), "{err}");
+        assert_eq!(rx_chunks.iter().count(), 0);
+
+        let (tx_chunks, _) = mpsc::channel();
+        let err = subscription
+            .run_stream("/data/attachment/size", tx_chunks)
+            .expect_err("should not resolve a missing field")
+            .to_string();
+        assert!(
+            err.starts_with("No value at /data/attachment/size"),
+            "{err}"
+        );
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn mock_canned_responses() {
+        let gqlmapi = MAPIGraphQL::new_mock_with_responses(
+            [(
+                String::from("{ stores { name } }"),
+                String::from(r#"{"data":{"stores":[{"name":"Mailbox"}]}}"#),
+            )]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(
+            gqlmapi
+                .run("{ __typename }", "", "")
+                .expect("runs the query"),
+            r#"{"data":{"__typename":"Query"}}"#
+        );
+        assert_eq!(
+            gqlmapi
+                .run("  { stores { name } }\n", "", "")
+                .expect("runs the query"),
+            r#"{"data":{"stores":[{"name":"Mailbox"}]}}"#
+        );
+        let response = gqlmapi
+            .execute_full("{ unknown }", "", "")
+            .expect("runs the query");
+        assert_eq!(response.errors.len(), 1, "should report a missing response");
+
+        let query = gqlmapi
+            .parse_query("subscription { items }")
+            .expect("parses the subscription");
+        let mut subscription = gqlmapi.subscribe(query, "", "");
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        let handle = subscription
+            .listen(tx_next, tx_complete)
+            .expect("subscribes to the subscription");
+        assert!(rx_complete.try_recv().is_err(), "should keep listening");
+        handle.cancel().expect("cancels the subscription");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should call complete after cancel");
+        assert!(
+            rx_next.try_recv().is_err(),
+            "should not deliver any payloads"
+        );
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn mock_operations_and_payloads() {
+        let gqlmapi = MAPIGraphQL::new_mock();
+        let query = gqlmapi
+            .parse_query(
+                "query Stores($id: ID!) { stores { id } } fragment F on Folder { id } \
+                 mutation Mark { markAsRead } subscription { items }",
+            )
+            .expect("parses the document");
+        assert_eq!(
+            query.operation_names().expect("gets the operation names"),
+            ["Stores", "Mark", ""]
+        );
+        query.operation("Mark").expect("prepares a named operation");
+
+        let query = gqlmapi
+            .parse_query("subscription Items { items }")
+            .expect("parses the subscription");
+        let prepared = query.operation("").expect("selects the only operation");
+        let mut subscription = prepared.subscribe();
+        let (tx_next, rx_next) = mpsc::channel();
+        let (tx_complete, rx_complete) = mpsc::channel();
+        let handle = subscription
+            .listen(tx_next, tx_complete)
+            .expect("subscribes to the subscription");
+        let id = gqlmapi.active_subscriptions()[0].id;
+
+        for payload in [r#"{"data":{"items":[]}}"#, r#"{"data":{"items":[1]}}"#] {
+            gqlmapi
+                .push_mock_payload(id, payload)
+                .expect("pushes the payload");
+            assert_eq!(rx_next.try_recv().as_deref(), Ok(payload));
+        }
+
+        handle.cancel().expect("cancels the subscription");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should call complete after cancel");
+        let err = gqlmapi
+            .push_mock_payload(id, "{}")
+            .expect_err("should not push to a cancelled subscription");
+        assert_eq!(err.to_string(), "Unknown subscriptionId");
+    }
+
+    #[test]
+    fn list_profiles() {
+        let profiles = MAPIGraphQL::list_profiles().expect("lists the profiles");
+        assert!(
+            profiles.iter().filter(|profile| profile.is_default).count() <= 1,
+            "should have at most one default profile"
+        );
+        assert!(
+            profiles.iter().all(|profile| !profile.name.is_empty()),
+            "should have a name for every profile"
+        );
+    }
+
+    #[test]
+    fn request_stop_from_another_thread() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let clone = gqlmapi.clone();
+        thread::spawn(move || clone.request_stop())
+            .join()
+            .expect("requests a stop from another thread");
+
+        let err = gqlmapi
+            .run("{ __typename }", "", "")
+            .err()
+            .expect("should fail after the stop");
+        assert!(matches!(err, ServiceError::WorkerGone(_)), "{err}");
+
+        // Asking again, and then dropping it, does not send another Stop.
+        gqlmapi.request_stop();
+        drop(gqlmapi);
+    }
+
+    #[test]
+    fn report_worker_thread_id() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        assert_ne!(gqlmapi.worker_thread_id(), 0);
+        assert_ne!(gqlmapi.worker_thread_id(), unsafe { GetCurrentThreadId() });
+    }
+
+    #[test]
+    fn wait_until_ready() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        gqlmapi
+            .wait_ready(Duration::from_secs(60))
+            .expect("logs on to the default profile");
+        gqlmapi
+            .wait_ready(Duration::ZERO)
+            .expect("stays ready once it has logged on");
+        let results = gqlmapi
+            .run("{ __typename }", "", "")
+            .expect("runs the query");
+        assert_eq!(results, r#"{"data":{"__typename":"Query"}}"#);
+    }
+
+    /// Drop everything it holds the first time it receives a payload, on the worker thread.
+    struct DropOnNext {
+        held: Mutex<Option<(MAPIGraphQL, Arc<ParsedQuery>, Subscription)>>,
+        tx_dropped: mpsc::Sender<()>,
+    }
+
+    impl PayloadSink for DropOnNext {
+        fn send(&self, _payload: String) -> Result<(), String> {
+            if let Some(held) = self.held.lock().expect("locks the held values").take() {
+                drop(held);
+                self.tx_dropped.send(()).map_err(|err| err.to_string())?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn drop_handles_inside_callback() {
+        let gqlmapi = MAPIGraphQL::new(true);
+        let (held, _handle, _rx_next, rx_complete) = listen_root_folders(&gqlmapi);
+        let stores = gqlmapi
+            .parse_query("{ stores { id } }")
+            .expect("parses the query");
+
+        let (tx_dropped, rx_dropped) = mpsc::channel();
+        let sink = DropOnNext {
+            held: Mutex::new(Some((gqlmapi.clone(), stores, held))),
+            tx_dropped,
+        };
+        let mut subscription = gqlmapi.subscribe(
+            gqlmapi
+                .parse_query("{ __typename }")
+                .expect("parses the query"),
+            "",
+            "",
+        );
+        let (tx_complete_query, _rx_complete_query) = mpsc::channel();
+        subscription
+            .listen(sink, tx_complete_query)
+            .expect("runs the query");
+        rx_dropped
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should drop the handles in the callback");
+        rx_complete
+            .recv_timeout(Duration::from_secs(5))
+            .expect("should unsubscribe the dropped subscription");
+
+        let results = gqlmapi
+            .run("{ __typename }", "", "")
+            .expect("keeps handling commands");
+        assert_eq!(results, r#"{"data":{"__typename":"Query"}}"#);
+    }
+
+    #[test]
+    fn count_pending_commands() {
+        let (gqlmapi, mut pump) = MAPIGraphQL::attach(true).expect("attaches to this thread");
+        assert_eq!(gqlmapi.pending_commands(), 0);
+
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..2 {
+            gqlmapi
+                .0
+                .send(ServiceCommand::ParsedQuery {
+                    query: String::from("{ __typename }"),
+                    tx_result: tx.clone(),
+                })
+                .expect("sends the command");
+        }
+        assert_eq!(gqlmapi.pending_commands(), 2);
+
+        assert!(pump.pump_once().expect("pumps the service"));
+        assert_eq!(gqlmapi.pending_commands(), 0);
+        for _ in 0..2 {
+            rx.try_recv()
+                .expect("should have handled the command")
+                .expect("parses the query");
+        }
+    }
+
+    #[test]
+    fn attach_to_current_thread() {
+        let (gqlmapi, mut pump) = MAPIGraphQL::attach(true).expect("attaches to this thread");
+        assert_eq!(gqlmapi.worker_thread_id(), unsafe { GetCurrentThreadId() });
+        let client = thread::spawn(move || {
+            let results = gqlmapi
+                .run("{ __typename }", "", "")
+                .expect("runs the query");
+            drop(gqlmapi);
+            results
+        });
+
+        while pump.pump_once().expect("pumps the service") {
+            thread::yield_now();
+        }
+
+        let results = client.join().expect("joins the client thread");
+        assert_eq!(results, r#"{"data":{"__typename":"Query"}}"#);
     }
 }
@@ -0,0 +1,344 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+};
+
+use crate::{
+    backend::{Backend, CompleteCallbackFn, NextCallbackFn},
+    bindings::{CompleteContext, NextContext},
+    ServiceError,
+};
+
+/// Payload for `{ __typename }`, which every [MockBackend] answers like the real service.
+const TYPENAME_QUERY: &str = "{ __typename }";
+const TYPENAME_PAYLOAD: &str = r#"{"data":{"__typename":"Query"}}"#;
+/// Same error the `Bindings` return from a second `startService` before `stopService`.
+const ALREADY_STARTED: &str = "startService was already called";
+
+/// In-process fake for the `Bindings`, used by [new_mock](crate::MAPIGraphQL::new_mock).
+///
+/// It does not parse or validate anything against the `MAPI` schema, it only splits the request
+/// document into its top-level operations to find their names and types. A `Query` or `Mutation`
+/// delivers the canned payload registered for the exact (trimmed) request document, or a
+/// payload with a single error if there is none, and then calls `complete`. A `Subscription`
+/// only delivers the payloads passed to [push_payload](Backend::push_payload), and calls
+/// `complete` once it is unsubscribed or the service stops.
+pub(crate) struct MockBackend {
+    responses: HashMap<String, String>,
+    state: RefCell<MockState>,
+}
+
+#[derive(Default)]
+struct MockState {
+    started: bool,
+    start_failures: u32,
+    last_query_id: i32,
+    last_subscription_id: i32,
+    queries: BTreeMap<i32, MockQuery>,
+    subscriptions: BTreeMap<i32, MockSubscription>,
+}
+
+/// A parsed request document, and the type and name of each operation it defines.
+struct MockQuery {
+    document: String,
+    operations: Vec<(OperationType, String)>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OperationType {
+    Query,
+    Mutation,
+    Subscription,
+}
+
+impl MockQuery {
+    /// Split the `document` into its top-level definitions, and keep the operations. Every `{`
+    /// outside of a selection set starts one, and the text in front of it says what it is, e.g.
+    /// `query Name($id: ID!)`, `fragment Name on Type`, or nothing for an anonymous query.
+    fn new(document: &str) -> Self {
+        let mut operations = Vec::new();
+        let mut header = String::new();
+        let mut depth = 0_i32;
+        for c in document.chars() {
+            match c {
+                '{' => {
+                    if depth == 0 {
+                        operations.extend(Self::operation(&header));
+                        header.clear();
+                    }
+                    depth += 1;
+                }
+                '}' => depth -= 1,
+                _ if depth == 0 => header.push(c),
+                _ => (),
+            }
+        }
+        Self {
+            document: document.to_owned(),
+            operations,
+        }
+    }
+
+    fn operation(header: &str) -> Option<(OperationType, String)> {
+        let header = header.trim();
+        let keyword_end = header
+            .find(|c: char| !is_name_char(c))
+            .unwrap_or(header.len());
+        let operation_type = match &header[..keyword_end] {
+            "" => return Some((OperationType::Query, String::new())),
+            "query" => OperationType::Query,
+            "mutation" => OperationType::Mutation,
+            "subscription" => OperationType::Subscription,
+            _ => return None,
+        };
+        let name: String = header[keyword_end..]
+            .trim_start()
+            .chars()
+            .take_while(|&c| is_name_char(c))
+            .collect();
+        Some((operation_type, name))
+    }
+
+    /// Select the operation like gqlmapi does, by name, or the only one if the name is empty.
+    fn select(&self, operation_name: &str) -> Result<OperationType, String> {
+        match self.operations.as_slice() {
+            [(operation_type, _)] if operation_name.is_empty() => Ok(*operation_type),
+            operations => operations
+                .iter()
+                .find(|(_, name)| !operation_name.is_empty() && name == operation_name)
+                .map(|(operation_type, _)| *operation_type)
+                .ok_or_else(|| format!("Mock subscribe error: unknown operation {operation_name}")),
+        }
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// A registered `subscription`, which holds on to the `next` channel until it completes. The
+/// `next_context` is only taken out while [push_payload](Backend::push_payload) delivers a
+/// payload.
+struct MockSubscription {
+    next_context: Option<Box<NextContext>>,
+    next_callback: NextCallbackFn,
+    complete_context: Box<CompleteContext>,
+    complete_callback: CompleteCallbackFn,
+}
+
+impl MockSubscription {
+    fn complete(self) {
+        (self.complete_callback)(self.complete_context);
+    }
+}
+
+impl MockBackend {
+    pub(crate) fn new(responses: HashMap<String, String>) -> Self {
+        let mut responses: HashMap<String, String> = responses
+            .into_iter()
+            .map(|(query, payload)| (query.trim().to_owned(), payload))
+            .collect();
+        responses
+            .entry(String::from(TYPENAME_QUERY))
+            .or_insert_with(|| String::from(TYPENAME_PAYLOAD));
+        Self {
+            responses,
+            state: RefCell::default(),
+        }
+    }
+
+    /// Fail the next `failures` calls to `start_service`, like a `MAPI` logon which fails while
+    /// `MAPI` is still initializing.
+    #[cfg(test)]
+    pub(crate) fn fail_start(self, failures: u32) -> Self {
+        self.state.borrow_mut().start_failures = failures;
+        self
+    }
+
+    fn payload(&self, query: &str) -> String {
+        self.responses.get(query).cloned().unwrap_or_else(|| {
+            serde_json::json!({
+                "data": null,
+                "errors": [{ "message": format!("No mock response for: {query}") }],
+            })
+            .to_string()
+        })
+    }
+}
+
+impl Backend for MockBackend {
+    fn start_service(&self, _use_default_profile: bool) -> Result<(), ServiceError> {
+        let mut state = self.state.borrow_mut();
+        if state.started {
+            return Err(ServiceError::AlreadyStarted(String::from(ALREADY_STARTED)));
+        }
+        if state.start_failures > 0 {
+            state.start_failures -= 1;
+            return Err(ServiceError::Other(String::from(
+                "Mock start error: MAPI is still initializing",
+            )));
+        }
+        state.started = true;
+        Ok(())
+    }
+
+    fn stop_service(&self) -> Result<(), String> {
+        self.state.borrow_mut().started = false;
+        self.reset_service()
+    }
+
+    fn reset_service(&self) -> Result<(), String> {
+        let subscriptions = {
+            let mut state = self.state.borrow_mut();
+            state.queries.clear();
+            std::mem::take(&mut state.subscriptions)
+        };
+        for subscription in subscriptions.into_values() {
+            subscription.complete();
+        }
+        Ok(())
+    }
+
+    fn parse_query(&self, query: &str) -> Result<i32, String> {
+        let query = query.trim();
+        if query.is_empty() {
+            return Err(String::from("Mock parse error: empty request document"));
+        }
+
+        let mut depth = 0_i32;
+        for c in query.chars() {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => (),
+            }
+            if depth < 0 {
+                break;
+            }
+        }
+        if depth != 0 {
+            return Err(String::from("Mock parse error: unbalanced braces"));
+        }
+
+        let mut state = self.state.borrow_mut();
+        state.last_query_id += 1;
+        let query_id = state.last_query_id;
+        state.queries.insert(query_id, MockQuery::new(query));
+        Ok(query_id)
+    }
+
+    fn discard_query(&self, query_id: i32) {
+        self.state.borrow_mut().queries.remove(&query_id);
+    }
+
+    fn operation_names(&self, query_id: i32) -> Result<Vec<String>, String> {
+        self.state
+            .borrow()
+            .queries
+            .get(&query_id)
+            .map(|query| {
+                query
+                    .operations
+                    .iter()
+                    .map(|(_, name)| name.clone())
+                    .collect()
+            })
+            .ok_or_else(|| String::from("Unknown queryId"))
+    }
+
+    fn validate_query(&self, query_id: i32) -> Result<Vec<String>, String> {
+        self.operation_names(query_id).map(|_| Vec::new())
+    }
+
+    fn query_complexity(&self, query_id: i32, _operation_name: &str) -> Result<u32, String> {
+        self.operation_names(query_id).map(|_| 1)
+    }
+
+    fn subscribe(
+        &self,
+        query_id: i32,
+        operation_name: &str,
+        variables: &str,
+        next_context: Box<NextContext>,
+        next_callback: NextCallbackFn,
+        complete_context: Box<CompleteContext>,
+        complete_callback: CompleteCallbackFn,
+    ) -> Result<i32, String> {
+        let (document, operation_type) = {
+            let state = self.state.borrow();
+            let query = state
+                .queries
+                .get(&query_id)
+                .ok_or_else(|| String::from("Unknown queryId"))?;
+            (query.document.clone(), query.select(operation_name)?)
+        };
+        if !variables.is_empty() {
+            match serde_json::from_str(variables) {
+                Ok(serde_json::Value::Object(_)) => (),
+                _ => return Err(String::from("Invalid variables: expected a JSON object")),
+            }
+        }
+
+        let subscription_id = {
+            let mut state = self.state.borrow_mut();
+            state.last_subscription_id += 1;
+            state.last_subscription_id
+        };
+
+        if operation_type == OperationType::Subscription {
+            self.state.borrow_mut().subscriptions.insert(
+                subscription_id,
+                MockSubscription {
+                    next_context: Some(next_context),
+                    next_callback,
+                    complete_context,
+                    complete_callback,
+                },
+            );
+        } else {
+            // Like the real service, deliver the result before subscribe returns.
+            let payload = self.payload(&document);
+            let _ = next_callback(next_context, payload);
+            complete_callback(complete_context);
+        }
+
+        Ok(subscription_id)
+    }
+
+    fn unsubscribe(&self, subscription_id: i32) {
+        let subscription = self
+            .state
+            .borrow_mut()
+            .subscriptions
+            .remove(&subscription_id);
+        if let Some(subscription) = subscription {
+            subscription.complete();
+        }
+    }
+
+    fn push_payload(&self, subscription_id: i32, payload: String) -> Result<(), String> {
+        // The next callback runs the consumer's code, so do not keep the state borrowed.
+        let (next_context, next_callback) = {
+            let mut state = self.state.borrow_mut();
+            let subscription = state
+                .subscriptions
+                .get_mut(&subscription_id)
+                .ok_or_else(|| String::from("Unknown subscriptionId"))?;
+            let next_context = subscription
+                .next_context
+                .take()
+                .ok_or_else(|| String::from("Subscription is already delivering a payload"))?;
+            (next_context, subscription.next_callback)
+        };
+        let next_context = next_callback(next_context, payload);
+        if let Some(subscription) = self
+            .state
+            .borrow_mut()
+            .subscriptions
+            .get_mut(&subscription_id)
+        {
+            subscription.next_context = Some(next_context);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll, Waker},
+};
+
+/// Single-use channel for replies from the worker thread, where the [Receiver] is a [Future]
+/// which completes as soon as the worker sends the reply, without blocking any thread.
+pub(crate) fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Mutex::new(State {
+        value: None,
+        waker: None,
+        closed: false,
+        receiver_dropped: false,
+    }));
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+struct State<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+    closed: bool,
+    /// Set under the lock once the [Receiver] stops accepting the reply, so [Sender::send] and
+    /// [Receiver::close] always agree on which side owns the value.
+    receiver_dropped: bool,
+}
+
+pub(crate) struct Sender<T>(Arc<Mutex<State<T>>>);
+
+impl<T> Sender<T> {
+    /// Send the reply and wake the [Receiver], or give the value back if it was dropped.
+    pub(crate) fn send(self, value: T) -> Result<(), T> {
+        let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.receiver_dropped {
+            return Err(value);
+        }
+        state.value = Some(value);
+        Ok(())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    /// Wake the [Receiver] whether or not a value was sent, so it stops waiting.
+    fn drop(&mut self) {
+        let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        state.closed = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+pub(crate) struct Receiver<T>(Arc<Mutex<State<T>>>);
+
+impl<T> Receiver<T> {
+    /// Stop accepting the reply, and take it if it has already been sent, e.g. to release it
+    /// when the [Future] is dropped before it is polled again. If this returns [None], any later
+    /// [send](Sender::send) gives the value back to the sender instead.
+    pub(crate) fn close(&self) -> Option<T> {
+        let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        state.receiver_dropped = true;
+        state.value.take()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .receiver_dropped = true;
+    }
+}
+
+/// The [Sender] was dropped without sending a value, e.g. because the worker thread exited.
+pub(crate) struct Canceled;
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, Canceled>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(value) = state.value.take() {
+            Poll::Ready(Ok(value))
+        } else if state.closed {
+            Poll::Ready(Err(Canceled))
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::channel;
+
+    #[test]
+    fn take_reply_sent_before_close() {
+        let (tx, rx) = channel();
+        assert!(tx.send(1).is_ok());
+        assert_eq!(rx.close(), Some(1), "should hand the reply to the receiver");
+    }
+
+    #[test]
+    fn return_reply_sent_after_close() {
+        let (tx, rx) = channel();
+        assert_eq!(rx.close(), None);
+        assert_eq!(
+            tx.send(1),
+            Err(1),
+            "should give the reply back to the sender"
+        );
+
+        let (tx, rx) = channel::<i32>();
+        drop(rx);
+        assert_eq!(
+            tx.send(2),
+            Err(2),
+            "should give the reply back to the sender"
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    io::{self, Read, Write},
+    sync::mpsc,
+};
+
+/// Number of chunks which may be waiting in the [pipe] before the writer blocks, so a slow reader
+/// keeps the worker thread from buffering the whole payload. The worker thread does nothing else
+/// while it is blocked, so this trades the memory of those chunks against stalling the service.
+const PIPE_CAPACITY: usize = 4;
+
+/// Create a bounded, in-memory byte pipe, which moves each chunk written to the [PipeWriter] to
+/// the [PipeReader] on another thread. The reader sees the end of the stream once the writer
+/// drops, and the writer fails with [io::ErrorKind::BrokenPipe] once the reader drops.
+pub(crate) fn pipe() -> (PipeWriter, PipeReader) {
+    let (tx, rx) = mpsc::sync_channel(PIPE_CAPACITY);
+    (
+        PipeWriter(tx),
+        PipeReader {
+            rx,
+            chunk: Vec::new(),
+            offset: 0,
+        },
+    )
+}
+
+pub(crate) struct PipeWriter(mpsc::SyncSender<Vec<u8>>);
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !buf.is_empty() {
+            self.0
+                .send(buf.to_vec())
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub(crate) struct PipeReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    offset: usize,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while self.offset == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.offset = 0;
+                }
+                Err(mpsc::RecvError) => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.chunk.len() - self.offset);
+        buf[..len].copy_from_slice(&self.chunk[self.offset..self.offset + len]);
+        self.offset += len;
+        Ok(len)
+    }
+}
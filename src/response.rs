@@ -0,0 +1,116 @@
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+use crate::QueryError;
+
+/// Top-level structure of a [GraphQL](https://graphql.org) response payload.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Response {
+    /// The `data` member, which is `null` if the request failed before execution.
+    #[serde(default)]
+    pub data: Value,
+    /// Any `errors` reported while executing the request.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<GraphQLError>,
+    /// The optional `extensions` member, e.g. timing or tracing information.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<Value>,
+}
+
+/// A single entry in the `errors` member of a [Response].
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct GraphQLError {
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub locations: Vec<ErrorLocation>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub path: Vec<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<Value>,
+}
+
+/// Location in the request document where a [GraphQLError] occurred.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ErrorLocation {
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Response {
+    /// Parse a JSON payload delivered to `next` into a [Response].
+    ///
+    /// The worker reports exceptions caught while delivering a payload with a single string in
+    /// `errors`, so that is accepted as well as the usual list of error objects.
+    pub fn from_payload(payload: &str) -> Result<Self, String> {
+        let mut payload: Value = serde_json::from_str(payload)
+            .map_err(|err| format!("Error parsing response: {err}"))?;
+
+        if let Some(Value::String(message)) = payload.get("errors") {
+            let errors = serde_json::json!([{ "message": message }]);
+            payload["errors"] = errors;
+        }
+
+        serde_json::from_value(payload).map_err(|err| format!("Error parsing response: {err}"))
+    }
+}
+
+/// [Response] whose `data` is deserialized straight into `T`, for
+/// [query](crate::MAPIGraphQL::query). If the `data` does not match `T`, the error takes its
+/// place, so the `errors` which explain why are still reported.
+#[derive(Deserialize)]
+#[serde(bound = "T: DeserializeOwned")]
+pub(crate) struct TypedResponse<T> {
+    #[serde(default = "missing_data", deserialize_with = "deserialize_data")]
+    data: Result<T, String>,
+    #[serde(default, deserialize_with = "deserialize_errors")]
+    errors: Vec<GraphQLError>,
+}
+
+impl<T: DeserializeOwned> TypedResponse<T> {
+    /// Parse a JSON payload delivered to `next` into a [TypedResponse]. It goes through a [Value],
+    /// so a `data` member which does not match `T` leaves the rest of the payload readable.
+    pub fn from_payload(payload: &str) -> Result<Self, String> {
+        let payload: Value = serde_json::from_str(payload)
+            .map_err(|err| format!("Error parsing response: {err}"))?;
+        Self::deserialize(payload).map_err(|err| format!("Error parsing response: {err}"))
+    }
+
+    /// Return the `data`, or every one of the `errors` instead if there are any.
+    pub fn into_data(self) -> Result<T, QueryError> {
+        if !self.errors.is_empty() {
+            return Err(QueryError::GraphQL(self.errors));
+        }
+        self.data
+            .map_err(|err| QueryError::Deserialize(format!("Error parsing results: {err}")))
+    }
+}
+
+fn missing_data<T: DeserializeOwned>() -> Result<T, String> {
+    T::deserialize(Value::Null).map_err(|err| err.to_string())
+}
+
+fn deserialize_data<'de, D: Deserializer<'de>, T: DeserializeOwned>(
+    deserializer: D,
+) -> Result<Result<T, String>, D::Error> {
+    Ok(T::deserialize(deserializer).map_err(|err| err.to_string()))
+}
+
+/// Either form of `errors` accepted by [Response::from_payload].
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Errors {
+    List(Vec<GraphQLError>),
+    Message(String),
+}
+
+fn deserialize_errors<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<GraphQLError>, D::Error> {
+    Ok(match Errors::deserialize(deserializer)? {
+        Errors::List(errors) => errors,
+        Errors::Message(message) => vec![GraphQLError {
+            message,
+            ..Default::default()
+        }],
+    })
+}
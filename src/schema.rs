@@ -0,0 +1,620 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+/// Introspection query used by [schema_sdl](crate::MAPIGraphQL::schema_sdl).
+pub(crate) const INTROSPECTION_QUERY: &str = r#"query {
+    __schema {
+        queryType { name }
+        mutationType { name }
+        subscriptionType { name }
+        types {
+            kind
+            name
+            fields(includeDeprecated: true) {
+                name
+                args { name type { ...TypeRef } defaultValue }
+                type { ...TypeRef }
+            }
+            inputFields { name type { ...TypeRef } defaultValue }
+            interfaces { name }
+            enumValues(includeDeprecated: true) { name }
+            possibleTypes { name }
+        }
+    }
+}
+
+fragment TypeRef on __Type {
+    kind
+    name
+    ofType {
+        kind
+        name
+        ofType {
+            kind
+            name
+            ofType {
+                kind
+                name
+                ofType {
+                    kind
+                    name
+                    ofType { kind name }
+                }
+            }
+        }
+    }
+}"#;
+
+/// Types which every [GraphQL](https://graphql.org) schema defines, so they are left out of the
+/// rendered SDL.
+const BUILT_IN_SCALARS: [&str; 5] = ["Boolean", "Float", "ID", "Int", "String"];
+
+/// Difference between the live schema and the expected SDL, returned from
+/// [verify_schema](crate::MAPIGraphQL::verify_schema). Each list holds type names in sorted order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SchemaDiff {
+    /// Types in the live schema which are missing from the expected SDL.
+    pub added: Vec<String>,
+    /// Types in the expected SDL which are missing from the live schema.
+    pub removed: Vec<String>,
+    /// Types in both whose kind, interfaces, members, or member signatures differ.
+    pub changed: Vec<String>,
+}
+
+impl SchemaDiff {
+    /// Compare two SDL documents by type definition, ignoring descriptions, comments,
+    /// directives, and the order of fields, enum values, and union members.
+    pub(crate) fn compare(live: &str, expected: &str) -> Self {
+        let live = definitions(live);
+        let expected = definitions(expected);
+        let mut diff = Self::default();
+
+        for (name, definition) in live.iter() {
+            match expected.get(name) {
+                None => diff.added.push(name.clone()),
+                Some(other) if other != definition => diff.changed.push(name.clone()),
+                Some(_) => (),
+            }
+        }
+        diff.removed = expected
+            .into_keys()
+            .filter(|name| !live.contains_key(name))
+            .collect();
+
+        diff
+    }
+
+    /// Check whether the live schema matches the expected SDL.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct IntrospectionData {
+    #[serde(rename = "__schema")]
+    schema: IntrospectionSchema,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IntrospectionSchema {
+    query_type: Option<NamedType>,
+    mutation_type: Option<NamedType>,
+    subscription_type: Option<NamedType>,
+    types: Vec<FullType>,
+}
+
+#[derive(Deserialize)]
+struct NamedType {
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FullType {
+    kind: String,
+    name: String,
+    fields: Option<Vec<Field>>,
+    input_fields: Option<Vec<InputValue>>,
+    interfaces: Option<Vec<NamedType>>,
+    enum_values: Option<Vec<NamedType>>,
+    possible_types: Option<Vec<NamedType>>,
+}
+
+#[derive(Deserialize)]
+struct Field {
+    name: String,
+    args: Vec<InputValue>,
+    #[serde(rename = "type")]
+    field_type: TypeRef,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InputValue {
+    name: String,
+    #[serde(rename = "type")]
+    value_type: TypeRef,
+    default_value: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TypeRef {
+    kind: String,
+    name: Option<String>,
+    of_type: Option<Box<TypeRef>>,
+}
+
+impl TypeRef {
+    fn render(&self) -> String {
+        match (self.kind.as_str(), &self.of_type) {
+            ("NON_NULL", Some(of_type)) => format!("{}!", of_type.render()),
+            ("LIST", Some(of_type)) => format!("[{}]", of_type.render()),
+            _ => self.name.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl InputValue {
+    fn render(&self) -> String {
+        match &self.default_value {
+            Some(default_value) => format!(
+                "{}: {} = {default_value}",
+                self.name,
+                self.value_type.render()
+            ),
+            None => format!("{}: {}", self.name, self.value_type.render()),
+        }
+    }
+}
+
+impl IntrospectionData {
+    /// Render the introspected types as SDL, leaving out the introspection types, the built-in
+    /// scalars, and the `schema` block if the root types have their default names.
+    pub(crate) fn render_sdl(&self) -> String {
+        let schema = &self.schema;
+        let mut sections = Vec::new();
+
+        let roots: Vec<_> = [
+            ("query", &schema.query_type, "Query"),
+            ("mutation", &schema.mutation_type, "Mutation"),
+            ("subscription", &schema.subscription_type, "Subscription"),
+        ]
+        .into_iter()
+        .filter_map(|(operation, root, default)| {
+            root.as_ref()
+                .map(|root| (operation, root.name.as_str(), default))
+        })
+        .collect();
+        if roots.iter().any(|(_, name, default)| name != default) {
+            let roots: Vec<_> = roots
+                .iter()
+                .map(|(operation, name, _)| format!("  {operation}: {name}\n"))
+                .collect();
+            sections.push(format!("schema {{\n{}}}", roots.concat()));
+        }
+
+        for full_type in schema.types.iter() {
+            let name = full_type.name.as_str();
+            if name.starts_with("__") || BUILT_IN_SCALARS.contains(&name) {
+                continue;
+            }
+
+            let section = match full_type.kind.as_str() {
+                "SCALAR" => format!("scalar {name}"),
+                "UNION" => {
+                    let members: Vec<_> = full_type
+                        .possible_types
+                        .iter()
+                        .flatten()
+                        .map(|member| member.name.as_str())
+                        .collect();
+                    format!("union {name} = {}", members.join(" | "))
+                }
+                "ENUM" => Self::render_block(
+                    &format!("enum {name}"),
+                    full_type
+                        .enum_values
+                        .iter()
+                        .flatten()
+                        .map(|value| value.name.clone()),
+                ),
+                "INPUT_OBJECT" => Self::render_block(
+                    &format!("input {name}"),
+                    full_type
+                        .input_fields
+                        .iter()
+                        .flatten()
+                        .map(InputValue::render),
+                ),
+                kind => {
+                    let keyword = if kind == "INTERFACE" {
+                        "interface"
+                    } else {
+                        "type"
+                    };
+                    let interfaces: Vec<_> = full_type
+                        .interfaces
+                        .iter()
+                        .flatten()
+                        .map(|interface| interface.name.as_str())
+                        .collect();
+                    let header = if interfaces.is_empty() {
+                        format!("{keyword} {name}")
+                    } else {
+                        format!("{keyword} {name} implements {}", interfaces.join(" & "))
+                    };
+                    Self::render_block(
+                        &header,
+                        full_type.fields.iter().flatten().map(|field| {
+                            let args: Vec<_> = field.args.iter().map(InputValue::render).collect();
+                            if args.is_empty() {
+                                format!("{}: {}", field.name, field.field_type.render())
+                            } else {
+                                format!(
+                                    "{}({}): {}",
+                                    field.name,
+                                    args.join(", "),
+                                    field.field_type.render()
+                                )
+                            }
+                        }),
+                    )
+                }
+            };
+            sections.push(section);
+        }
+
+        let mut sdl = sections.join("\n\n");
+        sdl.push('\n');
+        sdl
+    }
+
+    fn render_block(header: &str, members: impl Iterator<Item = String>) -> String {
+        let members: Vec<_> = members.map(|member| format!("  {member}\n")).collect();
+        format!("{header} {{\n{}}}", members.concat())
+    }
+}
+
+/// Lexical token of an SDL document, with descriptions, comments, and commas already dropped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Name(String),
+    Punctuator(char),
+    /// Any other literal, e.g. a number or a string used as a default value.
+    Literal(String),
+}
+
+fn tokenize(sdl: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = sdl.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                for c in chars.by_ref() {
+                    if c == '\n' || c == '\r' {
+                        break;
+                    }
+                }
+            }
+            '"' => {
+                let mut literal = String::from('"');
+                let block = chars.peek() == Some(&'"') && {
+                    literal.push(chars.next().unwrap_or_default());
+                    if chars.peek() == Some(&'"') {
+                        literal.push(chars.next().unwrap_or_default());
+                        true
+                    } else {
+                        // An empty string.
+                        false
+                    }
+                };
+                if block || literal.len() == 1 {
+                    let mut quotes = 0;
+                    while let Some(c) = chars.next() {
+                        literal.push(c);
+                        match c {
+                            '\\' if !block => {
+                                if let Some(c) = chars.next() {
+                                    literal.push(c);
+                                }
+                            }
+                            '"' if !block => break,
+                            '"' => {
+                                quotes += 1;
+                                if quotes == 3 {
+                                    break;
+                                }
+                                continue;
+                            }
+                            _ => (),
+                        }
+                        quotes = 0;
+                    }
+                }
+
+                // Keep default values, but drop descriptions.
+                if tokens.last() == Some(&Token::Punctuator('=')) {
+                    tokens.push(Token::Literal(literal));
+                }
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = String::from(c);
+                while let Some(&c) = chars.peek() {
+                    if !c.is_ascii_alphanumeric() && c != '_' {
+                        break;
+                    }
+                    name.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Name(name));
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut literal = String::from(c);
+                while let Some(&c) = chars.peek() {
+                    if !c.is_ascii_alphanumeric() && c != '.' && c != '+' && c != '-' {
+                        break;
+                    }
+                    literal.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Literal(literal));
+            }
+            '.' => {
+                // The spread punctuator only appears in executable documents, but skip it anyway.
+                while chars.peek() == Some(&'.') {
+                    chars.next();
+                }
+            }
+            ',' => (),
+            c if c.is_whitespace() || c == '\u{feff}' => (),
+            c => tokens.push(Token::Punctuator(c)),
+        }
+    }
+
+    tokens
+}
+
+/// Drop every directive, e.g. `@deprecated(reason: "...")`, along with its arguments.
+fn strip_directives(tokens: Vec<Token>) -> Vec<Token> {
+    let mut stripped = Vec::with_capacity(tokens.len());
+    let mut tokens = tokens.into_iter().peekable();
+
+    while let Some(token) = tokens.next() {
+        if token != Token::Punctuator('@') {
+            stripped.push(token);
+            continue;
+        }
+
+        tokens.next();
+        if tokens.peek() == Some(&Token::Punctuator('(')) {
+            let mut depth = 0;
+            for token in tokens.by_ref() {
+                match token {
+                    Token::Punctuator('(') => depth += 1,
+                    Token::Punctuator(')') => {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    stripped
+}
+
+fn render_tokens(tokens: &[Token]) -> String {
+    let tokens: Vec<_> = tokens
+        .iter()
+        .map(|token| match token {
+            Token::Name(text) | Token::Literal(text) => text.clone(),
+            Token::Punctuator(c) => c.to_string(),
+        })
+        .collect();
+    tokens.join(" ")
+}
+
+/// Split an SDL document into canonical type definitions keyed by name, with their members
+/// sorted so that reordering them is not reported as a change. Directive definitions and
+/// `extend` definitions are skipped.
+fn definitions(sdl: &str) -> BTreeMap<String, String> {
+    const KEYWORDS: [&str; 8] = [
+        "directive",
+        "enum",
+        "extend",
+        "input",
+        "interface",
+        "scalar",
+        "type",
+        "union",
+    ];
+
+    let tokens = strip_directives(tokenize(sdl));
+    let mut definitions = BTreeMap::new();
+    let mut index = 0;
+
+    while index < tokens.len() {
+        // Find the end of this definition, which is the next keyword at the top level.
+        let mut depth = 0;
+        let mut end = index + 1;
+        while end < tokens.len() {
+            match &tokens[end] {
+                Token::Punctuator('{') => depth += 1,
+                Token::Punctuator('}') => depth -= 1,
+                Token::Name(name)
+                    if depth == 0 && (KEYWORDS.contains(&name.as_str()) || name == "schema") =>
+                {
+                    break
+                }
+                _ => (),
+            }
+            end += 1;
+        }
+
+        let definition = &tokens[index..end];
+        index = end;
+
+        let (keyword, name) = match definition {
+            [Token::Name(keyword), ..] if keyword == "schema" => {
+                (keyword.as_str(), keyword.clone())
+            }
+            [Token::Name(keyword), Token::Name(name), ..]
+                if KEYWORDS.contains(&keyword.as_str()) =>
+            {
+                (keyword.as_str(), name.clone())
+            }
+            _ => continue,
+        };
+        if keyword == "directive" || keyword == "extend" {
+            continue;
+        }
+
+        definitions.insert(name, canonical(keyword, definition));
+    }
+
+    definitions
+}
+
+/// Canonical text of a single definition, with implemented interfaces, union members, and the
+/// members of its body sorted.
+fn canonical(keyword: &str, definition: &[Token]) -> String {
+    let open = definition
+        .iter()
+        .position(|token| *token == Token::Punctuator('{'));
+    let (header, body) = match open {
+        Some(open) => (&definition[..open], &definition[open..]),
+        None => (definition, &definition[definition.len()..]),
+    };
+
+    // Sort `implements A & B` and `= A | B`, which are lists of names after the type name.
+    let mut names: Vec<String> = header
+        .iter()
+        .skip(2)
+        .filter_map(|token| match token {
+            Token::Name(name) if name != "implements" => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    names.sort();
+    let mut text = format!(
+        "{keyword} {}",
+        render_tokens(header.get(1..2).unwrap_or(&[]))
+    );
+    if !names.is_empty() {
+        text.push_str(&format!(" : {}", names.join(" ")));
+    }
+
+    // Split the body into members: a name at the top level of the body starts a new member,
+    // unless it is the type after a `:` or the value after an `=`.
+    let mut members = Vec::new();
+    let mut member = Vec::new();
+    let (mut braces, mut nested) = (0, 0);
+    for (position, token) in body.iter().enumerate() {
+        match token {
+            Token::Punctuator('{') => {
+                braces += 1;
+                if braces == 1 {
+                    continue;
+                }
+            }
+            Token::Punctuator('}') => {
+                braces -= 1;
+                if braces == 0 {
+                    continue;
+                }
+            }
+            Token::Punctuator('(') | Token::Punctuator('[') => nested += 1,
+            Token::Punctuator(')') | Token::Punctuator(']') => nested -= 1,
+            Token::Name(_) if braces == 1 && nested == 0 && position > 1 => {
+                let previous = &body[position - 1];
+                if !matches!(
+                    previous,
+                    Token::Punctuator(':') | Token::Punctuator('=') | Token::Punctuator('[')
+                ) && !member.is_empty()
+                {
+                    members.push(render_tokens(&member));
+                    member.clear();
+                }
+            }
+            _ => (),
+        }
+        member.push(token.clone());
+    }
+    if !member.is_empty() {
+        members.push(render_tokens(&member));
+    }
+    members.sort();
+
+    if open.is_some() {
+        text.push_str(&format!(" {{ {} }}", members.join(" ; ")));
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::SchemaDiff;
+
+    #[test]
+    fn ignore_formatting_and_order() {
+        let live = r#"
+            type Query {
+              stores: [Store!]!
+              store(id: ID!): Store
+            }
+
+            union Value = IntValue | StringValue
+
+            enum Color {
+              RED
+              GREEN
+            }
+        "#;
+        let expected = r#"
+            # The root query type.
+            type Query {
+              "Look up a single store."
+              store(id: ID!): Store @deprecated(reason: "use stores")
+              """
+              Every store in the profile.
+              """
+              stores: [Store!]!
+            }
+            union Value = StringValue | IntValue
+            enum Color { GREEN, RED }
+        "#;
+        let diff = SchemaDiff::compare(live, expected);
+        assert!(diff.is_empty(), "{diff:?}");
+    }
+
+    #[test]
+    fn report_added_removed_and_changed() {
+        let live = r#"
+            type Query { stores: [Store!]! }
+            type Store { id: ID! name: String! }
+            scalar DateTime
+        "#;
+        let expected = r#"
+            type Query { stores: [Store!]! }
+            type Store { id: ID! name: String }
+            scalar Guid
+        "#;
+        assert_eq!(
+            SchemaDiff::compare(live, expected),
+            SchemaDiff {
+                added: vec![String::from("DateTime")],
+                removed: vec![String::from("Guid")],
+                changed: vec![String::from("Store")],
+            }
+        );
+    }
+}
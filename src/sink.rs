@@ -0,0 +1,65 @@
+use std::sync::mpsc;
+
+/// Destination for the `next` payloads of [listen](crate::Subscription::listen), so consumers
+/// are not tied to [std::sync::mpsc] channels.
+///
+/// It is implemented for [mpsc::Sender] and [mpsc::SyncSender], and with the `crossbeam-channel`
+/// or `flume` features, for their `Sender` types as well. The payloads are sent from the worker
+/// thread, so the sink must be [Send] and `'static`.
+///
+/// # Blocking
+///
+/// The worker thread handles every command, every other subscription, and the `MAPI` message
+/// pump, so a sink must never block it waiting for the consumer. The impls for bounded channels,
+/// [mpsc::SyncSender] and a bounded `crossbeam-channel` or `flume` `Sender`, use `try_send`, so a
+/// full channel fails the send instead, and the worker thread unsubscribes just like it does when
+/// the receiver is dropped (see [listen](crate::Subscription::listen)). Size a bounded channel
+/// for the largest burst of notifications it should absorb, or use an unbounded one.
+pub trait PayloadSink: Send + 'static {
+    /// Deliver a single JSON payload without blocking. Return an [Err(String)](Err) if the
+    /// receiving end is gone, or if it cannot accept the payload right away.
+    fn send(&self, payload: String) -> Result<(), String>;
+}
+
+impl PayloadSink for mpsc::Sender<String> {
+    fn send(&self, payload: String) -> Result<(), String> {
+        mpsc::Sender::send(self, payload).map_err(|err| err.to_string())
+    }
+}
+
+impl PayloadSink for mpsc::SyncSender<String> {
+    fn send(&self, payload: String) -> Result<(), String> {
+        mpsc::SyncSender::try_send(self, payload).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(feature = "crossbeam-channel")]
+impl PayloadSink for crossbeam_channel::Sender<String> {
+    fn send(&self, payload: String) -> Result<(), String> {
+        crossbeam_channel::Sender::try_send(self, payload).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(feature = "flume")]
+impl PayloadSink for flume::Sender<String> {
+    fn send(&self, payload: String) -> Result<(), String> {
+        flume::Sender::try_send(self, payload).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PayloadSink;
+    use std::sync::mpsc;
+
+    #[test]
+    fn fail_full_sync_sender() {
+        let (tx, rx) = mpsc::sync_channel(1);
+        PayloadSink::send(&tx, String::from("1")).expect("sends the first payload");
+        assert!(
+            PayloadSink::send(&tx, String::from("2")).is_err(),
+            "should fail instead of blocking on a full channel"
+        );
+        assert_eq!(rx.try_recv().as_deref(), Ok("1"));
+    }
+}
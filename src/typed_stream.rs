@@ -0,0 +1,97 @@
+use std::{
+    collections::VecDeque,
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll, Waker},
+};
+
+/// Unbounded channel for the `next` payloads of
+/// [stream_typed](crate::Subscription::stream_typed), where the [TypedStream] wakes the task
+/// polling it as soon as the worker thread sends an item, without blocking any thread.
+pub(crate) fn channel<T>() -> (Sender<T>, TypedStream<T>) {
+    let shared = Arc::new(Mutex::new(State {
+        items: VecDeque::new(),
+        waker: None,
+        closed: false,
+    }));
+    (Sender(shared.clone()), TypedStream(shared))
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+pub(crate) struct Sender<T>(Arc<Mutex<State<T>>>);
+
+impl<T> Sender<T> {
+    /// Queue an item and wake the [TypedStream], or give the item back if it was dropped.
+    pub(crate) fn send(&self, item: T) -> Result<(), T> {
+        let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if Arc::strong_count(&self.0) == 1 {
+            return Err(item);
+        }
+        state.items.push_back(item);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// End the [TypedStream] once the queued items have been taken, e.g. on `complete`.
+    pub(crate) fn close(&self) {
+        let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        state.closed = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    /// End the [TypedStream] if the worker thread drops the callbacks without calling `complete`.
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Async stream returned from [stream_typed](crate::Subscription::stream_typed), with an item
+/// for each `next` payload of a [Subscription](crate::Subscription), which ends once `complete`
+/// is called. This works with any async runtime, since the worker thread wakes the task
+/// directly.
+///
+/// Wait for each item with [recv](TypedStream::recv), or enable the `futures-core` feature to
+/// use it as a `futures_core::Stream`.
+pub struct TypedStream<T>(Arc<Mutex<State<T>>>);
+
+impl<T> TypedStream<T> {
+    /// Wait for the next item, or [None] once the stream has ended.
+    pub fn recv(&mut self) -> impl Future<Output = Option<T>> + '_ {
+        poll_fn(|cx| Pin::new(&mut *self).poll_next(cx))
+    }
+
+    /// Poll for the next item like `futures_core::Stream::poll_next`, registering the task to
+    /// be woken when it arrives. Returns `Poll::Ready(None)` once the stream has ended.
+    pub fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(item) = state.items.pop_front() {
+            Poll::Ready(Some(item))
+        } else if state.closed {
+            Poll::Ready(None)
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(feature = "futures-core")]
+impl<T> futures_core::Stream for TypedStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        TypedStream::poll_next(self, cx)
+    }
+}
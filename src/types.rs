@@ -0,0 +1,215 @@
+//! [serde](https://serde.rs) structs mirroring the core objects in the `MAPI` schema, so common
+//! results can be deserialized without redefining them in every project. Enable the
+//! `schema-types` feature to use them.
+//!
+//! Every field is optional, so each struct fits whatever subset of its fields a query selected,
+//! and the field names are converted to and from the `camelCase` names in the schema. The union
+//! types need `__typename` in their selection set to tell the members apart, e.g.:
+//!
+//! ```graphql
+//! columns {
+//!     value {
+//!         __typename
+//!         ... on StringValue { string }
+//!     }
+//! }
+//! ```
+//!
+//! The custom `DateTime`, `Guid`, and `Stream` scalars are kept as [Value], since their shape
+//! depends on [with_tagged_scalars](crate::MAPIGraphQL::with_tagged_scalars).
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A `Store` in the `MAPI` profile, e.g. a mailbox or a PST file.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Store {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub columns: Option<Vec<Property>>,
+    pub root_folders: Option<Vec<Folder>>,
+    pub special_folders: Option<Vec<Folder>>,
+    pub search_folders: Option<Vec<Folder>>,
+    pub items: Option<Vec<Item>>,
+}
+
+/// A `Folder` in a [Store].
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Folder {
+    pub id: Option<String>,
+    pub parent_folder: Option<Box<Folder>>,
+    pub store: Option<Box<Store>>,
+    pub name: Option<String>,
+    pub count: Option<i32>,
+    pub unread: Option<i32>,
+    /// Value of the `SpecialFolder` enum, e.g. `INBOX`, if this is a special folder.
+    pub special_folder: Option<String>,
+    pub columns: Option<Vec<Property>>,
+    pub sub_folders: Option<Vec<Folder>>,
+    pub items: Option<Vec<Item>>,
+}
+
+/// An `Item` in a [Folder], e.g. a message, or an embedded [Attachment].
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Item {
+    pub id: Option<String>,
+    pub parent_folder: Option<Box<Folder>>,
+    pub subject: Option<String>,
+    pub sender: Option<String>,
+    pub to: Option<String>,
+    pub cc: Option<String>,
+    pub body: Option<String>,
+    pub received: Option<Value>,
+    pub modified: Option<Value>,
+    pub preview: Option<String>,
+    pub columns: Option<Vec<Property>>,
+    pub attachments: Option<Vec<Attachment>>,
+}
+
+/// A `FileAttachment` on an [Item].
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct FileAttachment {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub contents: Option<Value>,
+}
+
+/// The `Attachment` union, which needs `__typename` in its selection set.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "__typename")]
+pub enum Attachment {
+    FileAttachment(FileAttachment),
+    Item(Box<Item>),
+}
+
+/// A `Property` from the `columns` of a [Store], [Folder], or [Item].
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Property {
+    pub id: Option<PropId>,
+    pub value: Option<PropValue>,
+}
+
+/// The `PropId` union, which needs `__typename` in its selection set.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "__typename")]
+pub enum PropId {
+    IntId {
+        #[serde(default)]
+        id: Option<i32>,
+    },
+    NamedId {
+        #[serde(default)]
+        propset: Option<Value>,
+        #[serde(default)]
+        id: Option<NamedPropId>,
+    },
+}
+
+/// The `NamedPropId` union, which needs `__typename` in its selection set.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "__typename")]
+pub enum NamedPropId {
+    IntId {
+        #[serde(default)]
+        id: Option<i32>,
+    },
+    StringId {
+        #[serde(default)]
+        name: Option<String>,
+    },
+}
+
+/// The `PropValue` union, which needs `__typename` in its selection set.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "__typename")]
+pub enum PropValue {
+    IntValue {
+        #[serde(default)]
+        integer: Option<i32>,
+    },
+    BoolValue {
+        #[serde(default)]
+        boolean: Option<bool>,
+    },
+    StringValue {
+        #[serde(default)]
+        string: Option<String>,
+    },
+    GuidValue {
+        #[serde(default)]
+        guid: Option<Value>,
+    },
+    DateTimeValue {
+        #[serde(default)]
+        time: Option<Value>,
+    },
+    BinaryValue {
+        #[serde(default)]
+        bits: Option<Value>,
+    },
+    StreamValue {
+        #[serde(default)]
+        stream: Option<Value>,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Attachment, FileAttachment, Folder, PropValue, Property, Store};
+
+    #[test]
+    fn partial_selection() {
+        let store: Store = serde_json::from_str(
+            r#"{
+                "name": "Mailbox",
+                "rootFolders": [{ "name": "Top", "unread": 2, "specialFolder": null }],
+                "columns": [{ "value": { "__typename": "StringValue", "string": "Mailbox" } }]
+            }"#,
+        )
+        .expect("deserializes a partial Store");
+        assert_eq!(
+            store,
+            Store {
+                name: Some(String::from("Mailbox")),
+                root_folders: Some(vec![Folder {
+                    name: Some(String::from("Top")),
+                    unread: Some(2),
+                    ..Default::default()
+                }]),
+                columns: Some(vec![Property {
+                    id: None,
+                    value: Some(PropValue::StringValue {
+                        string: Some(String::from("Mailbox")),
+                    }),
+                }]),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn tagged_attachments() {
+        let attachments: Vec<Attachment> = serde_json::from_str(
+            r#"[
+                { "__typename": "FileAttachment", "name": "report.docx" },
+                { "__typename": "Item", "subject": "Forwarded" }
+            ]"#,
+        )
+        .expect("deserializes the Attachment union");
+        assert_eq!(
+            attachments[0],
+            Attachment::FileAttachment(FileAttachment {
+                name: Some(String::from("report.docx")),
+                ..Default::default()
+            })
+        );
+        assert!(
+            matches!(&attachments[1], Attachment::Item(item) if item.subject.as_deref() == Some("Forwarded"))
+        );
+    }
+}